@@ -0,0 +1,440 @@
+use polars::prelude::{CsvReader, DataFrame, PolarsResult, SerReader};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+
+use crate::audit::{event, open_audit_log, record};
+use crate::cancel::CancelToken;
+use crate::balance::{covariate_balance, SMD_THRESHOLD};
+use crate::cli::{parse_args, spec_args, Args};
+use crate::compare::{run_parallel, Comparison, SpecResult};
+use crate::completions::completions;
+use crate::data::{column_values, data_paths};
+use crate::dml::double_ml;
+use crate::estimate::{balance_covariates, matches, n_treat_control, rematch, unselected_columns, Strike};
+use crate::filter::population;
+use crate::formula::predictor_values;
+use crate::gps::dose_response;
+use crate::influence::{influence_contributions, write_influence};
+use crate::format::{outcome_line, set_number_format, single_line, OutputFormat};
+use crate::log::{log, set_log_level, LogLevel};
+use crate::memory::{memory_report, track_memory};
+use crate::meta::{MetaAnalysis, SiteEstimate};
+use crate::partition::partitioned;
+use crate::plots;
+use crate::propensity::covariate_names;
+use crate::session::Session;
+use crate::warnings::Warning;
+
+// Imports a csv file from a specified path to a Polars DataFrame
+fn import_data(path: &str) -> PolarsResult<DataFrame> {
+    let data = CsvReader::from_path(path)?.finish()?;
+    Ok(data)
+}
+
+// Run the same specification over several datasets
+//
+// Each dataset (site) is analysed independently and the site-level ATTs are
+// pooled with fixed- and random-effects meta-analysis. If the run is
+// cancelled, the sites completed so far are pooled.
+fn batch(paths: &[String], args: &Args) -> Result<MetaAnalysis, Box<dyn Error>> {
+    let mut sites = Vec::with_capacity(paths.len());
+    for path in paths {
+        if args.cancel.is_cancelled() {
+            break;
+        }
+        let start = Instant::now();
+        let data = import_data(path)?;
+        record("import", None, Some(data.height()), start.elapsed(), path);
+        let data = population(data, args)?;
+        let strike = match matches(&data, args) {
+            Err(_) if args.cancel.is_cancelled() => break,
+            result => result?
+        };
+        sites.push(SiteEstimate {
+            site: path.clone(),
+            att: strike.att,
+            att_variance: strike.att_variance
+        });
+    }
+    if args.cancel.is_cancelled() && sites.is_empty() {
+        return Err("Cancelled before any site was completed".into());
+    }
+    MetaAnalysis::new(sites)
+}
+
+// Render the plots requested on the command line for a single analysis
+//
+// The overlap plot compares the propensity scores of the treated units with
+// the full control pool, the Love plot shows covariate SMDs before and after
+// matching, and one eQQ plot per covariate compares the treated units with
+// their matched controls.
+fn write_plots(strike: &Strike, args: &Args) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = &args.plots.overlap {
+        let treat = column_values(&strike.treat, "propensities")?;
+        let pool = column_values(&strike.pool, "propensities")?;
+        plots::overlap_plot(path, &treat, &pool)?;
+    }
+    if let (Some(path), Some(caliper)) = (&args.plots.caliper, &strike.caliper) {
+        plots::caliper_plot(path, &caliper.plot_rows())?;
+    }
+    let covariates = balance_covariates(&strike.treat, &strike.pool, args)?;
+    if let Some(path) = &args.plots.balance {
+        let balance: Vec<(String, f64, f64)> = covariate_balance(
+            &strike.treat,
+            &strike.pool,
+            &strike.control,
+            &covariates
+        )?
+            .into_iter()
+            .map(|b| (b.covariate, b.smd_before, b.smd_after))
+            .collect();
+        plots::love_plot(path, &balance, args.balance_threshold.unwrap_or(SMD_THRESHOLD))?;
+    }
+    let selected: Vec<String> = match args.plots.covariates.is_empty() {
+        true => covariates,
+        false => args.plots.covariates.clone()
+    };
+    if let Some(dir) = &args.plots.eqq {
+        fs::create_dir_all(dir)?;
+        for covariate in &selected {
+            let treat = predictor_values(&strike.treat, covariate)?;
+            let control = predictor_values(&strike.control, covariate)?;
+            let path = Path::new(dir).join(format!("{}.svg", covariate));
+            plots::eqq_plot(&path.to_string_lossy(), covariate, &treat, &control)?;
+        }
+    }
+    if let Some(dir) = &args.plots.density {
+        fs::create_dir_all(dir)?;
+        for covariate in &selected {
+            let treat = predictor_values(&strike.treat, covariate)?;
+            let pool = predictor_values(&strike.pool, covariate)?;
+            let control = predictor_values(&strike.control, covariate)?;
+            let path = Path::new(dir).join(format!("{}.svg", covariate));
+            plots::distribution_plot(&path.to_string_lossy(), covariate, &treat, &pool, &control)?;
+        }
+    }
+    Ok(())
+}
+
+// Compare several specifications on the same data
+//
+// Every `--spec` is estimated independently on the shared DataFrame, at
+// most `--jobs` at a time (one by default).
+fn compare(data: &DataFrame, argv: &[String], args: &Args) -> Result<Comparison, Box<dyn Error>> {
+    let mut spec_list = Vec::with_capacity(args.specs.len());
+    for spec in &args.specs {
+        let mut spec_args = spec_args(argv, spec)?;
+        spec_args.cancel = args.cancel.clone();
+        spec_list.push((spec.clone(), spec_args));
+    }
+    let results = run_parallel(spec_list.len(), args.jobs.unwrap_or(1), |i| {
+        let (spec, spec_args) = &spec_list[i];
+        let estimate = matches(data, spec_args)
+            .map(|strike| (strike.att, strike.att_variance, strike.treat.height()))
+            .map_err(|e| e.to_string());
+        SpecResult { spec: spec.clone(), estimate }
+    });
+    Ok(Comparison { results })
+}
+
+// Exit status of a run stopped by `--fail-on-warning`.
+pub const WARNING_EXIT_CODE: i32 = 3;
+
+// Stop a run on a warning selected by `--fail-on-warning`
+//
+// Records the warning in the audit log, prints it (and the memory use so
+// far, if requested) and exits with status `WARNING_EXIT_CODE`.
+fn stop_failed(warning: &Warning, args: &Args, run_start: Instant) -> ! {
+    record("failed", None, None, run_start.elapsed(), &warning.to_string());
+    report_memory(args);
+    eprintln!("{}", warning);
+    process::exit(WARNING_EXIT_CODE);
+}
+
+// Stop a cancelled run
+//
+// Records where the run stopped in the audit log, reports it (and the
+// memory use so far, if requested) and exits with status 130, the
+// convention for a run stopped by Ctrl-C.
+fn stop_cancelled(reason: &str, args: &Args, run_start: Instant) -> ! {
+    record("cancelled", None, None, run_start.elapsed(), reason);
+    report_memory(args);
+    eprintln!("{}", reason);
+    process::exit(130);
+}
+
+// Handle Ctrl-C and SIGTERM
+//
+// The first signal sets the run's cancellation token so that it stops at
+// the next unit and reports what was completed; a second one exits at once.
+fn handle_signals(cancel: &CancelToken) -> io::Result<()> {
+    for signal in [SIGINT, SIGTERM] {
+        flag::register_conditional_shutdown(signal, 130, cancel.flag())?;
+        flag::register(signal, cancel.flag())?;
+    }
+    Ok(())
+}
+
+// Print the peak memory of every stage to stderr, if requested
+//
+// Goes to stderr so that it never mixes with single-line output.
+fn report_memory(args: &Args) {
+    if args.memory {
+        eprint!("{}", memory_report());
+    }
+}
+
+// Run the `strike` command line tool
+//
+// Everything the binary does: parse the command line, run the analysis and
+// print it. Crates that add matchers or estimators through
+// `strike::registry` register them and then call this from their own `main`,
+// so that their methods are selectable by name on the command line. Errors
+// are returned for `main` to report; runs stopped by `--fail-on-warning` or
+// cancelled exit with their own status.
+pub fn main() -> Result<(), Box<dyn Error>> {
+    // Import command line arguments
+    let argv: Vec<String> = env::args().collect();
+    if argv.get(1).map(String::as_str) == Some("completions") {
+        let shell = argv.get(2).map_or("", String::as_str);
+        print!("{}", completions(shell)?);
+        return Ok(());
+    }
+    let args = parse_args(&argv)?;
+    handle_signals(&args.cancel).map_err(|e| format!("Failed to handle signals: {}", e))?;
+    let run_start = Instant::now();
+    set_log_level(args.log_level);
+    if let Some(path) = &args.audit_log {
+        open_audit_log(path).map_err(|e| format!("Failed to open the audit log {}: {}", path, e))?;
+    }
+    event("start", &format!("{:?}", args));
+    if args.memory {
+        track_memory();
+    }
+    set_number_format(args.format);
+    // Must happen before polars starts its thread pool
+    if args.deterministic {
+        env::set_var("POLARS_MAX_THREADS", "1");
+    } else if let Some(threads) = args.threads {
+        env::set_var("POLARS_MAX_THREADS", threads.to_string());
+    }
+
+    // Several datasets are analysed separately and then pooled
+    let extensions: &[&str] = match args.partitions {
+        true => &["csv", "parquet"],
+        false => &["csv"]
+    };
+    let paths = data_paths(&args.path, extensions).map_err(|e| format!("Failed to read {}: {}", args.path, e))?;
+    if paths.is_empty() {
+        return Err(format!("No {} files were found at {}", extensions.join(" or "), args.path).into());
+    }
+    let batch_mode = paths.len() > 1 && !args.partitions;
+    if batch_mode && (args.save_session.is_some() || args.update_session.is_some()) {
+        return Err("Sessions work with a single csv file, not in batch mode".into());
+    }
+    if batch_mode && args.by.is_some() {
+        return Err("Subgroups (`--by`) are estimated on a single csv file, not in batch mode".into());
+    }
+    if batch_mode && (args.balance || args.balance_only) {
+        return Err("The balance table is reported for a single csv file, not in batch mode".into());
+    }
+    if batch_mode {
+        let meta = match batch(&paths, &args) {
+            Err(e) if args.cancel.is_cancelled() => stop_cancelled(&e.to_string(), &args, run_start),
+            result => result?
+        };
+        if let Some(forest) = &args.forest {
+            meta.write_forest(forest)?;
+        }
+        if let Some(path) = &args.plots.forest {
+            plots::forest_plot(path, &meta.forest_rows())?;
+        }
+        println!("{}", meta);
+        record(
+            "finish",
+            Some(paths.len()),
+            None,
+            run_start.elapsed(),
+            &format!("fixed={} random={}", meta.fixed, meta.random)
+        );
+        if args.cancel.is_cancelled() {
+            let reason = format!(
+                "Cancelled after {} of {} sites; the pooled estimates cover the completed sites only",
+                meta.sites.len(),
+                paths.len()
+            );
+            stop_cancelled(&reason, &args, run_start);
+        }
+        report_memory(&args);
+        return Ok(());
+    }
+
+    // Partitions of one dataset are matched in parallel and merged
+    let (mut strike, rows) = match args.partitions {
+        true => {
+            let strike = match partitioned(&paths, &args) {
+                Err(e) if args.cancel.is_cancelled() => stop_cancelled(&e.to_string(), &args, run_start),
+                result => result?
+            };
+            let rows = strike.treat.height() + strike.pool.height();
+            (strike, rows)
+        },
+        false => {
+            // Execute matching algo
+            let start = Instant::now();
+            let match_data = import_data(&paths[0]).map_err(|e| format!("Failed to read {}: {}", paths[0], e))?;
+            record("import", None, Some(match_data.height()), start.elapsed(), &paths[0]);
+            let match_data = population(match_data, &args)?;
+
+            // A continuous treatment gets a dose-response curve instead
+            if !args.gps.is_empty() {
+                let start = Instant::now();
+                let unselected = unselected_columns(&match_data, &args)?;
+                let covariates: Vec<String> = covariate_names(&match_data, &[&args.treatment])
+                    .into_iter()
+                    .filter(|c| !unselected.contains(c))
+                    .collect();
+                let curve = dose_response(&match_data, &args.treatment, &args.outcome, &covariates, &args.gps)?;
+                record(
+                    "gps",
+                    Some(match_data.height()),
+                    None,
+                    start.elapsed(),
+                    &format!("effective_n={} max_weight={}", curve.effective_n, curve.max_weight)
+                );
+                println!("{}", curve);
+                record("finish", Some(match_data.height()), None, run_start.elapsed(), "");
+                report_memory(&args);
+                return Ok(());
+            }
+
+            // Double machine learning replaces matching altogether
+            if args.dml {
+                let start = Instant::now();
+                let estimate = double_ml(&match_data, &args)?;
+                record(
+                    "dml",
+                    Some(match_data.height()),
+                    None,
+                    start.elapsed(),
+                    &format!("folds={} clipped={}", estimate.folds, estimate.clipped)
+                );
+                for warning in &estimate.warnings {
+                    event("warning", &warning.to_string());
+                }
+                if let Some(fail_on) = &args.fail_on_warning {
+                    if let Some(warning) = estimate.warnings.iter().find(|w| fail_on.fails(w)) {
+                        stop_failed(warning, &args, run_start);
+                    }
+                }
+                println!("{}", estimate);
+                record("finish", Some(match_data.height()), None, run_start.elapsed(), "");
+                report_memory(&args);
+                return Ok(());
+            }
+
+            // Several specifications are compared on the same data
+            if !args.specs.is_empty() {
+                let comparison = compare(&match_data, &argv, &args)?;
+                println!("{}", comparison);
+                record("finish", Some(match_data.height()), None, run_start.elapsed(), "");
+                if args.cancel.is_cancelled() {
+                    stop_cancelled("Cancelled; specifications still running were stopped", &args, run_start);
+                }
+                report_memory(&args);
+                return Ok(());
+            }
+
+            // A saved session is updated with the appended rows only
+            let session = match &args.update_session {
+                Some(path) => {
+                    Some(Session::read(path).map_err(|e| format!("Failed to read the session {}: {}", path, e))?)
+                },
+                None => None
+            };
+            let estimate = match &session {
+                Some(session) => rematch(&match_data, session, &args),
+                None => matches(&match_data, &args)
+            };
+            let strike = match estimate {
+                Err(e) if args.cancel.is_cancelled() => stop_cancelled(&e.to_string(), &args, run_start),
+                result => result?
+            };
+            (strike, match_data.height())
+        }
+    };
+    strike.budget = args.budget.as_ref().map(|budget| budget.report());
+    let start = Instant::now();
+    strike.warnings = strike.check(&args)?;
+    record("check", Some(strike.treat.height()), None, start.elapsed(), "");
+    for warning in &strike.warnings {
+        event("warning", &warning.to_string());
+    }
+    if let Some(fail_on) = &args.fail_on_warning {
+        if let Some(warning) = strike.warnings.iter().find(|w| fail_on.fails(w)) {
+            stop_failed(warning, &args, run_start);
+        }
+    }
+    if let Some(path) = args.save_session.as_ref().or(args.update_session.as_ref()) {
+        let start = Instant::now();
+        Session::new(&strike, &args, rows)
+            .and_then(|session| session.write(path))
+            .map_err(|e| format!("Failed to save the session {}: {}", path, e))?;
+        record("session", Some(rows), None, start.elapsed(), path);
+    }
+    write_plots(&strike, &args)?;
+    if let (Some(path), Some(caliper)) = (&args.caliper_csv, &strike.caliper) {
+        caliper.write_csv(path)?;
+    }
+    if let Some(path) = &args.influence {
+        let start = Instant::now();
+        let contributions = influence_contributions(&strike.treat, &strike.control, &args.outcome)?;
+        write_influence(path, &contributions)?;
+        record("influence", Some(contributions.len()), None, start.elapsed(), path);
+    }
+
+    // Display the ATT and corresponding 95% confidence interval
+    match args.output {
+        _ if args.balance_only => print!("{}", strike.balance.as_ref().ok_or("The balance table is missing")?),
+        OutputFormat::Summary => println!("{}", strike),
+        format => {
+            let (n_treat, n_control) = n_treat_control(&strike.treat, &strike.control)?;
+            let line = single_line(format, strike.att, strike.att_variance, n_treat, n_control);
+            match strike.extra_outcomes.is_empty() {
+                true => println!("{}", line),
+                false => println!("{}", outcome_line(format, &strike.outcome, &line))
+            }
+            for extra in &strike.extra_outcomes {
+                let line = single_line(format, extra.att, extra.att_variance, n_treat, n_control);
+                println!("{}", outcome_line(format, &extra.outcome, &line));
+            }
+            for warning in &strike.warnings {
+                log(LogLevel::Warn, &warning.to_string());
+            }
+        }
+    }
+    record(
+        "finish",
+        Some(rows),
+        Some(strike.treat.height()),
+        run_start.elapsed(),
+        &format!("att={} variance={}", strike.att, strike.att_variance)
+    );
+    if args.cancel.is_cancelled() {
+        let reason = match strike.cancelled.is_empty() {
+            true => "Cancelled after the estimate was complete".to_string(),
+            false => format!("Cancelled; the summary leaves out the {}", strike.cancelled.join(", "))
+        };
+        stop_cancelled(&reason, &args, run_start);
+    }
+    report_memory(&args);
+    Ok(())
+}
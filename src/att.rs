@@ -1,7 +1,28 @@
+use ndarray::{s, Array1, Array2};
+use polars::datatypes::DataType::{Float64, Utf8};
 use polars::frame::UniqueKeepStrategy;
-use polars::prelude::{DataFrame, DataFrameJoinOps, PolarsResult};
+use polars::prelude::{
+    BooleanChunked,
+    DataFrame,
+    DataFrameJoinOps,
+    NamedFrom,
+    PolarsError,
+    PolarsResult,
+    Series
+};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
+use crate::balance::mean_variance;
+use crate::cancel::CancelToken;
+use crate::data::{column_values, quantile};
 use crate::distance::nn_match;
+use crate::formula::predictor_matrix;
+use crate::regression::wls;
 
 // Calculate the ATT
 //
@@ -9,26 +30,146 @@ use crate::distance::nn_match;
 // and the second is the matched control sample. Then, given the name of
 // the outcomes column, it calculates and returns the ATT. The ATT is
 // simply the mean of the element-wise difference between the treated
-// outcomes and control outcomes.
-// NOTE: This is NOT the bias-corrected ATT estimator.
+// outcomes and control outcomes. With k:1 matching a treated unit fills
+// one row per match, and it is the mean over treated units of the
+// difference from the average of their matched outcomes.
+// NOTE: This is NOT the bias-corrected ATT estimator (see
+// `calculate_bias_corrected_att`).
 pub fn calculate_att(
     treat: &DataFrame,
     control: &DataFrame,
     outcome: &str
 ) -> PolarsResult<f64> {
+    let (weights, n_treat) = match_weights(treat)?;
     let observed_y = treat.column(outcome)?;
     let matched_y = control.column(outcome)?;
-    let y_diff = (observed_y - matched_y).clone();
-    let att: Vec<Option<f64>> = y_diff.mean_as_series().f64()?.to_vec();
-    let att: &Option<f64> = att.first().unwrap();
+    let y_diff = (observed_y - matched_y).cast(&Float64)?;
+    let att: Option<f64> = (&y_diff * &Series::new("weights", weights)).sum();
     match att {
-        Some(a) => {
-            return Ok(*a);
-        },
+        Some(a) => Ok(a / n_treat as f64),
         None => panic!("calculate_att: ATT could not be calculated!")
     }
 }
 
+// Calculate the bias-corrected ATT
+//
+// The matching estimator of Abadie and Imbens (2011) with regression bias
+// correction: a linear model of the outcome on an intercept and the
+// predictors `covariates` (columns or `column=level` dummies, see
+// `predictor_values`) is fit on the matched controls, each weighted by how
+// often it is used, and every pair's outcome difference is adjusted by the
+// difference the model predicts from the covariate gap between the treated
+// unit and its match, mu(X_i) - mu(X_j). The ATT is the mean of the
+// adjusted differences, weighted as in `calculate_att`.
+pub fn calculate_bias_corrected_att(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    covariates: &[String]
+) -> PolarsResult<f64> {
+    let design = |data: &DataFrame| -> PolarsResult<Array2<f64>> {
+        let mut x = Array2::<f64>::ones((data.height(), covariates.len() + 1));
+        x.slice_mut(s![.., 1..]).assign(&predictor_matrix(data, covariates)?);
+        Ok(x)
+    };
+    let uses = match_uses(treat, control)?;
+    let units = control.unique_stable(Some(&["strike_id".to_string()]), UniqueKeepStrategy::First, None)?;
+    let w: Array1<f64> = column_values(&units, "strike_id")?.iter().map(|id| uses[&(*id as i64)]).collect();
+    let fit = wls(&design(&units)?, &Array1::from(column_values(&units, outcome)?), &w);
+    let gap = design(treat)?.dot(&fit.coefficients) - design(control)?.dot(&fit.coefficients);
+    let (weights, n_treat) = match_weights(treat)?;
+    let differences = pair_differences(treat, control, outcome)?;
+    let att: f64 = differences
+        .iter()
+        .zip(gap.iter())
+        .zip(weights)
+        .map(|((d, g), w)| w * (d - g))
+        .sum();
+    Ok(att / n_treat as f64)
+}
+
+// Weight of every matched pair
+//
+// A treated unit with M matches fills M rows of `treat`, each weighted 1/M.
+// Also returns the number of distinct treated units.
+pub fn match_weights(treat: &DataFrame) -> PolarsResult<(Vec<f64>, usize)> {
+    let ids = column_values(treat, "strike_id")?;
+    let mut matches: HashMap<i64, usize> = HashMap::new();
+    for id in &ids {
+        *matches.entry(*id as i64).or_insert(0) += 1;
+    }
+    let weights = ids.iter().map(|id| 1.0 / matches[&(*id as i64)] as f64).collect();
+    Ok((weights, matches.len()))
+}
+
+// Variance estimators of the matching ATT (`--variance`)
+//
+// `AiRobust` is the heteroskedasticity-robust estimator of Abadie and
+// Imbens (2006), with a conditional outcome variance for every unit.
+// `AiHomo` is their homoskedastic estimator, which pools those conditional
+// variances into one. `Paired` is the variance of the matched differences
+// over the number of treated units, as in a paired t-test; it ignores
+// control reuse, so it is too small when controls are matched often.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Variance {
+    #[default]
+    AiRobust,
+    AiHomo,
+    Paired
+}
+
+// Implement `FromStr` for `Variance`.
+impl FromStr for Variance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ai-robust" => Ok(Variance::AiRobust),
+            "ai-homo" => Ok(Variance::AiHomo),
+            "paired" => Ok(Variance::Paired),
+            _ => Err(format!("Unknown variance `{}`; use ai-robust, ai-homo or paired", s))
+        }
+    }
+}
+
+// Implement `Display` for `Variance`.
+impl fmt::Display for Variance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variance::AiRobust => write!(f, "ai-robust"),
+            Variance::AiHomo => write!(f, "ai-homo"),
+            Variance::Paired => write!(f, "paired")
+        }
+    }
+}
+
+// Same-group neighbors behind every unit's conditional outcome variance
+// unless `--variance-neighbors` says otherwise (as in Stata's `teffects
+// nnmatch`).
+pub const DEFAULT_VARIANCE_NEIGHBORS: usize = 2;
+
+// How the conditional outcome variances of the ATT variance are estimated
+//
+// Every unit's conditional variance comes from its `neighbors` closest
+// other units of its own group. With `sorted` the closest unit is found in
+// propensity score order instead of by scanning the group, which takes
+// O(n log n) rather than O(n^2); several neighbors are always found that
+// way. With `homoskedastic` every unit gets the mean of the conditional
+// variances of all units (the `ai-homo` variance).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VarianceOptions {
+    pub neighbors: usize,
+    pub sorted: bool,
+    pub homoskedastic: bool
+}
+
+// The Abadie-Imbens defaults.
+impl Default for VarianceOptions {
+    fn default() -> Self {
+        VarianceOptions { neighbors: DEFAULT_VARIANCE_NEIGHBORS, sorted: false, homoskedastic: false }
+    }
+}
+
 // Calculate the ATT variance
 //
 // This function implements the consistent estimator for the variance of the
@@ -40,20 +181,49 @@ pub fn calculate_variance(
     treat: &DataFrame,
     control: &DataFrame,
     outcome: &str,
-    treatment: &str
+    treatment: &str,
+    cancel: &CancelToken
+) -> PolarsResult<f64> {
+    calculate_variance_with(treat, control, outcome, treatment, cancel, VarianceOptions::default())
+}
+
+// Calculate the ATT variance with the conditional variances of `options`
+//
+// The self-matches found in propensity score order, and so the variance,
+// are the same as those found by scanning the group except when several
+// units tie for the closest match, where a different one of them may be
+// picked.
+pub fn calculate_variance_with(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    treatment: &str,
+    cancel: &CancelToken,
+    options: VarianceOptions
 ) -> PolarsResult<f64> {
-    let treat_with_variance = subsample_conditional_variance(treat, outcome)?;
-    let control_with_variance = subsample_conditional_variance(control, outcome)?;
-    let treat_control = treat_with_variance.vstack(&control_with_variance)?;
+    let treat_with_variance = subsample_conditional_variance(treat, outcome, options, cancel)?;
+    let control_with_variance = subsample_conditional_variance(control, outcome, options, cancel)?;
+    let mut treat_control = treat_with_variance.vstack(&control_with_variance)?;
+    if options.homoskedastic {
+        let variances = column_values(&treat_control, "conditional_variance")?;
+        let pooled = variances.iter().sum::<f64>() / variances.len() as f64;
+        treat_control.with_column(Series::new("conditional_variance", vec![pooled; variances.len()]))?;
+    }
     let sample_treat = treat_control.column(treatment)?;
-    let sample_id_count = treat_control
-        .column("strike_id_count")
-        .expect("This column should never be missing!");
+    // K_M: how often each control is used, each use weighted by the 1/M of
+    // its pair
+    let (_, n_treat) = match_weights(treat)?;
+    let uses = match_uses(treat, control)?;
+    let sample_uses: Vec<f64> = column_values(&treat_control, "strike_id")?
+        .iter()
+        .map(|id| uses.get(&(*id as i64)).copied().unwrap_or(0.0))
+        .collect();
+    let sample_uses = Series::new("uses", sample_uses);
     let sample_conditional_var = treat_control
         .column("conditional_variance")
         .expect("This column should never be missing!");
-    let n_treat_sq = (treat.height() * treat.height()) as f64;
-    let weighted_treat = sample_treat - &(((sample_treat - 1) * -1) * sample_id_count.clone());
+    let n_treat_sq = (n_treat * n_treat) as f64;
+    let weighted_treat = sample_treat - &(((sample_treat - 1) * -1) * sample_uses);
     let weighted_treat = &weighted_treat * &weighted_treat;
     let treat_by_var: Option<f64> = (weighted_treat * sample_conditional_var.clone()).sum();
     match treat_by_var {
@@ -65,24 +235,350 @@ pub fn calculate_variance(
     }
 }
 
+// Calculate the paired variance of the ATT
+//
+// The sample variance of the differences between every treated unit and
+// the mean of its matches, divided by the number of treated units.
+pub fn calculate_paired_variance(treat: &DataFrame, control: &DataFrame, outcome: &str) -> PolarsResult<f64> {
+    let (treated, matched) = unit_outcomes(treat, control, outcome)?;
+    let n = treated.len() as f64;
+    if n < 2.0 {
+        return Err(PolarsError::ComputeError("The paired variance needs at least 2 treated units".into()));
+    }
+    let differences: Vec<f64> = treated.iter().zip(&matched).map(|(t, m)| t - m).collect();
+    let mean = differences.iter().sum::<f64>() / n;
+    Ok(differences.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (n - 1.0) / n)
+}
+
+// How often every control is used as a match, each use weighted by the
+// 1/M of its pair, by `strike_id`.
+fn match_uses(treat: &DataFrame, control: &DataFrame) -> PolarsResult<HashMap<i64, f64>> {
+    let (weights, _) = match_weights(treat)?;
+    let mut uses: HashMap<i64, f64> = HashMap::new();
+    for (id, w) in column_values(control, "strike_id")?.iter().zip(weights) {
+        *uses.entry(*id as i64).or_insert(0.0) += w;
+    }
+    Ok(uses)
+}
+
+// Outcome of every treated unit and the mean outcome of its matches
+//
+// A treated unit with M matches fills M consecutive rows of `treat`, as
+// matched samples are kept in `strike_id` order.
+pub fn unit_outcomes(treat: &DataFrame, control: &DataFrame, outcome: &str) -> PolarsResult<(Vec<f64>, Vec<f64>)> {
+    let ids = column_values(treat, "strike_id")?;
+    let y_treat = column_values(treat, outcome)?;
+    let y_control = column_values(control, outcome)?;
+    let (mut treated, mut matched) = (Vec::new(), Vec::new());
+    let mut start = 0;
+    while start < ids.len() {
+        let end = (start..ids.len()).find(|i| ids[*i] != ids[start]).unwrap_or(ids.len());
+        treated.push(y_treat[start]);
+        matched.push(y_control[start..end].iter().sum::<f64>() / (end - start) as f64);
+        start = end;
+    }
+    Ok((treated, matched))
+}
+
+// How the differences of the treated units from their matches are averaged
+//
+// `Mean` is the usual ATT. `Median` and `Trimmed(p)`, the mean after
+// dropping the fraction `p` of differences at each end, are robust to
+// heavy-tailed outcomes such as costs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Location {
+    #[default]
+    Mean,
+    Median,
+    Trimmed(f64)
+}
+
+// Trimming fraction of `trimmed` without one.
+pub const DEFAULT_LOCATION_TRIM: f64 = 0.1;
+
+// Resamples of the treated units behind the variance of a median or
+// trimmed-mean ATT.
+pub const LOCATION_BOOTSTRAP_REPLICATES: usize = 500;
+
+// Implement `FromStr` for `Location`.
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "mean" => Ok(Location::Mean),
+            None if s == "median" => Ok(Location::Median),
+            None if s == "trimmed" => Ok(Location::Trimmed(DEFAULT_LOCATION_TRIM)),
+            Some(("trimmed", trim)) => match trim.parse::<f64>() {
+                Ok(trim) if (0.0..0.5).contains(&trim) => Ok(Location::Trimmed(trim)),
+                _ => Err(format!("The trimming fraction of `{}` must be in [0, 0.5)", s))
+            },
+            _ => Err(format!("Unknown location `{}`; use mean, median or trimmed[:p]", s))
+        }
+    }
+}
+
+// Implement `Display` for `Location`.
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Mean => write!(f, "mean"),
+            Location::Median => write!(f, "median"),
+            Location::Trimmed(trim) => write!(f, "trimmed:{}", trim)
+        }
+    }
+}
+
+impl Location {
+    // The location of `differences`, which are sorted in place.
+    fn of(&self, differences: &mut [f64]) -> f64 {
+        differences.sort_by(|a, b| a.total_cmp(b));
+        let n = differences.len();
+        match self {
+            Location::Mean => differences.iter().sum::<f64>() / n as f64,
+            Location::Median => quantile(differences, 0.5),
+            Location::Trimmed(trim) => {
+                let g = (trim * n as f64).floor() as usize;
+                let kept = &differences[g..(n - g)];
+                kept.iter().sum::<f64>() / kept.len() as f64
+            }
+        }
+    }
+}
+
+// Calculate a robust ATT and its bootstrap variance
+//
+// The ATT is the `location` of the differences between every treated unit
+// and the mean of its matches. The Abadie-Imbens variance only applies to
+// the mean, so the variance is that of the estimate over
+// `LOCATION_BOOTSTRAP_REPLICATES` resamples of the treated units with
+// their matches, drawn with `rng`. Like the paired tests it treats the
+// treated units as independent, ignoring control reuse. Stops with an
+// error if `cancel` is set.
+pub fn calculate_location_att(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    location: Location,
+    rng: &mut StdRng,
+    cancel: &CancelToken
+) -> PolarsResult<(f64, f64)> {
+    let (treated, matched) = unit_outcomes(treat, control, outcome)?;
+    let differences: Vec<f64> = treated.iter().zip(&matched).map(|(t, c)| t - c).collect();
+    let n = differences.len();
+    if n < 2 || matches!(location, Location::Trimmed(trim) if 2 * (trim * n as f64).floor() as usize >= n) {
+        return Err(PolarsError::ComputeError(
+            format!("Not enough treated units for a {} ATT", location).into()
+        ));
+    }
+    let att = location.of(&mut differences.clone());
+    let mut replicates = Vec::with_capacity(LOCATION_BOOTSTRAP_REPLICATES);
+    for b in 0..LOCATION_BOOTSTRAP_REPLICATES {
+        cancel.check(|| {
+            format!("during the {} ATT bootstrap after {} of {} replicates", location, b, LOCATION_BOOTSTRAP_REPLICATES)
+        })?;
+        let mut resample: Vec<f64> = (0..n).map(|_| differences[rng.gen_range(0..n)]).collect();
+        replicates.push(location.of(&mut resample));
+    }
+    let (_, att_variance) = mean_variance(&replicates);
+    Ok((att, att_variance))
+}
+
+// Which average treatment effect is estimated
+//
+// `Att` matches treated units to controls, `Atc` controls to treated units,
+// and `Ate` does both and combines the two directions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Estimand {
+    #[default]
+    Att,
+    Atc,
+    Ate
+}
+
+// Implement `FromStr` for `Estimand`.
+impl FromStr for Estimand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "att" => Ok(Estimand::Att),
+            "atc" => Ok(Estimand::Atc),
+            "ate" => Ok(Estimand::Ate),
+            _ => Err(format!("Unknown estimand `{}`; use att, atc or ate", s))
+        }
+    }
+}
+
+// Implement `Display` for `Estimand`.
+impl fmt::Display for Estimand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Estimand::Att => write!(f, "att"),
+            Estimand::Atc => write!(f, "atc"),
+            Estimand::Ate => write!(f, "ate")
+        }
+    }
+}
+
+// The ATE and the effects on the treated and on the controls it combines.
+#[derive(Debug)]
+pub struct Ate {
+    pub att: f64,
+    pub atc: f64,
+    pub ate: f64,
+    pub ate_variance: f64
+}
+
+impl Ate {
+    // Average two independent estimates, as in a cross-fit.
+    pub fn combine(self, other: Ate) -> Ate {
+        Ate {
+            att: (self.att + other.att) / 2.0,
+            atc: (self.atc + other.atc) / 2.0,
+            ate: (self.ate + other.ate) / 2.0,
+            ate_variance: (self.ate_variance + other.ate_variance) / 4.0
+        }
+    }
+}
+
+// Calculate the ATE and its variance
+//
+// `treated` holds the treated units and their matched controls, as for
+// `calculate_att`, and `controls` the control units and their matched
+// treated units. The ATT and the ATC (the effect on the controls, matched
+// treated outcome minus control outcome) are weighted by the group sizes.
+// The variance is the sample ATE variance of Abadie and Imbens (2006),
+// sum_i (1 + K_M(i) / M)^2 sigma^2(X_i) / N^2 over all N units, where
+// K_M(i) counts the uses of unit i as a match in the other direction and
+// sigma^2 comes from the `neighbors` closest other units of its group.
+pub fn calculate_ate(
+    treated: (&DataFrame, &DataFrame),
+    controls: (&DataFrame, &DataFrame),
+    outcome: &str,
+    neighbors: usize,
+    cancel: &CancelToken
+) -> PolarsResult<Ate> {
+    let att = calculate_att(treated.0, treated.1, outcome)?;
+    let atc = -calculate_att(controls.0, controls.1, outcome)?;
+    let (_, n_treat) = match_weights(treated.0)?;
+    let (_, n_control) = match_weights(controls.0)?;
+    let n = (n_treat + n_control) as f64;
+    let ate = (n_treat as f64 * att + n_control as f64 * atc) / n;
+    let mut total = 0.0;
+    // Treated units are used by the controls' matches and vice versa
+    let groups = [
+        (treated.0, match_uses(controls.0, controls.1)?),
+        (controls.0, match_uses(treated.0, treated.1)?)
+    ];
+    for (units, uses) in groups {
+        let options = VarianceOptions { neighbors, ..VarianceOptions::default() };
+        let units = subsample_conditional_variance(units, outcome, options, cancel)?;
+        let variances = column_values(&units, "conditional_variance")?;
+        for (id, variance) in column_values(&units, "strike_id")?.iter().zip(variances) {
+            let k = uses.get(&(*id as i64)).copied().unwrap_or(0.0);
+            total += (1.0 + k).powi(2) * variance;
+        }
+    }
+    Ok(Ate { att, atc, ate, ate_variance: total / (n * n) })
+}
+
+// Calculate the ATT and its variance on a subset of the matched pairs
+//
+// `keep` marks the pairs (rows of `treat` and `control`) to retain. Subsets
+// of fewer than two pairs have no estimate and return NaN.
+pub fn calculate_subset_att(
+    treat: &DataFrame,
+    control: &DataFrame,
+    keep: &[bool],
+    outcome: &str,
+    treatment: &str,
+    cancel: &CancelToken
+) -> PolarsResult<(f64, f64)> {
+    if keep.iter().filter(|k| **k).count() < 2 {
+        return Ok((f64::NAN, f64::NAN));
+    }
+    let mask = BooleanChunked::new("subset", keep);
+    let treat = treat.filter(&mask)?;
+    let control = control.filter(&mask)?;
+    let att = calculate_att(&treat, &control, outcome)?;
+    let att_variance = calculate_variance(&treat, &control, outcome, treatment, cancel)?;
+    Ok((att, att_variance))
+}
+
 // Estimates the observation-level conditional variance as a necessary piece of
-// estimating the full-sample ATT variance.
+// estimating the full-sample ATT variance. Every unit is matched to its J
+// closest other units of its own group (`options.neighbors`, or all of
+// them if the group is smaller), and its conditional variance is
+// J / (J + 1) times the squared difference of its outcome from theirs
+// (Abadie and Imbens, 2006), so each group needs at least two distinct
+// units.
 fn subsample_conditional_variance(
     data: &DataFrame,
-    outcome: &str
+    outcome: &str,
+    options: VarianceOptions,
+    cancel: &CancelToken
 ) -> PolarsResult<DataFrame> {
     let mut data = subsample_count_matches(data)?;
-    let self_matches = nn_match(&data, &mut data.clone())?;
-    let observed_y = data.column(outcome)?;
-    let matched_y = self_matches.column(outcome)?;
-    let mean_y = (observed_y + matched_y) / 2.0;
-    let mut cond_var = ((observed_y - &mean_y) * (observed_y - &mean_y))
-        + ((matched_y - &mean_y) * (matched_y - &mean_y));
-    let cond_var = cond_var.rename("conditional_variance").clone();
-    data.with_column(cond_var)?;
+    if data.height() < 2 {
+        return Err(PolarsError::ComputeError(format!(
+            "The ATT variance needs at least 2 distinct units in each matched group but one has {}; \
+             the propensity scores may be separated (see --drop-separating)",
+            data.height()
+        ).into()));
+    }
+    let y = column_values(&data, outcome)?;
+    let j = options.neighbors.clamp(1, data.height() - 1);
+    let matched_y = match (j, options.sorted) {
+        (1, false) => column_values(&nn_match(&data, &mut data.clone(), cancel)?, outcome)?,
+        _ => sorted_neighbors(&data, j)?
+            .iter()
+            .map(|rows| rows.iter().map(|r| y[*r]).sum::<f64>() / j as f64)
+            .collect()
+    };
+    let scale = j as f64 / (j + 1) as f64;
+    let cond_var: Vec<f64> = y.iter().zip(&matched_y).map(|(y, m)| scale * (y - m).powi(2)).collect();
+    data.with_column(Series::new("conditional_variance", cond_var))?;
     Ok(data)
 }
 
+// Rows of the `j` closest other units of every unit
+//
+// Units are sorted by propensity score and row, so the closest other units
+// are the nearest ones on either side in that order; at equal distance the
+// earlier row wins, as in `nn_match`. `j` must be less than the number of
+// units.
+fn sorted_neighbors(data: &DataFrame, j: usize) -> PolarsResult<Vec<Vec<usize>>> {
+    let scores = column_values(data, "propensities")?;
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|a, b| scores[*a].total_cmp(&scores[*b]).then(a.cmp(b)));
+    let mut neighbors = vec![Vec::new(); scores.len()];
+    for (k, row) in order.iter().enumerate() {
+        let (mut lower, mut upper) = (k.checked_sub(1), k + 1);
+        while neighbors[*row].len() < j {
+            let below = lower.map(|i| order[i]);
+            let above = order.get(upper).copied();
+            let nearest = match (below, above) {
+                (Some(b), Some(a)) => {
+                    let (db, da) = ((scores[b] - scores[*row]).abs(), (scores[a] - scores[*row]).abs());
+                    match db.total_cmp(&da).then(b.cmp(&a)) {
+                        Ordering::Greater => a,
+                        _ => b
+                    }
+                },
+                (Some(n), None) | (None, Some(n)) => n,
+                (None, None) => break
+            };
+            match Some(nearest) == below {
+                true => lower = lower.and_then(|i| i.checked_sub(1)),
+                false => upper += 1
+            }
+            neighbors[*row].push(nearest);
+        }
+    }
+    Ok(neighbors)
+}
+
 // Estimates the number of times each observation is used as a match
 // since we are doing matching with replacement. A necessary piece of
 // estimating the full-sample ATT variance.
@@ -95,4 +591,315 @@ fn subsample_count_matches(data: &DataFrame) -> PolarsResult<DataFrame> {
         .left_join(&counts, ["strike_id"], ["strike_id"])?
         .unique(None, UniqueKeepStrategy::First, None)?;
     Ok(data)
-}
\ No newline at end of file
+}
+
+// Matched-pair outcome differences
+//
+// Returns the element-wise difference between the treated outcomes and
+// their matched control outcomes, one value per treated observation. A
+// missing outcome in either unit of a pair is an error.
+pub fn pair_differences(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str
+) -> PolarsResult<Vec<f64>> {
+    let observed_y = treat.column(outcome)?.cast(&Float64)?;
+    let matched_y = control.column(outcome)?.cast(&Float64)?;
+    let y_diff = &observed_y - &matched_y;
+    let y_diff = y_diff.f64()?;
+    if y_diff.null_count() > 0 {
+        return Err(PolarsError::ComputeError(
+            format!("{} matched pairs are missing the outcome `{}`", y_diff.null_count(), outcome).into()
+        ));
+    }
+    Ok(y_diff.into_no_null_iter().collect())
+}
+
+// A trimmed-mean ATT and its variance.
+#[derive(Debug)]
+pub struct TrimmedAtt {
+    pub trim: f64,
+    pub att: f64,
+    pub att_variance: f64
+}
+
+// Calculate the trimmed-mean ATT
+//
+// A robust alternative to `calculate_att`: the `trim` fraction of the
+// smallest and of the largest matched-pair differences are dropped before
+// averaging, so a handful of wild pairs can't swing the estimate. The
+// variance is the Tukey-McLaughlin estimator, which is the winsorized
+// variance of the differences scaled by (1 - 2 * trim)^2.
+pub fn calculate_trimmed_att(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    trim: f64
+) -> PolarsResult<TrimmedAtt> {
+    let mut y_diff = pair_differences(treat, control, outcome)?;
+    y_diff.sort_by(|a, b| a.total_cmp(b));
+    let n = y_diff.len();
+    let g = (trim * n as f64).floor() as usize;
+    if n < 2 || 2 * g >= n {
+        return Err(PolarsError::ComputeError(
+            format!("Not enough matched pairs ({}) to trim {} from each end", n, trim).into()
+        ));
+    }
+    let kept = &y_diff[g..(n - g)];
+    let att = kept.iter().sum::<f64>() / kept.len() as f64;
+    let (lo, hi) = (y_diff[g], y_diff[n - g - 1]);
+    let winsorized: Vec<f64> = y_diff.iter().map(|d| d.clamp(lo, hi)).collect();
+    let w_mean = winsorized.iter().sum::<f64>() / n as f64;
+    let w_var = winsorized.iter().map(|w| (w - w_mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+    let kept_frac = kept.len() as f64 / n as f64;
+    let att_variance = w_var / (n as f64 * kept_frac * kept_frac);
+    Ok(TrimmedAtt { trim, att, att_variance })
+}
+
+// A regression-adjusted ATT from the matched sample.
+#[derive(Debug)]
+pub struct AdjustedAtt {
+    pub unadjusted: f64,
+    pub att: f64,
+    pub robust_variance: f64,
+    pub cluster_variance: f64
+}
+
+// Calculate the regression-adjusted ATT
+//
+// Treated units and their matched controls are stacked into one sample and
+// the outcome is regressed on an intercept, the treatment indicator and the
+// predictors `covariates` (columns or `column=level` dummies, see
+// `predictor_values`) by weighted least squares, every row weighted by its
+// pair's weight (see `match_weights`). A treated unit with M matches fills
+// M rows of weight 1/M, so it counts once, and a control used several times
+// enters the fit once per match. The adjusted ATT is the coefficient on the
+// treatment indicator, reported with a heteroskedasticity-robust and a
+// pair-clustered variance. The robust variance is the cluster sandwich with
+// every row its own cluster: its G / (G - 1) * (n - 1) / (n - k) correction
+// then reduces to n / (n - k), which makes it HC1.
+pub fn calculate_adjusted_att(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    covariates: &[String]
+) -> PolarsResult<AdjustedAtt> {
+    let (n_treat, n_control) = (treat.height(), control.height());
+    let n = n_treat + n_control;
+    let (x_treat, x_control) = (predictor_matrix(treat, covariates)?, predictor_matrix(control, covariates)?);
+    let mut x = Array2::<f64>::ones((n, covariates.len() + 2));
+    for i in 0..n {
+        let (d, row) = match i < n_treat {
+            true => (1.0, x_treat.row(i)),
+            false => (0.0, x_control.row(i - n_treat))
+        };
+        x[[i, 1]] = d;
+        x.slice_mut(s![i, 2..]).assign(&row);
+    }
+    let y_treat = treat.column(outcome)?.cast(&Float64)?;
+    let y_control = control.column(outcome)?.cast(&Float64)?;
+    let y: Array1<f64> = y_treat
+        .f64()?
+        .into_no_null_iter()
+        .chain(y_control.f64()?.into_no_null_iter())
+        .collect();
+    let (pair_weights, _) = match_weights(treat)?;
+    let w: Array1<f64> = pair_weights.iter().chain(&pair_weights).copied().collect();
+    let pairs: Vec<usize> = (0..n_treat).chain(0..n_control).collect();
+    let singletons: Vec<usize> = (0..n).collect();
+    let fit = wls(&x, &y, &w);
+    let unadjusted = calculate_att(treat, control, outcome)?;
+    Ok(AdjustedAtt {
+        unadjusted,
+        att: fit.coefficients[1],
+        robust_variance: fit.cluster_vcov(&x, &w, &singletons)[[1, 1]],
+        cluster_variance: fit.cluster_vcov(&x, &w, &pairs)[[1, 1]]
+    })
+}
+
+// A matching ATT with a cluster-robust variance (`--cluster`).
+#[derive(Debug)]
+pub struct ClusterAtt {
+    pub att: f64,
+    pub att_variance: f64,
+    pub n_clusters: usize
+}
+
+// Calculate the ATT with a variance robust to correlation within clusters
+//
+// Treated units and their matched controls are stacked into one sample and
+// the outcome is regressed on an intercept and the treatment indicator,
+// every row weighted by its pair's weight (see `match_weights`), so the
+// coefficient on the treatment is the matching ATT. Its variance is the
+// cluster-robust sandwich with the clusters of column `cluster`: a treated
+// unit and its control count in their own clusters, and a control used
+// several times counts in its cluster every time, so the variance allows
+// for correlated outcomes within clusters as well as for control reuse. It
+// takes the matches as given and needs at least two clusters.
+pub fn calculate_cluster_att(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    cluster: &str
+) -> PolarsResult<ClusterAtt> {
+    let (pair_weights, _) = match_weights(treat)?;
+    let n = treat.height() + control.height();
+    let y: Array1<f64> = column_values(treat, outcome)?.into_iter().chain(column_values(control, outcome)?).collect();
+    let mut x = Array2::<f64>::ones((n, 2));
+    x.slice_mut(s![treat.height().., 1]).fill(0.0);
+    let w: Array1<f64> = pair_weights.iter().chain(&pair_weights).copied().collect();
+    let mut ids: HashMap<String, usize> = HashMap::new();
+    let mut clusters = Vec::with_capacity(n);
+    for data in [treat, control] {
+        let column = data.column(cluster)?;
+        if column.null_count() > 0 {
+            let message = format!("The cluster column `{}` has missing values", cluster);
+            return Err(PolarsError::ComputeError(message.into()));
+        }
+        for id in column.cast(&Utf8)?.utf8()?.into_no_null_iter() {
+            let next = ids.len();
+            clusters.push(*ids.entry(id.to_string()).or_insert(next));
+        }
+    }
+    if ids.len() < 2 {
+        return Err(PolarsError::ComputeError(
+            format!("`--cluster` needs at least two clusters but the matched sample has {}", ids.len()).into()
+        ));
+    }
+    let fit = wls(&x, &y, &w);
+    Ok(ClusterAtt {
+        att: fit.coefficients[1],
+        att_variance: fit.cluster_vcov(&x, &w, &clusters)[[1, 1]],
+        n_clusters: ids.len()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Args;
+    use crate::estimate::adjustment_covariates;
+    use polars::df;
+
+    // 1:1 matching: three treated units, the last two matched to the same
+    // control.
+    fn one_to_one() -> (DataFrame, DataFrame) {
+        let treat = df!(
+            "strike_id" => [1i64, 2, 3],
+            "propensities" => [0.2, 0.5, 0.8],
+            "y" => [5.0, 7.0, 9.0],
+            "treated" => [1i32, 1, 1]
+        ).unwrap();
+        let control = df!(
+            "strike_id" => [10i64, 11, 11],
+            "propensities" => [0.25, 0.55, 0.55],
+            "y" => [4.0, 6.0, 6.0],
+            "treated" => [0i32, 0, 0]
+        ).unwrap();
+        (treat, control)
+    }
+
+    // 2:1 matching: two treated units with two rows each, sharing control 11.
+    fn two_to_one() -> (DataFrame, DataFrame) {
+        let treat = df!(
+            "strike_id" => [1i64, 1, 2, 2],
+            "propensities" => [0.3, 0.3, 0.7, 0.7],
+            "y" => [5.0, 5.0, 10.0, 10.0],
+            "treated" => [1i32, 1, 1, 1]
+        ).unwrap();
+        let control = df!(
+            "strike_id" => [10i64, 11, 11, 12],
+            "propensities" => [0.25, 0.35, 0.35, 0.75],
+            "y" => [2.0, 4.0, 4.0, 10.0],
+            "treated" => [0i32, 0, 0, 0]
+        ).unwrap();
+        (treat, control)
+    }
+
+    fn variance(treat: &DataFrame, control: &DataFrame) -> f64 {
+        let options = VarianceOptions { neighbors: 1, ..Default::default() };
+        calculate_variance_with(treat, control, "y", "treated", &CancelToken::default(), options).unwrap()
+    }
+
+    #[test]
+    fn att_one_to_one() {
+        // ((5 - 4) + (7 - 6) + (9 - 6)) / 3
+        let (treat, control) = one_to_one();
+        assert!((calculate_att(&treat, &control, "y").unwrap() - 5.0 / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn att_two_to_one() {
+        // ((5 - (2 + 4) / 2) + (10 - (4 + 10) / 2)) / 2
+        let (treat, control) = two_to_one();
+        assert!((calculate_att(&treat, &control, "y").unwrap() - 2.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn variance_one_to_one() {
+        // Every conditional variance is (1 / 2) (y - y_nearest)^2 = 2. The
+        // treated units count once each and the controls K = 1 and K = 2
+        // times: (3 * 2 + 1 * 2 + 4 * 2) / 3^2.
+        let (treat, control) = one_to_one();
+        assert!((variance(&treat, &control) - 16.0 / 9.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn variance_two_to_one() {
+        // Conditional variances: 12.5 for both treated units, and 2, 2 and
+        // 18 for controls 10, 11 and 12, used K = 1/2, 1 and 1/2 times:
+        // (2 * 12.5 + 0.25 * 2 + 1 * 2 + 0.25 * 18) / 2^2.
+        let (treat, control) = two_to_one();
+        assert!((variance(&treat, &control) - 8.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn variance_needs_two_units_per_group() {
+        let (treat, control) = one_to_one();
+        let control = control.head(Some(1));
+        let treat = treat.head(Some(1));
+        assert!(calculate_variance_with(
+            &treat,
+            &control,
+            "y",
+            "treated",
+            &CancelToken::default(),
+            VarianceOptions::default()
+        ).is_err());
+    }
+
+    #[test]
+    fn adjusted_att_ignores_other_outcomes() {
+        // `z` is a second outcome: it differs between the treated units and
+        // their controls, so adjusting for it would change the estimate, but
+        // `adjustment_covariates` leaves it out and only `x` adjusts.
+        let treat = df!(
+            "strike_id" => [1i64, 2, 3, 4],
+            "treated" => [1i32, 1, 1, 1],
+            "x" => [1.0, 2.0, 3.0, 5.0],
+            "z" => [3.0, 1.0, 4.0, 1.0],
+            "y" => [4.0, 7.0, 7.0, 12.0]
+        ).unwrap();
+        let control = df!(
+            "strike_id" => [5i64, 6, 7, 8],
+            "treated" => [0i32, 0, 0, 0],
+            "x" => [1.5, 2.0, 2.5, 4.0],
+            "z" => [0.0, 2.0, 1.0, 2.0],
+            "y" => [3.0, 4.0, 6.0, 8.0]
+        ).unwrap();
+        let args = Args {
+            treatment: "treated".to_string(),
+            outcome: "y".to_string(),
+            extra_outcomes: vec!["z".to_string()],
+            ..Args::default()
+        };
+        let covariates = adjustment_covariates(&treat, &control, &args).unwrap();
+        assert_eq!(covariates, vec!["x".to_string()]);
+        let adjusted = calculate_adjusted_att(&treat, &control, "y", &covariates).unwrap().att;
+        let without = |data: &DataFrame| data.drop("z").unwrap();
+        let expected = calculate_adjusted_att(&without(&treat), &without(&control), "y", &covariates).unwrap().att;
+        assert_eq!(adjusted, expected);
+        let with_z = calculate_adjusted_att(&treat, &control, "y", &["x".to_string(), "z".to_string()]).unwrap().att;
+        assert!((adjusted - with_z).abs() > 1e-6);
+    }
+}
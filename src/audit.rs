@@ -0,0 +1,111 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::log::{enabled, log, LogLevel};
+use crate::memory::{stage_peak, track_memory};
+
+// The open audit log, the id of this run and whether writing to the log
+// has failed.
+struct AuditLog {
+    file: File,
+    run: String,
+    failed: bool
+}
+
+static LOG: OnceLock<Mutex<AuditLog>> = OnceLock::new();
+
+// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// Open the audit log
+//
+// The log is only ever appended to, so it accumulates a processing trail
+// across runs. Every line is tagged with a run id (start time and process
+// id) that ties the records of one run together. Like the number format,
+// the log is global so that any stage can write to it.
+pub fn open_audit_log(path: &str) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let run = format!("{}-{}", now(), process::id());
+    let _ = LOG.set(Mutex::new(AuditLog { file, run, failed: false }));
+    track_memory();
+    Ok(())
+}
+
+// Append a record for one stage of the run
+//
+// Each record is a tab-separated line: run id, Unix time, stage, rows in,
+// rows out, wall time in milliseconds, peak memory in KiB and free-form
+// details (parameters, estimates, warnings). Counts that don't apply are
+// written as `-`. Writes nothing when no audit log was opened. The stage
+// is also logged to standard error at `--log-level info` and above. If a
+// record can't be written (say the disk is full) the run goes on: the
+// failure is logged as a warning once and nothing more is written.
+pub fn record(stage: &str, rows_in: Option<usize>, rows_out: Option<usize>, elapsed: Duration, details: &str) {
+    write_record(stage, rows_in, rows_out, Some(elapsed), details);
+}
+
+// Append a record for an event of the run
+//
+// Events (the start of the run, warnings, approximations) are not stages:
+// their wall time and peak memory are written as `-`. They are logged to
+// standard error at `--log-level debug`.
+pub fn event(name: &str, details: &str) {
+    write_record(name, None, None, None, details);
+}
+
+fn write_record(
+    stage: &str,
+    rows_in: Option<usize>,
+    rows_out: Option<usize>,
+    elapsed: Option<Duration>,
+    details: &str
+) {
+    let peak = match elapsed {
+        Some(_) => stage_peak(stage),
+        None => None
+    };
+    let count = |n: Option<usize>| n.map_or("-".to_string(), |n| n.to_string());
+    let message = match elapsed {
+        Some(elapsed) => format!(
+            "{}: {} -> {} rows in {} ms",
+            stage,
+            count(rows_in),
+            count(rows_out),
+            elapsed.as_millis()
+        ),
+        None => stage.to_string()
+    };
+    match (elapsed, enabled(LogLevel::Debug)) {
+        (_, true) => log(LogLevel::Debug, &format!("{} ({})", message, details)),
+        (Some(_), false) => log(LogLevel::Info, &message),
+        (None, false) => {}
+    }
+    let log = match LOG.get() {
+        Some(log) => log,
+        None => return
+    };
+    let mut log = log.lock().unwrap();
+    if log.failed {
+        return;
+    }
+    let line = format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+        log.run,
+        now(),
+        stage,
+        count(rows_in),
+        count(rows_out),
+        count(elapsed.map(|elapsed| elapsed.as_millis() as usize)),
+        count(peak.map(|bytes| bytes as usize / 1024)),
+        details.replace(['\t', '\n'], " ")
+    );
+    if let Err(e) = log.file.write_all(line.as_bytes()) {
+        log.failed = true;
+        crate::log::log(LogLevel::Warn, &format!("Failed to write the audit log, which stops here: {}", e));
+    }
+}
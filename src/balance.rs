@@ -0,0 +1,501 @@
+use polars::prelude::{DataFrame, PolarsResult};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::fmt;
+use std::time::Instant;
+
+use crate::budget::Budget;
+use crate::cancel::CancelToken;
+use crate::data::{column_values, quantile, sorted};
+use crate::format::num;
+use crate::formula::predictor_values;
+use crate::subclass::{subclass_breaks, subclass_of};
+
+// Absolute SMD above which a covariate is considered imbalanced.
+pub const SMD_THRESHOLD: f64 = 0.1;
+
+// Mean and (sample) variance of a slice.
+pub fn mean_variance(x: &[f64]) -> (f64, f64) {
+    let n = x.len() as f64;
+    let mean = x.iter().sum::<f64>() / n;
+    let variance = x.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean, variance)
+}
+
+// Standardized mean difference
+//
+// The difference in treated and control means divided by `scale`, which
+// should be the pooled standard deviation from the unmatched sample so that
+// before- and after-matching SMDs are on the same scale.
+pub fn smd(treat: &[f64], control: &[f64], scale: f64) -> f64 {
+    let (treat_mean, _) = mean_variance(treat);
+    let (control_mean, _) = mean_variance(control);
+    match scale > 0.0 {
+        true => (treat_mean - control_mean) / scale,
+        false => 0.0
+    }
+}
+
+// Pooled standard deviation sqrt((s_t^2 + s_c^2) / 2).
+pub fn pooled_sd(treat: &[f64], control: &[f64]) -> f64 {
+    let (_, treat_var) = mean_variance(treat);
+    let (_, control_var) = mean_variance(control);
+    ((treat_var + control_var) / 2.0).sqrt()
+}
+
+// Variance ratio of treated to control values.
+pub fn variance_ratio(treat: &[f64], control: &[f64]) -> f64 {
+    let (_, treat_var) = mean_variance(treat);
+    let (_, control_var) = mean_variance(control);
+    treat_var / control_var
+}
+
+// Mean and largest absolute difference of the treated and control
+// empirical CDFs, over every distinct value of either
+//
+// The largest is the Kolmogorov-Smirnov statistic. Unlike the SMD, both
+// pick up differences in the shape of the distributions, such as equal
+// means with different spreads or modes. Missing (non-finite) values are
+// left out of both distributions.
+pub fn ecdf_differences(treat: &[f64], control: &[f64]) -> (f64, f64) {
+    let finite = |x: &[f64]| sorted(&x.iter().copied().filter(|v| v.is_finite()).collect::<Vec<_>>());
+    let (treat, control) = (finite(treat), finite(control));
+    let (n_treat, n_control) = (treat.len() as f64, control.len() as f64);
+    let (mut i, mut j) = (0, 0);
+    let (mut total, mut largest, mut points) = (0.0, 0.0_f64, 0);
+    while i < treat.len() || j < control.len() {
+        let next = |x: &[f64], k: usize| x.get(k).copied().unwrap_or(f64::INFINITY);
+        let value = next(&treat, i).min(next(&control, j));
+        while i < treat.len() && treat[i] <= value {
+            i += 1;
+        }
+        while j < control.len() && control[j] <= value {
+            j += 1;
+        }
+        let difference = (i as f64 / n_treat - j as f64 / n_control).abs();
+        total += difference;
+        largest = largest.max(difference);
+        points += 1;
+    }
+    (total / points as f64, largest)
+}
+
+// Balance of a single covariate before and after matching
+//
+// Variance ratios are of the treated units over the controls; 1 means
+// equal spread. `ecdf_mean` and `ks` are the mean and largest differences
+// of the empirical CDFs (see `ecdf_differences`).
+#[derive(Clone, Debug)]
+pub struct CovariateBalance {
+    pub covariate: String,
+    pub smd_before: f64,
+    pub smd_after: f64,
+    pub variance_ratio_before: f64,
+    pub variance_ratio_after: f64,
+    pub ecdf_mean_before: f64,
+    pub ecdf_mean_after: f64,
+    pub ks_before: f64,
+    pub ks_after: f64
+}
+
+// Calculate covariate balance
+//
+// For every covariate, the SMD, variance ratio and eCDF differences between
+// the treated units and the full control pool (before matching) and
+// between the treated units and their matched controls (after matching).
+pub fn covariate_balance(
+    treat: &DataFrame,
+    pool: &DataFrame,
+    control: &DataFrame,
+    covariates: &[String]
+) -> PolarsResult<Vec<CovariateBalance>> {
+    let mut balance = Vec::with_capacity(covariates.len());
+    for covariate in covariates {
+        let x_treat = predictor_values(treat, covariate)?;
+        let x_pool = predictor_values(pool, covariate)?;
+        let x_control = predictor_values(control, covariate)?;
+        let scale = pooled_sd(&x_treat, &x_pool);
+        let (ecdf_mean_before, ks_before) = ecdf_differences(&x_treat, &x_pool);
+        let (ecdf_mean_after, ks_after) = ecdf_differences(&x_treat, &x_control);
+        balance.push(CovariateBalance {
+            covariate: covariate.clone(),
+            smd_before: smd(&x_treat, &x_pool, scale),
+            smd_after: smd(&x_treat, &x_control, scale),
+            variance_ratio_before: variance_ratio(&x_treat, &x_pool),
+            variance_ratio_after: variance_ratio(&x_treat, &x_control),
+            ecdf_mean_before,
+            ecdf_mean_after,
+            ks_before,
+            ks_after
+        });
+    }
+    Ok(balance)
+}
+
+// Covariate balance table (`--balance`, `strike balance`)
+//
+// Every covariate's SMD and variance ratio before and after matching,
+// then its mean eCDF difference and Kolmogorov-Smirnov statistic.
+// Covariates whose absolute SMD after matching exceeds `threshold` are
+// flagged with `*`.
+#[derive(Clone, Debug)]
+pub struct BalanceTable {
+    pub threshold: f64,
+    pub covariates: Vec<CovariateBalance>
+}
+
+impl BalanceTable {
+    // Covariates whose absolute SMD after matching exceeds the threshold.
+    pub fn imbalanced(&self) -> Vec<&CovariateBalance> {
+        self.covariates.iter().filter(|b| b.smd_after.abs() > self.threshold || b.smd_after.is_nan()).collect()
+    }
+}
+
+// Implement `Display` for `BalanceTable`.
+impl fmt::Display for BalanceTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Covariate balance (|SMD| after matching > {} flagged with *)", self.threshold)?;
+        writeln!(
+            f,
+            "{:>20}  {:>10}  {:>10}  {:>10}  {:>10}",
+            "Covariate", "SMD before", "SMD after", "VR before", "VR after"
+        )?;
+        for b in &self.covariates {
+            let flag = match b.smd_after.abs() > self.threshold || b.smd_after.is_nan() {
+                true => "*",
+                false => " "
+            };
+            writeln!(
+                f,
+                "{:>20}  {:>10}  {:>9}{}  {:>10}  {:>10}",
+                b.covariate,
+                num(b.smd_before),
+                num(b.smd_after),
+                flag,
+                num(b.variance_ratio_before),
+                num(b.variance_ratio_after)
+            )?;
+        }
+        writeln!(
+            f,
+            "{} of {} covariates imbalanced after matching",
+            self.imbalanced().len(),
+            self.covariates.len()
+        )?;
+        writeln!(f, "\nDistributional balance (empirical CDFs)")?;
+        writeln!(
+            f,
+            "{:>20}  {:>11}  {:>11}  {:>10}  {:>10}",
+            "Covariate", "eCDF before", "eCDF after", "KS before", "KS after"
+        )?;
+        for b in &self.covariates {
+            writeln!(
+                f,
+                "{:>20}  {:>11}  {:>11}  {:>10}  {:>10}",
+                b.covariate,
+                num(b.ecdf_mean_before),
+                num(b.ecdf_mean_after),
+                num(b.ks_before),
+                num(b.ks_after)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Balance within a single propensity score subclass.
+#[derive(Debug)]
+pub struct SubclassBalance {
+    pub lower: f64,
+    pub upper: f64,
+    pub n_treat: usize,
+    pub n_control: usize,
+    pub smds: Vec<(String, f64)>
+}
+
+impl SubclassBalance {
+    // Covariates whose absolute SMD exceeds `SMD_THRESHOLD`.
+    fn imbalanced(&self) -> Vec<&str> {
+        self.smds
+            .iter()
+            .filter(|(_, smd)| smd.abs() > SMD_THRESHOLD || smd.is_nan())
+            .map(|(covariate, _)| covariate.as_str())
+            .collect()
+    }
+}
+
+// Covariate balance by propensity score subclass
+//
+// Overall balance can hide subclasses in which treated and control units
+// remain very different, which is exactly where a stratified estimate is
+// extrapolating. Subclasses without any treated or any control units are
+// flagged as well.
+#[derive(Debug)]
+pub struct SubclassBalanceReport {
+    pub subclasses: Vec<SubclassBalance>
+}
+
+// Calculate covariate balance within propensity score subclasses
+//
+// All treated units and the full control pool are split into `n_strata`
+// subclasses at quantiles of the pooled propensity scores, and the SMD of
+// every covariate is computed within each subclass. SMDs are scaled by the
+// pooled standard deviation of the whole sample so that subclasses are
+// comparable.
+pub fn subclass_balance(
+    treat: &DataFrame,
+    pool: &DataFrame,
+    covariates: &[String],
+    n_strata: usize
+) -> PolarsResult<SubclassBalanceReport> {
+    let treat_ps = column_values(treat, "propensities")?;
+    let pool_ps = column_values(pool, "propensities")?;
+    let all_ps: Vec<f64> = treat_ps.iter().chain(&pool_ps).copied().collect();
+    let breaks = subclass_breaks(&all_ps, n_strata);
+    let treat_class: Vec<usize> = treat_ps.iter().map(|p| subclass_of(*p, &breaks)).collect();
+    let pool_class: Vec<usize> = pool_ps.iter().map(|p| subclass_of(*p, &breaks)).collect();
+    let mut subclasses: Vec<SubclassBalance> = (0..n_strata)
+        .map(|k| SubclassBalance {
+            lower: if k == 0 { f64::NEG_INFINITY } else { breaks[k - 1] },
+            upper: if k == n_strata - 1 { f64::INFINITY } else { breaks[k] },
+            n_treat: treat_class.iter().filter(|c| **c == k).count(),
+            n_control: pool_class.iter().filter(|c| **c == k).count(),
+            smds: Vec::with_capacity(covariates.len())
+        })
+        .collect();
+    for covariate in covariates {
+        let x_treat = predictor_values(treat, covariate)?;
+        let x_pool = predictor_values(pool, covariate)?;
+        let scale = pooled_sd(&x_treat, &x_pool);
+        for (k, subclass) in subclasses.iter_mut().enumerate() {
+            let in_treat: Vec<f64> = x_treat
+                .iter()
+                .zip(&treat_class)
+                .filter(|(_, c)| **c == k)
+                .map(|(x, _)| *x)
+                .collect();
+            let in_pool: Vec<f64> = x_pool
+                .iter()
+                .zip(&pool_class)
+                .filter(|(_, c)| **c == k)
+                .map(|(x, _)| *x)
+                .collect();
+            subclass.smds.push((covariate.clone(), smd(&in_treat, &in_pool, scale)));
+        }
+    }
+    Ok(SubclassBalanceReport { subclasses })
+}
+
+// Implement `Display` for `SubclassBalanceReport`.
+impl fmt::Display for SubclassBalanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Balance by propensity subclass (|SMD| > {} flagged with *)", SMD_THRESHOLD)?;
+        writeln!(
+            f,
+            "{:>8}  {:>18}  {:>7}  {:>9}  {:>9}  Imbalanced covariates",
+            "Subclass", "PS range", "# Treat", "# Control", "Max |SMD|"
+        )?;
+        for (k, subclass) in self.subclasses.iter().enumerate() {
+            let range = format!("({}, {}]", num(subclass.lower), num(subclass.upper));
+            let imbalanced = subclass.imbalanced();
+            let max_smd = subclass
+                .smds
+                .iter()
+                .fold(0.0_f64, |m, (_, smd)| m.max(smd.abs()));
+            let flag = match subclass.n_treat == 0 || subclass.n_control == 0 || !imbalanced.is_empty() {
+                true => "*",
+                false => " "
+            };
+            let detail = match (subclass.n_treat, subclass.n_control) {
+                (0, _) => "no treated units".to_string(),
+                (_, 0) => "no control units".to_string(),
+                _ => imbalanced.join(", ")
+            };
+            writeln!(
+                f,
+                "{:>8}  {:>18}  {:>7}  {:>9}  {:>8}{}  {}",
+                k + 1,
+                range,
+                subclass.n_treat,
+                subclass.n_control,
+                num(max_smd),
+                flag,
+                detail
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Bootstrap replicates timed before the rest are projected against the
+// `--time-budget`.
+const BOOTSTRAP_PROBE: usize = 20;
+
+// Post-match balance of a covariate with bootstrap 95% intervals
+//
+// `variance_ratio` is the variance of the treated units over that of their
+// matched controls; 1 means equal spread.
+#[derive(Clone, Debug)]
+pub struct BalanceInterval {
+    pub covariate: String,
+    pub smd: f64,
+    pub smd_ci: (f64, f64),
+    pub variance_ratio: f64,
+    pub variance_ratio_ci: (f64, f64)
+}
+
+impl BalanceInterval {
+    // Whether the whole SMD interval lies beyond `SMD_THRESHOLD`, i.e. the
+    // imbalance isn't just noise.
+    pub fn imbalanced(&self) -> bool {
+        self.smd_ci.0 > SMD_THRESHOLD || self.smd_ci.1 < -SMD_THRESHOLD
+    }
+}
+
+// Bootstrap intervals for post-match balance
+#[derive(Debug)]
+pub struct BalanceBootstrap {
+    pub replicates: usize,
+    pub intervals: Vec<BalanceInterval>
+}
+
+// Percentile 95% interval of bootstrap replicates, ignoring undefined ones.
+fn percentile_interval(replicates: &[f64]) -> (f64, f64) {
+    let defined: Vec<f64> = replicates.iter().copied().filter(|r| !r.is_nan()).collect();
+    if defined.is_empty() {
+        return (f64::NAN, f64::NAN);
+    }
+    let defined = sorted(&defined);
+    (quantile(&defined, 0.025), quantile(&defined, 0.975))
+}
+
+// Limits on the running time of a bootstrap
+//
+// With a `budget`, the first replicates are timed and the rest cut short if
+// they would not fit. The bootstrap stops with an error once `cancel` is
+// set.
+#[derive(Clone, Copy)]
+pub struct BootstrapLimits<'a> {
+    pub budget: Option<&'a Budget>,
+    pub cancel: &'a CancelToken
+}
+
+// Bootstrap the post-match SMDs and variance ratios
+//
+// Matched pairs are resampled with replacement `replicates` times and both
+// statistics are recomputed on every resample, within `limits`. SMDs keep
+// the pooled standard deviation of the unmatched sample as their scale, as
+// in `covariate_balance`.
+pub fn balance_bootstrap(
+    treat: &DataFrame,
+    pool: &DataFrame,
+    control: &DataFrame,
+    covariates: &[String],
+    replicates: usize,
+    rng: &mut StdRng,
+    limits: BootstrapLimits
+) -> PolarsResult<BalanceBootstrap> {
+    let mut columns = Vec::with_capacity(covariates.len());
+    for covariate in covariates {
+        let x_treat = predictor_values(treat, covariate)?;
+        let x_control = predictor_values(control, covariate)?;
+        let scale = pooled_sd(&x_treat, &predictor_values(pool, covariate)?);
+        columns.push((x_treat, x_control, scale));
+    }
+    let n = treat.height();
+    let mut smds = vec![Vec::with_capacity(replicates); covariates.len()];
+    let mut ratios = vec![Vec::with_capacity(replicates); covariates.len()];
+    let mut target = replicates;
+    let start = Instant::now();
+    let mut done = 0;
+    while done < target {
+        limits.cancel.check(|| format!("during the balance bootstrap after {} of {} replicates", done, target))?;
+        let rows: Vec<usize> = (0..n).map(|_| rng.gen_range(0..n)).collect();
+        for (k, (x_treat, x_control, scale)) in columns.iter().enumerate() {
+            let t: Vec<f64> = rows.iter().map(|i| x_treat[*i]).collect();
+            let c: Vec<f64> = rows.iter().map(|i| x_control[*i]).collect();
+            smds[k].push(smd(&t, &c, *scale));
+            ratios[k].push(variance_ratio(&t, &c));
+        }
+        done += 1;
+        if let Some(budget) = limits.budget.filter(|_| done == BOOTSTRAP_PROBE && target > done) {
+            let per_replicate = start.elapsed() / done as u32;
+            if !budget.fits(per_replicate * (target - done) as u32) {
+                let affordable = (budget.remaining() / 2).as_secs_f64() / per_replicate.as_secs_f64();
+                target = done + affordable as usize;
+                budget.apply(
+                    "balance bootstrap",
+                    &format!("{} of {} replicates; the interval endpoints are noisier", target, replicates)
+                );
+            }
+        }
+    }
+    let intervals = covariates
+        .iter()
+        .zip(&columns)
+        .enumerate()
+        .map(|(k, (covariate, (x_treat, x_control, scale)))| BalanceInterval {
+            covariate: covariate.clone(),
+            smd: smd(x_treat, x_control, *scale),
+            smd_ci: percentile_interval(&smds[k]),
+            variance_ratio: variance_ratio(x_treat, x_control),
+            variance_ratio_ci: percentile_interval(&ratios[k])
+        })
+        .collect();
+    Ok(BalanceBootstrap { replicates: target, intervals })
+}
+
+// Implement `Display` for `BalanceBootstrap`.
+impl fmt::Display for BalanceBootstrap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Post-match balance ({} bootstrap replicates; * = 95% CI entirely beyond |SMD| {})",
+            self.replicates,
+            SMD_THRESHOLD
+        )?;
+        writeln!(
+            f,
+            "{:>20}  {:>9}  {:>22}  {:>10}  {:>22}",
+            "Covariate", "SMD", "95% CI", "Var. ratio", "95% CI"
+        )?;
+        for interval in &self.intervals {
+            let flag = match interval.imbalanced() {
+                true => "*",
+                false => " "
+            };
+            writeln!(
+                f,
+                "{:>20}  {:>8}{}  {:>22}  {:>10}  {:>22}",
+                interval.covariate,
+                num(interval.smd),
+                flag,
+                format!("({}, {})", num(interval.smd_ci.0), num(interval.smd_ci.1)),
+                num(interval.variance_ratio),
+                format!("({}, {})", num(interval.variance_ratio_ci.0), num(interval.variance_ratio_ci.1))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ecdf_differences_of_identical_samples_are_zero() {
+        assert_eq!(ecdf_differences(&[1.0, 2.0, 3.0], &[3.0, 1.0, 2.0]), (0.0, 0.0));
+    }
+
+    #[test]
+    fn ecdf_differences_skip_missing_values() {
+        // Without the NaNs, the treated values are {1, 2} and the controls
+        // {2, 3}. At 1, 2 and 3 the treated eCDF is 1/2, 1 and 1 and the
+        // control eCDF 0, 1/2 and 1, so the differences are 1/2, 1/2 and 0:
+        // a mean of 1/3 and a largest of 1/2.
+        let (mean, largest) = ecdf_differences(&[1.0, f64::NAN, 2.0], &[f64::NAN, 3.0, 2.0]);
+        assert!((mean - 1.0 / 3.0).abs() < 1e-12);
+        assert_eq!(largest, 0.5);
+    }
+}
@@ -0,0 +1,219 @@
+use polars::prelude::{DataFrame, PolarsResult};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::att::unit_outcomes;
+use crate::balance::mean_variance;
+use crate::data::{column_values, quantile, sorted};
+use crate::format::num;
+
+// Resamples of the treated units behind `RatioCi::Bootstrap` intervals.
+pub const RATIO_BOOTSTRAP_REPLICATES: usize = 1000;
+
+// What kind of outcome is analysed
+//
+// With `Auto` an outcome whose values are all 0 or 1 is treated as binary.
+// For a binary outcome the ATT is a risk difference and the risk ratio and
+// odds ratio are reported as well. A `Survival` outcome is a time to an
+// event, possibly censored, and is contrasted with a log-rank statistic
+// instead of a mean difference (see `survival_contrast`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutcomeType {
+    #[default]
+    Auto,
+    Continuous,
+    Binary,
+    Survival
+}
+
+// Implement `FromStr` for `OutcomeType`.
+impl FromStr for OutcomeType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(OutcomeType::Auto),
+            "continuous" => Ok(OutcomeType::Continuous),
+            "binary" => Ok(OutcomeType::Binary),
+            "survival" => Ok(OutcomeType::Survival),
+            _ => Err(format!("Unknown outcome type `{}`; use auto, continuous, binary or survival", s))
+        }
+    }
+}
+
+// Implement `Display` for `OutcomeType`.
+impl fmt::Display for OutcomeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutcomeType::Auto => write!(f, "auto"),
+            OutcomeType::Continuous => write!(f, "continuous"),
+            OutcomeType::Binary => write!(f, "binary"),
+            OutcomeType::Survival => write!(f, "survival")
+        }
+    }
+}
+
+// How the confidence intervals of the risk and odds ratios are computed
+//
+// `Delta` is the delta method on the log scale; `Bootstrap` takes the
+// percentiles of `RATIO_BOOTSTRAP_REPLICATES` resamples of the treated
+// units with their matches.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RatioCi {
+    #[default]
+    Delta,
+    Bootstrap
+}
+
+// Implement `FromStr` for `RatioCi`.
+impl FromStr for RatioCi {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delta" => Ok(RatioCi::Delta),
+            "bootstrap" => Ok(RatioCi::Bootstrap),
+            _ => Err(format!("Unknown ratio interval `{}`; use delta or bootstrap", s))
+        }
+    }
+}
+
+// Implement `Display` for `RatioCi`.
+impl fmt::Display for RatioCi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RatioCi::Delta => write!(f, "delta method"),
+            RatioCi::Bootstrap => write!(f, "bootstrap")
+        }
+    }
+}
+
+// Risk and odds ratios of a binary outcome in the matched sample
+//
+// `risk_treat` is the share of treated units with the outcome and
+// `risk_control` that of their matched controls (each treated unit's
+// matches averaged, as in `calculate_att`).
+#[derive(Debug)]
+pub struct BinaryEffects {
+    pub risk_treat: f64,
+    pub risk_control: f64,
+    pub risk_ratio: f64,
+    pub risk_ratio_ci: (f64, f64),
+    pub odds_ratio: f64,
+    pub odds_ratio_ci: (f64, f64),
+    pub ci: RatioCi
+}
+
+// Whether every outcome value of `data` is 0 or 1.
+pub fn is_binary(data: &DataFrame, outcome: &str) -> PolarsResult<bool> {
+    Ok(column_values(data, outcome)?.iter().all(|y| *y == 0.0 || *y == 1.0))
+}
+
+// Risk ratio and odds ratio of the risks `p1` and `p0`.
+fn ratios(p1: f64, p0: f64) -> (f64, f64) {
+    (p1 / p0, p1 * (1.0 - p0) / (p0 * (1.0 - p1)))
+}
+
+// Mean of `x` over the units in `rows`.
+fn resampled_mean(x: &[f64], rows: &[usize]) -> f64 {
+    rows.iter().map(|i| x[*i]).sum::<f64>() / rows.len() as f64
+}
+
+// 2.5th and 97.5th percentiles of the finite values of `x`.
+fn percentile_ci(x: &[f64]) -> (f64, f64) {
+    let finite: Vec<f64> = x.iter().cloned().filter(|v| v.is_finite()).collect();
+    match finite.is_empty() {
+        true => (f64::NAN, f64::NAN),
+        false => {
+            let finite = sorted(&finite);
+            (quantile(&finite, 0.025), quantile(&finite, 0.975))
+        }
+    }
+}
+
+// Calculate the risk ratio and odds ratio of a binary outcome
+//
+// The delta-method intervals use the variances and covariance of the
+// treated outcomes and their matched controls' mean outcomes over treated
+// units, treating those as independent across treated units (ignoring
+// that a control may be reused). They are symmetric on the log scale. The
+// bootstrap resamples the treated units, with their matches, using `rng`.
+// A ratio whose risks are 0 or 1 is infinite or undefined, and so is its
+// delta-method interval.
+pub fn binary_effects(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    ci: RatioCi,
+    rng: &mut StdRng
+) -> PolarsResult<BinaryEffects> {
+    let (treated, matched) = unit_outcomes(treat, control, outcome)?;
+    let n = treated.len() as f64;
+    let (p1, var1) = mean_variance(&treated);
+    let (p0, var0) = mean_variance(&matched);
+    let (risk_ratio, odds_ratio) = ratios(p1, p0);
+    let (risk_ratio_ci, odds_ratio_ci) = match ci {
+        RatioCi::Delta => {
+            let covariance = treated
+                .iter()
+                .zip(&matched)
+                .map(|(a, b)| (a - p1) * (b - p0))
+                .sum::<f64>() / (n - 1.0);
+            let (var1, var0, covariance) = (var1 / n, var0 / n, covariance / n);
+            let log_rr_se = (var1 / p1.powi(2) + var0 / p0.powi(2) - 2.0 * covariance / (p1 * p0)).sqrt();
+            let (d1, d0) = (p1 * (1.0 - p1), p0 * (1.0 - p0));
+            let log_or_se = (var1 / d1.powi(2) + var0 / d0.powi(2) - 2.0 * covariance / (d1 * d0)).sqrt();
+            let interval = |ratio: f64, se: f64| ((ratio.ln() - 1.96 * se).exp(), (ratio.ln() + 1.96 * se).exp());
+            (interval(risk_ratio, log_rr_se), interval(odds_ratio, log_or_se))
+        },
+        RatioCi::Bootstrap => {
+            let units = treated.len();
+            let mut rr = Vec::with_capacity(RATIO_BOOTSTRAP_REPLICATES);
+            let mut or = Vec::with_capacity(RATIO_BOOTSTRAP_REPLICATES);
+            for _ in 0..RATIO_BOOTSTRAP_REPLICATES {
+                let rows: Vec<usize> = (0..units).map(|_| rng.gen_range(0..units)).collect();
+                let (r, o) = ratios(resampled_mean(&treated, &rows), resampled_mean(&matched, &rows));
+                rr.push(r);
+                or.push(o);
+            }
+            (percentile_ci(&rr), percentile_ci(&or))
+        }
+    };
+    Ok(BinaryEffects {
+        risk_treat: p1,
+        risk_control: p0,
+        risk_ratio,
+        risk_ratio_ci,
+        odds_ratio,
+        odds_ratio_ci,
+        ci
+    })
+}
+
+// Implement `Display` for `BinaryEffects`.
+impl fmt::Display for BinaryEffects {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ci_label = format!("95% CI ({})", self.ci);
+        write!(
+            f,
+            "Binary outcome (the ATT is a risk difference)\n\
+            Risk (treated | control): {} | {}\n\
+            Risk ratio              : {}\n\
+            {:<24}: ({}, {})\n\
+            Odds ratio              : {}\n\
+            {:<24}: ({}, {})\n",
+            num(self.risk_treat),
+            num(self.risk_control),
+            num(self.risk_ratio),
+            ci_label,
+            num(self.risk_ratio_ci.0),
+            num(self.risk_ratio_ci.1),
+            num(self.odds_ratio),
+            ci_label,
+            num(self.odds_ratio_ci.0),
+            num(self.odds_ratio_ci.1)
+        )
+    }
+}
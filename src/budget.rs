@@ -0,0 +1,141 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audit::event;
+
+// Number of units timed to project the run time of a step.
+pub const PROBE_UNITS: usize = 50;
+
+// An approximation applied to stay within `--time-budget`: the step it
+// affects and its expected impact on the results.
+#[derive(Clone, Debug)]
+pub struct Approximation {
+    pub step: String,
+    pub impact: String
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    start: Instant,
+    limit: Duration,
+    estimate: Mutex<Option<Duration>>,
+    applied: Mutex<Vec<Approximation>>
+}
+
+// A time budget for one run
+//
+// Clones share the budget, like `CancelToken`, so one budget covers every
+// step of a run. The steps whose cost grows fastest with the sample check
+// their projected run time against what is left of it and switch to a
+// cheaper method, recorded with `apply`, when it wouldn't fit.
+#[derive(Clone)]
+pub struct Budget(Arc<BudgetState>);
+
+impl fmt::Debug for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Budget({:?})", self.0.limit)
+    }
+}
+
+impl Budget {
+    // A budget of `limit`, starting now.
+    pub fn new(limit: Duration) -> Budget {
+        Budget(Arc::new(BudgetState {
+            start: Instant::now(),
+            limit,
+            estimate: Mutex::new(None),
+            applied: Mutex::new(Vec::new())
+        }))
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.0.limit.saturating_sub(self.0.start.elapsed())
+    }
+
+    // Whether a step projected to take `projected` leaves at least half of
+    // the remaining budget for the steps after it.
+    pub fn fits(&self, projected: Duration) -> bool {
+        projected <= self.remaining() / 2
+    }
+
+    // Record an approximation, also in the audit log.
+    pub fn apply(&self, step: &str, impact: &str) {
+        event("approximation", &format!("{}: {}", step, impact));
+        self.0.applied.lock().unwrap().push(Approximation {
+            step: step.to_string(),
+            impact: impact.to_string()
+        });
+    }
+
+    // Time taken by the main ATT and variance estimate, which the optional
+    // diagnostics repeat on subsets of the pairs.
+    pub fn set_estimate_time(&self, elapsed: Duration) {
+        *self.0.estimate.lock().unwrap() = Some(elapsed);
+    }
+
+    // Whether a diagnostic that repeats the main estimate `repeats` times
+    // fits in the remaining budget; if not, it is recorded as skipped.
+    pub fn allows(&self, diagnostic: &str, repeats: u32) -> bool {
+        let estimate = self.0.estimate.lock().unwrap().unwrap_or(Duration::ZERO);
+        if estimate * repeats <= self.remaining() {
+            return true;
+        }
+        self.apply(diagnostic, "skipped; it would not finish within the time budget");
+        false
+    }
+
+    pub fn report(&self) -> BudgetReport {
+        BudgetReport {
+            limit: self.0.limit,
+            elapsed: self.0.start.elapsed(),
+            approximations: self.0.applied.lock().unwrap().clone()
+        }
+    }
+}
+
+// Parse a duration such as `90`, `90s`, `500ms`, `2m` or `1h` (plain
+// numbers are seconds).
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| c.is_alphabetic()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration `{}`; expected e.g. 60s, 500ms or 2m", value))?;
+    let seconds = match unit {
+        "" | "s" => number,
+        "ms" => number / 1000.0,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("Unknown duration unit `{}`; use ms, s, m or h", unit))
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("Invalid duration `{}`", value))
+}
+
+// The approximations a run applied to stay within its time budget.
+#[derive(Clone, Debug)]
+pub struct BudgetReport {
+    pub limit: Duration,
+    pub elapsed: Duration,
+    pub approximations: Vec<Approximation>
+}
+
+// Implement `Display` for `BudgetReport`.
+impl fmt::Display for BudgetReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Time budget {:.1}s (estimation took {:.1}s)",
+            self.limit.as_secs_f64(),
+            self.elapsed.as_secs_f64()
+        )?;
+        if self.approximations.is_empty() {
+            return writeln!(f, "  No approximations were needed");
+        }
+        for a in &self.approximations {
+            writeln!(f, "  {:<22}: {}", a.step, a.impact)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use polars::prelude::{DataFrame, DataType, PolarsResult};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::data::column_values;
+use crate::propensity::{Coefficients, LogitFit, Separation};
+
+// Changes whenever the format of the cache files does, so that entries
+// written by an older Strike are never read.
+const CACHE_VERSION: u32 = 2;
+
+// Number of temporary files written by this process, to name them.
+static TEMPORARY: AtomicUsize = AtomicUsize::new(0);
+
+// A fitted propensity model and the scores it assigned
+//
+// `propensities` has one score per row of the scored data, in row order.
+// `model` is `None` for models without coefficients (trees, ensembles).
+#[derive(Clone, Debug)]
+pub struct CachedFit {
+    pub fit: LogitFit,
+    pub model: Option<Coefficients>,
+    pub separation: Vec<Separation>,
+    pub propensities: Vec<f64>
+}
+
+// Feed a DataFrame's column names, types and values into `hasher`.
+fn hash_data(data: &DataFrame, hasher: &mut DefaultHasher) -> PolarsResult<()> {
+    for series in data.get_columns() {
+        series.name().hash(hasher);
+        series.dtype().to_string().hash(hasher);
+        if series.dtype().is_numeric() || series.dtype() == &DataType::Boolean {
+            for value in column_values(data, series.name())? {
+                value.to_bits().hash(hasher);
+            }
+        } else {
+            for value in series.cast(&DataType::Utf8)?.utf8()?.into_iter() {
+                value.hash(hasher);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Cache key of a computation
+//
+// A hash of the input `frames` and of `options`, a description of every
+// setting the computation depends on. Keys are only stable for one build of
+// Strike, so upgrading starts with an empty cache.
+pub fn cache_key(frames: &[&DataFrame], options: &str) -> PolarsResult<String> {
+    let mut hasher = DefaultHasher::new();
+    CACHE_VERSION.hash(&mut hasher);
+    for frame in frames {
+        hash_data(frame, &mut hasher)?;
+    }
+    options.hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+// Write a cache file
+//
+// Writes to a temporary file first and renames it, so that concurrent runs
+// (e.g. `--spec` with `--jobs`) never read a half-written entry.
+fn store(dir: &str, name: &str, text: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let temporary = format!(".{}.{}-{}", name, process::id(), TEMPORARY.fetch_add(1, Ordering::SeqCst));
+    let temporary = Path::new(dir).join(temporary);
+    fs::write(&temporary, text)?;
+    fs::rename(&temporary, Path::new(dir).join(name))
+}
+
+// Read a cached propensity model fit
+//
+// Returns `None` when there is no entry for `key` or it can't be read, in
+// which case the model is simply fit again.
+pub fn load_fit(dir: &str, key: &str) -> Option<CachedFit> {
+    let text = fs::read_to_string(Path::new(dir).join(format!("{}.fit", key))).ok()?;
+    let mut cached = CachedFit {
+        fit: LogitFit { max_gradient: f64::NAN, log_likelihood: f64::NAN, iterations: None },
+        model: None,
+        separation: Vec::new(),
+        propensities: Vec::new()
+    };
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields[..] {
+            ["max_gradient", value] => cached.fit.max_gradient = value.parse().ok()?,
+            ["log_likelihood", value] => cached.fit.log_likelihood = value.parse().ok()?,
+            ["iterations", "-"] => cached.fit.iterations = None,
+            ["iterations", value] => cached.fit.iterations = Some(value.parse().ok()?),
+            ["intercept", value] => {
+                cached.model = Some(Coefficients { intercept: value.parse().ok()?, slopes: Vec::new() })
+            },
+            ["slope", name, value] => cached.model.as_mut()?.slopes.push((name.to_string(), value.parse().ok()?)),
+            ["separation", name, complete, dropped] => cached.separation.push(Separation {
+                covariate: name.to_string(),
+                complete: complete.parse().ok()?,
+                dropped: dropped.parse().ok()?
+            }),
+            ["propensity", value] => cached.propensities.push(value.parse().ok()?),
+            _ => return None
+        }
+    }
+    Some(cached)
+}
+
+// Save a propensity model fit under `key`.
+pub fn store_fit(dir: &str, key: &str, cached: &CachedFit) -> io::Result<()> {
+    let mut text = format!("max_gradient\t{:e}\n", cached.fit.max_gradient);
+    text.push_str(&format!("log_likelihood\t{:e}\n", cached.fit.log_likelihood));
+    text.push_str(&format!(
+        "iterations\t{}\n",
+        cached.fit.iterations.map_or("-".to_string(), |i| i.to_string())
+    ));
+    if let Some(model) = &cached.model {
+        text.push_str(&format!("intercept\t{:e}\n", model.intercept));
+        for (name, slope) in &model.slopes {
+            text.push_str(&format!("slope\t{}\t{:e}\n", name, slope));
+        }
+    }
+    for s in &cached.separation {
+        text.push_str(&format!("separation\t{}\t{}\t{}\n", s.covariate, s.complete, s.dropped));
+    }
+    for p in &cached.propensities {
+        text.push_str(&format!("propensity\t{:e}\n", p));
+    }
+    store(dir, &format!("{}.fit", key), &text)
+}
+
+// Read cached matches: the `strike_id` of every treated unit's control, in
+// the row order of the treated units. `None` when there is no usable entry.
+pub fn load_matches(dir: &str, key: &str) -> Option<Vec<i64>> {
+    let text = fs::read_to_string(Path::new(dir).join(format!("{}.match", key))).ok()?;
+    text.lines().map(|line| line.parse().ok()).collect()
+}
+
+// Save matches under `key`.
+pub fn store_matches(dir: &str, key: &str, controls: &[i64]) -> io::Result<()> {
+    let text: String = controls.iter().map(|id| format!("{}\n", id)).collect();
+    store(dir, &format!("{}.match", key), &text)
+}
@@ -0,0 +1,294 @@
+use ndarray::{Array1, Array2};
+use polars::prelude::{DataFrame, PolarsResult};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::data::column_values;
+use crate::format::num;
+use crate::formula::predictor_values;
+use crate::overlap::c_statistic;
+use crate::propensity::{Coefficients, LogitOptions, PropensityModel};
+use crate::regression::generalized_inverse;
+use crate::survey::WeightedLogit;
+
+// Bins of the calibration table, each holding a tenth of the units.
+pub const CALIBRATION_BINS: usize = 10;
+
+// Folds of the cross-validated AUC unless `--ps-folds` says otherwise.
+pub const DIAGNOSTIC_FOLDS: usize = 5;
+
+// Folds of the cross-fitted scores a calibration map is fit on.
+pub const CALIBRATION_FOLDS: usize = 5;
+
+// A coefficient of the propensity model with its standard error.
+#[derive(Clone, Debug)]
+pub struct CoefficientEstimate {
+    pub name: String,
+    pub estimate: f64,
+    pub se: f64
+}
+
+// One bin of the calibration table
+//
+// The units whose propensity scores lie between `lower` and `upper`, their
+// mean score and the share of them that were actually treated.
+#[derive(Clone, Debug)]
+pub struct CalibrationBin {
+    pub lower: f64,
+    pub upper: f64,
+    pub n: usize,
+    pub predicted: f64,
+    pub observed: f64
+}
+
+// Diagnostics of the propensity model (`--ps-diagnostics`)
+//
+// The coefficients with their standard errors (none for tree models and
+// supplied scores), the AUC of the scores used for matching and, for
+// fitted models, of cross-validated scores, and a calibration table of the
+// scores used for matching. An in-sample AUC well above the cross-validated
+// one means the model overfits; bins whose observed treated share is far
+// from their mean score mean it is miscalibrated.
+#[derive(Clone, Debug)]
+pub struct PsDiagnostics {
+    pub model: PropensityModel,
+    pub coefficients: Vec<CoefficientEstimate>,
+    pub auc: f64,
+    pub cv_auc: Option<(f64, usize)>,
+    pub calibration: Vec<CalibrationBin>
+}
+
+// Standard errors of the coefficients of a logistic propensity model
+//
+// The inverse of the information X'VX at the estimates, where V holds
+// p (1 - p) for every row of `data` and X the intercept and predictors.
+// With sampling `weights` (scaled to a mean of 1) the fit maximizes a
+// pseudo-likelihood, whose standard errors are the sandwich A^-1 B A^-1 of
+// the weighted information A and the outer product B of the weighted
+// scores. Penalties are ignored, so the standard errors of a penalized
+// model are only a rough guide. Aliased coefficients have a NaN standard
+// error.
+pub fn coefficient_table(
+    data: &DataFrame,
+    treatment: &str,
+    model: &Coefficients,
+    weights: Option<&Array1<f64>>
+) -> PolarsResult<Vec<CoefficientEstimate>> {
+    let d = column_values(data, treatment)?;
+    let p = model.score(data)?;
+    let (n, q) = (d.len(), model.slopes.len() + 1);
+    let mut x = Array2::<f64>::ones((n, q));
+    for (k, (name, _)) in model.slopes.iter().enumerate() {
+        x.column_mut(k + 1).assign(&Array1::from(predictor_values(data, name)?));
+    }
+    let w = weights.cloned().unwrap_or_else(|| Array1::ones(n));
+    let (mut information, mut meat) = (Array2::<f64>::zeros((q, q)), Array2::<f64>::zeros((q, q)));
+    for i in 0..n {
+        let (v, s) = (w[i] * p[i] * (1.0 - p[i]), (w[i] * (d[i] - p[i])).powi(2));
+        for j in 0..q {
+            for k in 0..q {
+                information[[j, k]] += v * x[[i, j]] * x[[i, k]];
+                meat[[j, k]] += s * x[[i, j]] * x[[i, k]];
+            }
+        }
+    }
+    let (inverse, aliased) = generalized_inverse(&information);
+    let covariance = match weights {
+        Some(_) => inverse.dot(&meat).dot(&inverse),
+        None => inverse
+    };
+    let names = ["intercept".to_string()].into_iter().chain(model.slopes.iter().map(|(name, _)| name.clone()));
+    let estimates = [model.intercept].into_iter().chain(model.slopes.iter().map(|(_, b)| *b));
+    Ok(names
+        .zip(estimates)
+        .enumerate()
+        .map(|(j, (name, estimate))| CoefficientEstimate {
+            name,
+            estimate,
+            se: if aliased[j] { f64::NAN } else { covariance[[j, j]].sqrt() }
+        })
+        .collect())
+}
+
+// AUC of propensity scores `p` for treatment `d`.
+pub fn auc(p: &[f64], d: &[f64]) -> f64 {
+    let treat: Vec<f64> = p.iter().zip(d).filter(|(_, d)| **d == 1.0).map(|(p, _)| *p).collect();
+    let control: Vec<f64> = p.iter().zip(d).filter(|(_, d)| **d == 0.0).map(|(p, _)| *p).collect();
+    c_statistic(&treat, &control)
+}
+
+// Calibration table of propensity scores `p` for treatment `d`
+//
+// Units are sorted by score and split into `CALIBRATION_BINS` bins of
+// (nearly) equal size, the deciles of the score.
+pub fn calibration_table(p: &[f64], d: &[f64]) -> Vec<CalibrationBin> {
+    let mut order: Vec<usize> = (0..p.len()).collect();
+    order.sort_by(|a, b| p[*a].total_cmp(&p[*b]));
+    let bins = CALIBRATION_BINS.min(order.len());
+    (0..bins)
+        .map(|b| {
+            let rows = &order[b * order.len() / bins..(b + 1) * order.len() / bins];
+            let n = rows.len();
+            CalibrationBin {
+                lower: p[rows[0]],
+                upper: p[rows[n - 1]],
+                n,
+                predicted: rows.iter().map(|i| p[*i]).sum::<f64>() / n as f64,
+                observed: rows.iter().map(|i| d[*i]).sum::<f64>() / n as f64
+            }
+        })
+        .collect()
+}
+
+// Implement `Display` for `PsDiagnostics`.
+impl fmt::Display for PsDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Propensity model diagnostics ({})", self.model)?;
+        if !self.coefficients.is_empty() {
+            writeln!(f, "{:<24}  {:>12}  {:>12}  {:>12}", "Coefficient", "Estimate", "Std. error", "z")?;
+            for c in &self.coefficients {
+                let z = c.estimate / c.se;
+                writeln!(f, "{:<24}  {:>12}  {:>12}  {:>12}", c.name, num(c.estimate), num(c.se), num(z))?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "AUC                     : {}", num(self.auc))?;
+        if let Some((cv_auc, folds)) = self.cv_auc {
+            writeln!(f, "{:<24}: {}", format!("AUC ({}-fold CV)", folds), num(cv_auc))?;
+        }
+        writeln!(f, "\nCalibration by decile of the score")?;
+        writeln!(f, "{:>6}  {:>12}  {:>12}  {:>8}  {:>12}  {:>12}", "Bin", "From", "To", "n", "Predicted", "Observed")?;
+        for (b, bin) in self.calibration.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>6}  {:>12}  {:>12}  {:>8}  {:>12}  {:>12}",
+                b + 1,
+                num(bin.lower),
+                num(bin.upper),
+                bin.n,
+                num(bin.predicted),
+                num(bin.observed)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Post-hoc calibration of propensity scores (`--calibrate`)
+//
+// `Platt` scaling fits a logistic regression of the treatment on the log
+// odds of the scores (Platt, 1999), which corrects scores that are too
+// extreme or too timid. `Isotonic` regression fits the non-decreasing step
+// function of the scores closest to the treatment (Zadrozny and Elkan,
+// 2002), which assumes no functional form but needs more units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Calibration {
+    Platt,
+    Isotonic
+}
+
+// Implement `FromStr` for `Calibration`.
+impl FromStr for Calibration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "platt" => Ok(Calibration::Platt),
+            "isotonic" => Ok(Calibration::Isotonic),
+            _ => Err(format!("Unknown calibration `{}`; use platt or isotonic", s))
+        }
+    }
+}
+
+// Implement `Display` for `Calibration`.
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Calibration::Platt => write!(f, "platt"),
+            Calibration::Isotonic => write!(f, "isotonic")
+        }
+    }
+}
+
+// A fitted calibration map from raw to calibrated scores
+//
+// Platt scaling maps a score p to 1 / (1 + exp(-(a + b logit(p)))). The
+// isotonic map is a step function: a score gets the value of the last
+// step whose lower end it reaches, and scores below the first step that
+// step's value.
+#[derive(Clone, Debug)]
+pub enum CalibrationMap {
+    Platt { intercept: f64, slope: f64 },
+    Isotonic { lower: Vec<f64>, values: Vec<f64> }
+}
+
+// Log odds of a score, kept finite.
+fn logit(p: f64) -> f64 {
+    let p = p.clamp(1e-6, 1.0 - 1e-6);
+    (p / (1.0 - p)).ln()
+}
+
+impl CalibrationMap {
+    // Fit the map to scores `p`, treatment `d` and sampling weights `w`
+    //
+    // Isotonic regression pools adjacent violators: units are taken in
+    // order of their scores, units with equal scores share a step, and a
+    // step whose (weighted) treated share isn't above the previous one's is
+    // merged into it. Units with zero weight are ignored.
+    pub fn fit(method: Calibration, p: &[f64], d: &[f64], w: &Array1<f64>) -> CalibrationMap {
+        match method {
+            Calibration::Platt => {
+                let x = Array2::from_shape_fn((p.len(), 1), |(i, _)| logit(p[i]));
+                let d: Array1<i64> = d.iter().map(|d| *d as i64).collect();
+                let (model, _, _) = WeightedLogit::fit(&x, &d, w, &["score"], &LogitOptions::default());
+                let (intercept, slopes) = model.coefficients();
+                CalibrationMap::Platt { intercept, slope: slopes[0] }
+            },
+            Calibration::Isotonic => {
+                let mut order: Vec<usize> = (0..p.len()).filter(|i| w[*i] > 0.0).collect();
+                order.sort_by(|a, b| p[*a].total_cmp(&p[*b]));
+                // Steps as (lower score, upper score, weight, weighted treated)
+                let mut steps: Vec<(f64, f64, f64, f64)> = Vec::new();
+                for i in order {
+                    match steps.last_mut() {
+                        Some(step) if step.1 == p[i] => {
+                            step.2 += w[i];
+                            step.3 += w[i] * d[i];
+                        },
+                        _ => steps.push((p[i], p[i], w[i], w[i] * d[i]))
+                    }
+                    while let [.., previous, last] = steps[..] {
+                        if previous.3 / previous.2 < last.3 / last.2 {
+                            break;
+                        }
+                        steps.pop();
+                        let merged = steps.last_mut().expect("a previous step");
+                        *merged = (previous.0, last.1, previous.2 + last.2, previous.3 + last.3);
+                    }
+                }
+                CalibrationMap::Isotonic {
+                    lower: steps.iter().map(|s| s.0).collect(),
+                    values: steps.iter().map(|s| s.3 / s.2).collect()
+                }
+            }
+        }
+    }
+
+    // The calibrated score of `p`.
+    pub fn apply(&self, p: f64) -> f64 {
+        match self {
+            CalibrationMap::Platt { intercept, slope } => 1.0 / (1.0 + (-(intercept + slope * logit(p))).exp()),
+            CalibrationMap::Isotonic { lower, values } => values[lower.partition_point(|l| *l <= p).saturating_sub(1)]
+        }
+    }
+}
+
+// Implement `Display` for `CalibrationMap`.
+impl fmt::Display for CalibrationMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalibrationMap::Platt { intercept, slope } => write!(f, "platt intercept={} slope={}", intercept, slope),
+            CalibrationMap::Isotonic { values, .. } => write!(f, "isotonic steps={}", values.len())
+        }
+    }
+}
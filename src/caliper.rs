@@ -0,0 +1,223 @@
+use polars::prelude::{BooleanChunked, DataFrame, NamedFrom, PolarsError, PolarsResult};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::att::calculate_subset_att;
+use crate::balance::mean_variance;
+use crate::cancel::CancelToken;
+use crate::data::column_values;
+use crate::distance::pair_distances;
+use crate::format::num;
+
+// Maximum propensity score distance of a match (`--caliper`)
+//
+// `Absolute` is a distance between propensity scores. `Auto` is the common
+// default of 0.2 standard deviations of the logit of the propensity score,
+// with distances measured between logits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Caliper {
+    Absolute(f64),
+    Auto
+}
+
+// Implement `FromStr` for `Caliper`.
+impl FromStr for Caliper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Caliper::Auto),
+            _ => match s.parse::<f64>() {
+                Ok(width) if width > 0.0 => Ok(Caliper::Absolute(width)),
+                _ => Err(format!("`--caliper` must be a positive distance or `auto` but was `{}`", s))
+            }
+        }
+    }
+}
+
+// Standard deviations of the logit of the propensity score in the width of
+// `Caliper::Auto`.
+pub const AUTO_CALIPER_SD: f64 = 0.2;
+
+// Treated units dropped by the `--caliper`
+//
+// `width` is the caliper on the scale distances were measured on, the
+// logit of the propensity score for `Caliper::Auto`.
+#[derive(Debug)]
+pub struct CaliperDrop {
+    pub caliper: Caliper,
+    pub width: f64,
+    pub n_treat: usize,
+    pub dropped: usize
+}
+
+// Drop the matches that are further away than the caliper
+//
+// `scores` are the propensity scores of every unit, which set the width of
+// `Caliper::Auto`. A treated unit with several matches (`--ratio`) keeps
+// those within the caliper and is dropped when none are. Returns the
+// remaining pairs and what was dropped, and fails if no pair is left.
+pub fn apply_caliper(
+    treat: &DataFrame,
+    control: &DataFrame,
+    scores: &[f64],
+    caliper: Caliper
+) -> PolarsResult<(DataFrame, DataFrame, CaliperDrop)> {
+    let logit = |p: f64| (p / (1.0 - p)).ln();
+    let (distances, width) = match caliper {
+        Caliper::Absolute(width) => (pair_distances(treat, control)?, width),
+        Caliper::Auto => {
+            let treat_ps = column_values(treat, "propensities")?;
+            let control_ps = column_values(control, "propensities")?;
+            let distances = treat_ps.iter().zip(&control_ps).map(|(t, c)| (logit(*t) - logit(*c)).abs()).collect();
+            let (_, variance) = mean_variance(&scores.iter().map(|p| logit(*p)).collect::<Vec<f64>>());
+            (distances, AUTO_CALIPER_SD * variance.sqrt())
+        }
+    };
+    let keep: Vec<bool> = distances.iter().map(|d| *d <= width).collect();
+    let mask = BooleanChunked::new("caliper", keep);
+    let (kept_treat, kept_control) = (treat.filter(&mask)?, control.filter(&mask)?);
+    let n_treat = treat.column("strike_id")?.n_unique()?;
+    if kept_treat.height() == 0 {
+        return Err(PolarsError::ComputeError(
+            format!("No treated unit has a match within the caliper of {}", num(width)).into()
+        ));
+    }
+    let dropped = n_treat - kept_treat.column("strike_id")?.n_unique()?;
+    Ok((kept_treat, kept_control, CaliperDrop { caliper, width, n_treat, dropped }))
+}
+
+// Implement `Display` for `CaliperDrop`.
+impl fmt::Display for CaliperDrop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scale = match self.caliper {
+            Caliper::Absolute(_) => "propensity score".to_string(),
+            Caliper::Auto => format!("logit propensity score, {} SD", AUTO_CALIPER_SD)
+        };
+        writeln!(
+            f,
+            "Caliper: {} ({}); dropped {} of {} treated units without a match within it",
+            num(self.width),
+            scale,
+            self.dropped,
+            self.n_treat
+        )
+    }
+}
+
+// Caliper widths, in standard deviations of the propensity score, at which
+// the caliper sensitivity curve is evaluated. The last point applies no
+// caliper at all.
+pub const CALIPER_GRID: [f64; 8] = [0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, f64::INFINITY];
+
+// The ATT after dropping treated units whose match is further away than the
+// caliper.
+#[derive(Debug)]
+pub struct CaliperPoint {
+    pub caliper: f64,
+    pub width: f64,
+    pub n_treat: usize,
+    pub att: f64,
+    pub att_variance: f64
+}
+
+// Caliper sensitivity curve
+//
+// The caliper is a tuning choice, and conclusions that only hold for some
+// caliper widths deserve suspicion. The curve re-estimates the ATT over a
+// grid of caliper widths, reporting the estimate, its Abadie-Imbens
+// variance and how many treated units are retained at each width.
+#[derive(Debug)]
+pub struct CaliperCurve {
+    pub points: Vec<CaliperPoint>
+}
+
+// Calculate the caliper sensitivity curve
+//
+// Calipers are measured in standard deviations of the propensity score
+// across all treated and control units. At every width, pairs whose
+// propensity score distance exceeds the caliper are dropped and the ATT is
+// re-estimated on the remaining pairs. Widths that retain fewer than two
+// treated units have no estimate.
+pub fn caliper_curve(
+    treat: &DataFrame,
+    control: &DataFrame,
+    pool: &DataFrame,
+    outcome: &str,
+    treatment: &str,
+    cancel: &CancelToken
+) -> PolarsResult<CaliperCurve> {
+    let distances = pair_distances(treat, control)?;
+    let mut scores = column_values(treat, "propensities")?;
+    scores.extend(column_values(pool, "propensities")?);
+    let (_, variance) = mean_variance(&scores);
+    let sd = variance.sqrt();
+    let mut points = Vec::with_capacity(CALIPER_GRID.len());
+    for caliper in CALIPER_GRID {
+        let width = caliper * sd;
+        let keep: Vec<bool> = distances.iter().map(|d| *d <= width).collect();
+        let n_treat = keep.iter().filter(|k| **k).count();
+        let (att, att_variance) = calculate_subset_att(treat, control, &keep, outcome, treatment, cancel)?;
+        points.push(CaliperPoint { caliper, width, n_treat, att, att_variance });
+    }
+    Ok(CaliperCurve { points })
+}
+
+impl CaliperCurve {
+    // (caliper, ATT, variance, # treated retained) rows for plotting.
+    pub fn plot_rows(&self) -> Vec<(f64, f64, f64, usize)> {
+        self.points
+            .iter()
+            .map(|p| (p.caliper, p.att, p.att_variance, p.n_treat))
+            .collect()
+    }
+
+    // Export the curve
+    //
+    // Writes one CSV row per caliper width with the number of retained
+    // treated units, the ATT, its standard error and 95% confidence
+    // interval. The row without a caliper has an empty caliper and width.
+    pub fn write_csv(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "caliper,width,n_treat,att,se,ci_lower,ci_upper")?;
+        for p in &self.points {
+            let se = p.att_variance.sqrt();
+            let (caliper, width) = match p.caliper.is_finite() {
+                true => (p.caliper.to_string(), p.width.to_string()),
+                false => (String::new(), String::new())
+            };
+            writeln!(
+                file,
+                "{},{},{},{},{},{},{}",
+                caliper,
+                width,
+                p.n_treat,
+                p.att,
+                se,
+                p.att - 1.96 * se,
+                p.att + 1.96 * se
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Implement `Display` for `CaliperCurve`.
+impl fmt::Display for CaliperCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Caliper sensitivity (caliper in SDs of the propensity score)")?;
+        writeln!(f, "{:>8}  {:>8}  {:>8}  {:>8}  {:>20}", "Caliper", "# Treat", "ATT", "Variance", "95% CI")?;
+        for p in &self.points {
+            let caliper = match p.caliper.is_finite() {
+                true => format!("{}", p.caliper),
+                false => "none".to_string()
+            };
+            let se = p.att_variance.sqrt();
+            let ci = format!("({}, {})", num(p.att - 1.96 * se), num(p.att + 1.96 * se));
+            writeln!(f, "{:>8}  {:>8}  {:>8}  {:>8}  {:>20}", caliper, p.n_treat, num(p.att), num(p.att_variance), ci)?;
+        }
+        Ok(())
+    }
+}
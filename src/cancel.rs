@@ -0,0 +1,39 @@
+use polars::prelude::{PolarsError, PolarsResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// A flag that asks a running analysis to stop
+//
+// Clones share the flag, so a caller keeps one clone and hands another to
+// the analysis through `Args`. The long loops (matching, the variance
+// estimator) check it after every unit and stop with an error that says
+// how far they got. On the command line Ctrl-C and SIGTERM set the flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    // Ask the analysis to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // The shared flag, e.g. to register with a signal handler.
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.0)
+    }
+
+    // Fail if cancelled
+    //
+    // `progress` describes where the analysis stopped, e.g. "while matching
+    // after 312 of 974 units", and is only built when it was cancelled.
+    pub fn check(&self, progress: impl FnOnce() -> String) -> PolarsResult<()> {
+        match self.is_cancelled() {
+            true => Err(PolarsError::ComputeError(format!("Cancelled {}", progress()).into())),
+            false => Ok(())
+        }
+    }
+}
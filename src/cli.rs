@@ -0,0 +1,1379 @@
+use std::env;
+use std::error::Error;
+
+use crate::att::{Estimand, Location, Variance};
+use crate::binary::{OutcomeType, RatioCi};
+use crate::budget::{parse_duration, Budget};
+use crate::caliper::Caliper;
+use crate::cancel::CancelToken;
+use crate::data::data_paths;
+use crate::distance::{Distance, MatchOrder, Ties};
+use crate::equivalence::parse_margin;
+use crate::format::{NumberFormat, OutputFormat};
+use crate::missing::MissingOutcome;
+use crate::multiplicity::Adjustment;
+use crate::overlap::{Discard, Trim};
+use crate::penalized::Penalty;
+use crate::propensity::{parse_init, Learner, LogitOptions, PropensityModel, DEFAULT_LEARNERS};
+use crate::genetic::GeneticOptions;
+use crate::log::LogLevel;
+use crate::registry::{
+    self,
+    DEFAULT_ESTIMATOR,
+    DEFAULT_MATCHER,
+    GENETIC_MATCHER,
+    OPTIMAL_MATCHER,
+    SUBCLASS_ESTIMATOR
+};
+use crate::selection::Selection;
+use crate::split::SampleSplit;
+use crate::subgroup::SubgroupMatching;
+use crate::survey::SurveyDesign;
+use crate::transform::OutcomeTransform;
+use crate::warnings::FailOn;
+use crate::weighting::Weighting;
+
+// Every `--option` Strike understands, and whether it expects a value.
+pub const OPTIONS: &[(&str, bool)] = &[
+    ("outcome-transform", true),
+    ("robust-trim", true),
+    ("weights", true),
+    ("strata", true),
+    ("psu", true),
+    ("cluster", true),
+    ("split-sample", false),
+    ("cross-fit", false),
+    ("seed", true),
+    ("forest", true),
+    ("regression-adjust", false),
+    ("paired-test", false),
+    ("hodges-lehmann", false),
+    ("mcnemar", false),
+    ("permute", true),
+    ("equivalence", true),
+    ("rosenbaum", true),
+    ("placebo-outcomes", true),
+    ("influence", true),
+    ("adjust", true),
+    ("joint-bootstrap", true),
+    ("outcome-type", true),
+    ("ratio-ci", true),
+    ("time", true),
+    ("event", true),
+    ("horizon", true),
+    ("pre", true),
+    ("subclassify", false),
+    ("n-strata", true),
+    ("plot-overlap", true),
+    ("plot-balance", true),
+    ("plot-eqq", true),
+    ("plot-forest", true),
+    ("plot-density", true),
+    ("plot-covariates", true),
+    ("ps-percentiles", false),
+    ("ps-diagnostics", false),
+    ("subclass-balance", true),
+    ("balance-ci", true),
+    ("balance", false),
+    ("balance-threshold", true),
+    ("caliper-curve", false),
+    ("caliper-csv", true),
+    ("plot-caliper", true),
+    ("match-quality", false),
+    ("extreme-ps", true),
+    ("exclude-extreme", false),
+    ("trim", true),
+    ("trim-method", true),
+    ("positivity", true),
+    ("common-support", false),
+    ("discard", true),
+    ("covariate-gaps", false),
+    ("fail-on-warning", true),
+    ("deterministic", false),
+    ("digits", true),
+    ("scientific", false),
+    ("thousands", false),
+    ("format", true),
+    ("threads", true),
+    ("audit-log", true),
+    ("log-level", true),
+    ("config", true),
+    ("spec", true),
+    ("jobs", true),
+    ("memory", false),
+    ("covariates", true),
+    ("formula", true),
+    ("exclude", true),
+    ("drop-separating", false),
+    ("select", true),
+    ("pscore-col", true),
+    ("ps-model", true),
+    ("ps-max-iter", true),
+    ("ps-folds", true),
+    ("ps-penalty", true),
+    ("ps-trees", true),
+    ("ps-depth", true),
+    ("ps-learners", true),
+    ("calibrate", true),
+    ("ps-tolerance", true),
+    ("standardize", false),
+    ("no-intercept", false),
+    ("ps-init", true),
+    ("design-matrix", true),
+    ("save-session", true),
+    ("update-session", true),
+    ("cache", true),
+    ("matcher", true),
+    ("genetic-population", true),
+    ("genetic-generations", true),
+    ("distance", true),
+    ("covariate-penalty", true),
+    ("prognostic", false),
+    ("ratio", true),
+    ("replace", false),
+    ("no-replace", false),
+    ("match-order", true),
+    ("max-reuse", true),
+    ("ties", true),
+    ("caliper", true),
+    ("estimator", true),
+    ("estimand", true),
+    ("bias-correct", false),
+    ("location", true),
+    ("variance", true),
+    ("variance-neighbors", true),
+    ("weighting", true),
+    ("partitions", false),
+    ("fit-fraction", true),
+    ("time-budget", true),
+    ("where", true),
+    ("missing-outcome", true),
+    ("by", true),
+    ("by-matching", true),
+    ("gps", true),
+    ("dml", false),
+];
+
+// The fixed set of values some options accept, for shell completion.
+pub const OPTION_VALUES: &[(&str, &[&str])] = &[
+    ("outcome-transform", &["log", "log1p"]),
+    ("outcome-type", &["auto", "continuous", "binary", "survival"]),
+    ("ratio-ci", &["delta", "bootstrap"]),
+    ("format", &["summary", "tsv", "kv"]),
+    ("log-level", &["error", "warn", "info", "debug"]),
+    ("ps-model", &["logit", "firth", "gbm", "rf", "ensemble"]),
+    ("calibrate", &["platt", "isotonic"]),
+    ("select", &["treatment", "outcome", "double"]),
+    ("estimand", &["att", "atc", "ate"]),
+    ("variance", &["ai-robust", "ai-homo", "paired"]),
+    ("adjust", &["holm", "bonferroni"]),
+    ("missing-outcome", &["ipw"]),
+    ("distance", &["propensity", "logit"]),
+    (
+        "match-order",
+        &["data", "largest-pscore", "smallest-pscore", "random", "closest-first", "worst-first"]
+    ),
+    ("ties", &["first", "random", "all-average"]),
+    ("trim-method", &["drop", "clip"]),
+    ("discard", &["off-support"]),
+    ("by-matching", &["pooled", "within"]),
+    (
+        "fail-on-warning",
+        &[
+            "all",
+            "serious",
+            "small-sample",
+            "poor-overlap",
+            "extreme-weights",
+            "non-convergence",
+            "control-reuse",
+            "separation"
+        ]
+    ),
+];
+
+// Combinations of options that can't be used together
+//
+// Each entry is a check on the parsed arguments and the error reported when
+// it holds. `parse_args` runs them in order once every option is read, and
+// reports the first that holds. Settings several checks share, such as
+// whether a session is used, are helpers on `Args`.
+pub type Conflict = (fn(&Args) -> bool, &'static str);
+
+pub const CONFLICTS: &[Conflict] = &[
+    (
+        |a| a.session() && (a.split.is_some() || a.trim.is_some() || !a.specs.is_empty()),
+        "Sessions can't be combined with `--split-sample`, `--cross-fit`, `--trim` or `--spec`"
+    ),
+    (
+        |a| a.partitions && (a.split.is_some() || a.trim.is_some() || a.session() || a.custom_matcher()),
+        "`--partitions` can't be combined with sample splitting, `--trim`, sessions or `--matcher`"
+    ),
+    (
+        |a| a.update_session.is_some() && a.custom_matcher(),
+        "Sessions are only updated with the built-in nearest neighbor matcher"
+    ),
+    (
+        |a| a.design_matrix.is_some() && (a.split == Some(SampleSplit::CrossFit) || !a.specs.is_empty()),
+        "`--design-matrix` can't be combined with `--cross-fit` or `--spec`, which fit several models"
+    ),
+    (
+        |a| a.missing_outcome.is_some() && (a.partitions || a.session() || a.custom_estimator()),
+        "`--missing-outcome` can't be combined with `--partitions`, sessions or `--estimator`"
+    ),
+    (
+        |a| a.missing_outcome.is_some() && a.subclassify,
+        "`--missing-outcome` can't be combined with `--subclassify`"
+    ),
+    (
+        |a| a.missing_outcome.is_some() && a.other_estimates(),
+        "`--missing-outcome` can't be combined with `--robust-trim`, survey designs, `--cluster`, \
+         `--regression-adjust`, `--paired-test`, `--hodges-lehmann`, `--mcnemar` or `--rosenbaum`"
+    ),
+    (
+        |a| {
+            let optimal = a.matcher.as_deref() == Some(OPTIMAL_MATCHER);
+            let replace = !a.no_replace && a.max_reuse.is_none() && !optimal;
+            let pairs_only = a.other_estimates() || a.subclassify || a.caliper.is_some();
+            let pairs_only = pairs_only || a.missing_outcome.is_some();
+            let unsupported = !replace || pairs_only || a.custom_estimator() || a.partitions || a.session();
+            a.estimand == Estimand::Ate && unsupported
+        },
+        "`--estimand ate` needs matching with replacement and the built-in estimator, and can't be combined with \
+         `--caliper`, `--missing-outcome`, `--subclassify`, the pair-based extras, `--partitions` or sessions"
+    ),
+    (
+        |a| a.bias_correct && (a.custom_estimator() || a.missing_outcome.is_some() || a.estimand == Estimand::Ate),
+        "`--bias-correct` applies to the built-in estimator and can't be combined with `--missing-outcome` or \
+         `--estimand ate`"
+    ),
+    (
+        |a| {
+            let mean_only = a.bias_correct || a.custom_estimator() || a.missing_outcome.is_some();
+            let mean_only = mean_only || a.estimand == Estimand::Ate;
+            a.location != Location::Mean && (mean_only || a.by.is_some())
+        },
+        "`--location` applies to the built-in estimator and can't be combined with `--bias-correct`, \
+         `--missing-outcome`, `--estimand ate` or `--by`"
+    ),
+    (
+        |a| {
+            let own_variance = a.custom_estimator() || a.missing_outcome.is_some() || a.estimand == Estimand::Ate;
+            a.variance != Variance::default() && (own_variance || a.location != Location::Mean)
+        },
+        "`--variance` applies to the mean of the built-in estimator and can't be combined with \
+         `--missing-outcome`, `--estimand ate` or `--location`"
+    ),
+    (
+        |a| {
+            let matched_variance = a.custom_estimator() || a.missing_outcome.is_some() || a.location != Location::Mean;
+            a.variance_neighbors.is_some() && (matched_variance || a.variance == Variance::Paired)
+        },
+        "`--variance-neighbors` applies to the Abadie-Imbens variance of the built-in estimator and can't be \
+         combined with `--missing-outcome`, `--location` or `--variance paired`"
+    ),
+    (
+        |a| {
+            let pairs_only = a.other_estimates() || a.subclassify || a.missing_outcome.is_some();
+            a.estimand == Estimand::Atc && (pairs_only || a.partitions || a.session())
+        },
+        "`--estimand atc` can't be combined with `--missing-outcome`, `--subclassify`, the pair-based extras, \
+         `--partitions` or sessions"
+    ),
+    (
+        |a| a.distance != Distance::Propensity && (a.partitions || a.session()),
+        "`--distance` can't be combined with `--partitions` or sessions"
+    ),
+    (
+        |a| a.caliper.is_some() && (a.partitions || a.session()),
+        "`--caliper` can't be combined with `--partitions` or sessions"
+    ),
+    (
+        |a| a.no_replace && (a.partitions || a.session() || a.registered_matcher()),
+        "`--no-replace` can't be combined with `--partitions`, sessions or a registered `--matcher`"
+    ),
+    (
+        |a| a.max_reuse.is_some() && (a.no_replace || a.partitions || a.session() || a.custom_matcher()),
+        "`--max-reuse` only applies to nearest neighbor matching with replacement, without `--partitions` or \
+         sessions"
+    ),
+    (
+        |a| a.matcher.as_deref() == Some(GENETIC_MATCHER) && (a.k_to_one() || a.no_replace),
+        "`--matcher genetic` matches 1:1 with replacement and can't be combined with `--ratio` or `--no-replace`"
+    ),
+    (
+        |a| a.k_to_one() && (a.partitions || a.session() || a.registered_matcher() || a.missing_outcome.is_some()),
+        "`--ratio` can't be combined with `--partitions`, sessions, a registered `--matcher` or `--missing-outcome`"
+    ),
+    (
+        |a| a.covariate_penalty.is_some() && (!a.with_replacement() || a.partitions || a.session()),
+        "`--covariate-penalty` only applies to nearest neighbor matching with replacement, without `--max-reuse`, \
+         `--partitions` or sessions"
+    ),
+    (
+        |a| {
+            let unsupported = a.partitions || a.session() || a.missing_outcome.is_some();
+            a.prognostic && (!a.with_replacement() || a.covariate_penalty.is_some() || unsupported)
+        },
+        "`--prognostic` only applies to nearest neighbor matching with replacement, without `--max-reuse`, \
+         `--covariate-penalty`, `--partitions`, sessions or `--missing-outcome`"
+    ),
+    (
+        |a| {
+            let nearest_neighbor = !a.k_to_one() && a.with_replacement() && a.covariate_penalty.is_none();
+            let nearest_neighbor = nearest_neighbor && !a.prognostic;
+            let unsupported = a.partitions || a.session() || a.missing_outcome.is_some();
+            a.ties != Ties::First && (!nearest_neighbor || unsupported)
+        },
+        "`--ties` only applies to 1:1 nearest neighbor matching with replacement, without `--max-reuse`, \
+         `--covariate-penalty`, `--prognostic`, `--partitions`, sessions or `--missing-outcome`"
+    ),
+    (
+        |a| (a.k_to_one() || a.ties == Ties::AllAverage) && a.other_estimates(),
+        "`--ratio` and `--ties all-average` can't be combined with `--robust-trim`, survey designs, \
+         `--regression-adjust`, `--paired-test`, `--hodges-lehmann`, `--mcnemar` or `--rosenbaum`, which assume \
+         1:1 pairs"
+    ),
+    (
+        |a| a.outcome_type == OutcomeType::Binary && (a.transformed() || a.estimand != Estimand::Att),
+        "`--outcome-type binary` reports risk and odds ratios of the ATT and can't be combined with \
+         `--outcome-transform`, `--missing-outcome` or `--estimand`"
+    ),
+    (
+        |a| {
+            let single_estimate = a.transformed() || a.estimand != Estimand::Att || a.by.is_some();
+            let single_estimate = single_estimate || !a.gps.is_empty();
+            let other_estimator = a.reweighted() || a.several_outcomes();
+            a.survival() && (single_estimate || other_estimator || a.refits())
+        },
+        "`--outcome-type survival` can't be combined with `--outcome-transform`, `--missing-outcome`, \
+         `--estimand`, `--by`, `--gps`, `--estimator`, `--bias-correct`, `--location`, several outcomes, sample \
+         splitting, `--partitions` or sessions"
+    ),
+    (
+        |a| a.several_outcomes() && (a.outcome_dependent() || a.estimand != Estimand::Att || a.session()),
+        "Several outcomes can't be combined with `--prognostic`, `--missing-outcome`, `--by`, `--estimand` or \
+         sessions"
+    ),
+    (
+        |a| !a.placebo_outcomes.is_empty() && a.placebo_unsupported(),
+        "`--placebo-outcomes` can't be combined with `--prognostic`, `--missing-outcome`, `--by`, `--estimand`, \
+         survival outcomes, `--gps`, `--dml`, `--pre` or sessions"
+    ),
+    (
+        |a| a.influence.is_some() && (a.placebo_unsupported() || a.reweighted() || a.split.is_some() || a.partitions),
+        "`--influence` writes the contributions to the matched ATT of the built-in estimator and can't be \
+         combined with `--estimator`, `--bias-correct`, `--location`, `--prognostic`, `--missing-outcome`, `--by`, \
+         `--estimand`, survival outcomes, `--gps`, `--dml`, `--pre`, sample splitting, `--partitions` or sessions"
+    ),
+    (
+        |a| (a.adjustment != Adjustment::default() || a.joint_bootstrap.is_some()) && !a.several_outcomes(),
+        "`--adjust` and `--joint-bootstrap` need several outcomes"
+    ),
+    (
+        |a| a.joint_bootstrap.is_some() && (a.reweighted() || a.split.is_some()),
+        "`--joint-bootstrap` resamples the matched differences of the built-in estimator and can't be combined \
+         with `--estimator`, `--bias-correct`, `--location` or sample splitting"
+    ),
+    (
+        |a| a.by.is_none() && a.by_matching != SubgroupMatching::Pooled,
+        "`--by-matching` needs a subgroup column given with `--by`"
+    ),
+    (
+        |a| {
+            let unsupported = a.split.is_some() || a.partitions || a.session() || a.missing_outcome.is_some();
+            a.by.is_some() && (unsupported || a.estimand != Estimand::Att)
+        },
+        "`--by` estimates the ATT by subgroup and can't be combined with `--estimand`, sample splitting, \
+         `--partitions`, sessions or `--missing-outcome`"
+    ),
+    (
+        |a| {
+            let binary_analysis = a.estimand != Estimand::Att || a.by.is_some() || a.missing_outcome.is_some();
+            !a.gps.is_empty() && (binary_analysis || a.several_runs() || a.several_outcomes())
+        },
+        "`--gps` estimates a dose-response curve and can't be combined with `--estimand`, `--by`, \
+         `--missing-outcome`, sample splitting, `--partitions`, sessions, `--spec` or several outcomes"
+    ),
+    (
+        |a| {
+            let weighted_sample = a.estimand != Estimand::Att || a.survival() || !a.gps.is_empty();
+            let unsupported = a.split.is_some() || a.partitions || a.session() || a.missing_outcome.is_some();
+            a.weighting.is_some() && (weighted_sample || unsupported)
+        },
+        "`--weighting` reports a weighted estimate next to the ATT and can't be combined with `--estimand`, \
+         `--missing-outcome`, `--outcome-type survival`, `--gps`, sample splitting, `--partitions` or sessions"
+    ),
+    (
+        |a| a.cluster.is_some() && (a.estimand != Estimand::Att || a.survival() || !a.gps.is_empty()),
+        "`--cluster` reports a cluster-robust variance of the matched ATT and can't be combined with \
+         `--estimand`, `--outcome-type survival` or `--gps`"
+    ),
+    (
+        |a| {
+            let changed = a.outcome_transform.is_some() || a.survival() || a.several_outcomes() || !a.gps.is_empty();
+            let unsupported = a.missing_outcome.is_some() || a.partitions || a.session();
+            a.pre.is_some() && (changed || unsupported)
+        },
+        "`--pre` can't be combined with `--outcome-transform`, `--missing-outcome`, survival outcomes, several \
+         outcomes, `--gps`, `--partitions` or sessions"
+    ),
+    (
+        |a| {
+            let other_analysis = a.by.is_some() || !a.gps.is_empty() || a.survival() || a.transformed();
+            let other_analysis = other_analysis || a.several_outcomes();
+            a.dml && (other_analysis || a.several_runs() || a.estimand == Estimand::Atc)
+        },
+        "`--dml` estimates the ATT or ATE without matching and can't be combined with `--estimand atc`, `--by`, \
+         `--gps`, survival outcomes, `--outcome-transform`, `--missing-outcome`, several outcomes, sample \
+         splitting, `--partitions`, sessions or `--spec`"
+    ),
+    (
+        |a| {
+            let single_estimate = a.transformed() || a.estimand != Estimand::Att || a.by.is_some();
+            let single_estimate = single_estimate || !a.gps.is_empty();
+            let other_att = a.reweighted() || a.survival() || a.dml;
+            a.permute.is_some() && (single_estimate || other_att || a.refits())
+        },
+        "`--permute` tests the matched ATT of the built-in estimator and can't be combined with \
+         `--outcome-transform`, `--missing-outcome`, `--estimand`, `--by`, `--gps`, `--estimator`, \
+         `--bias-correct`, `--location`, survival outcomes, `--dml`, sample splitting, `--partitions` or sessions"
+    ),
+    (
+        |a| a.equivalence.is_some() && a.other_output(),
+        "`--equivalence` tests the estimate of the summary and can't be combined with `--by`, `--gps`, `--dml` or \
+         `--spec`"
+    ),
+    (
+        |a| (a.balance || a.balance_only) && a.other_output(),
+        "The balance table describes the matched sample and can't be combined with `--by`, `--gps`, `--dml` or \
+         `--spec`"
+    ),
+    (
+        |a| a.balance && a.output != OutputFormat::Summary,
+        "`--balance` adds a table to the summary and can't be combined with `--format`"
+    ),
+    (
+        |a| a.balance_threshold.is_some() && !(a.balance || a.balance_only || a.plots.balance.is_some()),
+        "`--balance-threshold` needs `--balance`, `strike balance` or `--plot-balance`"
+    ),
+    (
+        |a| {
+            let unmatched = !a.gps.is_empty() || a.dml || a.weighting.is_some();
+            (a.common_support || a.discard.is_some()) && (unmatched || a.partitions || a.session())
+        },
+        "`--common-support` and `--discard` check the scores before matching and can't be combined with `--gps`, \
+         `--dml`, `--weighting`, `--partitions` or sessions"
+    ),
+    (
+        |a| a.ps_diagnostics && (a.refits() || a.dml || !a.gps.is_empty()),
+        "`--ps-diagnostics` describes the propensity model fit on the whole sample and can't be combined with \
+         sample splitting, `--partitions`, sessions, `--dml` or `--gps`"
+    ),
+    (
+        |a| a.logit.penalty.is_some() && (a.ps_model != PropensityModel::Logit || !a.logit.init.is_empty()),
+        "`--ps-penalty` applies to `--ps-model logit` and can't be combined with `--ps-init`"
+    ),
+    (
+        |a| a.logit.standardize && (!a.unpenalized_logit() || !a.logit.init.is_empty()),
+        "`--standardize` applies to `--ps-model logit` without `--ps-penalty`, which standardizes anyway, and \
+         can't be combined with `--ps-init`, whose starting values are on the original scale"
+    ),
+    (
+        |a| {
+            let origin_unsupported = a.logit.standardize || a.ps_diagnostics || a.weighting.is_some();
+            let unsupported = origin_unsupported || a.survey.weights.is_some();
+            !a.logit.intercept && (!a.unpenalized_logit() || unsupported)
+        },
+        "`--no-intercept` applies to `--ps-model logit` without `--ps-penalty` and can't be combined with \
+         `--standardize`, `--weights`, `--ps-diagnostics` or `--weighting`, which assume an intercept"
+    ),
+    (
+        |a| !a.logit.intercept && a.logit.init.iter().any(|(name, _)| name == "intercept"),
+        "`--ps-init` can't give the intercept a starting value with `--no-intercept`"
+    ),
+    (
+        |a| (a.logit.trees.is_some() || a.logit.depth.is_some()) && !a.tree_model(),
+        "`--ps-trees` and `--ps-depth` need `--ps-model gbm`, `rf` or `ensemble`"
+    ),
+    (
+        |a| !a.logit.learners.is_empty() && a.ps_model != PropensityModel::Ensemble,
+        "`--ps-learners` needs `--ps-model ensemble`"
+    ),
+    (
+        |a| a.logit.calibrate.is_some() && !a.tree_model(),
+        "`--calibrate` recalibrates the scores of a tree model or an ensemble (`--ps-model gbm`, `rf` or \
+         `ensemble`); a logit's are calibrated by its likelihood"
+    ),
+    (
+        |a| {
+            let selection = !a.covariates.is_empty() || !a.exclude.is_empty() || a.drop_separating;
+            a.logit.formula.is_some() && (selection || a.tree_model())
+        },
+        "`--formula` chooses the predictors itself and can't be combined with `--covariates`, `--exclude`, \
+         `--drop-separating`, a tree model or an ensemble"
+    ),
+    (
+        |a| {
+            let chosen = a.logit.formula.is_some() || a.pscore_col.is_some();
+            let unsupported = a.dml || !a.gps.is_empty() || a.partitions || a.update_session.is_some();
+            a.select.is_some() && (chosen || unsupported)
+        },
+        "`--select` chooses the covariates of the propensity model before matching and can't be combined with \
+         `--formula`, `--pscore-col`, `--dml`, `--gps`, `--partitions` or `--update-session`"
+    ),
+    (
+        |a| {
+            let selection = !a.covariates.is_empty() || !a.exclude.is_empty();
+            let model_options = selection
+                || a.ps_model != PropensityModel::default()
+                || a.logit != LogitOptions::default();
+            let refit = a.refits() || a.ps_folds.is_some();
+            let other = a.dml || !a.gps.is_empty() || a.weighting.is_some();
+            let unsupported = model_options || refit || other || a.drop_separating || a.design_matrix.is_some();
+            a.pscore_col.is_some() && unsupported
+        },
+        "`--pscore-col` supplies the propensity scores, so it can't be combined with propensity model options \
+         (`--covariates`, `--exclude`, `--ps-model`, `--ps-*`, `--drop-separating`, `--design-matrix`), sample \
+         splitting, `--partitions`, sessions, `--weighting`, `--dml` or `--gps`"
+    ),
+    (
+        |a| {
+            let coefficients_needed = a.partitions || a.session() || a.weighting.is_some();
+            let unsupported = coefficients_needed || a.design_matrix.is_some() || !a.logit.init.is_empty();
+            a.tree_model() && unsupported
+        },
+        "A tree or ensemble propensity model has no coefficients and can't be combined with `--partitions`, \
+         sessions, `--weighting`, `--design-matrix` or `--ps-init`"
+    ),
+    (
+        |a| a.ps_folds.is_some() && a.refits(),
+        "`--ps-folds` can't be combined with sample splitting, whose scores are already out of sample, \
+         `--partitions` or sessions"
+    ),
+];
+
+// Propensity thresholds used by `--exclude-extreme` without `--extreme-ps`
+// or `--trim`.
+const DEFAULT_EXTREME_PS: (f64, f64) = (0.01, 0.99);
+
+// Output paths of the requested plots
+//
+// `eqq` and `density` are directories that receive one plot per covariate;
+// the others are files whose extension (`.png` or `.svg`) picks the format.
+// `covariates` restricts the per-covariate plots to the named covariates
+// (all covariates when empty).
+#[derive(Clone, Debug, Default)]
+pub struct PlotPaths {
+    pub overlap: Option<String>,
+    pub balance: Option<String>,
+    pub eqq: Option<String>,
+    pub forest: Option<String>,
+    pub density: Option<String>,
+    pub caliper: Option<String>,
+    pub covariates: Vec<String>,
+}
+
+// Parsed command line arguments
+//
+// The data path, treatment column and outcome column are positional and
+// always required. The outcome may be a comma-separated list; the first is
+// `outcome` and the rest, estimated on the same matched sample, are
+// `extra_outcomes`. Everything else is an optional `--option`.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    pub path: String,
+    pub treatment: String,
+    pub outcome: String,
+    pub extra_outcomes: Vec<String>,
+    pub outcome_transform: Option<OutcomeTransform>,
+    pub robust_trim: Option<f64>,
+    pub survey: SurveyDesign,
+    pub cluster: Option<String>,
+    pub split: Option<SampleSplit>,
+    pub seed: Option<u64>,
+    pub forest: Option<String>,
+    pub regression_adjust: bool,
+    pub paired_test: bool,
+    pub hodges_lehmann: bool,
+    pub mcnemar: bool,
+    pub permute: Option<usize>,
+    pub equivalence: Option<(f64, f64)>,
+    pub rosenbaum: Option<f64>,
+    pub placebo_outcomes: Vec<String>,
+    pub influence: Option<String>,
+    pub adjustment: Adjustment,
+    pub joint_bootstrap: Option<usize>,
+    pub outcome_type: OutcomeType,
+    pub ratio_ci: RatioCi,
+    pub event: Option<String>,
+    pub horizon: Option<f64>,
+    pub pre: Option<String>,
+    pub subclassify: bool,
+    pub n_strata: Option<usize>,
+    pub plots: PlotPaths,
+    pub ps_percentiles: bool,
+    pub ps_diagnostics: bool,
+    pub subclass_balance: Option<usize>,
+    pub balance_ci: Option<usize>,
+    pub balance: bool,
+    pub balance_only: bool,
+    pub balance_threshold: Option<f64>,
+    pub caliper_curve: bool,
+    pub caliper_csv: Option<String>,
+    pub match_quality: bool,
+    pub extreme_ps: Option<(f64, f64)>,
+    pub trim: Option<Trim>,
+    pub positivity: Option<usize>,
+    pub common_support: bool,
+    pub discard: Option<Discard>,
+    pub covariate_gaps: bool,
+    pub fail_on_warning: Option<FailOn>,
+    pub deterministic: bool,
+    pub format: NumberFormat,
+    pub output: OutputFormat,
+    pub threads: Option<usize>,
+    pub audit_log: Option<String>,
+    pub log_level: LogLevel,
+    pub config: Option<String>,
+    pub specs: Vec<String>,
+    pub jobs: Option<usize>,
+    pub memory: bool,
+    pub covariates: Vec<String>,
+    pub exclude: Vec<String>,
+    pub drop_separating: bool,
+    pub select: Option<Selection>,
+    pub deselected: Vec<String>,
+    pub ps_model: PropensityModel,
+    pub ps_folds: Option<usize>,
+    pub pscore_col: Option<String>,
+    pub logit: LogitOptions,
+    pub design_matrix: Option<String>,
+    pub save_session: Option<String>,
+    pub update_session: Option<String>,
+    pub cache: Option<String>,
+    pub matcher: Option<String>,
+    pub genetic: GeneticOptions,
+    pub distance: Distance,
+    pub covariate_penalty: Option<f64>,
+    pub prognostic: bool,
+    pub ratio: Option<usize>,
+    pub no_replace: bool,
+    pub match_order: MatchOrder,
+    pub max_reuse: Option<usize>,
+    pub ties: Ties,
+    pub caliper: Option<Caliper>,
+    pub estimator: Option<String>,
+    pub estimand: Estimand,
+    pub bias_correct: bool,
+    pub variance: Variance,
+    pub variance_neighbors: Option<usize>,
+    pub location: Location,
+    pub weighting: Option<Weighting>,
+    pub partitions: bool,
+    pub fit_fraction: Option<f64>,
+    pub budget: Option<Budget>,
+    pub filter: Option<String>,
+    pub missing_outcome: Option<MissingOutcome>,
+    pub by: Option<String>,
+    pub by_matching: SubgroupMatching,
+    pub gps: Vec<f64>,
+    pub dml: bool,
+    pub cancel: CancelToken,
+}
+
+// Settings shared by the checks of `CONFLICTS`.
+impl Args {
+    // Whether a session is saved or updated.
+    pub fn session(&self) -> bool {
+        self.save_session.is_some() || self.update_session.is_some()
+    }
+
+    // Whether a matcher other than the built-in nearest neighbor one is used.
+    pub fn custom_matcher(&self) -> bool {
+        self.matcher.as_deref().is_some_and(|m| m != DEFAULT_MATCHER)
+    }
+
+    // Whether a matcher registered through `strike::registry` is used.
+    pub fn registered_matcher(&self) -> bool {
+        self.matcher.as_deref().is_some_and(|m| m != DEFAULT_MATCHER && m != OPTIMAL_MATCHER)
+    }
+
+    // Whether an estimator other than the built-in one is used.
+    pub fn custom_estimator(&self) -> bool {
+        self.estimator.as_deref().is_some_and(|e| e != DEFAULT_ESTIMATOR)
+    }
+
+    // Whether any of the pair-based estimates next to the ATT is requested.
+    pub fn other_estimates(&self) -> bool {
+        self.robust_trim.is_some()
+            || !self.survey.is_empty()
+            || self.cluster.is_some()
+            || self.regression_adjust
+            || self.paired_test
+            || self.hodges_lehmann
+            || self.mcnemar
+            || self.rosenbaum.is_some()
+    }
+
+    // Whether every treated unit is matched to more than one control.
+    pub fn k_to_one(&self) -> bool {
+        self.ratio.is_some_and(|k| k > 1)
+    }
+
+    // Whether the built-in matcher matches with unlimited replacement.
+    pub fn with_replacement(&self) -> bool {
+        !self.no_replace && self.max_reuse.is_none() && !self.custom_matcher()
+    }
+
+    // Whether the outcome is a time to event.
+    pub fn survival(&self) -> bool {
+        self.outcome_type == OutcomeType::Survival
+    }
+
+    // Whether more than one outcome is estimated.
+    pub fn several_outcomes(&self) -> bool {
+        !self.extra_outcomes.is_empty()
+    }
+
+    // Whether the outcome is transformed or reweighted for missingness.
+    pub fn transformed(&self) -> bool {
+        self.outcome_transform.is_some() || self.missing_outcome.is_some()
+    }
+
+    // Whether the propensity model is fit more than once.
+    pub fn refits(&self) -> bool {
+        self.split.is_some() || self.partitions || self.session()
+    }
+
+    // Whether the analysis runs more than once.
+    pub fn several_runs(&self) -> bool {
+        self.refits() || !self.specs.is_empty()
+    }
+
+    // Whether the ATT is estimated other than by the mean matched difference.
+    pub fn reweighted(&self) -> bool {
+        self.custom_estimator() || self.bias_correct || self.location != Location::Mean
+    }
+
+    // Whether the matched sample depends on the outcome.
+    pub fn outcome_dependent(&self) -> bool {
+        self.prognostic || self.missing_outcome.is_some() || self.by.is_some()
+    }
+
+    // Whether the analysis can't estimate placebo outcomes the way it does
+    // the outcome.
+    pub fn placebo_unsupported(&self) -> bool {
+        let other = self.outcome_dependent() || self.estimand != Estimand::Att || self.session() || self.survival();
+        other || !self.gps.is_empty() || self.dml || self.pre.is_some()
+    }
+
+    // Whether the output is something other than the summary of one estimate.
+    pub fn other_output(&self) -> bool {
+        self.by.is_some() || !self.gps.is_empty() || self.dml || !self.specs.is_empty()
+    }
+
+    // Whether the propensity model is a logit without a penalty.
+    pub fn unpenalized_logit(&self) -> bool {
+        self.ps_model == PropensityModel::Logit && self.logit.penalty.is_none()
+    }
+
+    // Whether the propensity model is a tree model or an ensemble, which
+    // have no coefficients.
+    pub fn tree_model(&self) -> bool {
+        matches!(self.ps_model, PropensityModel::Gbm | PropensityModel::Forest | PropensityModel::Ensemble)
+    }
+}
+
+// An option set by one layer of configuration
+//
+// `Some(value)` sets the option (flags have an empty value); `None` turns a
+// flag off again, undoing what an earlier layer set.
+type Setting = (&'static str, Option<String>);
+
+// Look up an option by name.
+fn option(name: &str) -> Option<&'static (&'static str, bool)> {
+    OPTIONS.iter().find(|(option, _)| *option == name)
+}
+
+// Read a flag's true/false value from the config file or the environment.
+fn flag_value(value: &str, source: &str) -> Result<bool, Box<dyn Error>> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" | "" => Ok(false),
+        _ => Err(format!("{} must be true or false but was {}", source, value).into())
+    }
+}
+
+// A setting from the config file or the environment.
+fn setting(name: &'static str, takes_value: bool, value: &str, source: &str) -> Result<Setting, Box<dyn Error>> {
+    match takes_value {
+        true => Ok((name, Some(value.to_string()))),
+        false => Ok((name, flag_value(value, source)?.then(String::new)))
+    }
+}
+
+// Options set in a config file
+//
+// Each line is `option = value`, with the option named as on the command
+// line but without the leading `--`. Flags are set by `true` (or a bare
+// `option` line) and turned off by `false`. Blank lines and lines starting
+// with `#` are ignored.
+fn config_options(path: &str) -> Result<Vec<Setting>, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read the config file {}: {}", path, e))?;
+    let mut settings = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => (line, "true")
+        };
+        let source = format!("`{}` on line {} of {}", name, number + 1, path);
+        let (name, takes_value) = option(name).ok_or(format!("Unknown option {}", source))?;
+        if *name == "config" {
+            return Err(format!("A config file can't name another one ({})", source).into());
+        }
+        settings.push(setting(name, *takes_value, value, &source)?);
+    }
+    Ok(settings)
+}
+
+// Name of the environment variable that sets an option, e.g.
+// `STRIKE_ROBUST_TRIM` for `--robust-trim`.
+fn env_name(option: &str) -> String {
+    format!("STRIKE_{}", option.to_uppercase().replace('-', "_"))
+}
+
+// Options set through environment variables
+//
+// Every option can be given as a `STRIKE_` environment variable, which is
+// handy in containerized batch jobs. Options that take a value use the
+// variable's value; flags are set by `1`, `true` or `yes` and turned off by
+// `0`, `false`, `no` or an empty value.
+fn env_options() -> Result<Vec<Setting>, Box<dyn Error>> {
+    let mut settings = Vec::new();
+    for (name, takes_value) in OPTIONS {
+        if let Ok(value) = env::var(env_name(name)) {
+            let source = format!("`{}`", env_name(name));
+            settings.push(setting(name, *takes_value, &value, &source)?);
+        }
+    }
+    Ok(settings)
+}
+
+// Parse command line arguments
+//
+// Positional arguments and options may be given in any order. Options that
+// take a value read it from the following argument. Unknown options and
+// missing values are reported as errors rather than silently ignored. Every
+// flag can be turned off with `--no-` in front of its name.
+//
+// Options are layered: a config file (`--config` or `STRIKE_CONFIG`), then
+// `STRIKE_` environment variables (see `env_options`), then the command
+// line, so that flags win over the environment and the environment over the
+// config file. A flag turned off in a later layer undoes an earlier one.
+pub fn parse_args(args: &[String]) -> Result<Args, Box<dyn Error>> {
+    // `strike balance ...` runs the analysis but reports only the balance table
+    let balance_only = args.get(1).map(String::as_str) == Some("balance");
+    let mut positional: Vec<String> = Vec::new();
+    let mut flags: Vec<Setting> = Vec::new();
+    let mut iter = args.iter().skip(1 + balance_only as usize);
+    while let Some(arg) = iter.next() {
+        match arg.strip_prefix("--") {
+            Some(name) => {
+                let turned_off = name
+                    .strip_prefix("no-")
+                    .and_then(option)
+                    .filter(|(_, takes_value)| !takes_value && option(name).is_none());
+                if let Some((name, _)) = turned_off {
+                    flags.push((name, None));
+                    continue;
+                }
+                let (name, takes_value) = option(name).ok_or(format!("Unknown option `--{}`", name))?;
+                let value = match takes_value {
+                    true => iter
+                        .next()
+                        .ok_or(format!("Option `--{}` expects a value", name))?
+                        .clone(),
+                    false => String::new()
+                };
+                flags.push((name, Some(value)));
+            },
+            None => positional.push(arg.clone())
+        }
+    }
+    let env = env_options()?;
+    let config = flags
+        .iter()
+        .rev()
+        .chain(env.iter().rev())
+        .find(|(name, _)| *name == "config")
+        .and_then(|(_, path)| path.clone());
+    let mut layers = match &config {
+        Some(path) => config_options(path)?,
+        None => Vec::new()
+    };
+    layers.extend(env);
+    layers.extend(flags);
+    let mut options: Vec<(&str, String)> = Vec::new();
+    for (name, value) in layers {
+        match value {
+            Some(value) => options.push((name, value)),
+            None => options.retain(|(option, _)| *option != name)
+        }
+    }
+    if positional.len() != 3 {
+        return Err(format!(
+            "Expected 3 arguments but {} {:?} were provided",
+            positional.len(),
+            positional
+        ).into());
+    }
+    let mut outcomes = positional[2].split(',').map(|o| o.trim().to_string());
+    let mut parsed = Args {
+        path: positional[0].clone(),
+        treatment: positional[1].clone(),
+        outcome: outcomes.next().unwrap_or_default(),
+        extra_outcomes: outcomes.collect(),
+        balance_only,
+        ..Default::default()
+    };
+    let mut time_given = false;
+    let (mut trim, mut trim_method): (bool, Option<Trim>) = (false, None);
+    for (name, value) in options {
+        match name {
+            "outcome-transform" => parsed.outcome_transform = Some(value.parse()?),
+            "robust-trim" => {
+                let trim: f64 = value.parse()?;
+                if !(0.0..0.5).contains(&trim) {
+                    return Err(format!("`--robust-trim` must be in [0, 0.5) but was {}", trim).into());
+                }
+                parsed.robust_trim = Some(trim);
+            },
+            "weights" => parsed.survey.weights = Some(value),
+            "strata" => parsed.survey.strata = Some(value),
+            "psu" => parsed.survey.psu = Some(value),
+            "cluster" => parsed.cluster = Some(value),
+            "split-sample" => {
+                if parsed.split.is_none() {
+                    parsed.split = Some(SampleSplit::Split);
+                }
+            },
+            "cross-fit" => parsed.split = Some(SampleSplit::CrossFit),
+            "seed" => parsed.seed = Some(value.parse()?),
+            "forest" => parsed.forest = Some(value),
+            "regression-adjust" => parsed.regression_adjust = true,
+            "paired-test" => parsed.paired_test = true,
+            "hodges-lehmann" => parsed.hodges_lehmann = true,
+            "mcnemar" => parsed.mcnemar = true,
+            "permute" => {
+                let permutations: usize = value.parse()?;
+                if permutations < 100 {
+                    return Err("`--permute` needs at least 100 permutations".into());
+                }
+                parsed.permute = Some(permutations);
+            },
+            "equivalence" => parsed.equivalence = Some(parse_margin(&value)?),
+            "rosenbaum" => {
+                let max_gamma: f64 = value.parse()?;
+                if !(max_gamma > 1.0 && max_gamma.is_finite()) {
+                    return Err(format!("`--rosenbaum` needs a largest Gamma above 1 but was {}", max_gamma).into());
+                }
+                parsed.rosenbaum = Some(max_gamma);
+            },
+            "placebo-outcomes" => parsed.placebo_outcomes = value.split(',').map(|c| c.trim().to_string()).collect(),
+            "influence" => parsed.influence = Some(value),
+            "adjust" => parsed.adjustment = value.parse()?,
+            "joint-bootstrap" => {
+                let replicates: usize = value.parse()?;
+                if replicates < 100 {
+                    return Err("`--joint-bootstrap` needs at least 100 bootstrap replicates".into());
+                }
+                parsed.joint_bootstrap = Some(replicates);
+            },
+            "outcome-type" => parsed.outcome_type = value.parse()?,
+            "ratio-ci" => parsed.ratio_ci = value.parse()?,
+            "time" => {
+                parsed.outcome = value;
+                time_given = true;
+            },
+            "event" => parsed.event = Some(value),
+            "pre" => parsed.pre = Some(value),
+            "horizon" => {
+                let horizon: f64 = value.parse()?;
+                if !(horizon >= 0.0 && horizon.is_finite()) {
+                    return Err(format!("`--horizon` must be a non-negative time but was {}", value).into());
+                }
+                parsed.horizon = Some(horizon);
+            },
+            "subclassify" => parsed.subclassify = true,
+            "n-strata" => {
+                let n_strata: usize = value.parse()?;
+                if n_strata < 2 {
+                    return Err("`--n-strata` needs at least two strata".into());
+                }
+                parsed.n_strata = Some(n_strata);
+            },
+            "plot-overlap" => parsed.plots.overlap = Some(value),
+            "plot-balance" => parsed.plots.balance = Some(value),
+            "plot-eqq" => parsed.plots.eqq = Some(value),
+            "plot-forest" => parsed.plots.forest = Some(value),
+            "plot-density" => parsed.plots.density = Some(value),
+            "plot-covariates" => {
+                parsed.plots.covariates = value.split(',').map(|c| c.trim().to_string()).collect();
+            },
+            "ps-percentiles" => parsed.ps_percentiles = true,
+            "ps-diagnostics" => parsed.ps_diagnostics = true,
+            "subclass-balance" => {
+                let n_strata: usize = value.parse()?;
+                if n_strata < 1 {
+                    return Err("`--subclass-balance` needs at least one subclass".into());
+                }
+                parsed.subclass_balance = Some(n_strata);
+            },
+            "balance-ci" => {
+                let replicates: usize = value.parse()?;
+                if replicates < 50 {
+                    return Err("`--balance-ci` needs at least 50 bootstrap replicates".into());
+                }
+                parsed.balance_ci = Some(replicates);
+            },
+            "balance" => parsed.balance = true,
+            "balance-threshold" => {
+                let threshold: f64 = value.parse()?;
+                if threshold <= 0.0 {
+                    return Err("`--balance-threshold` must be positive".into());
+                }
+                parsed.balance_threshold = Some(threshold);
+            },
+            "caliper-curve" => parsed.caliper_curve = true,
+            "caliper-csv" => {
+                parsed.caliper_curve = true;
+                parsed.caliper_csv = Some(value);
+            },
+            "plot-caliper" => {
+                parsed.caliper_curve = true;
+                parsed.plots.caliper = Some(value);
+            },
+            "match-quality" => parsed.match_quality = true,
+            "extreme-ps" | "trim" => {
+                let bounds: Vec<f64> = value
+                    .split(',')
+                    .map(|b| b.trim().parse::<f64>())
+                    .collect::<Result<_, _>>()?;
+                match bounds[..] {
+                    [lower, upper] if 0.0 <= lower && lower < upper && upper <= 1.0 => {
+                        if parsed.extreme_ps.is_some_and(|b| b != (lower, upper)) {
+                            return Err("`--extreme-ps` and `--trim` set different thresholds".into());
+                        }
+                        parsed.extreme_ps = Some((lower, upper));
+                    },
+                    _ => return Err(format!("`--{}` expects `lower,upper` in [0, 1] but was {}", name, value).into())
+                }
+                if name == "trim" {
+                    trim = true;
+                }
+            },
+            "exclude-extreme" => trim = true,
+            "trim-method" => trim_method = Some(value.parse()?),
+            "common-support" => parsed.common_support = true,
+            "discard" => parsed.discard = Some(value.parse()?),
+            "positivity" => {
+                let n_bins: usize = value.parse()?;
+                if n_bins < 1 {
+                    return Err("`--positivity` needs at least one propensity score bin".into());
+                }
+                parsed.positivity = Some(n_bins);
+            },
+            "covariate-gaps" => parsed.covariate_gaps = true,
+            "fail-on-warning" => parsed.fail_on_warning = Some(value.parse()?),
+            "deterministic" => parsed.deterministic = true,
+            "digits" => parsed.format.digits = value.parse()?,
+            "scientific" => parsed.format.scientific = true,
+            "thousands" => parsed.format.thousands = true,
+            "format" => parsed.output = value.parse()?,
+            "threads" => parsed.threads = Some(value.parse()?),
+            "audit-log" => parsed.audit_log = Some(value),
+            "log-level" => parsed.log_level = value.parse()?,
+            "config" => parsed.config = Some(value),
+            "spec" => parsed.specs.push(value),
+            "jobs" => {
+                let jobs: usize = value.parse()?;
+                if jobs < 1 {
+                    return Err("`--jobs` needs at least one job".into());
+                }
+                parsed.jobs = Some(jobs);
+            },
+            "memory" => parsed.memory = true,
+            "covariates" => parsed.covariates = value.split(',').map(|c| c.trim().to_string()).collect(),
+            "exclude" => parsed.exclude = value.split(',').map(|c| c.trim().to_string()).collect(),
+            "formula" => parsed.logit.formula = Some(value.parse()?),
+            "drop-separating" => parsed.drop_separating = true,
+            "select" => parsed.select = Some(value.parse()?),
+            "pscore-col" => parsed.pscore_col = Some(value),
+            "ps-model" => parsed.ps_model = value.parse()?,
+            "calibrate" => parsed.logit.calibrate = Some(value.parse()?),
+            "ps-learners" => {
+                parsed.logit.learners = value.split(',').map(|l| l.trim().parse()).collect::<Result<_, _>>()?
+            },
+            "ps-max-iter" => parsed.logit.max_iterations = value.parse()?,
+            "ps-folds" => {
+                let folds: usize = value.parse()?;
+                if folds < 2 {
+                    return Err(format!("`--ps-folds` needs at least 2 folds but was {}", folds).into());
+                }
+                parsed.ps_folds = Some(folds);
+            },
+            "ps-tolerance" => {
+                let tolerance: f64 = value.parse()?;
+                if tolerance <= 0.0 {
+                    return Err(format!("`--ps-tolerance` must be positive but was {}", tolerance).into());
+                }
+                parsed.logit.tolerance = tolerance;
+            },
+            "ps-init" => parsed.logit.init = parse_init(&value)?,
+            "standardize" => parsed.logit.standardize = true,
+            "no-intercept" => parsed.logit.intercept = false,
+            "ps-penalty" => parsed.logit.penalty = Some(value.parse()?),
+            "ps-trees" | "ps-depth" => {
+                let count: usize = value.parse()?;
+                if count < 1 {
+                    return Err(format!("`--{}` must be at least 1 but was {}", name, count).into());
+                }
+                match name {
+                    "ps-trees" => parsed.logit.trees = Some(count),
+                    _ => parsed.logit.depth = Some(count)
+                }
+            },
+            "design-matrix" => parsed.design_matrix = Some(value),
+            "save-session" => parsed.save_session = Some(value),
+            "update-session" => parsed.update_session = Some(value),
+            "cache" => parsed.cache = Some(value),
+            "matcher" => {
+                if registry::matcher(&value).is_none() {
+                    return Err(format!(
+                        "Unknown matcher `{}`; available: {}",
+                        value,
+                        registry::matcher_names().join(", ")
+                    ).into());
+                }
+                parsed.matcher = Some(value);
+            },
+            "genetic-population" => {
+                let population: usize = value.parse()?;
+                if population < 2 {
+                    return Err("`--genetic-population` must be at least 2".into());
+                }
+                parsed.genetic.population = population;
+            },
+            "genetic-generations" => parsed.genetic.generations = value.parse()?,
+            "distance" => parsed.distance = value.parse()?,
+            "prognostic" => parsed.prognostic = true,
+            "covariate-penalty" => {
+                let penalty: f64 = value.parse()?;
+                if !(penalty >= 0.0 && penalty.is_finite()) {
+                    return Err(format!("`--covariate-penalty` must be a non-negative number but was {}", value).into());
+                }
+                parsed.covariate_penalty = Some(penalty);
+            },
+            "ratio" => {
+                let ratio: usize = value.parse()?;
+                if ratio == 0 {
+                    return Err("`--ratio` must be at least 1".into());
+                }
+                parsed.ratio = Some(ratio);
+            },
+            "replace" => parsed.no_replace = false,
+            "no-replace" => parsed.no_replace = true,
+            "match-order" => parsed.match_order = value.parse()?,
+            "max-reuse" => {
+                let max_reuse: usize = value.parse()?;
+                if max_reuse == 0 {
+                    return Err("`--max-reuse` must be at least 1".into());
+                }
+                parsed.max_reuse = Some(max_reuse);
+            },
+            "ties" => parsed.ties = value.parse()?,
+            "caliper" => parsed.caliper = Some(value.parse()?),
+            "estimator" => {
+                if registry::estimator(&value).is_none() {
+                    return Err(format!(
+                        "Unknown estimator `{}`; available: {}",
+                        value,
+                        registry::estimator_names().join(", ")
+                    ).into());
+                }
+                parsed.estimator = Some(value);
+            },
+            "estimand" => parsed.estimand = value.parse()?,
+            "bias-correct" => parsed.bias_correct = true,
+            "location" => parsed.location = value.parse()?,
+            "variance" => parsed.variance = value.parse()?,
+            "variance-neighbors" => {
+                let neighbors: usize = value.parse()?;
+                if neighbors == 0 {
+                    return Err("`--variance-neighbors` must be at least 1".into());
+                }
+                parsed.variance_neighbors = Some(neighbors);
+            },
+            "weighting" => parsed.weighting = Some(value.parse()?),
+            "partitions" => parsed.partitions = true,
+            "fit-fraction" => {
+                let fraction: f64 = value.parse()?;
+                if !(fraction > 0.0 && fraction <= 1.0) {
+                    return Err(format!("`--fit-fraction` must be in (0, 1] but was {}", fraction).into());
+                }
+                parsed.fit_fraction = Some(fraction);
+            },
+            "time-budget" => parsed.budget = Some(Budget::new(parse_duration(&value)?)),
+            "where" => parsed.filter = Some(value),
+            "missing-outcome" => {
+                parsed.missing_outcome = match value.as_str() {
+                    "ipw" => Some(MissingOutcome::Ipw),
+                    _ => return Err(format!("Unknown `--missing-outcome` method `{}`; use ipw", value).into())
+                };
+            },
+            "by" => parsed.by = Some(value),
+            "by-matching" => parsed.by_matching = value.parse()?,
+            "gps" => {
+                parsed.gps = value
+                    .split(',')
+                    .map(|d| d.trim().parse::<f64>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("`--gps` expects comma-separated doses but was {}", value))?;
+            },
+            "dml" => parsed.dml = true,
+            _ => unreachable!("parse_args: option `--{}` is not handled", name)
+        }
+    }
+    match (trim, trim_method) {
+        (true, method) => parsed.trim = Some(method.unwrap_or_default()),
+        (false, Some(_)) => return Err("`--trim-method` needs `--trim` or `--exclude-extreme`".into()),
+        (false, None) => ()
+    }
+    if parsed.trim.is_some() && parsed.extreme_ps.is_none() {
+        parsed.extreme_ps = Some(DEFAULT_EXTREME_PS);
+    }
+    if parsed.deterministic && parsed.seed.is_none() {
+        parsed.seed = Some(0);
+    }
+    // `--n-strata` asks for the subclassification report unless it is the estimator
+    let subclass_estimator = parsed.estimator.as_deref() == Some(SUBCLASS_ESTIMATOR);
+    parsed.subclassify |= parsed.n_strata.is_some() && !subclass_estimator;
+    if parsed.forest.is_some() || parsed.plots.forest.is_some() {
+        let batch_mode = !parsed.partitions && data_paths(&parsed.path, &["csv"])?.len() > 1;
+        if !batch_mode {
+            return Err("`--forest` and `--plot-forest` export the pooled estimates of several data files".into());
+        }
+    }
+    let survival = parsed.survival();
+    if survival != parsed.event.is_some() || ((parsed.horizon.is_some() || time_given) && !survival) {
+        return Err("`--outcome-type survival` needs `--event`, and `--time`, `--event` and `--horizon` need it".into());
+    }
+    let outcomes: Vec<&String> = std::iter::once(&parsed.outcome).chain(&parsed.extra_outcomes).collect();
+    if outcomes.iter().any(|o| o.is_empty()) {
+        return Err(format!("Empty outcome column name in `{}`", positional[2]).into());
+    }
+    if outcomes.iter().enumerate().any(|(i, o)| outcomes[..i].contains(o)) {
+        return Err(format!("Outcome columns are listed more than once in `{}`", positional[2]).into());
+    }
+    let placebos = &parsed.placebo_outcomes;
+    if placebos.iter().any(|c| c.is_empty() || *c == parsed.treatment || outcomes.contains(&c)) {
+        return Err("`--placebo-outcomes` needs column names other than the treatment and the outcomes".into());
+    }
+    if placebos.iter().enumerate().any(|(i, c)| placebos[..i].contains(c)) {
+        return Err("Placebo outcome columns are listed more than once in `--placebo-outcomes`".into());
+    }
+    let by = parsed.by.as_deref();
+    if by.is_some_and(|by| by == parsed.treatment || by == parsed.outcome) {
+        return Err("`--by` needs a column other than the treatment and the outcome".into());
+    }
+    if let Some(cluster) = &parsed.cluster {
+        if *cluster == parsed.treatment || *cluster == parsed.outcome || parsed.extra_outcomes.contains(cluster) {
+            return Err("`--cluster` needs a column other than the treatment and the outcomes".into());
+        }
+    }
+    if let Some(pre) = &parsed.pre {
+        if *pre == parsed.treatment || *pre == parsed.outcome {
+            return Err("`--pre` needs a column other than the treatment and the outcome".into());
+        }
+    }
+    if let Some(column) = &parsed.pscore_col {
+        if *column == parsed.treatment || *column == parsed.outcome {
+            return Err("`--pscore-col` needs a column other than the treatment and the outcome".into());
+        }
+    }
+    let outcomes: Vec<&String> =
+        [&parsed.outcome].into_iter().chain(&parsed.extra_outcomes).chain(&parsed.placebo_outcomes).collect();
+    let (weights, cluster) = (parsed.survey.weights.as_ref(), parsed.cluster.as_ref());
+    let never_covariate =
+        |c: &String| *c == parsed.treatment || outcomes.contains(&c) || weights == Some(c) || cluster == Some(c);
+    if parsed.covariates.iter().any(never_covariate) {
+        return Err(
+            "`--covariates` can't include the treatment, an outcome, a placebo outcome, the survey weights or the \
+             clusters, which are never covariates"
+                .into()
+        );
+    }
+    if let Some(formula) = &parsed.logit.formula {
+        if formula.response != parsed.treatment {
+            return Err(format!(
+                "The formula models `{}` but the treatment is `{}`",
+                formula.response,
+                parsed.treatment
+            ).into());
+        }
+        if formula.columns().iter().any(never_covariate) {
+            return Err(
+                "`--formula` terms can't use the treatment, an outcome, the survey weights or the clusters".into()
+            );
+        }
+    }
+    if let Some((_, conflict)) = CONFLICTS.iter().find(|(conflicts, _)| conflicts(&parsed)) {
+        return Err((*conflict).into());
+    }
+    parsed.logit.seed = parsed.seed;
+    let unweighted = |model, penalty: Option<Penalty>| model == PropensityModel::Firth || penalty.is_some();
+    let unweighted_fit = match parsed.ps_model == PropensityModel::Ensemble {
+        true => match parsed.logit.learners.is_empty() {
+            true => DEFAULT_LEARNERS.iter().map(|name| name.parse()).collect::<Result<Vec<Learner>, _>>()?,
+            false => parsed.logit.learners.clone()
+        }
+        .iter()
+        .any(|learner| unweighted(learner.model, learner.penalty)),
+        false => unweighted(parsed.ps_model, parsed.logit.penalty)
+    };
+    if parsed.survey.weights.is_some() && unweighted_fit {
+        return Err(
+            "`--weights` can't weight a Firth or penalized propensity model or learner; use `--ps-model logit` or \
+             `--ps-learners` without firth, l1 and l2"
+                .into()
+        );
+    }
+    parsed.logit.weights = parsed.survey.weights.clone();
+    Ok(parsed)
+}
+
+// Arguments of one specification in a comparison
+//
+// The command line `args` with its `--spec` and `--jobs` options removed
+// and the options of `spec` (whitespace-separated) appended, so that a
+// specification adds to, and overrides, the options shared by all of them.
+pub fn spec_args(args: &[String], spec: &str) -> Result<Args, Box<dyn Error>> {
+    let mut shared = Vec::with_capacity(args.len());
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--spec" | "--jobs" => {
+                iter.next();
+            },
+            _ => shared.push(arg.clone())
+        }
+    }
+    shared.extend(spec.split_whitespace().map(|s| s.to_string()));
+    parse_args(&shared)
+}
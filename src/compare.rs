@@ -0,0 +1,90 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::format::num;
+
+// Run `n` independent tasks on at most `jobs` threads
+//
+// Threads pull the next task index from a shared counter until all tasks
+// are done. Results are returned in task order, whatever order the tasks
+// finish in.
+pub fn run_parallel<T, F>(n: usize, jobs: usize, task: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<T>>> = Mutex::new((0..n).map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, n.max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= n {
+                    break;
+                }
+                let result = task(i);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("run_parallel: every task produces a result"))
+        .collect()
+}
+
+// The estimate of a single specification, or why it failed.
+#[derive(Debug)]
+pub struct SpecResult {
+    pub spec: String,
+    pub estimate: Result<(f64, f64, usize), String>
+}
+
+// Estimates of several specifications on the same data
+//
+// Each row is one specification (the options it adds to the shared ones)
+// with its ATT, variance, 95% CI and number of treated units, in the order
+// the specifications were given.
+#[derive(Debug)]
+pub struct Comparison {
+    pub results: Vec<SpecResult>
+}
+
+// Implement `Display` for `Comparison`.
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "STRIKE COMPARISON ============================\n")?;
+        writeln!(
+            f,
+            "{:<32}  {:>8}  {:>8}  {:>20}  {:>7}",
+            "Specification", "ATT", "Variance", "95% CI", "# Treat"
+        )?;
+        for result in &self.results {
+            let spec = match result.spec.is_empty() {
+                true => "(baseline)",
+                false => result.spec.as_str()
+            };
+            match &result.estimate {
+                Ok((att, variance, n_treat)) => {
+                    let se = variance.sqrt();
+                    let ci = format!("({}, {})", num(att - 1.96 * se), num(att + 1.96 * se));
+                    writeln!(
+                        f,
+                        "{:<32}  {:>8}  {:>8}  {:>20}  {:>7}",
+                        spec,
+                        num(*att),
+                        num(*variance),
+                        ci,
+                        n_treat
+                    )?;
+                },
+                Err(e) => writeln!(f, "{:<32}  failed: {}", spec, e)?
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::error::Error;
+
+use crate::cli::{OPTIONS, OPTION_VALUES};
+
+// Shells completion scripts can be generated for.
+const SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+// The fixed values of an option, if it has any.
+fn values(option: &str) -> Option<&'static [&'static str]> {
+    OPTION_VALUES.iter().find(|(name, _)| *name == option).map(|(_, values)| *values)
+}
+
+fn bash() -> String {
+    let mut cases = String::new();
+    for (name, _) in OPTIONS.iter().filter(|(_, takes_value)| *takes_value) {
+        let reply = match values(name) {
+            Some(values) => format!("compgen -W \"{}\" -- \"$cur\"", values.join(" ")),
+            None => "compgen -f -- \"$cur\"".to_string()
+        };
+        cases.push_str(&format!("        --{}) COMPREPLY=($({})); return ;;\n", name, reply));
+    }
+    let options: Vec<String> = OPTIONS.iter().map(|(name, _)| format!("--{}", name)).collect();
+    format!(
+        "_strike() {{\n\
+        \x20   local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n\
+        \x20   if [[ $COMP_CWORD -eq 2 && $prev == completions ]]; then\n\
+        \x20       COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return\n\
+        \x20   fi\n\
+        \x20   case \"$prev\" in\n\
+        {}\
+        \x20   esac\n\
+        \x20   if [[ $cur == --* ]]; then\n\
+        \x20       COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n\
+        \x20   elif [[ $COMP_CWORD -eq 1 ]]; then\n\
+        \x20       COMPREPLY=($(compgen -W \"completions balance\" -- \"$cur\") $(compgen -f -- \"$cur\"))\n\
+        \x20   else\n\
+        \x20       COMPREPLY=($(compgen -f -- \"$cur\"))\n\
+        \x20   fi\n\
+        }}\n\
+        complete -F _strike strike\n",
+        SHELLS.join(" "),
+        cases,
+        options.join(" ")
+    )
+}
+
+fn zsh() -> String {
+    let mut specs: Vec<String> = OPTIONS
+        .iter()
+        .map(|(name, takes_value)| match (takes_value, values(name)) {
+            (true, Some(values)) => format!("'--{}:value:({})'", name, values.join(" ")),
+            (true, None) => format!("'--{}:value:_files'", name),
+            (false, _) => format!("'--{}'", name)
+        })
+        .collect();
+    specs.push("'*:file:_files'".to_string());
+    format!(
+        "#compdef strike\n\n\
+        if [[ $CURRENT -eq 3 && $words[2] == completions ]]; then\n\
+        \x20   _values 'shell' {}\n\
+        \x20   return\n\
+        fi\n\
+        _arguments \\\n    {}\n",
+        SHELLS.join(" "),
+        specs.join(" \\\n    ")
+    )
+}
+
+fn fish() -> String {
+    let mut lines = vec![
+        "complete -c strike -n __fish_use_subcommand -a completions -d 'Generate shell completions'".to_string(),
+        "complete -c strike -n __fish_use_subcommand -a balance -d 'Report covariate balance only'".to_string(),
+        format!("complete -c strike -n '__fish_seen_subcommand_from completions' -f -a '{}'", SHELLS.join(" "))
+    ];
+    for (name, takes_value) in OPTIONS {
+        lines.push(match (takes_value, values(name)) {
+            (true, Some(values)) => format!("complete -c strike -l {} -r -f -a '{}'", name, values.join(" ")),
+            (true, None) => format!("complete -c strike -l {} -r", name),
+            (false, _) => format!("complete -c strike -l {}", name)
+        });
+    }
+    lines.join("\n") + "\n"
+}
+
+// Generate a shell completion script
+//
+// Built from the option table in `cli`, so new options are completed
+// without touching this module. Options with a fixed set of values complete
+// those values; other options that take a value complete file names.
+pub fn completions(shell: &str) -> Result<String, Box<dyn Error>> {
+    match shell {
+        "bash" => Ok(bash()),
+        "zsh" => Ok(zsh()),
+        "fish" => Ok(fish()),
+        _ => Err(format!("Unknown shell `{}`; expected one of {}", shell, SHELLS.join(", ")).into())
+    }
+}
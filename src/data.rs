@@ -0,0 +1,103 @@
+use polars::datatypes::DataType::Float64;
+use polars::prelude::{DataFrame, IdxCa, IdxSize, PolarsResult, SortOptions};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Extract a numeric column as a Vec of f64
+//
+// The column is cast to Float64 first so that integer columns work as well.
+// Missing values become NaN.
+pub fn column_values(data: &DataFrame, column: &str) -> PolarsResult<Vec<f64>> {
+    let values = data.column(column)?.cast(&Float64)?;
+    let values = values
+        .f64()?
+        .into_iter()
+        .map(|v| v.unwrap_or(f64::NAN))
+        .collect();
+    Ok(values)
+}
+
+// Order matched pairs by `strike_id`
+//
+// Matched samples are kept in this order so that output never depends on
+// how rows were produced (sample halves, thread scheduling, polars
+// internals). `paired` is reordered along with `data`, so row i of both
+// frames still forms a pair. The sort is stable, so the k matches of a
+// treated unit under k:1 matching keep their nearest-first order.
+pub fn order_by_id(data: &DataFrame, paired: &DataFrame) -> PolarsResult<(DataFrame, DataFrame)> {
+    let ids = column_values(data, "strike_id")?;
+    let mut order: Vec<IdxSize> = (0..ids.len() as IdxSize).collect();
+    order.sort_by(|a, b| ids[*a as usize].total_cmp(&ids[*b as usize]));
+    let order = IdxCa::from_vec("order", order);
+    Ok((data.take(&order)?, paired.take(&order)?))
+}
+
+// Repeat every row as often as `counts` says, in place, e.g. a treated
+// unit once per match.
+pub fn repeat_rows(data: &DataFrame, counts: &[usize]) -> PolarsResult<DataFrame> {
+    let rows: Vec<IdxSize> = counts
+        .iter()
+        .enumerate()
+        .flat_map(|(row, k)| std::iter::repeat_n(row as IdxSize, *k))
+        .collect();
+    data.take(&IdxCa::from_vec("rows", rows))
+}
+
+// Order rows by `strike_id`.
+pub fn sort_by_id(data: &DataFrame) -> PolarsResult<DataFrame> {
+    let order = data.column("strike_id")?.arg_sort(SortOptions::default());
+    data.take(&order)
+}
+
+// Sort a slice of f64 in ascending order into a new Vec. NaNs are placed
+// by `total_cmp` rather than panicking.
+pub fn sorted(x: &[f64]) -> Vec<f64> {
+    let mut x = x.to_vec();
+    x.sort_by(|a, b| a.total_cmp(b));
+    x
+}
+
+// Empirical quantile of sorted data at probability `p`, interpolating
+// linearly between order statistics. NaN for empty data.
+pub fn quantile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let pos = p * (sorted.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (pos - lo as f64)
+}
+
+// Gaussian kernel density estimate of `x` evaluated at `grid`, using
+// Silverman's rule of thumb for the bandwidth.
+pub fn density(x: &[f64], grid: &[f64]) -> Vec<f64> {
+    let n = x.len() as f64;
+    let mean = x.iter().sum::<f64>() / n;
+    let sd = (x.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+    let bandwidth = (1.06 * sd * n.powf(-0.2)).max(1e-3);
+    let norm = 1.0 / (n * bandwidth * (2.0 * std::f64::consts::PI).sqrt());
+    grid.iter()
+        .map(|g| x.iter().map(|v| (-0.5 * ((g - v) / bandwidth).powi(2)).exp()).sum::<f64>() * norm)
+        .collect()
+}
+
+// Resolve the data path argument into one or more data files
+//
+// The path may be a single file, a comma-separated list of files, or a
+// directory, in which case every file in it with one of the `extensions` is
+// used (sorted by name). More than one file switches Strike into batch mode,
+// unless they are `--partitions` of one dataset.
+pub fn data_paths(path: &str, extensions: &[&str]) -> io::Result<Vec<String>> {
+    if !Path::new(path).is_dir() {
+        return Ok(path.split(',').map(|p| p.to_string()).collect());
+    }
+    let mut paths: Vec<String> = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|p| p.extension().is_some_and(|ext| extensions.iter().any(|e| ext == *e)))
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
@@ -0,0 +1,47 @@
+use polars::prelude::{CsvWriter, DataFrame, SerWriter};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::audit::record;
+use crate::formula::Formula;
+use crate::propensity::{design_matrix, Coefficients};
+
+// Export the propensity model for review
+//
+// Writes two files to `dir`: `design.csv`, the design matrix the model was
+// fit on (one row per unit with its `strike_id`, its treatment and every
+// predictor as the optimizer saw it), and `coefficients.csv`, one `term,
+// estimate` row for the intercept and for each predictor in the same
+// order. Scoring a row of `design.csv` with `coefficients.csv` reproduces
+// its propensity score.
+pub fn write_design(
+    dir: &str,
+    train: &DataFrame,
+    treatment: &str,
+    exclude: &[String],
+    formula: Option<&Formula>,
+    coefficients: &Coefficients
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    fs::create_dir_all(dir)?;
+    let mut design = design_matrix(train, treatment, exclude, formula)?;
+    let mut file = File::create(Path::new(dir).join("design.csv"))?;
+    CsvWriter::new(&mut file).finish(&mut design)?;
+    let mut file = File::create(Path::new(dir).join("coefficients.csv"))?;
+    writeln!(file, "term,estimate")?;
+    writeln!(file, "intercept,{}", coefficients.intercept)?;
+    for (name, slope) in &coefficients.slopes {
+        writeln!(file, "{},{}", name, slope)?;
+    }
+    record(
+        "design",
+        Some(train.height()),
+        Some(design.height()),
+        start.elapsed(),
+        &format!("dir={} predictors={}", dir, coefficients.slopes.len())
+    );
+    Ok(())
+}
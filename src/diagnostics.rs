@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use crate::balance::{CovariateBalance, SMD_THRESHOLD};
+use crate::warnings::{Severity, Warning};
+
+// How often matched controls are reused
+//
+// Matching is with replacement, so one control can be the match of several
+// treated units. `uses` holds every matched control's `strike_id` and the
+// number of treated units matched to it, ordered by id.
+#[derive(Clone, Debug)]
+pub struct ControlReuse {
+    pub n_treat: usize,
+    pub n_controls: usize,
+    pub max_uses: usize,
+    pub max_share: f64,
+    pub uses: Vec<(i64, usize)>
+}
+
+// Count the uses of the matched controls, given their ids (one per treated
+// unit).
+pub fn control_reuse(control_ids: &[f64]) -> ControlReuse {
+    let mut counts: HashMap<i64, usize> = HashMap::new();
+    for id in control_ids {
+        *counts.entry(*id as i64).or_insert(0) += 1;
+    }
+    let mut uses: Vec<(i64, usize)> = counts.into_iter().collect();
+    uses.sort();
+    let max_uses = uses.iter().map(|(_, n)| *n).max().unwrap_or(0);
+    ControlReuse {
+        n_treat: control_ids.len(),
+        n_controls: uses.len(),
+        max_uses,
+        max_share: max_uses as f64 / control_ids.len() as f64,
+        uses
+    }
+}
+
+// Overlap of the treated and control propensity distributions.
+#[derive(Clone, Copy, Debug)]
+pub struct OverlapMetrics {
+    pub overlap_coefficient: f64,
+    pub c_statistic: f64
+}
+
+// The diagnostics of a matched analysis
+//
+// Everything the summary reports about match quality, as values rather than
+// tables, so pipelines can check an analysis programmatically (see
+// `Strike::diagnostics`).
+#[derive(Clone, Debug)]
+pub struct Diagnostics {
+    pub balance: Vec<CovariateBalance>,
+    pub overlap: OverlapMetrics,
+    pub reuse: ControlReuse,
+    pub warnings: Vec<Warning>
+}
+
+impl Diagnostics {
+    // Covariates whose absolute SMD after matching exceeds `SMD_THRESHOLD`.
+    pub fn imbalanced(&self) -> Vec<&CovariateBalance> {
+        self.balance.iter().filter(|b| b.smd_after.abs() > SMD_THRESHOLD).collect()
+    }
+
+    // Whether any warning is serious.
+    pub fn has_serious_warnings(&self) -> bool {
+        self.warnings.iter().any(|w| w.severity == Severity::Serious)
+    }
+}
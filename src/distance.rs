@@ -1,5 +1,19 @@
-use polars::prelude::{ChunkCompare, DataFrame, PolarsResult};
+use ndarray::{concatenate, Array1, Array2, Axis};
+use polars::prelude::{ChunkCompare, DataFrame, IdxCa, IdxSize, NamedFrom, PolarsError, PolarsResult, Series};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use std::collections::{BinaryHeap, HashMap};
+use std::cmp::{Ordering, Reverse};
+use std::fmt;
 use std::iter::zip;
+use std::str::FromStr;
+
+use crate::balance::pooled_sd;
+use crate::cancel::CancelToken;
+use crate::data::column_values;
+use crate::format::num;
+use crate::formula::predictor_values;
+use crate::regression::generalized_inverse;
 
 // Nearest Neighbor match
 //
@@ -41,8 +55,8 @@ fn find_nn(data: &DataFrame, pscore: f64, strike_id: i64) -> PolarsResult<DataFr
 // `main`. The return value is a matched DataFrame where every row is the
 // match for the corresponding row in `main`. E.g. the return DataFrame has
 // the same # of rows as `main` and the first row is the matched observation
-// for the first row in `main`.
-pub fn nn_match(main: &DataFrame, target: &mut DataFrame) -> PolarsResult<DataFrame> {
+// for the first row in `main`. Stops with an error if `cancel` is set.
+pub fn nn_match(main: &DataFrame, target: &mut DataFrame, cancel: &CancelToken) -> PolarsResult<DataFrame> {
     let propensities = main
         .column(&"propensities")
         .expect("This column should never be missing!")
@@ -54,11 +68,832 @@ pub fn nn_match(main: &DataFrame, target: &mut DataFrame) -> PolarsResult<DataFr
         .i64()?
         .to_vec();
     let mut targets = DataFrame::empty();
-    for (pscore, strike_id) in zip(propensities.iter(), ids.iter()) {
+    for (i, (pscore, strike_id)) in zip(propensities.iter(), ids.iter()).enumerate() {
+        cancel.check(|| format!("while matching after {} of {} units", i, propensities.len()))?;
         let pscore = pscore.unwrap();
         let strike_id = strike_id.unwrap();
         let nearest_neighbor = find_nn(target, pscore, strike_id)?;
         targets = targets.vstack(&nearest_neighbor)?;
     }
     Ok(targets)
-}
\ No newline at end of file
+}
+
+// Scale on which matching measures propensity score distances
+//
+// `Propensity` compares the probabilities themselves, which compresses
+// distances near 0 and 1; `Logit` compares log(p / (1 - p)), the usual
+// recommendation. Either way the output reports the probabilities.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Distance {
+    #[default]
+    Propensity,
+    Logit
+}
+
+// Implement `FromStr` for `Distance`.
+impl FromStr for Distance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "propensity" => Ok(Distance::Propensity),
+            "logit" => Ok(Distance::Logit),
+            _ => Err(format!("Unknown distance `{}`; use propensity or logit", s))
+        }
+    }
+}
+
+// Implement `Display` for `Distance`.
+impl fmt::Display for Distance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Distance::Propensity => write!(f, "propensity"),
+            Distance::Logit => write!(f, "logit")
+        }
+    }
+}
+
+// Propensity scores on the scale of `distance`
+//
+// A copy of `data` whose `propensities` column holds the scores matching
+// should compare, so that every matcher measures distances on that scale.
+// Logits are taken of scores kept `f64::EPSILON` away from 0 and 1, where
+// they would be infinite.
+pub fn on_distance_scale(data: &DataFrame, distance: Distance) -> PolarsResult<DataFrame> {
+    let mut data = data.clone();
+    if distance == Distance::Logit {
+        let logits: Vec<f64> = column_values(&data, "propensities")?
+            .iter()
+            .map(|p| p.clamp(f64::EPSILON, 1.0 - f64::EPSILON))
+            .map(|p| (p / (1.0 - p)).ln())
+            .collect();
+        data.with_column(Series::new("propensities", logits))?;
+    }
+    Ok(data)
+}
+
+// What nearest neighbor matching does when several controls are equally
+// close to a treated unit
+//
+// `First` takes the first of them in pool order, `Random` one of them at
+// random, and `AllAverage` all of them, so that the treated unit is
+// compared with their average outcome.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Ties {
+    #[default]
+    First,
+    Random,
+    AllAverage
+}
+
+// Implement `FromStr` for `Ties`.
+impl FromStr for Ties {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Ties::First),
+            "random" => Ok(Ties::Random),
+            "all-average" => Ok(Ties::AllAverage),
+            _ => Err(format!("Unknown tie-breaking policy `{}`; use first, random or all-average", s))
+        }
+    }
+}
+
+// Implement `Display` for `Ties`.
+impl fmt::Display for Ties {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ties::First => write!(f, "first"),
+            Ties::Random => write!(f, "random"),
+            Ties::AllAverage => write!(f, "all-average")
+        }
+    }
+}
+
+// Nearest neighbor matching with a tie-breaking policy
+//
+// Finds the nearest controls of every row of `main` in `target` like
+// `nn_match` and resolves ties by `ties`, drawing from `rng` for
+// `Ties::Random`. Returns the matched controls, the matches of each row of
+// `main` in consecutive rows, and how many matches every row of `main` got,
+// which is more than one only for `Ties::AllAverage`. Stops with an error
+// if `cancel` is set.
+pub fn tied_match(
+    main: &DataFrame,
+    target: &DataFrame,
+    ties: Ties,
+    rng: &mut StdRng,
+    cancel: &CancelToken
+) -> PolarsResult<(DataFrame, Vec<usize>)> {
+    let pool = column_values(target, "propensities")?;
+    let scores = column_values(main, "propensities")?;
+    let mut rows = Vec::with_capacity(scores.len());
+    let mut counts = Vec::with_capacity(scores.len());
+    for (i, pscore) in scores.iter().enumerate() {
+        cancel.check(|| format!("while matching after {} of {} units", i, scores.len()))?;
+        let nearest = pool.iter().map(|c| (c - pscore).abs()).fold(f64::NAN, f64::min);
+        let tied: Vec<IdxSize> = (0..pool.len())
+            .filter(|row| (pool[*row] - pscore).abs() == nearest)
+            .map(|row| row as IdxSize)
+            .collect();
+        let chosen = match ties {
+            Ties::First => &tied[..1.min(tied.len())],
+            Ties::Random => match tied.choose(rng) {
+                Some(row) => std::slice::from_ref(row),
+                None => &[]
+            },
+            Ties::AllAverage => &tied[..]
+        };
+        if chosen.is_empty() {
+            panic!("tied_match: No nearest neighbor control observation was found");
+        }
+        rows.extend_from_slice(chosen);
+        counts.push(chosen.len());
+    }
+    Ok((target.take(&IdxCa::from_vec("matches", rows))?, counts))
+}
+
+// k nearest neighbor propensity score matching
+//
+// Like `nn_match`, but every row of `main` is matched to its `k` closest
+// rows of `target`. The result has `k` rows per row of `main`, in the same
+// order and nearest first: rows i * k to i * k + k - 1 are the matches of
+// row i. Ties go to the earlier row of `target`, so `k = 1` finds the same
+// matches as `nn_match`. Stops with an error if `cancel` is set.
+pub fn knn_match(main: &DataFrame, target: &DataFrame, k: usize, cancel: &CancelToken) -> PolarsResult<DataFrame> {
+    let pool = column_values(target, "propensities")?;
+    let scores = column_values(main, "propensities")?;
+    let rows = nearest_rows(scores.len(), pool.len(), k, |i, j| (pool[j] - scores[i]).abs(), cancel)?;
+    target.take(&IdxCa::from_vec("matches", rows))
+}
+
+// The `k` rows of the target nearest to each of `n_main` rows under
+// `distance(main row, target row)`
+//
+// The kernel of `knn_match` for any distance between pairs: rows i * k to
+// i * k + k - 1 of the result are the target rows nearest to main row i,
+// nearest first, ties going to the earlier target row. Fails if the
+// target has fewer than `k` rows.
+pub fn nearest_rows(
+    n_main: usize,
+    n_target: usize,
+    k: usize,
+    distance: impl Fn(usize, usize) -> f64,
+    cancel: &CancelToken
+) -> PolarsResult<Vec<IdxSize>> {
+    if n_target < k {
+        return Err(PolarsError::ComputeError(format!(
+            "Matching {} controls per treated unit needs at least {} controls but there are {}",
+            k,
+            k,
+            n_target
+        ).into()));
+    }
+    let mut order: Vec<usize> = (0..n_target).collect();
+    let mut rows = Vec::with_capacity(n_main * k);
+    for i in 0..n_main {
+        cancel.check(|| format!("while matching after {} of {} units", i, n_main))?;
+        let closer = |a: &usize, b: &usize| distance(i, *a).total_cmp(&distance(i, *b)).then(a.cmp(b));
+        order.select_nth_unstable_by(k - 1, closer);
+        let mut nearest = order[..k].to_vec();
+        nearest.sort_by(closer);
+        rows.extend(nearest.into_iter().map(|row| row as IdxSize));
+    }
+    Ok(rows)
+}
+
+// Propensity score distance plus a covariate penalty
+//
+// The distance between a treated unit and a control is their propensity
+// score distance plus `penalty` times the Mahalanobis distance of their
+// covariates, so that among controls with similar scores the one most
+// like the treated unit in its covariates wins. The covariance is that of
+// the treated units and the control pool together; its generalized
+// inverse drops covariates that are linear combinations of others.
+#[derive(Debug)]
+pub struct HybridDistance {
+    penalty: f64,
+    treat_ps: Vec<f64>,
+    pool_ps: Vec<f64>,
+    treat_x: Array2<f64>,
+    // The pool covariates times the inverse covariance
+    pool_ax: Array2<f64>,
+    // x' S^-1 x of every treated unit and control
+    treat_q: Array1<f64>,
+    pool_q: Array1<f64>
+}
+
+impl HybridDistance {
+    pub fn new(treat: &DataFrame, pool: &DataFrame, covariates: &[String], penalty: f64) -> PolarsResult<Self> {
+        let matrix = |data: &DataFrame| -> PolarsResult<Array2<f64>> {
+            let mut x = Array2::zeros((data.height(), covariates.len()));
+            for (k, covariate) in covariates.iter().enumerate() {
+                x.column_mut(k).assign(&Array1::from(predictor_values(data, covariate)?));
+            }
+            Ok(x)
+        };
+        let (treat_x, pool_x) = (matrix(treat)?, matrix(pool)?);
+        let all = concatenate(Axis(0), &[treat_x.view(), pool_x.view()]).expect("Both have a column per covariate");
+        let centered = &all - &all.mean_axis(Axis(0)).expect("The sample is not empty");
+        let covariance = centered.t().dot(&centered) / (all.nrows() as f64 - 1.0);
+        let (inverse, _) = generalized_inverse(&covariance);
+        let pool_ax = pool_x.dot(&inverse);
+        let treat_q = (&treat_x.dot(&inverse) * &treat_x).sum_axis(Axis(1));
+        let pool_q = (&pool_ax * &pool_x).sum_axis(Axis(1));
+        Ok(HybridDistance {
+            penalty,
+            treat_ps: column_values(treat, "propensities")?,
+            pool_ps: column_values(pool, "propensities")?,
+            treat_x,
+            pool_ax,
+            treat_q,
+            pool_q
+        })
+    }
+
+    // Distance between treated unit `i` and control `j`.
+    pub fn between(&self, i: usize, j: usize) -> f64 {
+        let cross = self.treat_x.row(i).dot(&self.pool_ax.row(j));
+        let mahalanobis = (self.treat_q[i] - 2.0 * cross + self.pool_q[j]).max(0.0).sqrt();
+        (self.treat_ps[i] - self.pool_ps[j]).abs() + self.penalty * mahalanobis
+    }
+}
+
+// k nearest neighbor matching on a `HybridDistance`
+//
+// Laid out like `knn_match`, whose matches it finds when the penalty is 0.
+pub fn hybrid_match(
+    main: &DataFrame,
+    target: &DataFrame,
+    distance: &HybridDistance,
+    k: usize,
+    cancel: &CancelToken
+) -> PolarsResult<DataFrame> {
+    let rows = nearest_rows(main.height(), target.height(), k, |i, j| distance.between(i, j), cancel)?;
+    target.take(&IdxCa::from_vec("matches", rows))
+}
+
+// Order in which greedy matching hands out controls
+//
+// Treated units take turns: in data order (`Data`), from the highest
+// propensity score down (`LargestPscore`, since treated units with high
+// scores have the fewest comparable controls), from the lowest up
+// (`SmallestPscore`), in random order (`Random`) or from the treated unit
+// farthest from its nearest control down (`WorstFirst`). `ClosestFirst`
+// has no turns: it goes through all treated-control pairs from the
+// smallest distance up.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MatchOrder {
+    Data,
+    #[default]
+    LargestPscore,
+    SmallestPscore,
+    Random,
+    ClosestFirst,
+    WorstFirst
+}
+
+// Implement `FromStr` for `MatchOrder`.
+impl FromStr for MatchOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "data" => Ok(MatchOrder::Data),
+            // `propensity` is the name the default had at first
+            "largest-pscore" | "propensity" => Ok(MatchOrder::LargestPscore),
+            "smallest-pscore" => Ok(MatchOrder::SmallestPscore),
+            "random" => Ok(MatchOrder::Random),
+            "closest-first" => Ok(MatchOrder::ClosestFirst),
+            "worst-first" => Ok(MatchOrder::WorstFirst),
+            _ => Err(format!(
+                "Unknown match order `{}`; use data, largest-pscore, smallest-pscore, random, closest-first or \
+                 worst-first",
+                s
+            ))
+        }
+    }
+}
+
+// Implement `Display` for `MatchOrder`.
+impl fmt::Display for MatchOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatchOrder::Data => write!(f, "data"),
+            MatchOrder::LargestPscore => write!(f, "largest-pscore"),
+            MatchOrder::SmallestPscore => write!(f, "smallest-pscore"),
+            MatchOrder::Random => write!(f, "random"),
+            MatchOrder::ClosestFirst => write!(f, "closest-first"),
+            MatchOrder::WorstFirst => write!(f, "worst-first")
+        }
+    }
+}
+
+// Greedy nearest neighbor matching with a cap on control reuse
+//
+// Every treated unit gets the `k` closest distinct controls of `target`
+// that were used fewer than `max_uses` times before it picked; with
+// `max_uses` 1 this is matching without replacement. Units pick in
+// `order`, shuffled with `rng` for `MatchOrder::Random`. The result is
+// laid out like that of `knn_match`: `k` rows per row of `main`, in the
+// same order and nearest first. Ties go to the earlier row of `target`.
+// Fails if `target` has too few controls to give every treated unit `k`.
+// Stops with an error if `cancel` is set.
+pub fn greedy_match(
+    main: &DataFrame,
+    target: &DataFrame,
+    k: usize,
+    max_uses: usize,
+    order: MatchOrder,
+    rng: &mut StdRng,
+    cancel: &CancelToken
+) -> PolarsResult<DataFrame> {
+    let pool = column_values(target, "propensities")?;
+    let scores = column_values(main, "propensities")?;
+    let needed = k.max((k * scores.len()).div_ceil(max_uses));
+    if pool.len() < needed {
+        let reuse = match max_uses {
+            1 => "without replacement".to_string(),
+            n => format!("using a control at most {} times", n)
+        };
+        return Err(PolarsError::ComputeError(format!(
+            "Matching {} treated units to {} controls each {} needs at least {} controls but there are {}",
+            scores.len(),
+            k,
+            reuse,
+            needed,
+            pool.len()
+        ).into()));
+    }
+    let matches = match order {
+        MatchOrder::ClosestFirst => closest_first(&scores, &pool, k, max_uses, cancel)?,
+        _ => {
+            let mut turns: Vec<usize> = (0..scores.len()).collect();
+            let descending = |priority: &[f64], turns: &mut Vec<usize>| {
+                turns.sort_by(|a, b| priority[*b].total_cmp(&priority[*a]).then(a.cmp(b)))
+            };
+            match order {
+                MatchOrder::LargestPscore => descending(&scores, &mut turns),
+                MatchOrder::SmallestPscore => turns.sort_by(|a, b| scores[*a].total_cmp(&scores[*b]).then(a.cmp(b))),
+                MatchOrder::Random => turns.shuffle(rng),
+                MatchOrder::WorstFirst => {
+                    let nearest: Vec<f64> = scores
+                        .iter()
+                        .map(|t| pool.iter().map(|c| (c - t).abs()).fold(f64::NAN, f64::min))
+                        .collect();
+                    descending(&nearest, &mut turns)
+                },
+                MatchOrder::Data | MatchOrder::ClosestFirst => {}
+            }
+            in_turns(&scores, &pool, &turns, k, max_uses, cancel)?
+        }
+    };
+    target.take(&IdxCa::from_vec("matches", matches.concat()))
+}
+
+// Greedy matching with treated units taking turns
+//
+// Unit `turns[0]` picks its `k` controls first, then `turns[1]`, and so
+// on. Returns the rows of `pool` matched to every treated unit.
+fn in_turns(
+    scores: &[f64],
+    pool: &[f64],
+    turns: &[usize],
+    k: usize,
+    max_uses: usize,
+    cancel: &CancelToken
+) -> PolarsResult<Vec<Vec<IdxSize>>> {
+    let mut uses = vec![0; pool.len()];
+    let mut matches: Vec<Vec<IdxSize>> = vec![Vec::with_capacity(k); scores.len()];
+    for (step, i) in turns.iter().enumerate() {
+        cancel.check(|| format!("while matching after {} of {} units", step, scores.len()))?;
+        for _ in 0..k {
+            let mut best: Option<(usize, f64)> = None;
+            for (row, c) in pool.iter().enumerate() {
+                let distance = (c - scores[*i]).abs();
+                let eligible = uses[row] < max_uses && !matches[*i].contains(&(row as IdxSize));
+                if eligible && best.is_none_or(|(_, d)| distance < d) {
+                    best = Some((row, distance));
+                }
+            }
+            let (row, _) = best.ok_or_else(|| out_of_controls(step, scores.len()))?;
+            uses[row] += 1;
+            matches[*i].push(row as IdxSize);
+        }
+    }
+    Ok(matches)
+}
+
+// Greedy matching in order of pair distance
+//
+// Every treated unit keeps its next nearest eligible control in a heap,
+// found by walking outwards from its score through the pool sorted by
+// score, and the closest pair of the heap is matched next. A pair whose
+// control was used up since it was pushed is replaced by the unit's next
+// candidate. Returns the rows of `pool` matched to every treated unit.
+fn closest_first(
+    scores: &[f64],
+    pool: &[f64],
+    k: usize,
+    max_uses: usize,
+    cancel: &CancelToken
+) -> PolarsResult<Vec<Vec<IdxSize>>> {
+    let mut by_score: Vec<usize> = (0..pool.len()).collect();
+    by_score.sort_by(|a, b| pool[*a].total_cmp(&pool[*b]).then(a.cmp(b)));
+    let mut uses = vec![0; pool.len()];
+    // For every treated unit, the positions in `by_score` just outside the
+    // controls it has passed over on either side
+    let mut bounds: Vec<(usize, usize)> = scores
+        .iter()
+        .map(|t| {
+            let above = by_score.partition_point(|row| pool[*row] < *t);
+            (above, above)
+        })
+        .collect();
+    // The closest eligible control of treated unit `i`, moving its bounds
+    // past used-up controls
+    let next = |i: usize, bounds: &mut (usize, usize), uses: &[usize]| -> Option<(f64, usize)> {
+        while bounds.0 > 0 && uses[by_score[bounds.0 - 1]] >= max_uses {
+            bounds.0 -= 1;
+        }
+        while bounds.1 < by_score.len() && uses[by_score[bounds.1]] >= max_uses {
+            bounds.1 += 1;
+        }
+        let below = (bounds.0 > 0).then(|| by_score[bounds.0 - 1]);
+        let above = by_score.get(bounds.1).copied();
+        [below, above]
+            .into_iter()
+            .flatten()
+            .map(|row| ((pool[row] - scores[i]).abs(), row))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+    };
+    // Distances are non-negative, so their bit patterns sort like them
+    let mut heap = BinaryHeap::new();
+    for (i, bound) in bounds.iter_mut().enumerate() {
+        if let Some((distance, row)) = next(i, bound, &uses) {
+            heap.push(Reverse((distance.to_bits(), i, row)));
+        }
+    }
+    let mut matches: Vec<Vec<IdxSize>> = vec![Vec::with_capacity(k); scores.len()];
+    let mut matched = 0;
+    while let Some(Reverse((_, i, row))) = heap.pop() {
+        cancel.check(|| format!("while matching after {} of {} matches", matched, k * scores.len()))?;
+        if uses[row] < max_uses {
+            uses[row] += 1;
+            matches[i].push(row as IdxSize);
+            matched += 1;
+            // Step past the control on its side
+            match bounds[i].0 > 0 && by_score[bounds[i].0 - 1] == row {
+                true => bounds[i].0 -= 1,
+                false => bounds[i].1 += 1
+            }
+        }
+        if matches[i].len() < k {
+            if let Some((distance, row)) = next(i, &mut bounds[i], &uses) {
+                heap.push(Reverse((distance.to_bits(), i, row)));
+            }
+        }
+    }
+    if matched < k * scores.len() {
+        return Err(out_of_controls(matched / k, scores.len()));
+    }
+    Ok(matches)
+}
+
+// Greedy matching can only run out of controls when k > 1 and reused
+// controls leave fewer than k to pick from.
+fn out_of_controls(step: usize, n: usize) -> PolarsError {
+    PolarsError::ComputeError(format!("Ran out of distinct controls after matching {} of {} units", step, n).into())
+}
+
+// Optimal matching without replacement
+//
+// Builds the treated-by-control matrix of propensity score distances and
+// solves the minimum-cost assignment, so that the total distance over all
+// pairs is as small as possible, unlike greedy matching, where early
+// treated units can take the controls later ones needed. With `k > 1`
+// every treated unit is assigned `k` distinct controls. The result is laid
+// out like that of `knn_match`. Fails if `target` has fewer than `k`
+// controls per treated unit. Stops with an error if `cancel` is set.
+pub fn optimal_match(main: &DataFrame, target: &DataFrame, k: usize, cancel: &CancelToken) -> PolarsResult<DataFrame> {
+    let pool = column_values(target, "propensities")?;
+    let scores = column_values(main, "propensities")?;
+    if pool.len() < k * scores.len() {
+        return Err(PolarsError::ComputeError(format!(
+            "Optimal matching of {} treated units to {} controls each needs at least {} controls but there are {}",
+            scores.len(),
+            k,
+            k * scores.len(),
+            pool.len()
+        ).into()));
+    }
+    // One row per match, so k rows per treated unit
+    let cost: Vec<f64> = scores
+        .iter()
+        .flat_map(|t| std::iter::repeat_n(t, k))
+        .flat_map(|t| pool.iter().map(move |c| (c - t).abs()))
+        .collect();
+    let assigned = assignment(&cost, scores.len() * k, pool.len(), cancel)?;
+    let mut rows = Vec::with_capacity(assigned.len());
+    for matches in assigned.chunks(k) {
+        let mut matches = matches.to_vec();
+        matches.sort_by(|a, b| cost[*a].total_cmp(&cost[*b]).then(a.cmp(b)));
+        rows.extend(matches.into_iter().map(|column| (column % pool.len()) as IdxSize));
+    }
+    target.take(&IdxCa::from_vec("matches", rows))
+}
+
+// Minimum-cost assignment of every row of a `rows` x `columns` cost matrix
+// (row-major, `rows <= columns`) to a distinct column
+//
+// The Hungarian algorithm in its shortest augmenting path form: rows are
+// added one at a time and the assignment is extended along the cheapest
+// path in reduced costs, which takes O(rows^2 columns) time. Returns, for
+// every row, the index into `cost` of its assigned entry.
+fn assignment(cost: &[f64], rows: usize, columns: usize, cancel: &CancelToken) -> PolarsResult<Vec<usize>> {
+    // Potentials and matches are 1-based, with column 0 a virtual start
+    let mut u = vec![0.0; rows + 1];
+    let mut v = vec![0.0; columns + 1];
+    let mut row_of = vec![0; columns + 1];
+    let mut way = vec![0; columns + 1];
+    for row in 1..=rows {
+        cancel.check(|| format!("while matching after {} of {} units", row - 1, rows))?;
+        row_of[0] = row;
+        let mut column = 0;
+        let mut slack = vec![f64::INFINITY; columns + 1];
+        let mut used = vec![false; columns + 1];
+        loop {
+            used[column] = true;
+            let current = row_of[column];
+            let mut delta = f64::INFINITY;
+            let mut next = 0;
+            for j in 1..=columns {
+                if !used[j] {
+                    let reduced = cost[(current - 1) * columns + j - 1] - u[current] - v[j];
+                    if reduced < slack[j] {
+                        slack[j] = reduced;
+                        way[j] = column;
+                    }
+                    if slack[j] < delta {
+                        delta = slack[j];
+                        next = j;
+                    }
+                }
+            }
+            for j in 0..=columns {
+                match used[j] {
+                    true => {
+                        u[row_of[j]] += delta;
+                        v[j] -= delta;
+                    },
+                    false => slack[j] -= delta
+                }
+            }
+            column = next;
+            if row_of[column] == 0 {
+                break;
+            }
+        }
+        // Flip the assignment along the augmenting path
+        while column != 0 {
+            let previous = way[column];
+            row_of[column] = row_of[previous];
+            column = previous;
+        }
+    }
+    let mut assigned = vec![0; rows];
+    for j in 1..=columns {
+        if row_of[j] != 0 {
+            assigned[row_of[j] - 1] = (row_of[j] - 1) * columns + j - 1;
+        }
+    }
+    Ok(assigned)
+}
+
+// Nearest control of every treated unit
+//
+// The matching kernel on plain propensity scores: for every score in
+// `treat`, the index of the closest score in `pool`. Ties go to the first
+// such control, as in `nn_match`. `None` when the pool is empty or a score
+// is missing (NaN).
+pub fn nearest_controls(treat: &[f64], pool: &[f64]) -> Vec<Option<usize>> {
+    treat
+        .iter()
+        .map(|t| {
+            let mut best: Option<(usize, f64)> = None;
+            for (i, c) in pool.iter().enumerate() {
+                let distance = (c - t).abs();
+                if best.map_or(!distance.is_nan(), |(_, d)| distance < d) {
+                    best = Some((i, distance));
+                }
+            }
+            best.map(|(i, _)| i)
+        })
+        .collect()
+}
+
+// Control units sorted by propensity score
+//
+// A shared index of the control pool for matching many treated units, e.g.
+// from several partitions at once: each lookup is a binary search instead
+// of a scan of the pool. Controls are sorted by score and then `strike_id`,
+// so ties go to the control with the lowest id, which is the first control
+// in pool order, as in `nn_match`.
+#[derive(Debug)]
+pub struct ControlIndex {
+    controls: Vec<(f64, i64)>
+}
+
+impl ControlIndex {
+    // Index controls given as (propensity score, strike_id) pairs.
+    pub fn new(mut controls: Vec<(f64, i64)>) -> ControlIndex {
+        controls.retain(|(p, _)| !p.is_nan());
+        controls.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        ControlIndex { controls }
+    }
+
+    // The `strike_id` of the control nearest to `pscore`, if any.
+    pub fn nearest(&self, pscore: f64) -> Option<i64> {
+        let above = self.controls.partition_point(|(p, _)| *p < pscore);
+        let upper = self.controls.get(above);
+        // The first control with the largest score below `pscore`
+        let lower = match above {
+            0 => None,
+            _ => {
+                let below = self.controls[above - 1].0;
+                self.controls.get(self.controls.partition_point(|(p, _)| *p < below))
+            }
+        };
+        match (lower, upper) {
+            (Some((l, l_id)), Some((u, u_id))) => {
+                let (dl, du) = ((l - pscore).abs(), (u - pscore).abs());
+                match dl.total_cmp(&du).then(l_id.cmp(u_id)) {
+                    Ordering::Greater => Some(*u_id),
+                    _ => Some(*l_id)
+                }
+            },
+            (Some((_, id)), None) | (None, Some((_, id))) => Some(*id),
+            (None, None) => None
+        }
+    }
+}
+
+// Nearest neighbor matching through a `ControlIndex`
+//
+// The same pairs as `nn_match`, but each treated unit is matched with a
+// binary search of the sorted pool rather than a scan of it, so matching
+// takes O((n + m) log m) instead of O(n m).
+pub fn index_match(treat: &DataFrame, pool: &DataFrame, cancel: &CancelToken) -> PolarsResult<DataFrame> {
+    let pool_ps = column_values(pool, "propensities")?;
+    let pool_ids = column_values(pool, "strike_id")?;
+    let index = ControlIndex::new(zip(&pool_ps, &pool_ids).map(|(p, id)| (*p, *id as i64)).collect());
+    let pool_rows: HashMap<i64, IdxSize> = pool_ids
+        .iter()
+        .enumerate()
+        .map(|(row, id)| (*id as i64, row as IdxSize))
+        .collect();
+    let scores = column_values(treat, "propensities")?;
+    let mut rows = Vec::with_capacity(scores.len());
+    for (i, pscore) in scores.iter().enumerate() {
+        cancel.check(|| format!("while matching after {} of {} units", i, scores.len()))?;
+        match index.nearest(*pscore) {
+            Some(id) => rows.push(pool_rows[&id]),
+            None => panic!("index_match: No nearest neighbor control observation was found")
+        }
+    }
+    pool.take(&IdxCa::from_vec("matches", rows))
+}
+
+// Match distances
+//
+// The absolute propensity score difference between every treated unit and
+// its matched control, in the row order of `treat` and `control`.
+pub fn pair_distances(treat: &DataFrame, control: &DataFrame) -> PolarsResult<Vec<f64>> {
+    let treat_ps = column_values(treat, "propensities")?;
+    let control_ps = column_values(control, "propensities")?;
+    Ok(zip(treat_ps, control_ps).map(|(t, c)| (t - c).abs()).collect())
+}
+
+// One covariate's share of the covariate gaps of the matched pairs.
+#[derive(Debug)]
+pub struct CovariateGap {
+    pub covariate: String,
+    pub mean_gap: f64,
+    pub share: f64,
+    pub exact: f64
+}
+
+// Post-hoc breakdown of the covariate gaps within matched pairs
+//
+// Matched pairs are compared on every covariate by their squared
+// standardized difference ((x_tk - x_ck) / s_k)^2, where s_k is the pooled
+// standard deviation of the treated units and the control pool. This is
+// not the distance the matcher minimized, which is on the propensity score
+// (or the `--covariate-penalty`/prognostic distance): it describes the
+// pairs after matching. Each covariate's share of the summed gaps shows
+// which variables the pairs differ on most and might deserve exact
+// constraints. `exact` is the fraction of pairs that agree exactly on the
+// covariate.
+#[derive(Debug)]
+pub struct CovariateGaps {
+    pub gaps: Vec<CovariateGap>
+}
+
+// Break the covariate gaps of the matched pairs down by covariate
+//
+// Covariates are listed from the largest to the smallest share.
+pub fn covariate_gaps(
+    treat: &DataFrame,
+    control: &DataFrame,
+    pool: &DataFrame,
+    covariates: &[String]
+) -> PolarsResult<CovariateGaps> {
+    let mut gaps = Vec::with_capacity(covariates.len());
+    for covariate in covariates {
+        let x_treat = predictor_values(treat, covariate)?;
+        let x_control = predictor_values(control, covariate)?;
+        let scale = pooled_sd(&x_treat, &predictor_values(pool, covariate)?);
+        let n = x_treat.len() as f64;
+        let squared: f64 = zip(&x_treat, &x_control)
+            .map(|(t, c)| match scale > 0.0 {
+                true => ((t - c) / scale).powi(2),
+                false => 0.0
+            })
+            .sum();
+        let exact = zip(&x_treat, &x_control).filter(|(t, c)| t == c).count() as f64;
+        gaps.push(CovariateGap {
+            covariate: covariate.clone(),
+            mean_gap: squared / n,
+            share: 0.0,
+            exact: exact / n
+        });
+    }
+    let total: f64 = gaps.iter().map(|g| g.mean_gap).sum();
+    for gap in gaps.iter_mut() {
+        gap.share = match total > 0.0 {
+            true => gap.mean_gap / total,
+            false => 0.0
+        };
+    }
+    gaps.sort_by(|a, b| b.share.total_cmp(&a.share));
+    Ok(CovariateGaps { gaps })
+}
+
+// Implement `Display` for `CovariateGaps`.
+impl fmt::Display for CovariateGaps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Covariate gaps within matched pairs (standardized squared differences)")?;
+        writeln!(f, "{:<24}  {:>13}  {:>7}  {:>7}", "Covariate", "Mean gap", "Share", "% Exact")?;
+        for g in &self.gaps {
+            writeln!(
+                f,
+                "{:<24}  {:>13}  {:>6.1}%  {:>6.1}%",
+                g.covariate,
+                num(g.mean_gap),
+                g.share * 100.0,
+                g.exact * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::df;
+
+    fn matched_ids(main: &[f64], k: usize) -> PolarsResult<Vec<f64>> {
+        let main = df!("propensities" => main).unwrap();
+        let target = df!(
+            "id" => [0.0, 1.0, 2.0, 3.0, 4.0],
+            "propensities" => [0.75, 0.375, 0.25, 0.625, 0.0]
+        ).unwrap();
+        column_values(&knn_match(&main, &target, k, &CancelToken::default())?, "id")
+    }
+
+    #[test]
+    fn knn_match_nearest_first() {
+        // Distances from 0.0 are 0.75, 0.375, 0.25, 0.625 and 0
+        assert_eq!(matched_ids(&[0.0], 3).unwrap(), vec![4.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn knn_match_rows_per_unit() {
+        // Rows i * k to i * k + k - 1 belong to main row i
+        assert_eq!(matched_ids(&[0.75, 0.0], 2).unwrap(), vec![0.0, 3.0, 4.0, 2.0]);
+    }
+
+    #[test]
+    fn knn_match_ties_go_to_the_earlier_row() {
+        // From 0.5, rows 1 and 3 are 0.125 away and rows 0 and 2 are 0.25
+        assert_eq!(matched_ids(&[0.5], 4).unwrap(), vec![1.0, 3.0, 0.0, 2.0]);
+        assert_eq!(matched_ids(&[0.5], 1).unwrap(), vec![1.0]);
+    }
+
+    #[test]
+    fn knn_match_needs_k_controls() {
+        assert!(matched_ids(&[0.5], 6).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use ndarray::{Array1, Array2, Axis};
+use polars::prelude::DataFrame;
+use std::error::Error;
+use std::fmt;
+
+use crate::att::Estimand;
+use crate::cli::Args;
+use crate::data::column_values;
+use crate::estimate::{check_separation, unselected_columns};
+use crate::format::num;
+use crate::formula::predictor_values;
+use crate::propensity::{cross_fit_propensities, predictor_names};
+use crate::regression::wls;
+use crate::split::{assign_folds, rng};
+use crate::validate::validate;
+use crate::warnings::{model_warnings, Warning};
+
+// Folds of the cross-fitted nuisance models unless `--ps-folds` says
+// otherwise.
+pub const DEFAULT_DML_FOLDS: usize = 5;
+
+// Cross-fitted propensity scores are clipped to [DML_CLIP, 1 - DML_CLIP]
+// before they enter the orthogonal score, which divides by them.
+pub const DML_CLIP: f64 = 0.01;
+
+// A double machine learning estimate
+//
+// `clipped` counts the units whose cross-fitted propensity score was
+// clipped to `DML_CLIP` or `1 - DML_CLIP`. `warnings` are those about the
+// propensity model: non-convergence of any fold's fit and separation.
+#[derive(Debug)]
+pub struct DoubleMl {
+    pub estimand: Estimand,
+    pub estimate: f64,
+    pub variance: f64,
+    pub folds: usize,
+    pub n_treat: usize,
+    pub n_control: usize,
+    pub clipped: usize,
+    pub warnings: Vec<Warning>
+}
+
+// Cross-fitted outcome regressions
+//
+// For every unit, the linear regression of the outcome on the covariates
+// among the units of `group` (1 treated, 0 control) in the other folds,
+// evaluated at the unit's covariates.
+fn cross_fit_outcomes(x: &Array2<f64>, d: &[f64], y: &[f64], folds: &[usize], group: f64) -> Vec<f64> {
+    let k = folds.iter().max().map_or(0, |f| f + 1);
+    let mut predictions = vec![f64::NAN; y.len()];
+    for fold in 0..k {
+        let rows: Vec<usize> = (0..y.len()).filter(|i| folds[*i] != fold && d[*i] == group).collect();
+        let train_x = x.select(Axis(0), &rows);
+        let train_y: Array1<f64> = rows.iter().map(|i| y[*i]).collect();
+        let fit = wls(&train_x, &train_y, &Array1::ones(rows.len()));
+        for i in (0..y.len()).filter(|i| folds[*i] == fold) {
+            predictions[i] = x.row(i).dot(&fit.coefficients);
+        }
+    }
+    predictions
+}
+
+// Estimate the ATT or ATE by double machine learning
+//
+// Both nuisance models are cross-fitted over `--ps-folds` random folds:
+// the propensity model (the model of the matching) and linear regressions
+// of the outcome on the selected covariates among the controls and, for
+// the ATE, the treated units. Every
+// unit's nuisances come from models fit on the other folds, and the
+// estimate solves the orthogonal (doubly robust) score, whose first-order
+// insensitivity to errors in the nuisances keeps the usual variance, the
+// variance of the score over n, valid. For the ATE the score is
+// m1 - m0 + D (Y - m1) / p - (1 - D) (Y - m0) / (1 - p); for the ATT it is
+// [D (Y - m0) - p (1 - D) (Y - m0) / (1 - p) - D ATT] / P(D = 1).
+pub fn double_ml(data: &DataFrame, args: &Args) -> Result<DoubleMl, Box<dyn Error>> {
+    let (treatment, outcome, estimand) = (args.treatment.as_str(), args.outcome.as_str(), args.estimand);
+    if estimand == Estimand::Atc {
+        return Err("Double machine learning estimates the ATT or the ATE, not the ATC".into());
+    }
+    validate(data, treatment, outcome)?;
+    let d = column_values(data, treatment)?;
+    let y = column_values(data, outcome)?;
+    let n = d.len();
+    let k = args.ps_folds.unwrap_or(DEFAULT_DML_FOLDS);
+    let folds = assign_folds(n, k, &mut rng(args.seed));
+    let unselected = unselected_columns(data, args)?;
+    let (separated, exclude) = check_separation(data, args)?;
+    let (p, fit) = cross_fit_propensities(data, treatment, &exclude, args.ps_model, &args.logit, &folds)?;
+    let warnings = model_warnings(Some(fit), &separated);
+    let clipped = p.iter().filter(|p| **p < DML_CLIP || **p > 1.0 - DML_CLIP).count();
+    let p: Vec<f64> = p.into_iter().map(|p| p.clamp(DML_CLIP, 1.0 - DML_CLIP)).collect();
+    let covariates = predictor_names(data, treatment, &unselected)?;
+    let mut x = Array2::<f64>::ones((n, covariates.len() + 1));
+    for (j, covariate) in covariates.iter().enumerate() {
+        x.column_mut(j + 1).assign(&Array1::from(predictor_values(data, covariate)?));
+    }
+    for (fold, group) in (0..k).flat_map(|f| [(f, 0.0), (f, 1.0)]) {
+        let in_group = (0..n).filter(|i| folds[*i] != fold && d[*i] == group).count();
+        if in_group <= covariates.len() {
+            return Err(format!(
+                "Too few {} units outside fold {} to fit the outcome model; use fewer folds",
+                if group == 1.0 { "treated" } else { "control" },
+                fold + 1
+            ).into());
+        }
+    }
+    let m0 = cross_fit_outcomes(&x, &d, &y, &folds, 0.0);
+    let n_treat = d.iter().filter(|d| **d == 1.0).count();
+    let (estimate, scores): (f64, Vec<f64>) = match estimand {
+        Estimand::Ate => {
+            let m1 = cross_fit_outcomes(&x, &d, &y, &folds, 1.0);
+            let scores: Vec<f64> = (0..n)
+                .map(|i| {
+                    m1[i] - m0[i] + d[i] * (y[i] - m1[i]) / p[i] - (1.0 - d[i]) * (y[i] - m0[i]) / (1.0 - p[i])
+                })
+                .collect();
+            let estimate = scores.iter().sum::<f64>() / n as f64;
+            (estimate, scores.into_iter().map(|s| s - estimate).collect())
+        },
+        _ => {
+            let share = n_treat as f64 / n as f64;
+            let residual: Vec<f64> = (0..n)
+                .map(|i| d[i] * (y[i] - m0[i]) - p[i] * (1.0 - d[i]) * (y[i] - m0[i]) / (1.0 - p[i]))
+                .collect();
+            let estimate = residual.iter().sum::<f64>() / n_treat as f64;
+            (estimate, (0..n).map(|i| (residual[i] - d[i] * estimate) / share).collect())
+        }
+    };
+    let variance = scores.iter().map(|s| s * s).sum::<f64>() / (n * n) as f64;
+    Ok(DoubleMl { estimand, estimate, variance, folds: k, n_treat, n_control: n - n_treat, clipped, warnings })
+}
+
+// Implement `Display` for `DoubleMl`.
+impl fmt::Display for DoubleMl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let se = self.variance.sqrt();
+        writeln!(f, "STRIKE DOUBLE MACHINE LEARNING ===============\n")?;
+        writeln!(f, "# Treat: {} | # Control: {}\n", self.n_treat, self.n_control)?;
+        writeln!(f, "Nuisance models cross-fit over {} folds\n", self.folds)?;
+        writeln!(f, "{:<24}: {}", self.estimand.to_string().to_uppercase(), num(self.estimate))?;
+        writeln!(f, "Variance                : {}", num(self.variance))?;
+        let (lb, ub) = (self.estimate - 1.96 * se, self.estimate + 1.96 * se);
+        writeln!(f, "95% Confidence Interval : ({}, {})", num(lb), num(ub))?;
+        writeln!(f, "Clipped scores          : {} (to [{}, {}])", self.clipped, DML_CLIP, 1.0 - DML_CLIP)?;
+        if !self.warnings.is_empty() {
+            writeln!(f, "\nWarnings")?;
+            for warning in &self.warnings {
+                writeln!(f, "  {}", warning)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+
+use crate::format::num;
+use crate::paired::normal_cdf;
+
+// Significance level of each one-sided test of `--equivalence`.
+pub const EQUIVALENCE_ALPHA: f64 = 0.05;
+
+// Parse an equivalence margin: `d` for (-d, d) or `lower,upper`.
+pub fn parse_margin(s: &str) -> Result<(f64, f64), String> {
+    let malformed = || format!("`--equivalence` needs a margin `d` or bounds `lower,upper` but was `{}`", s);
+    let margin = match s.split_once(',') {
+        Some((lower, upper)) => (
+            lower.trim().parse().map_err(|_| malformed())?,
+            upper.trim().parse().map_err(|_| malformed())?
+        ),
+        None => {
+            let d: f64 = s.trim().parse().map_err(|_| malformed())?;
+            (-d, d)
+        }
+    };
+    match margin.0 < margin.1 && margin.0.is_finite() && margin.1.is_finite() {
+        true => Ok(margin),
+        false => Err(format!("The equivalence bounds must be finite with lower < upper but were {}", s))
+    }
+}
+
+// Two one-sided tests of equivalence (`--equivalence`)
+//
+// The effect is equivalent to no effect when it lies within the margin
+// (`lower`, `upper`). `lower_p` tests that it is at most `lower` and
+// `upper_p` that it is at least `upper`, each by the normal approximation
+// of the estimate; equivalence is shown at level `EQUIVALENCE_ALPHA` when
+// both are rejected, that is when the TOST p-value, the larger of the two,
+// is below it, or equivalently when the 90% confidence interval lies
+// within the margin (Schuirmann, 1987).
+#[derive(Clone, Debug)]
+pub struct Tost {
+    pub lower: f64,
+    pub upper: f64,
+    pub lower_p: f64,
+    pub upper_p: f64,
+    pub p_value: f64,
+    pub ci: (f64, f64)
+}
+
+// Run the two one-sided tests of the estimate `effect` with `variance`
+// against the margin.
+pub fn tost(effect: f64, variance: f64, (lower, upper): (f64, f64)) -> Tost {
+    let se = variance.sqrt();
+    let lower_p = 1.0 - normal_cdf((effect - lower) / se);
+    let upper_p = normal_cdf((effect - upper) / se);
+    Tost {
+        lower,
+        upper,
+        lower_p,
+        upper_p,
+        p_value: lower_p.max(upper_p),
+        ci: (effect - 1.645 * se, effect + 1.645 * se)
+    }
+}
+
+// Implement `Display` for `Tost`.
+impl fmt::Display for Tost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Equivalence test (TOST, margin {} to {})", num(self.lower), num(self.upper))?;
+        let lower = format!("p (effect <= {})", num(self.lower));
+        let upper = format!("p (effect >= {})", num(self.upper));
+        writeln!(f, "{:<24}: {}", lower, num(self.lower_p))?;
+        writeln!(f, "{:<24}: {}", upper, num(self.upper_p))?;
+        writeln!(f, "TOST p-value            : {}", num(self.p_value))?;
+        writeln!(f, "90% Confidence Interval : ({}, {})", num(self.ci.0), num(self.ci.1))?;
+        match self.p_value < EQUIVALENCE_ALPHA {
+            true => writeln!(f, "Equivalent at the {} level", EQUIVALENCE_ALPHA),
+            false => writeln!(f, "Equivalence not shown at the {} level", EQUIVALENCE_ALPHA)
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use polars::frame::UniqueKeepStrategy;
+use polars::prelude::{DataFrame, PolarsResult};
+use std::error::Error;
+
+use crate::att::{calculate_adjusted_att, calculate_cluster_att, calculate_trimmed_att, pair_differences, Estimand};
+use crate::balance::{
+    balance_bootstrap,
+    covariate_balance,
+    mean_variance,
+    subclass_balance,
+    BalanceTable,
+    BootstrapLimits,
+    SMD_THRESHOLD
+};
+use crate::binary::{binary_effects, is_binary, OutcomeType};
+use crate::caliper::{caliper_curve, CALIPER_GRID};
+use crate::cli::Args;
+use crate::data::column_values;
+use crate::diagnostics::{control_reuse, Diagnostics, OverlapMetrics};
+use crate::distance::covariate_gaps;
+use crate::overlap::{c_statistic, overlap_coefficient, positivity_check, PercentileTable};
+use crate::paired::{hodges_lehmann, mcnemar, paired_tests};
+use crate::permutation::permutation_test;
+use crate::quality::{match_quality_curve, QUALITY_STEPS};
+use crate::registry::{DEFAULT_ESTIMATOR, DEFAULT_MATCHER};
+use crate::sensitivity::rosenbaum_bounds;
+use crate::subclass::{calculate_subclass_att, DEFAULT_N_STRATA};
+use crate::split::rng;
+use crate::survey::calculate_survey_att;
+use crate::warnings::{collect_warnings, Warning};
+
+use super::{adjustment_covariates, balance_covariates, unless_cancelled, within_budget, Strike};
+
+impl Strike {
+    // Assemble the results from the matched samples
+    //
+    // Given the ATT and its variance, this computes every optional estimate
+    // that was requested on the command line from the matched samples.
+    pub fn new(
+        att: f64,
+        att_variance: f64,
+        treat: DataFrame,
+        control: DataFrame,
+        pool: DataFrame,
+        args: &Args
+    ) -> Result<Strike, Box<dyn Error>> {
+        let outcome = args.outcome.as_str();
+        let mut cancelled = Vec::new();
+        // One score per treated unit, which k:1 matching repeats once per match
+        let units = treat.unique_stable(Some(&["strike_id".to_string()]), UniqueKeepStrategy::First, None)?;
+        let treat_ps = column_values(&units, "propensities")?;
+        let pool_ps = column_values(&pool, "propensities")?;
+        // Pooled standard deviation of the outcome before matching, as in the SMDs
+        let outcome_sd = {
+            let mut pool = pool.clone();
+            if let Some(t) = args.outcome_transform {
+                t.apply(&mut pool, outcome)?;
+            }
+            let observed = |data: &DataFrame| -> PolarsResult<Vec<f64>> {
+                Ok(column_values(data, outcome)?.into_iter().filter(|y| y.is_finite()).collect())
+            };
+            let ((_, treat_variance), (_, pool_variance)) =
+                (mean_variance(&observed(&units)?), mean_variance(&observed(&pool)?));
+            ((treat_variance + pool_variance) / 2.0).sqrt()
+        };
+        let trimmed = match args.robust_trim {
+            Some(trim) => Some(calculate_trimmed_att(&treat, &control, outcome, trim)?),
+            None => None
+        };
+        let hodges_lehmann = match args.hodges_lehmann {
+            true => Some(hodges_lehmann(&pair_differences(&treat, &control, outcome)?)),
+            false => None
+        };
+        let subclass = match args.subclassify {
+            true => {
+                let mut pool = pool.clone();
+                if let Some(t) = args.outcome_transform {
+                    t.apply(&mut pool, outcome)?;
+                }
+                let n_strata = args.n_strata.unwrap_or(DEFAULT_N_STRATA);
+                Some(calculate_subclass_att(&units, &pool, outcome, n_strata)?)
+            },
+            false => None
+        };
+        let survey = match args.survey.is_empty() {
+            true => None,
+            false => Some(calculate_survey_att(&treat, &control, outcome, &args.survey)?)
+        };
+        let cluster = match &args.cluster {
+            Some(column) => Some(calculate_cluster_att(&treat, &control, outcome, column)?),
+            None => None
+        };
+        let adjusted = match args.regression_adjust {
+            true => {
+                let covariates = adjustment_covariates(&treat, &control, args)?;
+                Some(calculate_adjusted_att(&treat, &control, outcome, &covariates)?)
+            },
+            false => None
+        };
+        let paired = match args.paired_test {
+            true => Some(paired_tests(&pair_differences(&treat, &control, outcome)?)),
+            false => None
+        };
+        let permutation = match args.permute {
+            Some(permutations) => {
+                let test = permutation_test(&treat, &control, outcome, permutations, &mut rng(args.seed), &args.cancel);
+                unless_cancelled(test, "permutation test", args, &mut cancelled)?
+            },
+            None => None
+        };
+        let binary = match args.outcome_type {
+            OutcomeType::Continuous | OutcomeType::Survival => false,
+            OutcomeType::Binary => {
+                if !is_binary(&treat, outcome)? || !is_binary(&control, outcome)? {
+                    return Err(format!("`--outcome-type binary` needs a 0/1 outcome but `{}` is not", outcome).into());
+                }
+                true
+            },
+            OutcomeType::Auto => {
+                let single = args.outcome_transform.is_none() && args.missing_outcome.is_none();
+                single && args.estimand == Estimand::Att && is_binary(&treat, outcome)? && is_binary(&control, outcome)?
+            }
+        };
+        let binary = match binary {
+            true => Some(binary_effects(&treat, &control, outcome, args.ratio_ci, &mut rng(args.seed))?),
+            false => None
+        };
+        let mcnemar = match args.mcnemar {
+            true => Some(mcnemar(&column_values(&treat, outcome)?, &column_values(&control, outcome)?)?),
+            false => None
+        };
+        let rosenbaum = match args.rosenbaum {
+            Some(max_gamma) => Some(rosenbaum_bounds(&pair_differences(&treat, &control, outcome)?, max_gamma)),
+            None => None
+        };
+        let percentiles = match args.ps_percentiles {
+            true => {
+                let control_ps = column_values(&control, "propensities")?;
+                Some(PercentileTable::new(&[
+                    ("Treated", &treat_ps),
+                    ("Control", &pool_ps),
+                    ("Control (matched)", &control_ps)
+                ]))
+            },
+            false => None
+        };
+        let subclass_balance = match args.subclass_balance {
+            Some(n_strata) => {
+                let covariates = balance_covariates(&treat, &pool, args)?;
+                Some(subclass_balance(&units, &pool, &covariates, n_strata)?)
+            },
+            None => None
+        };
+        let balance_ci = match args.balance_ci {
+            Some(replicates) => {
+                let covariates = balance_covariates(&treat, &pool, args)?;
+                let mut rng = rng(args.seed);
+                let limits = BootstrapLimits { budget: args.budget.as_ref(), cancel: &args.cancel };
+                let bootstrap = balance_bootstrap(&treat, &pool, &control, &covariates, replicates, &mut rng, limits);
+                unless_cancelled(bootstrap, "balance bootstrap", args, &mut cancelled)?
+            },
+            None => None
+        };
+        let balance = match args.balance || args.balance_only {
+            true => {
+                let covariates = balance_covariates(&treat, &pool, args)?;
+                Some(BalanceTable {
+                    threshold: args.balance_threshold.unwrap_or(SMD_THRESHOLD),
+                    covariates: covariate_balance(&treat, &pool, &control, &covariates)?
+                })
+            },
+            false => None
+        };
+        let caliper = match args.caliper_curve && within_budget(args, "caliper curve", CALIPER_GRID.len()) {
+            true => Some(caliper_curve(&treat, &control, &pool, outcome, &args.treatment, &args.cancel)?),
+            false => None
+        };
+        let match_quality = match args.match_quality && within_budget(args, "match quality curve", QUALITY_STEPS) {
+            true => Some(match_quality_curve(&treat, &control, outcome, &args.treatment, &args.cancel)?),
+            false => None
+        };
+        let positivity = match args.positivity {
+            Some(n_bins) => {
+                let covariates = balance_covariates(&treat, &pool, args)?;
+                Some(positivity_check(&treat, &pool, &covariates, n_bins)?)
+            },
+            None => None
+        };
+        let covariate_gaps = match args.covariate_gaps {
+            true => {
+                let covariates = balance_covariates(&treat, &pool, args)?;
+                Some(covariate_gaps(&treat, &control, &pool, &covariates)?)
+            },
+            false => None
+        };
+        Ok(Strike {
+            att,
+            att_variance,
+            outcome: outcome.to_string(),
+            outcome_sd,
+            extra_outcomes: Vec::new(),
+            placebo: Vec::new(),
+            simultaneous: None,
+            estimand: args.estimand,
+            bias_corrected: args.bias_correct,
+            location: args.location,
+            ate: None,
+            treat,
+            control,
+            overlap: overlap_coefficient(&treat_ps, &pool_ps),
+            c_statistic: c_statistic(&treat_ps, &pool_ps),
+            pool,
+            split: args.split,
+            ps_folds: args.ps_folds,
+            pscore_col: args.pscore_col.clone(),
+            matcher: args.matcher.clone().unwrap_or(DEFAULT_MATCHER.to_string()),
+            estimator: args.estimator.clone().unwrap_or(DEFAULT_ESTIMATOR.to_string()),
+            transform: args.outcome_transform,
+            pre: args.pre.clone(),
+            trimmed,
+            hodges_lehmann,
+            subclass,
+            survey,
+            cluster,
+            missing: None,
+            adjusted,
+            paired,
+            permutation,
+            equivalence: args.equivalence,
+            mcnemar,
+            rosenbaum,
+            binary,
+            survival: None,
+            percentiles,
+            subclass_balance,
+            balance_ci,
+            balance,
+            caliper,
+            caliper_drop: None,
+            match_quality,
+            subgroups: None,
+            weighted: None,
+            selection: Vec::new(),
+            ps_diagnostics: None,
+            extreme: None,
+            support: None,
+            positivity,
+            covariate_gaps,
+            fit: None,
+            model: None,
+            separation: Vec::new(),
+            budget: None,
+            cancelled,
+            warnings: Vec::new()
+        })
+    }
+
+    // Check the analysis for problems
+    //
+    // Runs every check of the warning system on the final results, so it
+    // should be called once the estimate is complete.
+    pub fn check(&self, args: &Args) -> PolarsResult<Vec<Warning>> {
+        let control_ids = column_values(&self.control, "strike_id")?;
+        let weights = match &args.survey.weights {
+            Some(w) => Some(column_values(&self.treat, w)?),
+            None => None
+        };
+        Ok(collect_warnings(
+            self.treat.height(),
+            self.overlap,
+            &control_ids,
+            weights.as_deref(),
+            self.fit,
+            &self.separation
+        ))
+    }
+
+    // Diagnostics of the analysis as values
+    //
+    // Covariate balance before and after matching, the overlap metrics, the
+    // reuse of matched controls and every warning, for callers that check
+    // match quality in code rather than reading the summary.
+    pub fn diagnostics(&self, args: &Args) -> PolarsResult<Diagnostics> {
+        let covariates = balance_covariates(&self.treat, &self.pool, args)?;
+        Ok(Diagnostics {
+            balance: covariate_balance(&self.treat, &self.pool, &self.control, &covariates)?,
+            overlap: OverlapMetrics {
+                overlap_coefficient: self.overlap,
+                c_statistic: self.c_statistic
+            },
+            reuse: control_reuse(&column_values(&self.control, "strike_id")?),
+            warnings: self.check(args)?
+        })
+    }
+}
@@ -0,0 +1,166 @@
+use polars::frame::UniqueKeepStrategy;
+use polars::prelude::{ChunkCompare, DataFrame, PolarsResult};
+
+use crate::att::{AdjustedAtt, Ate, ClusterAtt, Estimand, Location, TrimmedAtt};
+use crate::calibration::PsDiagnostics;
+use crate::balance::{BalanceBootstrap, BalanceTable, SubclassBalanceReport};
+use crate::binary::BinaryEffects;
+use crate::budget::BudgetReport;
+use crate::caliper::{CaliperCurve, CaliperDrop};
+use crate::cli::Args;
+use crate::distance::CovariateGaps;
+use crate::missing::IpwAtt;
+use crate::multiplicity::Simultaneous;
+use crate::overlap::{CommonSupport, ExtremeUnits, PercentileTable, PositivityCheck};
+use crate::propensity::{Coefficients, LogitFit, Separation};
+use crate::paired::{HodgesLehmann, McNemar, PairedTests};
+use crate::permutation::PermutationTest;
+use crate::quality::MatchQualityCurve;
+use crate::selection::CovariateSelection;
+use crate::sensitivity::RosenbaumBounds;
+use crate::subclass::SubclassAtt;
+use crate::subgroup::Subgroups;
+use crate::split::SampleSplit;
+use crate::survey::SurveyAtt;
+use crate::survival::SurvivalContrast;
+use crate::transform::OutcomeTransform;
+use crate::warnings::Warning;
+use crate::weighting::WeightedEstimate;
+
+// The estimate is split by concern: `pipeline` validates the data, fits
+// the propensity model, matches and estimates; `extras` computes the
+// optional estimates and diagnostics requested on the command line from
+// the matched samples; `report` prints the summary.
+pub mod extras;
+pub mod pipeline;
+pub mod report;
+
+pub use pipeline::{
+    adjustment_covariates,
+    balance_covariates,
+    check_separation,
+    estimate_pairs,
+    fit_propensities,
+    matches,
+    rematch,
+    unselected_columns,
+    PropensityFit
+};
+
+// The ATT of one of the `--outcome` columns after the first or of a
+// `--placebo-outcomes` column, estimated on the same matched sample.
+#[derive(Debug)]
+pub struct OutcomeEstimate {
+    pub outcome: String,
+    pub att: f64,
+    pub att_variance: f64
+}
+
+// Simple class containing the results from an estimated 1:1 propensity
+// score matching routine.
+#[derive(Debug)]
+pub struct Strike {
+    pub att: f64,
+    pub att_variance: f64,
+    pub outcome: String,
+    pub outcome_sd: f64,
+    pub extra_outcomes: Vec<OutcomeEstimate>,
+    pub placebo: Vec<OutcomeEstimate>,
+    pub simultaneous: Option<Simultaneous>,
+    pub estimand: Estimand,
+    pub bias_corrected: bool,
+    pub location: Location,
+    pub ate: Option<Ate>,
+    pub treat: DataFrame,
+    pub control: DataFrame,
+    pub pool: DataFrame,
+    pub overlap: f64,
+    pub c_statistic: f64,
+    pub split: Option<SampleSplit>,
+    pub ps_folds: Option<usize>,
+    pub pscore_col: Option<String>,
+    pub matcher: String,
+    pub estimator: String,
+    pub transform: Option<OutcomeTransform>,
+    pub pre: Option<String>,
+    pub trimmed: Option<TrimmedAtt>,
+    pub hodges_lehmann: Option<HodgesLehmann>,
+    pub subclass: Option<SubclassAtt>,
+    pub survey: Option<SurveyAtt>,
+    pub cluster: Option<ClusterAtt>,
+    pub missing: Option<IpwAtt>,
+    pub adjusted: Option<AdjustedAtt>,
+    pub paired: Option<PairedTests>,
+    pub permutation: Option<PermutationTest>,
+    pub equivalence: Option<(f64, f64)>,
+    pub mcnemar: Option<McNemar>,
+    pub rosenbaum: Option<RosenbaumBounds>,
+    pub binary: Option<BinaryEffects>,
+    pub survival: Option<SurvivalContrast>,
+    pub percentiles: Option<PercentileTable>,
+    pub subclass_balance: Option<SubclassBalanceReport>,
+    pub balance_ci: Option<BalanceBootstrap>,
+    pub balance: Option<BalanceTable>,
+    pub caliper: Option<CaliperCurve>,
+    pub caliper_drop: Option<CaliperDrop>,
+    pub match_quality: Option<MatchQualityCurve>,
+    pub subgroups: Option<Subgroups>,
+    pub weighted: Option<WeightedEstimate>,
+    pub selection: Vec<CovariateSelection>,
+    pub ps_diagnostics: Option<PsDiagnostics>,
+    pub extreme: Option<ExtremeUnits>,
+    pub support: Option<CommonSupport>,
+    pub positivity: Option<PositivityCheck>,
+    pub covariate_gaps: Option<CovariateGaps>,
+    pub fit: Option<LogitFit>,
+    pub model: Option<Coefficients>,
+    pub separation: Vec<Separation>,
+    pub budget: Option<BudgetReport>,
+    pub cancelled: Vec<String>,
+    pub warnings: Vec<Warning>
+}
+
+// Keep an optional resampling diagnostic unless it was cancelled
+//
+// A diagnostic stopped by the cancellation token is left out and named in
+// `cancelled`, so that the estimates finished so far can still be reported.
+// Other errors are returned.
+fn unless_cancelled<T>(
+    result: PolarsResult<T>,
+    diagnostic: &str,
+    args: &Args,
+    cancelled: &mut Vec<String>
+) -> PolarsResult<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(_) if args.cancel.is_cancelled() => {
+            cancelled.push(diagnostic.to_string());
+            Ok(None)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+// Whether an optional diagnostic that repeats the estimate `repeats` times
+// fits in the `--time-budget`, if there is one.
+fn within_budget(args: &Args, diagnostic: &str, repeats: usize) -> bool {
+    args.budget.as_ref().is_none_or(|budget| budget.allows(diagnostic, repeats as u32))
+}
+
+// Count the number of unique treatment and control observations
+pub fn n_treat_control(treat: &DataFrame, control: &DataFrame) -> PolarsResult<(i64, i64)> {
+    let n_treat = treat.column("strike_id")?.n_unique()? as i64;
+    let n_control = control
+        .unique(None, UniqueKeepStrategy::First, None)?
+        .height() as i64;
+    Ok((n_treat, n_control))
+}
+
+// Split a DataFrame into treatment and control sub-frames.
+pub fn treat_control_split(data: &DataFrame, treatment: &str) -> PolarsResult<(DataFrame, DataFrame)> {
+    let mask_treat = data.column(treatment)?.equal(1)?;
+    let mask_control = data.column(treatment)?.equal(0)?;
+    let treat = data.filter(&mask_treat)?;
+    let control = data.filter(&mask_control)?;
+    Ok((treat, control))
+}
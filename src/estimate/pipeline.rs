@@ -0,0 +1,1087 @@
+use polars::prelude::{DataFrame, IdxCa, IdxSize, NamedFrom, PolarsError, PolarsResult, Series};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use crate::att::{calculate_ate, Ate, Estimand, DEFAULT_VARIANCE_NEIGHBORS};
+use crate::audit::record;
+use crate::cache::{cache_key, load_fit, load_matches, store_fit, store_matches, CachedFit};
+use crate::calibration::{auc, calibration_table, coefficient_table, PsDiagnostics, DIAGNOSTIC_FOLDS};
+use crate::budget::{Budget, PROBE_UNITS};
+use crate::caliper::apply_caliper;
+use crate::cli::Args;
+use crate::data::{column_values, order_by_id, repeat_rows, sort_by_id};
+use crate::design::write_design;
+use crate::distance::{index_match, nn_match, on_distance_scale, tied_match, Ties};
+use crate::missing::{calculate_ipw_att, check_missing_outcome, observation_probabilities};
+use crate::multiplicity::{joint_band, Simultaneous};
+use crate::overlap::{common_support, discard_off_support, extreme_units, trim_extreme, CommonSupport, Discard};
+use crate::propensity::{
+    add_ids,
+    cross_fit_propensities,
+    estimate_propensities_from,
+    predictor_names,
+    separation,
+    supplied_propensities,
+    Coefficients,
+    LogitFit,
+    PropensityModel,
+    Separation
+};
+use crate::registry::{estimator, matcher, DEFAULT_ESTIMATOR, DEFAULT_MATCHER, GENETIC_MATCHER};
+use crate::selection::{select_covariates, CovariateSelection, Selection};
+use crate::session::Session;
+use crate::subgroup::{pooled_subgroups, within_subgroups, SubgroupMatching};
+use crate::split::{assign_folds, rng, split_halves, SampleSplit};
+use crate::survey::fit_weights;
+use crate::survival::{survival_contrast, validate_survival};
+use crate::transform::difference_outcome;
+use crate::validate::{validate, validate_propensities, validate_pscore};
+use crate::weighting::weighted_estimate;
+
+use super::{treat_control_split, unless_cancelled, OutcomeEstimate, Strike};
+
+// Estimate ATT with 1:1 propensity score matching
+//
+// This is the whole thing. Validate the data, estimate propensities,
+// perform 1:1 matching on the propensity scores with replacement, and
+// calculate the ATT and variance. With sample splitting the propensity
+// model is fit on one random half of the data and matching and estimation
+// happen on the other half, so any specification choices made with the
+// fitting half (see `estimate_half`) can't contaminate the reported
+// confidence interval. Cross-fitting repeats this with the halves swapped
+// and averages the two estimates. With a pre-period outcome the outcome is
+// first replaced by its change since then, which makes the estimate a
+// difference-in-differences of the matched pairs.
+pub fn matches(data: &DataFrame, args: &Args) -> Result<Strike, Box<dyn Error>> {
+    let treatment = args.treatment.as_str();
+    let start = Instant::now();
+    validate(data, treatment, &args.outcome)?;
+    // With sample splitting the covariates are selected on the fitting half
+    if let (Some(method), None) = (args.select, args.split) {
+        let (selection, selected) = select_specification(data, method, args)?;
+        let mut strike = matches(data, &selected)?;
+        strike.selection = vec![selection];
+        return Ok(strike);
+    }
+    let original = data;
+    let changes;
+    let data = match &args.pre {
+        Some(pre) => {
+            validate(data, treatment, pre)?;
+            let mut data = data.clone();
+            difference_outcome(&mut data, &args.outcome, pre)?;
+            changes = data;
+            &changes
+        },
+        None => data
+    };
+    for outcome in args.extra_outcomes.iter().chain(&args.placebo_outcomes) {
+        validate(data, treatment, outcome)?;
+    }
+    if let Some(event) = &args.event {
+        validate_survival(data, &args.outcome, event)?;
+    }
+    check_missing_outcome(data, args)?;
+    record("validate", Some(data.height()), Some(data.height()), start.elapsed(), "");
+    args.cancel.check(|| "before fitting the propensity model".to_string())?;
+    let split = match args.split {
+        Some(split) => split,
+        None => {
+            let mut strike = data.clone();
+            let (fit, model, separated) = fit_propensities(data, &mut strike, args)?;
+            let scored = strike;
+            let mut strike = match_and_estimate(&scored, fit, separated, args)?;
+            if let Some(method) = args.weighting {
+                let mut scored = trim_scores(&scored, args)?;
+                if let Some(t) = args.outcome_transform {
+                    t.apply(&mut scored, &args.outcome)?;
+                }
+                let coefficients = model.as_ref().ok_or("`--weighting` needs a propensity model with coefficients")?;
+                let weighted = weighted_estimate(&[&scored], treatment, &args.outcome, coefficients, method)?;
+                strike.weighted = Some(weighted);
+            }
+            if args.ps_diagnostics {
+                strike.ps_diagnostics = Some(ps_diagnostics(data, &scored, model.as_ref(), args)?);
+            }
+            strike.model = model;
+            strike.subgroups = match (&args.by, args.by_matching) {
+                (Some(by), SubgroupMatching::Pooled) => {
+                    Some(pooled_subgroups(data, &strike.treat, &strike.control, by, args)?)
+                },
+                (Some(by), SubgroupMatching::Within) => Some(within_subgroups(original, by, args)?),
+                (None, _) => None
+            };
+            return Ok(strike);
+        }
+    };
+    let start = Instant::now();
+    let mut data = data.clone();
+    add_ids(&mut data)?;
+    let (first, second) = split_halves(&data, &mut rng(args.seed))?;
+    record(
+        "split",
+        Some(data.height()),
+        Some(first.height() + second.height()),
+        start.elapsed(),
+        &format!("halves={}/{} seed={:?}", first.height(), second.height(), args.seed)
+    );
+    let estimate = estimate_half(&first, &second, args)?;
+    if split == SampleSplit::Split {
+        return Ok(estimate);
+    }
+    args.cancel.check(|| "after the first half of the cross-fit".to_string())?;
+    let swapped = estimate_half(&second, &first, args)?;
+    cross_fit(estimate, swapped, args)
+}
+
+// Select the covariates of the propensity model on `data` (`--select`)
+//
+// Returns the selection and the arguments to fit the model with: the
+// covariates the lasso drops are left out as if by `--exclude`, except that
+// they stay in the balance diagnostics (see `balance_covariates`).
+fn select_specification(
+    data: &DataFrame,
+    method: Selection,
+    args: &Args
+) -> Result<(CovariateSelection, Args), Box<dyn Error>> {
+    let start = Instant::now();
+    let (_, exclude) = check_separation(data, args)?;
+    let selection = select_covariates(data, method, &exclude, args)?;
+    record(
+        "select",
+        Some(data.height()),
+        Some(data.height()),
+        start.elapsed(),
+        &format!("method={} dropped={}", method, selection.dropped.join(","))
+    );
+    let selected = Args {
+        deselected: selection.dropped.clone(),
+        select: None,
+        ..args.clone()
+    };
+    Ok((selection, selected))
+}
+
+// Choose the specification on `train` and estimate on `held_out`
+//
+// Every choice is made with `train` alone: `--select` picks the covariates
+// there, and the propensity model, including the learners, penalties and
+// calibration it tunes by cross-validation, is fit there. Matching and
+// estimation only see `held_out`, scored by that model.
+fn estimate_half(train: &DataFrame, held_out: &DataFrame, args: &Args) -> Result<Strike, Box<dyn Error>> {
+    let (selection, args) = match args.select {
+        Some(method) => {
+            let (selection, selected) = select_specification(train, method, args)?;
+            (Some(selection), selected)
+        },
+        None => (None, args.clone())
+    };
+    let mut scored = held_out.clone();
+    let (fit, _, separated) = fit_propensities(train, &mut scored, &args)?;
+    let mut strike = match_and_estimate(&scored, fit, separated, &args)?;
+    strike.selection = selection.into_iter().collect();
+    Ok(strike)
+}
+
+// A propensity model fit: its diagnostics, its coefficients (`None` for
+// supplied scores and models without coefficients) and the separation
+// found in the training data.
+pub type PropensityFit = (LogitFit, Option<Coefficients>, Vec<Separation>);
+
+// Fit the propensity model on `train` and score `data`
+//
+// Checks `train` for separation first. With `--cache` the fit and the
+// scores are read from the cache when the same data and model settings
+// were fit before, and saved to it otherwise. Records the fit in the audit
+// log, and with `--design-matrix` exports the model's design matrix and
+// coefficients. With `--pscore-col` nothing is fit: the supplied scores
+// are validated and used as they are.
+pub fn fit_propensities(
+    train: &DataFrame,
+    data: &mut DataFrame,
+    args: &Args
+) -> Result<PropensityFit, Box<dyn Error>> {
+    let start = Instant::now();
+    if let Some(column) = &args.pscore_col {
+        validate_pscore(data, column)?;
+        let (fit, model) = supplied_propensities(data, &args.treatment, column)?;
+        record("pscore-col", Some(data.height()), Some(data.height()), start.elapsed(), column);
+        return Ok((fit, model, Vec::new()));
+    }
+    let cache = match &args.cache {
+        Some(dir) => {
+            let outcomes: Vec<&String> =
+                [&args.outcome].into_iter().chain(&args.extra_outcomes).chain(&args.placebo_outcomes).collect();
+            let options = format!(
+                "fit treatment={} outcomes={:?} event={:?} covariates={:?} exclude={:?} deselected={:?} \
+                 survey={:?} cluster={:?} model={} logit={:?} drop_separating={} missing_outcome={:?} folds={:?} \
+                 seed={:?}",
+                args.treatment,
+                outcomes,
+                args.event,
+                args.covariates,
+                args.exclude,
+                args.deselected,
+                args.survey,
+                args.cluster,
+                args.ps_model,
+                args.logit,
+                args.drop_separating,
+                args.missing_outcome,
+                args.ps_folds,
+                args.ps_folds.and(args.seed)
+            );
+            Some((dir.as_str(), cache_key(&[train, data], &options)?))
+        },
+        None => None
+    };
+    let cached = cache
+        .as_ref()
+        .and_then(|(dir, key)| load_fit(dir, key))
+        .filter(|cached| cached.propensities.len() == data.height());
+    let cached = match cached {
+        Some(cached) => {
+            data.with_column(Series::new("propensities", cached.propensities.clone()))?;
+            add_ids(data)?;
+            (cached, true)
+        },
+        None => {
+            let (separation, exclude) = check_separation(train, args)?;
+            let (mut fit, model) =
+                estimate_propensities_from(train, data, &args.treatment, &exclude, args.ps_model, &args.logit)?;
+            // The scores come from the folds' fits, so their convergence
+            // counts too
+            if let Some(k) = args.ps_folds {
+                let start = Instant::now();
+                let folds = assign_folds(data.height(), k, &mut rng(args.seed));
+                let (model, options) = (args.ps_model, &args.logit);
+                let (scores, folds_fit) =
+                    cross_fit_propensities(data, &args.treatment, &exclude, model, options, &folds)?;
+                data.with_column(Series::new("propensities", scores))?;
+                fit = fit.worst(folds_fit);
+                record("ps-folds", Some(data.height()), Some(data.height()), start.elapsed(), &format!("folds={}", k));
+            }
+            let propensities = column_values(data, "propensities")?;
+            let cached = CachedFit { fit, model, separation, propensities };
+            if let Some((dir, key)) = &cache {
+                store_fit(dir, key, &cached)?;
+            }
+            (cached, false)
+        }
+    };
+    let (CachedFit { fit, model, separation, .. }, hit) = cached;
+    record_fit(train.height(), data.height(), start.elapsed(), args.ps_model, fit, &separation, hit);
+    if let Some(dir) = &args.design_matrix {
+        let (_, exclude) = check_separation(train, args)?;
+        let coefficients = model.as_ref().ok_or("`--design-matrix` needs a propensity model with coefficients")?;
+        write_design(dir, train, &args.treatment, &exclude, args.logit.formula.as_ref(), coefficients)?;
+    }
+    Ok((fit, model, separation))
+}
+
+// Diagnose the propensity model fit on `data`
+//
+// `scored` is `data` with the scores used for matching and `model` the
+// coefficients of the fit, if it has any. The cross-validated AUC refits
+// the model over `--ps-folds` (or `DIAGNOSTIC_FOLDS`) random folds;
+// supplied scores have none.
+fn ps_diagnostics(
+    data: &DataFrame,
+    scored: &DataFrame,
+    model: Option<&Coefficients>,
+    args: &Args
+) -> Result<PsDiagnostics, Box<dyn Error>> {
+    let start = Instant::now();
+    let d = column_values(scored, &args.treatment)?;
+    let p = column_values(scored, "propensities")?;
+    let coefficients = match model {
+        Some(model) => {
+            let weights = match &args.logit.weights {
+                Some(column) => Some(fit_weights(scored, column)?),
+                None => None
+            };
+            coefficient_table(scored, &args.treatment, model, weights.as_ref())?
+        },
+        None => Vec::new()
+    };
+    let cv_auc = match args.pscore_col {
+        Some(_) => None,
+        None => {
+            let k = args.ps_folds.unwrap_or(DIAGNOSTIC_FOLDS);
+            let folds = assign_folds(data.height(), k, &mut rng(args.seed));
+            let (_, exclude) = check_separation(data, args)?;
+            let (scores, _) =
+                cross_fit_propensities(data, &args.treatment, &exclude, args.ps_model, &args.logit, &folds)?;
+            Some((auc(&scores, &d), k))
+        }
+    };
+    let diagnostics = PsDiagnostics {
+        model: args.ps_model,
+        coefficients,
+        auc: auc(&p, &d),
+        cv_auc,
+        calibration: calibration_table(&p, &d)
+    };
+    let cv = cv_auc.map_or("none".to_string(), |(auc, _)| auc.to_string());
+    record(
+        "ps-diagnostics",
+        Some(data.height()),
+        None,
+        start.elapsed(),
+        &format!("auc={} cv_auc={}", diagnostics.auc, cv)
+    );
+    Ok(diagnostics)
+}
+
+// Columns the covariate selection leaves out of the propensity model
+//
+// The outcomes and the event time of a survival outcome never predict
+// treatment, since they are measured after it, and survey weights weight
+// the fit instead of predicting, and neither do the survey strata and PSU
+// ids or the `--cluster` ids. With `--covariates` every column that isn't
+// listed is left out as well, and so is every column listed in `--exclude`
+// or dropped by `--select`. A `--formula` selects the columns its terms are
+// built from. Names that aren't columns of `data` are an error.
+pub fn unselected_columns(data: &DataFrame, args: &Args) -> Result<Vec<String>, Box<dyn Error>> {
+    let names = data.get_column_names();
+    let (option, selected) = match &args.logit.formula {
+        Some(formula) => ("formula", formula.columns()),
+        None => ("covariates", args.covariates.clone())
+    };
+    let listed = selected.iter().map(|c| (option, c));
+    for (option, column) in listed.chain(args.exclude.iter().map(|c| ("exclude", c))) {
+        if !names.contains(&column.as_str()) {
+            return Err(format!(
+                "`{}` of `--{}` is not in the data; available columns are: {}",
+                column,
+                option,
+                names.join(", ")
+            ).into());
+        }
+    }
+    let mut exclude = vec![args.outcome.clone()];
+    let survey = args.survey.weights.iter().chain(&args.survey.strata).chain(&args.survey.psu);
+    let design = survey.chain(&args.cluster);
+    let outcomes = args.extra_outcomes.iter().chain(&args.placebo_outcomes).chain(&args.event);
+    exclude.extend(outcomes.chain(design).cloned());
+    for name in names {
+        let unlisted = !selected.is_empty() && !selected.iter().any(|c| c == name);
+        let excluded = args.exclude.iter().chain(&args.deselected).any(|c| c == name);
+        if (unlisted || excluded) && !exclude.iter().any(|c| c == name) {
+            exclude.push(name.to_string());
+        }
+    }
+    Ok(exclude)
+}
+
+// Covariates of the bias correction (`--bias-correct`) and the regression
+// adjustment (`--regression-adjust`)
+//
+// The predictors of the propensity model, dummy coded the same way: every
+// column `unselected_columns` keeps, so `--covariates`, `--exclude` and
+// `--formula` select them and no outcome, survey or cluster column adjusts
+// an outcome. The pre-period outcome of `--pre` is an outcome as well and
+// is left out. Categorical levels are read from both samples: the matched
+// samples here, the treated units and the control pool where the matchers
+// use the same covariates.
+pub fn adjustment_covariates(treat: &DataFrame, control: &DataFrame, args: &Args) -> PolarsResult<Vec<String>> {
+    let matched = treat.vstack(control)?;
+    let mut exclude =
+        unselected_columns(&matched, args).map_err(|e| PolarsError::ComputeError(e.to_string().into()))?;
+    exclude.extend(args.pre.iter().cloned());
+    predictor_names(&matched, &args.treatment, &exclude)
+}
+
+// Covariates of the balance diagnostics and plots
+//
+// Those of `adjustment_covariates` with the levels of the treated units and
+// the whole control pool, sorted by name so that every per-covariate table
+// has a fixed order. Covariates `--select` dropped from the propensity
+// model are kept, so the tables show whether they balanced anyway.
+pub fn balance_covariates(treat: &DataFrame, pool: &DataFrame, args: &Args) -> PolarsResult<Vec<String>> {
+    let candidates = Args { deselected: Vec::new(), ..args.clone() };
+    let mut covariates = adjustment_covariates(treat, pool, &candidates)?;
+    covariates.sort();
+    Ok(covariates)
+}
+
+// Check the data the propensity model is fit on for separation
+//
+// Returns the separating predictors and the columns to leave out of the
+// model: those `unselected_columns` leaves out, and with
+// `--drop-separating` the separating predictors too. Only predictors the
+// model would use are checked.
+pub fn check_separation(train: &DataFrame, args: &Args) -> Result<(Vec<Separation>, Vec<String>), Box<dyn Error>> {
+    let mut exclude = unselected_columns(train, args)?;
+    let mut separated = separation(train, &args.treatment, &exclude)?;
+    if args.drop_separating {
+        for s in separated.iter_mut() {
+            s.dropped = true;
+            exclude.push(s.covariate.clone());
+        }
+    }
+    Ok((separated, exclude))
+}
+
+// Record the propensity model fit in the audit log.
+fn record_fit(
+    rows_in: usize,
+    rows_out: usize,
+    elapsed: Duration,
+    model: PropensityModel,
+    fit: LogitFit,
+    separated: &[Separation],
+    cached: bool
+) {
+    let separated: Vec<&str> = separated.iter().map(|s| s.covariate.as_str()).collect();
+    record(
+        "propensity",
+        Some(rows_in),
+        Some(rows_out),
+        elapsed,
+        &format!(
+            "model={} max_gradient={:e} converged={} log_likelihood={} iterations={} separating={} cached={}",
+            model,
+            fit.max_gradient,
+            fit.converged(),
+            fit.log_likelihood,
+            fit.iterations.map_or("-".to_string(), |i| i.to_string()),
+            separated.join(","),
+            cached
+        )
+    );
+}
+
+// Drop or clip the units with extreme propensity scores
+//
+// With `--trim` or `--exclude-extreme` the units whose score lies outside
+// the `--extreme-ps` thresholds are dropped or have their scores clipped
+// to them, which is recorded in the audit log with the units affected in
+// each arm. Otherwise `strike` is returned as it is.
+fn trim_scores(strike: &DataFrame, args: &Args) -> PolarsResult<DataFrame> {
+    let ((lower, upper), trim) = match (args.extreme_ps, args.trim) {
+        (Some(bounds), Some(trim)) => (bounds, trim),
+        _ => return Ok(strike.clone())
+    };
+    let start = Instant::now();
+    let d = column_values(strike, &args.treatment)?;
+    let p = column_values(strike, "propensities")?;
+    let extreme = |group: f64| (0..d.len()).filter(|i| d[*i] == group && !(lower..=upper).contains(&p[*i])).count();
+    let trimmed = trim_extreme(strike, lower, upper, trim)?;
+    record(
+        "trim-extreme",
+        Some(strike.height()),
+        Some(trimmed.height()),
+        start.elapsed(),
+        &format!(
+            "method={} lower={} upper={} treated={} control={}",
+            trim,
+            lower,
+            upper,
+            extreme(1.0),
+            extreme(0.0)
+        )
+    );
+    Ok(trimmed)
+}
+
+// Check the common support and discard the units outside it
+//
+// With `--common-support` or `--discard` the range of each arm's scores is
+// checked. With `--discard off-support` the units outside the range of the
+// arm they are matched to are then dropped, which is recorded in the audit
+// log with the units dropped in each arm. Otherwise `strike` is returned as
+// it is.
+fn support_scores(strike: &DataFrame, args: &Args) -> PolarsResult<(DataFrame, Option<CommonSupport>)> {
+    if !args.common_support && args.discard.is_none() {
+        return Ok((strike.clone(), None));
+    }
+    let mut support = common_support(strike, &args.treatment)?;
+    let strike = match args.discard {
+        Some(Discard::OffSupport) => {
+            let start = Instant::now();
+            let matched = match args.estimand {
+                Estimand::Att => (true, false),
+                Estimand::Atc => (false, true),
+                Estimand::Ate => (true, true)
+            };
+            let kept = discard_off_support(strike, &args.treatment, &mut support, matched)?;
+            let (treated, controls) = support.discarded.unwrap_or_default();
+            record(
+                "discard",
+                Some(strike.height()),
+                Some(kept.height()),
+                start.elapsed(),
+                &format!("rule=off-support treated={} control={}", treated, controls)
+            );
+            kept
+        },
+        None => strike.clone()
+    };
+    Ok((strike, Some(support)))
+}
+
+// Match and estimate on a DataFrame that already has propensity scores
+//
+// Perform 1:1 matching (k:1 with `--ratio k`) on the propensity scores,
+// with replacement unless `--no-replace`, and calculate the ATT and
+// variance. If an outcome transformation is requested it is applied to the
+// matched samples, so the ATT and variance are on the transformed scale.
+fn match_and_estimate(
+    strike: &DataFrame,
+    fit: LogitFit,
+    separation: Vec<Separation>,
+    args: &Args
+) -> Result<Strike, Box<dyn Error>> {
+    let (treatment, outcome) = (args.treatment.as_str(), args.outcome.as_str());
+    validate_propensities(strike)?;
+    let extreme = match args.extreme_ps {
+        Some((lower, upper)) => Some(extreme_units(strike, treatment, outcome, lower, upper, args.trim)?),
+        None => None
+    };
+    let strike = trim_scores(strike, args)?;
+    let (strike, support) = support_scores(&strike, args)?;
+    let strike = match args.estimand {
+        Estimand::Atc => swap_roles(&strike, treatment)?,
+        Estimand::Att | Estimand::Ate => strike
+    };
+    let start = Instant::now();
+    let (strike_treat, strike_pool) = treat_control_split(&strike, treatment)?;
+    let (matched_treat, strike_control, cached) = match_controls(&strike_treat, &strike_pool, args)?;
+    record(
+        "match",
+        Some(strike.height()),
+        Some(strike_control.height()),
+        start.elapsed(),
+        &format!(
+            "method={} distance={} penalty={:?} ratio={} replace={} max_reuse={:?} ties={} treated={} pool={} \
+             cached={}",
+            args.matcher.as_deref().unwrap_or(DEFAULT_MATCHER),
+            args.distance,
+            args.covariate_penalty,
+            args.ratio.unwrap_or(1),
+            !args.no_replace,
+            args.max_reuse,
+            args.ties,
+            strike_treat.height(),
+            strike_pool.height(),
+            cached
+        )
+    );
+    let reverse = match args.estimand {
+        Estimand::Ate => {
+            let start = Instant::now();
+            let (controls, matched_treat, cached) = match_controls(&strike_pool, &strike_treat, args)?;
+            record(
+                "match",
+                Some(strike.height()),
+                Some(matched_treat.height()),
+                start.elapsed(),
+                &format!("direction=control-to-treated controls={} cached={}", strike_pool.height(), cached)
+            );
+            Some((controls, matched_treat))
+        },
+        Estimand::Att | Estimand::Atc => None
+    };
+    let strike_treat = matched_treat;
+    let (strike_treat, strike_control, caliper_drop) = match args.caliper {
+        Some(caliper) => {
+            let start = Instant::now();
+            let scores = column_values(&strike, "propensities")?;
+            let (treat, control, drop) = apply_caliper(&strike_treat, &strike_control, &scores, caliper)?;
+            record(
+                "caliper",
+                Some(strike_control.height()),
+                Some(control.height()),
+                start.elapsed(),
+                &format!("caliper={:?} width={} dropped={}", caliper, drop.width, drop.dropped)
+            );
+            (treat, control, Some(drop))
+        },
+        None => (strike_treat, strike_control, None)
+    };
+    let mut strike = estimate_pairs(&strike_treat, &strike_control, strike_pool, args)?;
+    // With the roles swapped the pairs estimate control minus treated
+    if args.estimand == Estimand::Atc {
+        strike.att = -strike.att;
+    }
+    if let Some((controls, matched_treat)) = reverse {
+        let ate = estimate_ate(&strike, &controls, &matched_treat, args)?;
+        strike.att = ate.ate;
+        strike.att_variance = ate.ate_variance;
+        strike.ate = Some(ate);
+    }
+    strike.caliper_drop = caliper_drop;
+    strike.extreme = extreme;
+    strike.support = support;
+    strike.fit = Some(fit);
+    strike.separation = separation;
+    Ok(strike)
+}
+
+// Match every treated unit to a control
+//
+// Uses the `--matcher` (nearest neighbor by default). With `--cache` the
+// matches depend only on the matcher and the ids and propensity scores of
+// the treated units and the control pool, so they are read from the cache
+// when those are unchanged (e.g. when only the outcome differs) and saved
+// to it otherwise. Matching that also looks at the covariates or the
+// outcome (`genetic`, `--covariate-penalty`, `--prognostic`) is keyed by
+// all the data instead. A treated unit with several matches (`--ratio`,
+// `--ties all-average`) is repeated once per match. Returns the treated
+// units and their matched controls, row i of both forming a pair, and
+// whether the matches came from the cache.
+fn match_controls(
+    treat: &DataFrame,
+    pool: &DataFrame,
+    args: &Args
+) -> Result<(DataFrame, DataFrame, bool), Box<dyn Error>> {
+    let name = args.matcher.as_deref().unwrap_or(DEFAULT_MATCHER);
+    let method = matcher(name).ok_or_else(|| format!("Unknown matcher `{}`", name))?;
+    let scaled_treat = on_distance_scale(treat, args.distance)?;
+    let scaled_pool = on_distance_scale(pool, args.distance)?;
+    if args.ties == Ties::AllAverage {
+        let mut rng = rng(args.seed);
+        let (control, counts) = tied_match(&scaled_treat, &scaled_pool, args.ties, &mut rng, &args.cancel)?;
+        return Ok((repeat_rows(treat, &counts)?, unscaled(pool, &control, name)?, false));
+    }
+    let ratio = args.ratio.unwrap_or(1);
+    let matched_treat = match ratio {
+        1 => treat.clone(),
+        k => repeat_rows(treat, &vec![k; treat.height()])?
+    };
+    let columns = ["strike_id", "propensities"];
+    let cache = match &args.cache {
+        Some(dir) => {
+            let replace = match (args.no_replace, args.max_reuse) {
+                (true, _) => format!("no order={}", args.match_order),
+                (false, Some(n)) => format!("max-reuse={} order={}", n, args.match_order),
+                (false, None) => "yes".to_string()
+            };
+            let options = format!(
+                "match matcher={} distance={} penalty={:?} prognostic={} outcome={} transform={:?} ratio={} \
+                 replace={} ties={} seed={:?}",
+                name,
+                args.distance,
+                args.covariate_penalty,
+                args.prognostic,
+                args.outcome,
+                args.outcome_transform,
+                ratio,
+                replace,
+                args.ties,
+                args.seed
+            );
+            let key = match name == GENETIC_MATCHER || args.covariate_penalty.is_some() || args.prognostic {
+                true => cache_key(&[treat, pool], &options)?,
+                false => cache_key(&[&treat.select(columns)?, &pool.select(columns)?], &options)?
+            };
+            Some((dir.as_str(), key))
+        },
+        None => None
+    };
+    if let Some(controls) = cache.as_ref().and_then(|(dir, key)| load_matches(dir, key)) {
+        if let Some(rows) = pool_rows(pool, &controls)?.filter(|rows| rows.len() == treat.height() * ratio) {
+            return Ok((matched_treat, pool.take(&IdxCa::from_vec("matches", rows))?, true));
+        }
+    }
+    let plain = name == DEFAULT_MATCHER
+        && ratio == 1
+        && !args.no_replace
+        && args.max_reuse.is_none()
+        && args.covariate_penalty.is_none()
+        && !args.prognostic
+        && args.ties == Ties::First;
+    let control = match (&args.budget, plain) {
+        (Some(budget), true) => budgeted_match(&scaled_treat, &scaled_pool, budget, args)?,
+        _ => method.match_controls(&scaled_treat, &scaled_pool, args)?
+    };
+    if control.height() != treat.height() * ratio {
+        return Err(format!(
+            "Matcher `{}` returned {} controls for {} treated units",
+            name,
+            control.height(),
+            treat.height()
+        ).into());
+    }
+    let control = unscaled(pool, &control, name)?;
+    if let Some((dir, key)) = &cache {
+        let controls: Vec<i64> = column_values(&control, "strike_id")?.iter().map(|id| *id as i64).collect();
+        store_matches(dir, key, &controls)?;
+    }
+    Ok((matched_treat, control, false))
+}
+
+// Rows of `pool` holding the units with `strike_id`s `ids`, in order, or
+// `None` if one of them isn't in the pool.
+fn pool_rows(pool: &DataFrame, ids: &[i64]) -> PolarsResult<Option<Vec<IdxSize>>> {
+    let rows: HashMap<i64, IdxSize> = column_values(pool, "strike_id")?
+        .iter()
+        .enumerate()
+        .map(|(row, id)| (*id as i64, row as IdxSize))
+        .collect();
+    Ok(ids.iter().map(|id| rows.get(id).copied()).collect())
+}
+
+// The matched `control` rows as they are in `pool`, with their propensity
+// scores back on the probability scale after matching on another
+// `--distance`. A control that isn't in the pool is an error of `matcher`.
+fn unscaled(pool: &DataFrame, control: &DataFrame, matcher: &str) -> PolarsResult<DataFrame> {
+    let ids: Vec<i64> = column_values(control, "strike_id")?.iter().map(|id| *id as i64).collect();
+    let rows = pool_rows(pool, &ids)?.ok_or_else(|| {
+        let message = format!("Matcher `{}` returned controls that are not in the control pool", matcher);
+        PolarsError::ComputeError(message.into())
+    })?;
+    pool.take(&IdxCa::from_vec("matches", rows))
+}
+
+// Nearest neighbor matching within the `--time-budget`
+//
+// Matches the first `PROBE_UNITS` treated units by scanning the pool and
+// projects how long the rest would take. If that would use more than half
+// of the remaining budget, the rest are matched through a sorted index of
+// the pool instead, which finds the same pairs in a fraction of the time.
+fn budgeted_match(treat: &DataFrame, pool: &DataFrame, budget: &Budget, args: &Args) -> PolarsResult<DataFrame> {
+    let start = Instant::now();
+    let probe = treat.head(Some(PROBE_UNITS));
+    let mut control = nn_match(&probe, &mut pool.clone(), &args.cancel)?;
+    let rest = treat.slice(probe.height() as i64, treat.height());
+    if rest.height() == 0 {
+        return Ok(control);
+    }
+    let projected = start.elapsed().mul_f64(rest.height() as f64 / probe.height() as f64);
+    let rest = match budget.fits(projected) {
+        true => nn_match(&rest, &mut pool.clone(), &args.cancel)?,
+        false => {
+            budget.apply("matching", "sorted control index instead of a full scan; the pairs are the same");
+            index_match(&rest, pool, &args.cancel)?
+        }
+    };
+    control.vstack_mut(&rest)?;
+    Ok(control)
+}
+
+// Name, ATT and variance of every outcome of `strike`, the first outcome
+// first.
+fn outcome_estimates(strike: &Strike) -> Vec<(String, f64, f64)> {
+    let first = (strike.outcome.clone(), strike.att, strike.att_variance);
+    let extra = strike.extra_outcomes.iter().map(|e| (e.outcome.clone(), e.att, e.att_variance));
+    std::iter::once(first).chain(extra).collect()
+}
+
+// Estimate the ATT and its variance from matched pairs
+//
+// Row i of `control` is the match of row i of `treat`. Applies any outcome
+// transformation, runs the `--estimator` (Abadie-Imbens by default) and
+// assembles the results; the caller fills in the propensity model
+// diagnostics.
+pub fn estimate_pairs(
+    treat: &DataFrame,
+    control: &DataFrame,
+    pool: DataFrame,
+    args: &Args
+) -> Result<Strike, Box<dyn Error>> {
+    let outcome = args.outcome.as_str();
+    let (mut strike_treat, mut strike_control) = order_by_id(treat, control)?;
+    let start = Instant::now();
+    if let Some(t) = args.outcome_transform {
+        t.apply(&mut strike_treat, outcome)?;
+        t.apply(&mut strike_control, outcome)?;
+    }
+    let name = args.estimator.as_deref().unwrap_or(DEFAULT_ESTIMATOR);
+    let method = estimator(name).ok_or_else(|| format!("Unknown estimator `{}`", name))?;
+    let missing = match args.missing_outcome {
+        Some(_) => {
+            let probabilities = observation_probabilities(&treat.vstack(&pool)?, args)?;
+            Some(calculate_ipw_att(&strike_treat, &strike_control, outcome, &probabilities)?)
+        },
+        None => None
+    };
+    let survival = match &args.event {
+        Some(event) => Some(survival_contrast(&strike_treat, &strike_control, outcome, event, args.horizon)?),
+        None => None
+    };
+    // The control pool on the outcome's scale, for estimators that use it
+    let mut scaled_pool = pool.clone();
+    if let Some(t) = args.outcome_transform {
+        t.apply(&mut scaled_pool, outcome)?;
+    }
+    let (att, att_variance) = match (&missing, &survival) {
+        (Some(ipw), _) => (ipw.att, ipw.att_variance),
+        (None, Some(survival)) => (survival.log_hazard_ratio, survival.log_hazard_ratio_variance),
+        (None, None) => method.estimate_with_pool(&strike_treat, &strike_control, &scaled_pool, args)?
+    };
+    if let Some(budget) = &args.budget {
+        budget.set_estimate_time(start.elapsed());
+    }
+    record(
+        "estimate",
+        Some(strike_treat.height()),
+        None,
+        start.elapsed(),
+        &format!(
+            "estimator={} outcome={} transform={:?} att={} variance={}",
+            match (&missing, &survival) {
+                (Some(_), _) => "missing-outcome-ipw",
+                (None, Some(_)) => "log-rank",
+                (None, None) => name
+            },
+            outcome,
+            args.outcome_transform,
+            att,
+            att_variance
+        )
+    );
+    let mut extra_outcomes = Vec::with_capacity(args.extra_outcomes.len() + args.placebo_outcomes.len());
+    for outcome in args.extra_outcomes.iter().chain(&args.placebo_outcomes) {
+        let start = Instant::now();
+        if let Some(t) = args.outcome_transform {
+            t.apply(&mut strike_treat, outcome)?;
+            t.apply(&mut strike_control, outcome)?;
+            t.apply(&mut scaled_pool, outcome)?;
+        }
+        // The primary outcome joins the other outcomes, so it never adjusts this one
+        let outcomes = std::iter::once(args.outcome.clone()).chain(args.extra_outcomes.iter().cloned()).collect();
+        let outcome_args = Args { outcome: outcome.clone(), extra_outcomes: outcomes, ..args.clone() };
+        let (att, att_variance) =
+            method.estimate_with_pool(&strike_treat, &strike_control, &scaled_pool, &outcome_args)?;
+        record(
+            "estimate",
+            Some(strike_treat.height()),
+            None,
+            start.elapsed(),
+            &format!(
+                "estimator={} outcome={} transform={:?} att={} variance={}",
+                name,
+                outcome,
+                args.outcome_transform,
+                att,
+                att_variance
+            )
+        );
+        extra_outcomes.push(OutcomeEstimate { outcome: outcome.clone(), att, att_variance });
+    }
+    let start = Instant::now();
+    let pairs = strike_treat.height();
+    let mut strike = Strike::new(att, att_variance, strike_treat, strike_control, pool, args)?;
+    strike.missing = missing;
+    strike.survival = survival;
+    strike.placebo = extra_outcomes.split_off(args.extra_outcomes.len());
+    strike.extra_outcomes = extra_outcomes;
+    if !strike.extra_outcomes.is_empty() {
+        let band = match args.joint_bootstrap {
+            Some(replicates) => {
+                let outcomes: Vec<String> = outcome_estimates(&strike).into_iter().map(|(o, _, _)| o).collect();
+                let (treat, control) = (&strike.treat, &strike.control);
+                let band = joint_band(treat, control, &outcomes, replicates, &mut rng(args.seed), &args.cancel);
+                unless_cancelled(band, "joint bootstrap", args, &mut strike.cancelled)?
+            },
+            None => None
+        };
+        strike.simultaneous = Some(Simultaneous::new(&outcome_estimates(&strike), args.adjustment, band));
+    }
+    record("diagnostics", Some(pairs), None, start.elapsed(), "");
+    Ok(strike)
+}
+
+// Swap the roles of treated and control units
+//
+// Flips the treatment indicator and the propensity scores, so that the
+// controls are matched to treated units as if they were the treated ones
+// and their scores are the probability of being a control.
+fn swap_roles(data: &DataFrame, treatment: &str) -> PolarsResult<DataFrame> {
+    let mut data = data.clone();
+    let flipped: Vec<i64> = column_values(&data, treatment)?.iter().map(|d| 1 - *d as i64).collect();
+    let scores: Vec<f64> = column_values(&data, "propensities")?.iter().map(|p| 1.0 - p).collect();
+    data.with_column(Series::new(treatment, flipped))?;
+    data.with_column(Series::new("propensities", scores))?;
+    Ok(data)
+}
+
+// Estimate the ATE from both matching directions
+//
+// `strike` holds the ATT pairs, already on the `--outcome-transform` scale,
+// and row i of `matched_treat` is the match of row i of `controls`.
+fn estimate_ate(
+    strike: &Strike,
+    controls: &DataFrame,
+    matched_treat: &DataFrame,
+    args: &Args
+) -> Result<Ate, Box<dyn Error>> {
+    let start = Instant::now();
+    let outcome = args.outcome.as_str();
+    let (mut controls, mut matched_treat) = order_by_id(controls, matched_treat)?;
+    if let Some(t) = args.outcome_transform {
+        t.apply(&mut controls, outcome)?;
+        t.apply(&mut matched_treat, outcome)?;
+    }
+    let neighbors = args.variance_neighbors.unwrap_or(DEFAULT_VARIANCE_NEIGHBORS);
+    let (treated, matched) = ((&strike.treat, &strike.control), (&controls, &matched_treat));
+    let ate = calculate_ate(treated, matched, outcome, neighbors, &args.cancel)?;
+    record(
+        "ate",
+        Some(strike.treat.height() + controls.height()),
+        None,
+        start.elapsed(),
+        &format!("att={} atc={} ate={} variance={}", ate.att, ate.atc, ate.ate, ate.ate_variance)
+    );
+    Ok(ate)
+}
+
+// Update a saved session with rows appended to its data
+//
+// The first `session.rows` rows of `data` must be the rows the session was
+// created from, unchanged. Rather than refitting and rematching everything,
+// this scores every row with the session's propensity model and matches
+// only what the appended rows can change: a treated unit from the session
+// keeps its control unless an appended control is strictly closer, and an
+// appended treated unit is matched against the whole control pool. Ties
+// resolve exactly as in `nn_match`, so the pairs equal those of a full
+// rematch with the session's model. The ATT and variance are then computed
+// on all pairs, so reuse counts reflect the appended controls.
+pub fn rematch(data: &DataFrame, session: &Session, args: &Args) -> Result<Strike, Box<dyn Error>> {
+    let treatment = args.treatment.as_str();
+    if session.treatment != args.treatment || session.outcome != args.outcome {
+        return Err(format!(
+            "The session is for treatment `{}` and outcome `{}`",
+            session.treatment,
+            session.outcome
+        ).into());
+    }
+    let start = Instant::now();
+    validate(data, treatment, &args.outcome)?;
+    check_missing_outcome(data, args)?;
+    if data.height() < session.rows {
+        return Err(format!(
+            "The session covers {} rows but the data has only {}; a session can only be updated with appended rows",
+            session.rows,
+            data.height()
+        ).into());
+    }
+    record("validate", Some(data.height()), Some(data.height()), start.elapsed(), "");
+    let start = Instant::now();
+    let mut data = data.clone();
+    add_ids(&mut data)?;
+    data.with_column(Series::new("propensities", session.model.score(&data)?))?;
+    validate_propensities(&data)?;
+    record(
+        "propensity",
+        Some(data.height()),
+        Some(data.height()),
+        start.elapsed(),
+        &format!("model=session appended={}", data.height() - session.rows)
+    );
+    let start = Instant::now();
+    let (strike_treat, strike_pool) = treat_control_split(&data, treatment)?;
+    let treat_ps = column_values(&strike_treat, "propensities")?;
+    let treat_ids = column_values(&strike_treat, "strike_id")?;
+    let pool_ps = column_values(&strike_pool, "propensities")?;
+    let pool_ids = column_values(&strike_pool, "strike_id")?;
+    let saved: HashMap<i64, i64> = session.pairs.iter().copied().collect();
+    let pool_rows: HashMap<i64, usize> = pool_ids.iter().enumerate().map(|(row, id)| (*id as i64, row)).collect();
+    let everyone: Vec<usize> = (0..pool_ids.len()).collect();
+    let appended: Vec<usize> = everyone.iter().copied().filter(|row| pool_ids[*row] as usize > session.rows).collect();
+    let n = treat_ids.len();
+    let mut matched = Vec::with_capacity(n);
+    let mut kept = 0;
+    for (i, (p, id)) in treat_ps.iter().zip(&treat_ids).enumerate() {
+        args.cancel.check(|| format!("while matching after {} of {} units", i, n))?;
+        let id = *id as i64;
+        let (candidates, mut best) = match saved.get(&id) {
+            Some(control) => match pool_rows.get(control) {
+                Some(row) => (&appended, Some(*row)),
+                None => return Err(format!("Control {} of the session is not a control unit in the data", control).into())
+            },
+            None if id as usize <= session.rows => {
+                return Err(format!("Treated unit {} has no match in the session; were rows changed?", id).into())
+            },
+            None => (&everyone, None)
+        };
+        let saved_row = best;
+        for row in candidates {
+            if best.is_none_or(|b| (pool_ps[*row] - p).abs() < (pool_ps[b] - p).abs()) {
+                best = Some(*row);
+            }
+        }
+        if best.is_some() && best == saved_row {
+            kept += 1;
+        }
+        matched.push(best.ok_or("There are no control units to match")? as IdxSize);
+    }
+    if treat_ids.iter().filter(|id| **id as usize <= session.rows).count() != saved.len() {
+        return Err("The session has matches for units that are not treated in the data".into());
+    }
+    let strike_control = strike_pool.take(&IdxCa::from_vec("matches", matched))?;
+    record(
+        "match",
+        Some(data.height()),
+        Some(strike_treat.height()),
+        start.elapsed(),
+        &format!(
+            "method=incremental ratio=1 replace=true treated={} pool={} kept={}",
+            strike_treat.height(),
+            strike_pool.height(),
+            kept
+        )
+    );
+    let mut strike = estimate_pairs(&strike_treat, &strike_control, strike_pool, args)?;
+    strike.model = Some(session.model.clone());
+    Ok(strike)
+}
+
+// Combine the two halves of a cross-fit estimate
+//
+// The ATT is the average of the two half-sample ATTs and its variance is
+// a quarter of the sum of their variances. That assumes the two estimates
+// are independent, which they are not quite: each half fits the model the
+// other is estimated with, so the variance can be somewhat too small (a
+// cross-fitting bootstrap would account for it). The ATE, the
+// missing-outcome and the survival estimates are averaged the same way,
+// everything else is computed on the pooled matched samples, and each
+// half's covariate selection is kept.
+fn cross_fit(first: Strike, second: Strike, args: &Args) -> Result<Strike, Box<dyn Error>> {
+    let (treat, control) = order_by_id(
+        &first.treat.vstack(&second.treat)?,
+        &first.control.vstack(&second.control)?
+    )?;
+    let pool = sort_by_id(&first.pool.vstack(&second.pool)?)?;
+    let mut strike = Strike::new(
+        (first.att + second.att) / 2.0,
+        (first.att_variance + second.att_variance) / 4.0,
+        treat,
+        control,
+        pool,
+        args
+    )?;
+    strike.ate = first.ate.zip(second.ate).map(|(a, b)| a.combine(b));
+    strike.missing = first.missing.zip(second.missing).map(|(a, b)| a.combine(b));
+    strike.survival = first.survival.zip(second.survival).map(|(a, b)| a.combine(b));
+    let combine = |first: Vec<OutcomeEstimate>, second: Vec<OutcomeEstimate>| -> Vec<OutcomeEstimate> {
+        first
+            .into_iter()
+            .zip(second)
+            .map(|(a, b)| OutcomeEstimate {
+                outcome: a.outcome,
+                att: (a.att + b.att) / 2.0,
+                att_variance: (a.att_variance + b.att_variance) / 4.0
+            })
+            .collect()
+    };
+    strike.extra_outcomes = combine(first.extra_outcomes, second.extra_outcomes);
+    if !strike.extra_outcomes.is_empty() {
+        strike.simultaneous = Some(Simultaneous::new(&outcome_estimates(&strike), args.adjustment, None));
+    }
+    strike.placebo = combine(first.placebo, second.placebo);
+    strike.extreme = first.extreme.zip(second.extreme).map(|(a, b)| a.combine(b));
+    strike.support = first.support.zip(second.support).map(|(a, b)| a.combine(b));
+    strike.fit = first.fit.zip(second.fit).map(|(a, b)| a.worst(b));
+    strike.selection = first.selection.into_iter().chain(second.selection).collect();
+    strike.separation = first.separation;
+    for s in second.separation {
+        if !strike.separation.iter().any(|f| f.covariate == s.covariate) {
+            strike.separation.push(s);
+        }
+    }
+    Ok(strike)
+}
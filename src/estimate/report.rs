@@ -0,0 +1,309 @@
+use std::fmt;
+
+use crate::att::{Estimand, Location};
+use crate::equivalence::tost;
+use crate::format::num;
+use crate::paired::normal_cdf;
+use crate::registry::{DEFAULT_ESTIMATOR, DEFAULT_MATCHER};
+use crate::sensitivity::{e_values, smd_e_values};
+use crate::split::SampleSplit;
+
+use super::{n_treat_control, Strike};
+
+// Implement `Display` for `Strike`.
+impl fmt::Display for Strike {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (lb, ub) = (
+            self.att - 1.96 * self.att_variance.sqrt(),
+            self.att + 1.96 * self.att_variance.sqrt()
+        );
+        let (n_treat, n_control) = n_treat_control(&self.treat, &self.control).unwrap();
+        let estimand = self.estimand.to_string().to_uppercase();
+        let groups = match self.estimand {
+            Estimand::Atc => ("Control", "Treat"),
+            Estimand::Att | Estimand::Ate => ("Treat", "Control")
+        };
+        let split_note = match self.split {
+            Some(SampleSplit::Split) => "Propensity model fit on a held-out half of the sample\n\n".to_string(),
+            Some(SampleSplit::CrossFit) => format!("Cross-fit: {} averaged over both sample halves\n\n", estimand),
+            None => String::new()
+        };
+        let split_note = match self.ps_folds {
+            Some(k) => format!("{}Propensity scores cross-fit over {} folds\n\n", split_note, k),
+            None => split_note
+        };
+        let split_note = match &self.pscore_col {
+            Some(column) => format!("{}Propensity scores supplied in `{}`\n\n", split_note, column),
+            None => split_note
+        };
+        let method_note = match self.matcher != DEFAULT_MATCHER || self.estimator != DEFAULT_ESTIMATOR {
+            true => format!("Matcher: {} | Estimator: {}\n\n", self.matcher, self.estimator),
+            false => String::new()
+        };
+        let missing_note = match &self.missing {
+            Some(missing) => format!("{}\n", missing),
+            None => String::new()
+        };
+        let caliper_note = match &self.caliper_drop {
+            Some(drop) => format!("{}\n", drop),
+            None => String::new()
+        };
+        let att_label = match (self.bias_corrected, &self.survival, self.location) {
+            (_, Some(_), _) => "Log hazard ratio".to_string(),
+            (true, None, _) => format!("Bias-corrected {}", estimand),
+            (false, None, Location::Mean) => estimand.clone(),
+            (false, None, Location::Median) => format!("Median {}", estimand),
+            (false, None, Location::Trimmed(trim)) => format!("Trimmed {} ({}%)", estimand, trim * 100.0)
+        };
+        let att_label = match self.transform {
+            Some(t) => format!("{} ({} scale)", att_label, t),
+            None => att_label
+        };
+        let outcome_note = match (&self.pre, self.extra_outcomes.is_empty()) {
+            (Some(pre), _) => format!("Difference-in-differences: change in {} since {}\n", self.outcome, pre),
+            (None, true) => String::new(),
+            (None, false) => format!("Outcome: {}\n", self.outcome)
+        };
+        let mut extra_outcomes = String::new();
+        for extra in &self.extra_outcomes {
+            let se = extra.att_variance.sqrt();
+            extra_outcomes.push_str(&format!(
+                "\nOutcome: {}\n\
+                {:<24}: {}\n\
+                Variance                : {}\n\
+                95% Confidence Interval : ({}, {})\n",
+                extra.outcome,
+                att_label,
+                num(extra.att),
+                num(extra.att_variance),
+                num(extra.att - 1.96 * se),
+                num(extra.att + 1.96 * se)
+            ));
+        }
+        let permutation_note = match &self.permutation {
+            Some(permutation) => permutation.to_string(),
+            None => String::new()
+        };
+        let ate_note = match &self.ate {
+            Some(ate) => format!("ATT | ATC               : {} | {}\n", num(ate.att), num(ate.atc)),
+            None => String::new()
+        };
+        write!(
+            f,
+            "STRIKE =======================================\n\n\
+            # {}: {} | # {} (distinct): {}\n\n\
+            {}{}{}{}{}{:<24}: {}\n\
+            Variance                : {}\n\
+            95% Confidence Interval : ({}, {})\n\
+            {}{}{}\n\
+            Overlap coefficient     : {}\n\
+            C-statistic             : {}\n",
+            groups.0,
+            n_treat,
+            groups.1,
+            n_control,
+            method_note,
+            split_note,
+            missing_note,
+            caliper_note,
+            outcome_note,
+            att_label,
+            num(self.att),
+            num(self.att_variance),
+            num(lb),
+            num(ub),
+            permutation_note,
+            ate_note,
+            extra_outcomes,
+            num(self.overlap),
+            num(self.c_statistic)
+        )?;
+        if !self.warnings.is_empty() {
+            writeln!(f, "\nWarnings")?;
+            for warning in &self.warnings {
+                writeln!(f, "  {}", warning)?;
+            }
+        }
+        if let Some(simultaneous) = &self.simultaneous {
+            write!(f, "\n{}", simultaneous)?;
+        }
+        if let Some(t) = self.transform {
+            let (ratio, ratio_lb, ratio_ub) = t.back_transform(self.att, self.att_variance);
+            write!(
+                f,
+                "\nRatio of geometric means: {}\n\
+                95% CI (delta method)   : ({}, {})\n",
+                num(ratio),
+                num(ratio_lb),
+                num(ratio_ub)
+            )?;
+        }
+        if let Some(survival) = &self.survival {
+            write!(f, "\n{}", survival)?;
+        }
+        if let Some(binary) = &self.binary {
+            write!(f, "\n{}", binary)?;
+        }
+        if self.survival.is_none() {
+            let e_values = match &self.binary {
+                Some(binary) => e_values(binary.risk_ratio, binary.risk_ratio_ci, false),
+                None => smd_e_values(self.att, (lb, ub), self.outcome_sd)
+            };
+            write!(f, "\n{}", e_values)?;
+        }
+        if let Some(margin) = self.equivalence {
+            write!(f, "\n{}", tost(self.att, self.att_variance, margin))?;
+        }
+        if !self.placebo.is_empty() {
+            writeln!(f, "\nPlacebo outcomes (no effect expected)")?;
+            writeln!(f, "{:<24}  {:>12}  {:>12}  {:>12}", "Outcome", estimand, "Std. error", "p-value")?;
+            for placebo in &self.placebo {
+                let se = placebo.att_variance.sqrt();
+                let p_value = 2.0 * (1.0 - normal_cdf((placebo.att / se).abs()));
+                let (att, se, p_value) = (num(placebo.att), num(se), num(p_value));
+                writeln!(f, "{:<24}  {:>12}  {:>12}  {:>12}", placebo.outcome, att, se, p_value)?;
+            }
+        }
+        if let Some(trimmed) = &self.trimmed {
+            let se = trimmed.att_variance.sqrt();
+            write!(
+                f,
+                "\n{:<24}: {}\n\
+                Variance                : {}\n\
+                95% Confidence Interval : ({}, {})\n",
+                format!("Trimmed ATT ({}%)", trimmed.trim * 100.0),
+                num(trimmed.att),
+                num(trimmed.att_variance),
+                num(trimmed.att - 1.96 * se),
+                num(trimmed.att + 1.96 * se)
+            )?;
+        }
+        if let Some(hl) = &self.hodges_lehmann {
+            write!(
+                f,
+                "\nHodges-Lehmann estimate : {}\n\
+                95% Confidence Interval : ({}, {}) {}\n",
+                num(hl.estimate),
+                num(hl.ci.0),
+                num(hl.ci.1),
+                match hl.exact {
+                    true => "exact",
+                    false => "normal approximation"
+                }
+            )?;
+        }
+        if let Some(subclass) = &self.subclass {
+            write!(f, "\n{}", subclass)?;
+        }
+        if let Some(survey) = &self.survey {
+            let se = survey.att_variance.sqrt();
+            write!(
+                f,
+                "\nSurvey design ({} strata, {} PSUs)\n\
+                Weighted ATT            : {}\n\
+                Design-based variance   : {}\n\
+                95% Confidence Interval : ({}, {})\n",
+                survey.n_strata,
+                survey.n_psu,
+                num(survey.att),
+                num(survey.att_variance),
+                num(survey.att - 1.96 * se),
+                num(survey.att + 1.96 * se)
+            )?;
+        }
+        if let Some(cluster) = &self.cluster {
+            let se = cluster.att_variance.sqrt();
+            write!(
+                f,
+                "\nCluster-robust variance ({} clusters)\n\
+                ATT                     : {}\n\
+                Variance                : {}\n\
+                95% Confidence Interval : ({}, {})\n",
+                cluster.n_clusters,
+                num(cluster.att),
+                num(cluster.att_variance),
+                num(cluster.att - 1.96 * se),
+                num(cluster.att + 1.96 * se)
+            )?;
+        }
+        if let Some(adjusted) = &self.adjusted {
+            let se = adjusted.cluster_variance.sqrt();
+            write!(
+                f,
+                "\nRegression adjustment (WLS on matched sample)\n\
+                Unadjusted difference   : {}\n\
+                Adjusted ATT            : {}\n\
+                Robust (HC1) variance   : {}\n\
+                Pair-clustered variance : {}\n\
+                95% Confidence Interval : ({}, {})\n",
+                num(adjusted.unadjusted),
+                num(adjusted.att),
+                num(adjusted.robust_variance),
+                num(adjusted.cluster_variance),
+                num(adjusted.att - 1.96 * se),
+                num(adjusted.att + 1.96 * se)
+            )?;
+        }
+        if let Some(paired) = &self.paired {
+            write!(f, "\n{}", paired)?;
+        }
+        if let Some(mcnemar) = &self.mcnemar {
+            write!(f, "\n{}", mcnemar)?;
+        }
+        if let Some(rosenbaum) = &self.rosenbaum {
+            write!(f, "\n{}", rosenbaum)?;
+        }
+        for (half, selection) in self.selection.iter().enumerate() {
+            match self.selection.len() > 1 {
+                true => write!(f, "\nFitting half {}\n{}", half + 1, selection)?,
+                false => write!(f, "\n{}", selection)?
+            }
+        }
+        if let Some(ps_diagnostics) = &self.ps_diagnostics {
+            write!(f, "\n{}", ps_diagnostics)?;
+        }
+        if let Some(extreme) = &self.extreme {
+            write!(f, "\n{}", extreme)?;
+        }
+        if let Some(support) = &self.support {
+            write!(f, "\n{}", support)?;
+        }
+        if let Some(positivity) = &self.positivity {
+            write!(f, "\n{}", positivity)?;
+        }
+        if let Some(gaps) = &self.covariate_gaps {
+            write!(f, "\n{}", gaps)?;
+        }
+        if let Some(percentiles) = &self.percentiles {
+            write!(f, "\n{}", percentiles)?;
+        }
+        if let Some(subclass_balance) = &self.subclass_balance {
+            write!(f, "\n{}", subclass_balance)?;
+        }
+        if let Some(balance) = &self.balance {
+            write!(f, "\n{}", balance)?;
+        }
+        if let Some(balance_ci) = &self.balance_ci {
+            write!(f, "\n{}", balance_ci)?;
+        }
+        if let Some(caliper) = &self.caliper {
+            write!(f, "\n{}", caliper)?;
+        }
+        if let Some(match_quality) = &self.match_quality {
+            write!(f, "\n{}", match_quality)?;
+        }
+        if let Some(subgroups) = &self.subgroups {
+            write!(f, "\n{}", subgroups)?;
+        }
+        if let Some(weighted) = &self.weighted {
+            write!(f, "\n{}", weighted)?;
+        }
+        if let Some(budget) = &self.budget {
+            write!(f, "\n{}", budget)?;
+        }
+        if !self.cancelled.is_empty() {
+            writeln!(f, "\nCancelled before finishing: {}", self.cancelled.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use polars::datatypes::DataType::{Float64, Int64};
+use polars::prelude::{Expr, GetOutput, NamedFrom, PolarsResult, Series};
+
+use crate::distance::nearest_controls;
+
+// Strike's expressions for polars lazy queries
+//
+// Brings the `strike()` namespace into scope for polars expressions, so
+// that matching can run inside a lazy query:
+//
+//   df.lazy().with_column(
+//       col("propensities").strike().nn_match(col("smoker"), col("id")).alias("match")
+//   )
+//
+// Needs the `lazy` feature.
+pub trait StrikeExpr {
+    fn strike(self) -> StrikeNameSpace;
+}
+
+impl StrikeExpr for Expr {
+    fn strike(self) -> StrikeNameSpace {
+        StrikeNameSpace(self)
+    }
+}
+
+// The `strike()` namespace of an expression of propensity scores.
+pub struct StrikeNameSpace(Expr);
+
+impl StrikeNameSpace {
+    // 1:1 nearest neighbor matching with replacement
+    //
+    // For every treated row (`treatment` is 1), the `id` (cast to Int64) of
+    // the control row (`treatment` is 0) with the closest propensity score,
+    // ties going to the first such row; null for every other row. Within
+    // `over(...)` or a group-by, units are only matched within their group,
+    // i.e. exactly on the grouping columns.
+    pub fn nn_match(self, treatment: Expr, id: Expr) -> Expr {
+        self.0.apply_many(nn_match_series, &[treatment, id], GetOutput::from_type(Int64))
+    }
+}
+
+// The `nn_match` expression on materialized propensities, treatment and ids.
+fn nn_match_series(columns: &mut [Series]) -> PolarsResult<Option<Series>> {
+    let propensities = columns[0].cast(&Float64)?;
+    let treatment = columns[1].cast(&Int64)?;
+    let ids: Vec<Option<i64>> = columns[2].cast(&Int64)?.i64()?.into_iter().collect();
+    let (mut treated, mut pool, mut pool_ids) = (Vec::new(), Vec::new(), Vec::new());
+    for (row, (p, d)) in propensities.f64()?.into_iter().zip(treatment.i64()?).enumerate() {
+        match d {
+            Some(1) => treated.push((row, p.unwrap_or(f64::NAN))),
+            Some(0) => {
+                pool.push(p.unwrap_or(f64::NAN));
+                pool_ids.push(ids[row]);
+            },
+            _ => ()
+        }
+    }
+    let scores: Vec<f64> = treated.iter().map(|(_, p)| *p).collect();
+    let mut matched = vec![None; propensities.len()];
+    for ((row, _), control) in treated.iter().zip(nearest_controls(&scores, &pool)) {
+        matched[*row] = control.and_then(|c| pool_ids[c]);
+    }
+    Ok(Some(Series::new(columns[0].name(), matched)))
+}
@@ -0,0 +1,199 @@
+#[cfg(feature = "lazy")]
+use polars::prelude::{col, lit, Expr};
+use polars::prelude::{DataFrame, PolarsError, PolarsResult};
+use std::time::Instant;
+
+use crate::audit::record;
+use crate::cli::Args;
+
+// Keep the rows of the analysis population
+//
+// Applies the `--where` filter, if any, to freshly read data. Rows for which
+// the filter is false or missing are dropped. The filter and the number of
+// rows it kept are recorded in the audit log.
+pub fn population(data: DataFrame, args: &Args) -> PolarsResult<DataFrame> {
+    let filter = match &args.filter {
+        Some(filter) => filter,
+        None => return Ok(data)
+    };
+    let start = Instant::now();
+    let rows = data.height();
+    let kept = apply_filter(data, filter)?;
+    record("where", Some(rows), Some(kept.height()), start.elapsed(), filter);
+    Ok(kept)
+}
+
+#[cfg(feature = "lazy")]
+fn apply_filter(data: DataFrame, filter: &str) -> PolarsResult<DataFrame> {
+    use polars::prelude::IntoLazy;
+
+    let expr = parse_filter(filter)
+        .map_err(|e| PolarsError::ComputeError(format!("Invalid `--where` filter: {}", e).into()))?;
+    data.lazy().filter(expr).collect()
+}
+
+#[cfg(not(feature = "lazy"))]
+fn apply_filter(_data: DataFrame, _filter: &str) -> PolarsResult<DataFrame> {
+    Err(PolarsError::ComputeError(
+        "`--where` needs the `lazy` feature: cargo build --features lazy".into()
+    ))
+}
+
+// A token of a `--where` filter.
+#[cfg(feature = "lazy")]
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Column(String),
+    Number(String),
+    Text(String),
+    Op(&'static str),
+    Open,
+    Close
+}
+
+// Operators of `--where` filters, longest first so `<=` isn't read as `<`.
+#[cfg(feature = "lazy")]
+const OPERATORS: [&str; 9] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!"];
+
+// Split a filter into tokens.
+#[cfg(feature = "lazy")]
+fn tokenize(filter: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = filter.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..].iter().take(2).collect();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            let end = chars[i + 1..]
+                .iter()
+                .position(|d| *d == c)
+                .ok_or_else(|| format!("Unterminated string starting at position {}", i + 1))?;
+            tokens.push(Token::Text(chars[i + 1..i + 1 + end].iter().collect()));
+            i += end + 2;
+        } else if c.is_ascii_digit() || c == '.' || (c == '-' && chars.get(i + 1).is_some_and(|d| d.is_ascii_digit())) {
+            let len = 1 + chars[i + 1..].iter().take_while(|d| d.is_ascii_digit() || **d == '.').count();
+            tokens.push(Token::Number(chars[i..i + len].iter().collect()));
+            i += len;
+        } else if c.is_alphabetic() || c == '_' {
+            let len = chars[i..].iter().take_while(|d| d.is_alphanumeric() || **d == '_').count();
+            tokens.push(Token::Column(chars[i..i + len].iter().collect()));
+            i += len;
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            i += op.len();
+        } else {
+            return Err(format!("Unexpected `{}` at position {}", c, i + 1));
+        }
+    }
+    Ok(tokens)
+}
+
+// A recursive descent parser of `--where` filters.
+#[cfg(feature = "lazy")]
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize
+}
+
+#[cfg(feature = "lazy")]
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // or := and ("||" and)*
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Op("||")) {
+            self.next();
+            expr = expr.or(self.and()?);
+        }
+        Ok(expr)
+    }
+
+    // and := not ("&&" not)*
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(&Token::Op("&&")) {
+            self.next();
+            expr = expr.and(self.not()?);
+        }
+        Ok(expr)
+    }
+
+    // not := "!" not | comparison
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some(&Token::Op("!")) {
+            self.next();
+            return Ok(self.not()?.not());
+        }
+        self.comparison()
+    }
+
+    // comparison := operand (("==" | "!=" | "<" | "<=" | ">" | ">=") operand)?
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.operand()?;
+        let op = match self.peek() {
+            Some(Token::Op(op)) if !matches!(*op, "&&" | "||" | "!") => *op,
+            _ => return Ok(left)
+        };
+        self.next();
+        let right = self.operand()?;
+        Ok(match op {
+            "==" => left.eq(right),
+            "!=" => left.neq(right),
+            "<" => left.lt(right),
+            "<=" => left.lt_eq(right),
+            ">" => left.gt(right),
+            _ => left.gt_eq(right)
+        })
+    }
+
+    // operand := column | number | string | "(" or ")"
+    fn operand(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Column(name)) => Ok(col(&name)),
+            Some(Token::Number(n)) => match n.parse::<i64>() {
+                Ok(n) => Ok(lit(n)),
+                Err(_) => n.parse::<f64>().map(lit).map_err(|_| format!("Invalid number `{}`", n))
+            },
+            Some(Token::Text(text)) => Ok(lit(text)),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("Missing `)`".to_string())
+                }
+            },
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err("Unexpected end of the filter".to_string())
+        }
+    }
+}
+
+// Parse a `--where` filter into a polars expression
+//
+// Filters compare columns, numbers and quoted strings with `==`, `!=`, `<`,
+// `<=`, `>` and `>=`, and combine the comparisons with `&&`, `||`, `!` and
+// parentheses, e.g. `age >= 18 && region == 'west'`.
+#[cfg(feature = "lazy")]
+pub fn parse_filter(filter: &str) -> Result<Expr, String> {
+    let mut parser = Parser { tokens: tokenize(filter)?, position: 0 };
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected {:?} after a complete filter", token))
+    }
+}
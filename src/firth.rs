@@ -0,0 +1,228 @@
+use ndarray::{Array1, Array2, Axis};
+
+use crate::propensity::LogitOptions;
+
+// Largest coefficient change in a single iteration, which keeps the first
+// steps from overshooting (as in the `logistf` R package).
+pub const MAX_STEP: f64 = 5.0;
+
+// Columns whose residual norm, after projecting out the columns already
+// kept, falls below this share of their own norm are treated as aliased.
+const ALIAS_TOLERANCE: f64 = 1e-7;
+
+// Firth bias-reduced logistic regression
+//
+// Maximizes the log-likelihood penalized by half the log determinant of the
+// Fisher information (Firth, 1993). The penalty removes the first-order bias
+// of the maximum likelihood estimates and, unlike plain maximum likelihood,
+// always yields finite coefficients, even under complete separation or with
+// few treated units. Predictors are standardized before fitting, which
+// leaves the fitted probabilities unchanged, and predictors that are linear
+// combinations of the intercept and earlier predictors (e.g. a full set of
+// dummies) are dropped.
+#[derive(Debug)]
+pub struct FirthLogit {
+    n_columns: usize,
+    columns: Vec<usize>,
+    mean: Vec<f64>,
+    sd: Vec<f64>,
+    beta: Array1<f64>
+}
+
+// Invert a symmetric positive definite matrix with Gauss-Jordan elimination.
+fn invert(a: &Array2<f64>) -> Option<Array2<f64>> {
+    let k = a.nrows();
+    let mut m = a.clone();
+    let mut inv = Array2::<f64>::eye(k);
+    for col in 0..k {
+        let pivot = (col..k).max_by(|i, j| m[[*i, col]].abs().total_cmp(&m[[*j, col]].abs()))?;
+        if m[[pivot, col]].abs() < 1e-12 {
+            return None;
+        }
+        for j in 0..k {
+            m.swap([col, j], [pivot, j]);
+            inv.swap([col, j], [pivot, j]);
+        }
+        let scale = m[[col, col]];
+        for j in 0..k {
+            m[[col, j]] /= scale;
+            inv[[col, j]] /= scale;
+        }
+        for i in 0..k {
+            let factor = m[[i, col]];
+            if i != col && factor != 0.0 {
+                for j in 0..k {
+                    m[[i, j]] -= factor * m[[col, j]];
+                    inv[[i, j]] -= factor * inv[[col, j]];
+                }
+            }
+        }
+    }
+    Some(inv)
+}
+
+fn sigmoid(eta: f64) -> f64 {
+    1.0 / (1.0 + (-eta).exp())
+}
+
+impl FirthLogit {
+    // The design matrix: an intercept and the kept, standardized predictors.
+    fn design(&self, x: &Array2<f64>) -> Array2<f64> {
+        let mut z = Array2::<f64>::ones((x.nrows(), self.columns.len() + 1));
+        for (k, col) in self.columns.iter().enumerate() {
+            let column = x.column(*col).mapv(|v| (v - self.mean[k]) / self.sd[k]);
+            z.column_mut(k + 1).assign(&column);
+        }
+        z
+    }
+
+    // Fit the model to predictors `x` (named `names`) and binary response `d`
+    //
+    // Newton-Raphson stops once the largest component of the modified score
+    // X'(d - p + h(1/2 - p)) / n, where h are the leverages, falls below the
+    // tolerance; the score is zero at the penalized maximum. Returns the
+    // model, the modified score residuals at the estimates and the number of
+    // iterations.
+    pub fn fit(
+        x: &Array2<f64>,
+        d: &Array1<i64>,
+        names: &[&str],
+        options: &LogitOptions
+    ) -> Result<(FirthLogit, Array1<f64>, usize), String> {
+        let n = x.nrows();
+        let y = d.mapv(|d| d as f64);
+        let mut model = FirthLogit {
+            n_columns: x.ncols(),
+            columns: Vec::new(),
+            mean: Vec::new(),
+            sd: Vec::new(),
+            beta: Array1::zeros(1)
+        };
+        let mut kept: Vec<Array1<f64>> = vec![Array1::from_elem(n, 1.0 / (n as f64).sqrt())];
+        for (col, column) in x.axis_iter(Axis(1)).enumerate() {
+            let (mean, sd) = (column.mean().unwrap_or(0.0), column.std(1.0));
+            if sd.is_nan() || sd == 0.0 {
+                continue;
+            }
+            let mut residual = column.mapv(|v| (v - mean) / sd);
+            let norm = residual.dot(&residual).sqrt();
+            for basis in &kept {
+                let projection = basis.dot(&residual);
+                residual.scaled_add(-projection, basis);
+            }
+            let residual_norm = residual.dot(&residual).sqrt();
+            if residual_norm > ALIAS_TOLERANCE * norm {
+                kept.push(residual / residual_norm);
+                model.columns.push(col);
+                model.mean.push(mean);
+                model.sd.push(sd);
+            }
+        }
+        let z = model.design(x);
+        // Starting values on the standardized scale
+        let mut beta = Array1::<f64>::zeros(z.ncols());
+        beta[0] = options.initial("intercept");
+        for (k, col) in model.columns.iter().enumerate() {
+            let b = options.initial(names[*col]);
+            beta[0] += b * model.mean[k];
+            beta[k + 1] = b * model.sd[k];
+        }
+        let mut iterations = 0;
+        let residuals = loop {
+            let p = z.dot(&beta).mapv(sigmoid);
+            let w = p.mapv(|p| p * (1.0 - p));
+            let zw = &z * &w.view().insert_axis(Axis(1));
+            let inv = invert(&z.t().dot(&zw))
+                .ok_or("the Firth model's information matrix is singular")?;
+            let leverage = (&zw.dot(&inv) * &z).sum_axis(Axis(1));
+            let residuals = &y - &p + &leverage * &p.mapv(|p| 0.5 - p);
+            let score = z.t().dot(&residuals);
+            let largest_score = score.iter().fold(0.0_f64, |m, s| m.max(s.abs())) / n as f64;
+            if largest_score < options.tolerance || iterations as u64 >= options.max_iterations {
+                break residuals;
+            }
+            let mut step = inv.dot(&score);
+            let largest = step.iter().fold(0.0_f64, |m, s| m.max(s.abs()));
+            if largest > MAX_STEP {
+                step *= MAX_STEP / largest;
+            }
+            beta += &step;
+            iterations += 1;
+        };
+        model.beta = beta;
+        Ok((model, residuals, iterations))
+    }
+
+    // Intercept and slopes on the scale of the original predictors
+    //
+    // One slope per column of the `x` the model was fit on, 0 for the
+    // columns that were dropped.
+    pub fn coefficients(&self) -> (f64, Vec<f64>) {
+        let mut intercept = self.beta[0];
+        let mut slopes = vec![0.0; self.n_columns];
+        for (k, col) in self.columns.iter().enumerate() {
+            slopes[*col] = self.beta[k + 1] / self.sd[k];
+            intercept -= slopes[*col] * self.mean[k];
+        }
+        (intercept, slopes)
+    }
+
+    // Predicted probabilities for the rows of `x`.
+    pub fn predict_probabilities(&self, x: &Array2<f64>) -> Array1<f64> {
+        self.design(x).dot(&self.beta).mapv(sigmoid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    fn options() -> LogitOptions {
+        LogitOptions { tolerance: 1e-10, ..Default::default() }
+    }
+
+    #[test]
+    fn intercept_only_adds_half_a_success() {
+        // With only an intercept the leverages are 1/n, and the modified
+        // score is zero at p = (successes + 1/2) / (n + 1). The constant
+        // predictor is dropped.
+        let x = array![[1.0], [1.0], [1.0], [1.0]];
+        let d = array![0, 1, 0, 0];
+        let (model, _, _) = FirthLogit::fit(&x, &d, &["x"], &options()).unwrap();
+        for p in model.predict_probabilities(&x) {
+            assert!((p - 1.5 / 5.0).abs() < 1e-8);
+        }
+        assert_eq!(model.coefficients().1, vec![0.0]);
+    }
+
+    #[test]
+    fn separation_gives_finite_estimates() {
+        // A binary predictor that separates the response completely. The
+        // model is saturated, so each group's probability is its successes
+        // plus 1/2 over its size plus 1, as if half a unit were added to
+        // every cell of the 2x2 table.
+        let x = array![[0.0], [0.0], [1.0], [1.0]];
+        let d = array![0, 0, 1, 1];
+        let (model, _, _) = FirthLogit::fit(&x, &d, &["x"], &options()).unwrap();
+        let p = model.predict_probabilities(&x);
+        assert!((p[0] - 1.0 / 6.0).abs() < 1e-8);
+        assert!((p[2] - 5.0 / 6.0).abs() < 1e-8);
+        let (intercept, slopes) = model.coefficients();
+        assert!((intercept - (0.2_f64).ln()).abs() < 1e-8);
+        assert!((slopes[0] - 25.0_f64.ln()).abs() < 1e-8);
+    }
+
+    #[test]
+    fn aliased_predictors_are_dropped() {
+        let x = array![[0.0, 0.0], [1.0, 2.0], [2.0, 4.0], [3.0, 6.0], [1.0, 2.0], [2.0, 4.0]];
+        let d = array![0, 0, 1, 1, 1, 0];
+        let (model, _, _) = FirthLogit::fit(&x, &d, &["x", "twice_x"], &options()).unwrap();
+        let (intercept, slopes) = model.coefficients();
+        assert_eq!(slopes[1], 0.0);
+        // The coefficients on the original scale give the same probabilities
+        for (row, p) in x.rows().into_iter().zip(model.predict_probabilities(&x)) {
+            assert!((sigmoid(intercept + slopes[0] * row[0]) - p).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// How the results are printed
+//
+// `Summary` is the human-readable report. `Tsv` and `KeyValue` print the
+// headline numbers on a single line, at full precision, for shell
+// pipelines.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Summary,
+    Tsv,
+    KeyValue
+}
+
+// Implement `FromStr` for `OutputFormat`.
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(OutputFormat::Summary),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "kv" => Ok(OutputFormat::KeyValue),
+            _ => Err(format!("Unknown output format `{}`; expected summary, tsv or kv", s))
+        }
+    }
+}
+
+// Implement `Display` for `OutputFormat`.
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputFormat::Summary => write!(f, "summary"),
+            OutputFormat::Tsv => write!(f, "tsv"),
+            OutputFormat::KeyValue => write!(f, "kv")
+        }
+    }
+}
+
+// Fields of the single-line output formats, in order.
+const LINE_FIELDS: [&str; 6] = ["att", "se", "ci_low", "ci_high", "n_treat", "n_control"];
+
+// Write the headline numbers on one line
+//
+// Tab-separated values in the order of `LINE_FIELDS` for `Tsv`, and
+// space-separated `key=value` pairs for `KeyValue`.
+pub fn single_line(format: OutputFormat, att: f64, variance: f64, n_treat: i64, n_control: i64) -> String {
+    let se = variance.sqrt();
+    let values = [
+        att.to_string(),
+        se.to_string(),
+        (att - 1.96 * se).to_string(),
+        (att + 1.96 * se).to_string(),
+        n_treat.to_string(),
+        n_control.to_string()
+    ];
+    match format {
+        OutputFormat::KeyValue => LINE_FIELDS
+            .iter()
+            .zip(&values)
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join(" "),
+        _ => values.join("\t")
+    }
+}
+
+// Prefix a single-line result with the outcome it belongs to
+//
+// Used when several outcomes are estimated, so that each line says which
+// outcome it reports: an `outcome=` pair for `KeyValue` and a leading
+// column for `Tsv`.
+pub fn outcome_line(format: OutputFormat, outcome: &str, line: &str) -> String {
+    match format {
+        OutputFormat::KeyValue => format!("outcome={} {}", outcome, line),
+        _ => format!("{}\t{}", outcome, line)
+    }
+}
+
+// How numbers are written in the summary and reports
+//
+// `digits` is the number of digits after the decimal point (of the mantissa
+// in scientific notation). `thousands` separates groups of three integer
+// digits with commas, which helps with outcomes such as costs.
+#[derive(Clone, Copy, Debug)]
+pub struct NumberFormat {
+    pub digits: usize,
+    pub scientific: bool,
+    pub thousands: bool
+}
+
+// Implement `Default` for `NumberFormat`.
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat { digits: 3, scientific: false, thousands: false }
+    }
+}
+
+static FORMAT: OnceLock<NumberFormat> = OnceLock::new();
+
+// Set the number format for the rest of the run
+//
+// Output is written through `Display`, which can't take arguments, so the
+// format is global. It can only be set once, before anything is printed.
+pub fn set_number_format(format: NumberFormat) {
+    let _ = FORMAT.set(format);
+}
+
+// Insert a comma between every group of three integer digits.
+fn group_thousands(number: &str) -> String {
+    let (sign, number) = match number.strip_prefix('-') {
+        Some(n) => ("-", n),
+        None => ("", number)
+    };
+    let (integer, fraction) = match number.find('.') {
+        Some(i) => number.split_at(i),
+        None => (number, "")
+    };
+    let mut grouped = String::with_capacity(integer.len() + integer.len() / 3);
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    format!("{}{}{}", sign, grouped, fraction)
+}
+
+// Format a number with the configured `NumberFormat`.
+pub fn num(x: f64) -> String {
+    let format = FORMAT.get().copied().unwrap_or_default();
+    if !x.is_finite() {
+        return format!("{}", x);
+    }
+    match (format.scientific, format.thousands) {
+        (true, _) => format!("{:.*e}", format.digits, x),
+        (false, true) => group_thousands(&format!("{:.*}", format.digits, x)),
+        (false, false) => format!("{:.*}", format.digits, x)
+    }
+}
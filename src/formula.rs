@@ -0,0 +1,210 @@
+use ndarray::{Array1, Array2};
+use polars::datatypes::DataType::Utf8;
+use polars::prelude::{DataFrame, PolarsError, PolarsResult};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::data::column_values;
+
+// A term of a model formula
+//
+// The product of one or more columns, each raised to a positive integer
+// power: `age` is the column itself, `age^2` its square and `age:income`
+// the interaction of two columns. A factor `region=north` is the dummy of
+// one level of a categorical column. Terms are named by their formula
+// text, which is how they appear among the coefficients.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Term {
+    pub factors: Vec<(String, u32)>
+}
+
+// Implement `FromStr` for `Term`.
+impl FromStr for Term {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut factors = Vec::new();
+        for factor in s.split(':') {
+            let (name, power) = match factor.split_once('^') {
+                Some((name, power)) => {
+                    let power: u32 = power.trim().parse().map_err(|_| {
+                        format!("The power of `{}` must be a positive integer", factor.trim())
+                    })?;
+                    (name.trim(), power)
+                },
+                None => (factor.trim(), 1)
+            };
+            if name.is_empty() || power == 0 {
+                return Err(format!("Malformed formula term `{}`", s.trim()));
+            }
+            factors.push((name.to_string(), power));
+        }
+        Ok(Term { factors })
+    }
+}
+
+// Implement `Display` for `Term`.
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let factors: Vec<String> = self
+            .factors
+            .iter()
+            .map(|(name, power)| match power {
+                1 => name.clone(),
+                _ => format!("{}^{}", name, power)
+            })
+            .collect();
+        write!(f, "{}", factors.join(":"))
+    }
+}
+
+impl Term {
+    // Values of the term for every row of `data`.
+    pub fn values(&self, data: &DataFrame) -> PolarsResult<Vec<f64>> {
+        let mut values = vec![1.0; data.height()];
+        for (name, power) in &self.factors {
+            for (v, x) in values.iter_mut().zip(factor_values(data, name)?) {
+                *v *= x.powi(*power as i32);
+            }
+        }
+        Ok(values)
+    }
+}
+
+// A propensity model formula
+//
+// `treatment ~ term + term + ...`, in the notation of R's model formulas
+// without their operators beyond `:` and `^`: terms are separated by `+`,
+// and the model has an intercept and one slope per term. Repeated terms
+// are kept once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Formula {
+    pub response: String,
+    pub terms: Vec<Term>
+}
+
+// Implement `FromStr` for `Formula`.
+impl FromStr for Formula {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (response, rhs) = s
+            .split_once('~')
+            .ok_or_else(|| format!("The formula `{}` needs a `~` between the treatment and the terms", s))?;
+        let mut terms: Vec<Term> = Vec::new();
+        for term in rhs.split('+') {
+            let term: Term = term.parse()?;
+            if !terms.iter().any(|t| t.to_string() == term.to_string()) {
+                terms.push(term);
+            }
+        }
+        let response = response.trim().to_string();
+        if response.is_empty() {
+            return Err(format!("The formula `{}` has no treatment before the `~`", s));
+        }
+        Ok(Formula { response, terms })
+    }
+}
+
+// Implement `Display` for `Formula`.
+impl fmt::Display for Formula {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let terms: Vec<String> = self.terms.iter().map(|t| t.to_string()).collect();
+        write!(f, "{} ~ {}", self.response, terms.join(" + "))
+    }
+}
+
+impl Formula {
+    // Columns the formula's terms are built from, each listed once.
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns: Vec<String> = Vec::new();
+        for (name, _) in self.terms.iter().flat_map(|t| &t.factors) {
+            let column = name.split_once('=').map_or(name.as_str(), |(column, _)| column).to_string();
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+        columns
+    }
+
+    // The predictors of the formula's terms for every row of `data`, with
+    // their names
+    //
+    // One column per term, except that a term that is just a categorical
+    // column stands for the dummies of its levels (see `encoded_names`).
+    pub fn design(&self, data: &DataFrame) -> PolarsResult<(Array2<f64>, Vec<String>)> {
+        let mut names = Vec::new();
+        for term in &self.terms {
+            match term.factors[..] {
+                [(ref name, 1)] if is_categorical(data, name) => names.extend(encoded_names(data, name)?),
+                _ => names.push(term.to_string())
+            }
+        }
+        Ok((predictor_matrix(data, &names)?, names))
+    }
+}
+
+// Whether column `name` of `data` holds categories (strings) rather than
+// numbers. A missing column is not categorical.
+pub fn is_categorical(data: &DataFrame, name: &str) -> bool {
+    data.column(name).is_ok_and(|column| *column.dtype() == Utf8)
+}
+
+// Levels of a categorical column, sorted, without missing values.
+pub fn levels(data: &DataFrame, name: &str) -> PolarsResult<Vec<String>> {
+    let levels: BTreeSet<String> = data.column(name)?.utf8()?.into_iter().flatten().map(String::from).collect();
+    Ok(levels.into_iter().collect())
+}
+
+// Predictors of column `name`
+//
+// A numeric column is its own predictor. A categorical column is dummy
+// coded: one predictor `name=level` per level but the first, the
+// reference level, which is absorbed by the intercept.
+pub fn encoded_names(data: &DataFrame, name: &str) -> PolarsResult<Vec<String>> {
+    match is_categorical(data, name) {
+        true => Ok(levels(data, name)?.into_iter().skip(1).map(|level| format!("{}={}", name, level)).collect()),
+        false => Ok(vec![name.to_string()])
+    }
+}
+
+// Values of the factor `name`: a column or the dummy `column=level` of
+// one level of a categorical column, which is missing where the column
+// is.
+fn factor_values(data: &DataFrame, name: &str) -> PolarsResult<Vec<f64>> {
+    match (data.column(name), name.split_once('=')) {
+        (Err(_), Some((column, level))) if is_categorical(data, column) => Ok(data
+            .column(column)?
+            .utf8()?
+            .into_iter()
+            .map(|v| v.map_or(f64::NAN, |v| (v == level) as u8 as f64))
+            .collect()),
+        _ => column_values(data, name)
+    }
+}
+
+// The values of the predictors `names` (see `predictor_values`) for every
+// row of `data`, one column per predictor.
+pub fn predictor_matrix(data: &DataFrame, names: &[String]) -> PolarsResult<Array2<f64>> {
+    let mut x = Array2::<f64>::zeros((data.height(), names.len()));
+    for (j, name) in names.iter().enumerate() {
+        x.column_mut(j).assign(&Array1::from(predictor_values(data, name)?));
+    }
+    Ok(x)
+}
+
+// Values of the predictor `name` for every row of `data`
+//
+// A numeric column of `data`, the dummy of a level of a categorical column
+// (`region=north`) or, for the coefficients of a formula model, a term
+// such as `age^2` or `age:income` built from its columns.
+pub fn predictor_values(data: &DataFrame, name: &str) -> PolarsResult<Vec<f64>> {
+    match data.column(name) {
+        Ok(_) => column_values(data, name),
+        Err(_) => {
+            let term: Term = name.parse().map_err(|_| PolarsError::ColumnNotFound(name.to_string().into()))?;
+            term.values(data)
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use polars::prelude::{DataFrame, IdxCa, IdxSize, PolarsError, PolarsResult};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::fmt;
+
+use crate::balance::pooled_sd;
+use crate::cancel::CancelToken;
+use crate::data::column_values;
+use crate::formula::predictor_values;
+
+// Probability that a weight is drawn anew rather than inherited when a
+// child weight vector is bred.
+const MUTATION_RATE: f64 = 0.2;
+
+// Settings of the evolutionary search of `genetic_match`
+//
+// `population` weight vectors are kept; every generation breeds as many
+// children and the best `population` of parents and children survive.
+// Matching runs population * (generations + 1) times in all.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeneticOptions {
+    pub population: usize,
+    pub generations: usize
+}
+
+impl Default for GeneticOptions {
+    fn default() -> Self {
+        GeneticOptions { population: 20, generations: 10 }
+    }
+}
+
+// The weights found by the search
+//
+// One weight per matching variable, scaled so that the largest is 1, and
+// the balance loss (the largest absolute SMD after matching) they reach.
+#[derive(Debug)]
+pub struct GeneticWeights {
+    pub weights: Vec<(String, f64)>,
+    pub loss: f64,
+    pub evaluations: usize
+}
+
+impl fmt::Display for GeneticWeights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let weights: Vec<String> = self.weights.iter().map(|(name, w)| format!("{}={:.4}", name, w)).collect();
+        write!(f, "weights={} loss={:.4} evaluations={}", weights.join(","), self.loss, self.evaluations)
+    }
+}
+
+// Matching variables standardized by their pooled SD, one row per unit
+//
+// Every variable must be known for every unit, since a single missing
+// value leaves a treated unit without any finite distance to a control.
+struct Standardized {
+    dim: usize,
+    treat: Vec<f64>,
+    pool: Vec<f64>
+}
+
+impl Standardized {
+    fn new(treat: &DataFrame, pool: &DataFrame, variables: &[String]) -> PolarsResult<Standardized> {
+        let dim = variables.len();
+        let mut standardized = Standardized {
+            dim,
+            treat: vec![0.0; treat.height() * dim],
+            pool: vec![0.0; pool.height() * dim]
+        };
+        for (k, variable) in variables.iter().enumerate() {
+            let x_treat = predictor_values(treat, variable)?;
+            let x_pool = predictor_values(pool, variable)?;
+            for (data, x) in [(treat, &x_treat), (pool, &x_pool)] {
+                if let Some(i) = x.iter().position(|v| !v.is_finite()) {
+                    return Err(PolarsError::ComputeError(format!(
+                        "Genetic matching needs every matching variable but `{}` is missing for unit {}",
+                        variable,
+                        column_values(data, "strike_id")?[i]
+                    ).into()));
+                }
+            }
+            let scale = match pooled_sd(&x_treat, &x_pool) {
+                sd if sd > 0.0 => sd,
+                _ => 1.0
+            };
+            for (i, x) in x_treat.iter().enumerate() {
+                standardized.treat[i * dim + k] = x / scale;
+            }
+            for (j, x) in x_pool.iter().enumerate() {
+                standardized.pool[j * dim + k] = x / scale;
+            }
+        }
+        Ok(standardized)
+    }
+
+    // Nearest control of every treated unit under the diagonal `weights`,
+    // ties going to the first such control.
+    fn nearest(&self, weights: &[f64]) -> Vec<usize> {
+        self.treat
+            .chunks(self.dim)
+            .map(|t| {
+                let mut best = (0, f64::INFINITY);
+                for (j, c) in self.pool.chunks(self.dim).enumerate() {
+                    let distance: f64 = (0..self.dim).map(|k| weights[k] * (t[k] - c[k]).powi(2)).sum();
+                    if distance < best.1 {
+                        best = (j, distance);
+                    }
+                }
+                best.0
+            })
+            .collect()
+    }
+
+    // The largest absolute SMD over the matching variables between the
+    // treated units and the controls in `matches`. Variables are already
+    // standardized, so this is the difference in means.
+    fn loss(&self, matches: &[usize]) -> f64 {
+        let n = matches.len() as f64;
+        (0..self.dim)
+            .map(|k| {
+                let treat_mean = self.treat.chunks(self.dim).map(|t| t[k]).sum::<f64>() / n;
+                let control_mean = matches.iter().map(|j| self.pool[j * self.dim + k]).sum::<f64>() / n;
+                (treat_mean - control_mean).abs()
+            })
+            .fold(0.0, f64::max)
+    }
+}
+
+// Genetic matching
+//
+// 1:1 nearest neighbor matching with replacement on a generalized
+// Mahalanobis distance: the squared differences of the standardized
+// `variables` (the covariates and usually the propensity score), summed
+// with one weight per variable. An evolutionary search looks for the
+// weights whose matches balance the variables best, i.e. minimize the
+// largest absolute SMD after matching. It starts from equal weights and
+// random ones drawn from `rng`; children mix two parents picked by
+// tournament and redraw each weight with probability `MUTATION_RATE`.
+// Returns the matches of the best weights, laid out like those of
+// `nn_match`, and the weights. Stops with an error if `cancel` is set.
+pub fn genetic_match(
+    main: &DataFrame,
+    target: &DataFrame,
+    variables: &[String],
+    options: &GeneticOptions,
+    rng: &mut StdRng,
+    cancel: &CancelToken
+) -> PolarsResult<(DataFrame, GeneticWeights)> {
+    if target.height() == 0 {
+        return Err(PolarsError::ComputeError("Genetic matching needs at least one control".into()));
+    }
+    let standardized = Standardized::new(main, target, variables)?;
+    let evaluate = |weights: Vec<f64>| {
+        let matches = standardized.nearest(&weights);
+        (standardized.loss(&matches), weights, matches)
+    };
+    let dim = variables.len();
+    let mut scored: Vec<(f64, Vec<f64>, Vec<usize>)> = Vec::with_capacity(2 * options.population);
+    for i in 0..options.population {
+        cancel.check(|| format!("while matching after {} of {} weight vectors", i, options.population))?;
+        let weights = match i {
+            0 => vec![1.0; dim],
+            _ => (0..dim).map(|_| rng.gen()).collect()
+        };
+        scored.push(evaluate(weights));
+    }
+    scored.sort_by(|a, b| a.0.total_cmp(&b.0));
+    for generation in 0..options.generations {
+        cancel.check(|| format!("while matching after {} of {} generations", generation, options.generations))?;
+        let mut children = Vec::with_capacity(options.population);
+        for _ in 0..options.population {
+            // Tournaments of two; `scored` is sorted, so the lower index wins
+            let mut parent = || &scored[rng.gen_range(0..scored.len()).min(rng.gen_range(0..scored.len()))].1;
+            let (a, b) = (parent().clone(), parent().clone());
+            let mix: f64 = rng.gen();
+            let child: Vec<f64> = a
+                .iter()
+                .zip(&b)
+                .map(|(x, y)| match rng.gen_bool(MUTATION_RATE) {
+                    true => rng.gen(),
+                    false => mix * x + (1.0 - mix) * y
+                })
+                .collect();
+            children.push(child);
+        }
+        scored.extend(children.into_iter().map(evaluate));
+        scored.sort_by(|a, b| a.0.total_cmp(&b.0));
+        scored.truncate(options.population);
+    }
+    let (loss, weights, matches) = scored.swap_remove(0);
+    let largest = weights.iter().cloned().fold(0.0, f64::max);
+    let weights = variables
+        .iter()
+        .zip(&weights)
+        .map(|(name, w)| match largest > 0.0 {
+            true => (name.clone(), w / largest),
+            false => (name.clone(), *w)
+        })
+        .collect();
+    let rows: Vec<IdxSize> = matches.into_iter().map(|j| j as IdxSize).collect();
+    let fit = GeneticWeights { weights, loss, evaluations: options.population * (options.generations + 1) };
+    Ok((target.take(&IdxCa::from_vec("matches", rows))?, fit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::df;
+    use polars::prelude::NamedFrom;
+    use rand::SeedableRng;
+
+    #[test]
+    fn genetic_match_rejects_missing_values() {
+        let treat = df!("strike_id" => [1i64, 2], "x" => [Some(1.0), None]).unwrap();
+        let pool = df!("strike_id" => [3i64, 4], "x" => [Some(1.5), Some(2.5)]).unwrap();
+        let variables = vec!["x".to_string()];
+        let options = GeneticOptions::default();
+        let (mut rng, cancel) = (StdRng::seed_from_u64(1), CancelToken::default());
+        let error = genetic_match(&treat, &pool, &variables, &options, &mut rng, &cancel).unwrap_err();
+        assert!(error.to_string().contains("`x` is missing for unit 2"));
+    }
+}
@@ -0,0 +1,140 @@
+use ndarray::{Array1, Array2};
+use polars::prelude::DataFrame;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::balance::mean_variance;
+use crate::data::column_values;
+use crate::format::num;
+use crate::regression::wls;
+
+// Density of the normal distribution with mean `mean` and standard
+// deviation `sd` at `x`.
+fn normal_density(x: f64, mean: f64, sd: f64) -> f64 {
+    (-0.5 * ((x - mean) / sd).powi(2)).exp() / (sd * (2.0 * PI).sqrt())
+}
+
+// The average outcome had everyone received one dose.
+#[derive(Debug)]
+pub struct DosePoint {
+    pub dose: f64,
+    pub response: f64,
+    pub variance: f64
+}
+
+// Dose-response curve of a continuous treatment
+//
+// `r_squared` is that of the treatment model, and `effective_n` the
+// effective sample size of the stabilized weights, (sum w)^2 / sum w^2,
+// which falls well below `n` when a few units carry most of the weight.
+#[derive(Debug)]
+pub struct DoseResponse {
+    pub treatment: String,
+    pub n: usize,
+    pub r_squared: f64,
+    pub effective_n: f64,
+    pub max_weight: f64,
+    pub points: Vec<DosePoint>
+}
+
+// Estimate a dose-response curve with the generalized propensity score
+//
+// The treatment is modelled as normal given the covariates, with the mean
+// from a linear regression on them and the residual variance; the
+// generalized propensity score of a unit is the density of its observed
+// dose under that model. Stabilized weights divide the marginal (normal)
+// density of the dose by the generalized propensity score, which removes
+// the association between the covariates and the dose. A marginal
+// structural model, the weighted regression of the outcome on the dose
+// and its square, then gives the average outcome at every one of `doses`,
+// with HC1 robust variances that treat the weights as known. Doses
+// outside the observed range of the treatment are rejected, as the curve
+// would be extrapolated.
+pub fn dose_response(
+    data: &DataFrame,
+    treatment: &str,
+    outcome: &str,
+    covariates: &[String],
+    doses: &[f64]
+) -> Result<DoseResponse, Box<dyn Error>> {
+    let t = column_values(data, treatment)?;
+    let y = column_values(data, outcome)?;
+    for (role, column, values) in [("treatment", treatment, &t), ("outcome", outcome, &y)] {
+        let missing = values.iter().filter(|v| v.is_nan()).count();
+        if missing > 0 {
+            return Err(format!(
+                "The {} column `{}` has {} missing values; drop or impute them first",
+                role,
+                column,
+                missing
+            ).into());
+        }
+    }
+    let (lowest, highest) = t
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+    if let Some(dose) = doses.iter().find(|d| **d < lowest || **d > highest) {
+        return Err(format!(
+            "The dose {} is outside the observed range of `{}`, [{}, {}]",
+            dose,
+            treatment,
+            lowest,
+            highest
+        ).into());
+    }
+    let n = t.len();
+    let mut x = Array2::ones((n, covariates.len() + 1));
+    for (k, covariate) in covariates.iter().enumerate() {
+        x.column_mut(k + 1).assign(&Array1::from(column_values(data, covariate)?));
+    }
+    let t = Array1::from(t);
+    let treatment_fit = wls(&x, &t, &Array1::ones(n));
+    let k = treatment_fit.aliased.iter().filter(|a| !**a).count();
+    let sigma = (treatment_fit.residuals.mapv(|e| e * e).sum() / (n - k) as f64).sqrt();
+    let (t_mean, t_variance) = mean_variance(&t.to_vec());
+    let weights: Array1<f64> = t
+        .iter()
+        .zip(treatment_fit.residuals.iter())
+        .map(|(dose, e)| normal_density(*dose, t_mean, t_variance.sqrt()) / normal_density(*e, 0.0, sigma))
+        .collect();
+    let mut design = Array2::ones((n, 3));
+    design.column_mut(1).assign(&t);
+    design.column_mut(2).assign(&t.mapv(|d| d * d));
+    let fit = wls(&design, &Array1::from(y), &weights);
+    let singletons: Vec<usize> = (0..n).collect();
+    let vcov = fit.cluster_vcov(&design, &weights, &singletons);
+    let points = doses
+        .iter()
+        .map(|dose| {
+            let g = Array1::from(vec![1.0, *dose, dose * dose]);
+            DosePoint { dose: *dose, response: g.dot(&fit.coefficients), variance: g.dot(&vcov.dot(&g)) }
+        })
+        .collect();
+    Ok(DoseResponse {
+        treatment: treatment.to_string(),
+        n,
+        r_squared: 1.0 - sigma.powi(2) * (n - k) as f64 / ((n - 1) as f64 * t_variance),
+        effective_n: weights.sum().powi(2) / weights.mapv(|w| w * w).sum(),
+        max_weight: weights.iter().cloned().fold(0.0, f64::max),
+        points
+    })
+}
+
+// Implement `Display` for `DoseResponse`.
+impl fmt::Display for DoseResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "STRIKE DOSE-RESPONSE =========================\n")?;
+        writeln!(f, "Generalized propensity score of `{}` (n = {})\n", self.treatment, self.n)?;
+        writeln!(f, "Treatment model R²      : {}", num(self.r_squared))?;
+        writeln!(f, "Effective sample size   : {}", num(self.effective_n))?;
+        writeln!(f, "Largest weight          : {}\n", num(self.max_weight))?;
+        writeln!(f, "{:>10}  {:>10}  {:>8}  {:>20}", "Dose", "Response", "Variance", "95% CI")?;
+        for p in &self.points {
+            let se = p.variance.sqrt();
+            let ci = format!("({}, {})", num(p.response - 1.96 * se), num(p.response + 1.96 * se));
+            writeln!(f, "{:>10}  {:>10}  {:>8}  {:>20}", p.dose, num(p.response), num(p.variance), ci)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use polars::prelude::{DataFrame, PolarsResult};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::att::match_weights;
+use crate::data::column_values;
+
+// One matched unit's contribution to the ATT (`--influence`)
+//
+// `weight` is the unit's coefficient in the ATT as a weighted sum of
+// outcomes over the treated units: 1 for a treated unit and minus the
+// number of times it is used (each use weighted as in `calculate_att`)
+// for a control.
+#[derive(Clone, Debug)]
+pub struct Influence {
+    pub strike_id: i64,
+    pub treated: bool,
+    pub weight: f64,
+    pub contribution: f64
+}
+
+// Influence-function contributions of the matched units to the ATT
+//
+// The ATT is the mean treated outcome minus the weighted mean outcome of
+// the matched controls. Holding the matches fixed, a treated unit
+// contributes (Y - mean treated outcome) / N1 and a control used K times
+// -K (Y - weighted mean control outcome) / N1, so the contributions sum to
+// zero, the sum of their squares is the variance of the ATT with the
+// matches taken as given (no Abadie-Imbens correction), and the sum over
+// a group is the deviation from the ATT that group accounts for. Treated
+// units come first, then controls, each in order of their first match.
+pub fn influence_contributions(treat: &DataFrame, control: &DataFrame, outcome: &str) -> PolarsResult<Vec<Influence>> {
+    let (weights, n_treat) = match_weights(treat)?;
+    let treat_ids = column_values(treat, "strike_id")?;
+    let control_ids = column_values(control, "strike_id")?;
+    let treat_y = column_values(treat, outcome)?;
+    let control_y = column_values(control, outcome)?;
+    // Each unit's outcome and weight, in order of first appearance
+    let mut units: Vec<(i64, bool, f64, f64)> = Vec::new();
+    let mut index: HashMap<(i64, bool), usize> = HashMap::new();
+    let treated_units = treat_ids.iter().zip(&treat_y).map(|(id, y)| (*id as i64, true, *y, 1.0));
+    let control_uses = control_ids.iter().zip(&control_y).zip(&weights).map(|((id, y), w)| (*id as i64, false, *y, -w));
+    for (id, treated, y, weight) in treated_units.chain(control_uses) {
+        match index.get(&(id, treated)) {
+            Some(i) if !treated => units[*i].3 += weight,
+            Some(_) => (),
+            None => {
+                index.insert((id, treated), units.len());
+                units.push((id, treated, y, weight));
+            }
+        }
+    }
+    let n = n_treat as f64;
+    let treat_mean = units.iter().filter(|u| u.1).map(|u| u.2).sum::<f64>() / n;
+    let control_mean = -units.iter().filter(|u| !u.1).map(|u| u.3 * u.2).sum::<f64>() / n;
+    Ok(units
+        .into_iter()
+        .map(|(strike_id, treated, y, weight)| Influence {
+            strike_id,
+            treated,
+            weight,
+            contribution: match treated {
+                true => (y - treat_mean) / n,
+                false => weight * (y - control_mean) / n
+            }
+        })
+        .collect())
+}
+
+// Write the contributions to `path` as CSV, one row per matched unit.
+pub fn write_influence(path: &str, contributions: &[Influence]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    writeln!(file, "strike_id,treated,weight,contribution")?;
+    for c in contributions {
+        writeln!(file, "{},{},{},{}", c.strike_id, c.treated as u8, c.weight, c.contribution)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+pub mod app;
+pub mod att;
+pub mod audit;
+pub mod cache;
+pub mod calibration;
+pub mod balance;
+pub mod binary;
+pub mod budget;
+pub mod caliper;
+pub mod cancel;
+pub mod cli;
+pub mod compare;
+pub mod completions;
+pub mod data;
+pub mod design;
+pub mod diagnostics;
+pub mod distance;
+pub mod dml;
+pub mod equivalence;
+pub mod estimate;
+#[cfg(feature = "lazy")]
+pub mod expr;
+pub mod filter;
+pub mod firth;
+pub mod format;
+pub mod formula;
+pub mod genetic;
+pub mod gps;
+pub mod influence;
+pub mod log;
+pub mod memory;
+pub mod meta;
+pub mod missing;
+pub mod multiplicity;
+pub mod overlap;
+pub mod paired;
+pub mod partition;
+pub mod penalized;
+pub mod permutation;
+pub mod plots;
+pub mod prognostic;
+pub mod propensity;
+pub mod quality;
+pub mod registry;
+pub mod regression;
+pub mod selection;
+pub mod sensitivity;
+pub mod session;
+pub mod split;
+pub mod subclass;
+pub mod subgroup;
+pub mod survey;
+pub mod survival;
+pub mod transform;
+pub mod trees;
+pub mod validate;
+pub mod warnings;
+pub mod weighting;
+
+
+// Every result and model type can be sent to and shared between threads
+//
+// Results own their data (polars DataFrames, numbers and strings) and have
+// no interior mutability, so one estimate can be handed to another thread
+// or read from several at once. The only process-wide state is the number
+// format, the log level, the audit log and the memory tracker, which are set
+// up once per process behind `OnceLock`, `Mutex` and atomics. This stops
+// compiling if a type ever loses `Send` or `Sync`.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<estimate::Strike>();
+    assert_send_sync::<cli::Args>();
+    assert_send_sync::<propensity::LogitFit>();
+    assert_send_sync::<propensity::LogitOptions>();
+    assert_send_sync::<formula::Formula>();
+    assert_send_sync::<propensity::Coefficients>();
+    assert_send_sync::<propensity::Separation>();
+    assert_send_sync::<firth::FirthLogit>();
+    assert_send_sync::<penalized::PenalizedLogit>();
+    assert_send_sync::<survey::WeightedLogit>();
+    assert_send_sync::<trees::Gbm>();
+    assert_send_sync::<trees::Forest>();
+    assert_send_sync::<propensity::EnsembleFit>();
+    assert_send_sync::<selection::CovariateSelection>();
+    assert_send_sync::<att::TrimmedAtt>();
+    assert_send_sync::<permutation::PermutationTest>();
+    assert_send_sync::<sensitivity::RosenbaumBounds>();
+    assert_send_sync::<equivalence::Tost>();
+    assert_send_sync::<sensitivity::EValues>();
+    assert_send_sync::<influence::Influence>();
+    assert_send_sync::<multiplicity::Simultaneous>();
+    assert_send_sync::<att::AdjustedAtt>();
+    assert_send_sync::<att::ClusterAtt>();
+    assert_send_sync::<binary::BinaryEffects>();
+    assert_send_sync::<survival::SurvivalContrast>();
+    assert_send_sync::<paired::PairedTests>();
+    assert_send_sync::<survey::SurveyAtt>();
+    assert_send_sync::<missing::IpwAtt>();
+    assert_send_sync::<balance::SubclassBalanceReport>();
+    assert_send_sync::<balance::BalanceBootstrap>();
+    assert_send_sync::<balance::BalanceTable>();
+    assert_send_sync::<caliper::CaliperCurve>();
+    assert_send_sync::<quality::MatchQualityCurve>();
+    assert_send_sync::<subgroup::Subgroups>();
+    assert_send_sync::<overlap::ExtremeUnits>();
+    assert_send_sync::<overlap::CommonSupport>();
+    assert_send_sync::<overlap::PositivityCheck>();
+    assert_send_sync::<overlap::PercentileTable>();
+    assert_send_sync::<distance::CovariateGaps>();
+    assert_send_sync::<warnings::Warning>();
+    assert_send_sync::<meta::MetaAnalysis>();
+    assert_send_sync::<gps::DoseResponse>();
+    assert_send_sync::<dml::DoubleMl>();
+    assert_send_sync::<weighting::WeightedEstimate>();
+    assert_send_sync::<calibration::PsDiagnostics>();
+    assert_send_sync::<calibration::CalibrationMap>();
+    assert_send_sync::<compare::Comparison>();
+    assert_send_sync::<memory::MemoryReport>();
+    assert_send_sync::<session::Session>();
+    assert_send_sync::<cache::CachedFit>();
+    assert_send_sync::<diagnostics::Diagnostics>();
+};
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// How much Strike writes to standard error
+//
+// `Error` prints only what stops the run, `Warn` (the default) adds the
+// warnings of a finished run, `Info` adds a line per stage of the run and
+// `Debug` adds the events between stages (the start of the run,
+// approximations) and the details of every record.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    #[default]
+    Warn,
+    Info,
+    Debug
+}
+
+// Implement `FromStr` for `LogLevel`.
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("Unknown log level `{}`; expected error, warn, info or debug", s))
+        }
+    }
+}
+
+// Implement `Display` for `LogLevel`.
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogLevel::Error => write!(f, "error"),
+            LogLevel::Warn => write!(f, "warn"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Debug => write!(f, "debug")
+        }
+    }
+}
+
+static LEVEL: OnceLock<LogLevel> = OnceLock::new();
+
+// Set the log level for the rest of the run
+//
+// Like the number format, the level is global so that any stage can log.
+// It can only be set once, before anything is logged.
+pub fn set_log_level(level: LogLevel) {
+    let _ = LEVEL.set(level);
+}
+
+// Whether messages at `level` are written.
+pub fn enabled(level: LogLevel) -> bool {
+    level <= LEVEL.get().copied().unwrap_or_default()
+}
+
+// Write a message to standard error if `level` is enabled.
+pub fn log(level: LogLevel, message: &str) {
+    if enabled(level) {
+        eprintln!("{}", message);
+    }
+}
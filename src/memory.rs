@@ -0,0 +1,85 @@
+use std::fmt;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// Peak resident memory of each stage, in bytes, in the order the stages ran.
+static STAGES: Mutex<Vec<(String, u64)>> = Mutex::new(Vec::new());
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+
+// Start recording peak memory per stage
+//
+// Memory is only tracked when it is reported, on the command line or in the
+// audit log, since resetting the peak costs a write to `/proc`.
+pub fn track_memory() {
+    TRACKING.store(true, Ordering::SeqCst);
+}
+
+// Peak resident set size of the process in bytes
+//
+// Read from `VmHWM` in `/proc/self/status`, so it is only available on
+// Linux.
+fn peak_rss() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+// Reset the peak resident set size to the current one.
+fn reset_peak_rss() {
+    let _ = fs::write("/proc/self/clear_refs", "5");
+}
+
+// Record the peak memory of a stage that just finished
+//
+// The peak is the high-water mark since the previous stage finished, which
+// is then reset for the next stage. If the kernel doesn't allow the reset
+// every stage reports the peak of the run so far. Stages that run
+// concurrently (`--jobs`) share one process-wide peak. Returns the peak, if
+// memory is tracked and the platform reports it.
+pub fn stage_peak(stage: &str) -> Option<u64> {
+    if !TRACKING.load(Ordering::SeqCst) {
+        return None;
+    }
+    let peak = peak_rss()?;
+    reset_peak_rss();
+    STAGES.lock().unwrap().push((stage.to_string(), peak));
+    Some(peak)
+}
+
+// Format a number of bytes in MiB.
+fn mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+// Peak memory of every stage of the run and overall
+//
+// Shows which stage dominates memory use, e.g. matching against a very large
+// control pool. Stages that ran more than once (both halves of a cross-fit,
+// every site of a batch) are listed each time they ran.
+#[derive(Debug)]
+pub struct MemoryReport {
+    pub stages: Vec<(String, u64)>
+}
+
+// Peak memory of the stages recorded so far.
+pub fn memory_report() -> MemoryReport {
+    MemoryReport { stages: STAGES.lock().unwrap().clone() }
+}
+
+// Implement `Display` for `MemoryReport`.
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "PEAK MEMORY ==================================\n")?;
+        if self.stages.is_empty() {
+            return writeln!(f, "Not available on this platform");
+        }
+        for (stage, peak) in &self.stages {
+            writeln!(f, "{:<18}  {:>12}", stage, mib(*peak))?;
+        }
+        let overall = self.stages.iter().map(|(_, peak)| *peak).max().unwrap_or(0);
+        writeln!(f, "{:<18}  {:>12}", "Overall", mib(overall))
+    }
+}
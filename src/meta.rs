@@ -0,0 +1,171 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::format::num;
+
+// The ATT estimated on a single dataset (site) in a batch run.
+#[derive(Debug)]
+pub struct SiteEstimate {
+    pub site: String,
+    pub att: f64,
+    pub att_variance: f64
+}
+
+// Fixed- and random-effects pooling of site-level ATTs
+//
+// The fixed-effect estimate is the inverse-variance weighted mean of the
+// site ATTs. The random-effects estimate uses the DerSimonian-Laird
+// estimate of the between-site variance tau^2, which is derived from
+// Cochran's Q. I^2 is the share of the total variability due to
+// between-site heterogeneity rather than sampling error. Pooling needs at
+// least one site, and every site a finite ATT and a positive, finite
+// variance, since a site's weight is the inverse of its variance.
+#[derive(Debug)]
+pub struct MetaAnalysis {
+    pub sites: Vec<SiteEstimate>,
+    pub fixed: f64,
+    pub fixed_variance: f64,
+    pub random: f64,
+    pub random_variance: f64,
+    pub q: f64,
+    pub tau_squared: f64,
+    pub i_squared: f64
+}
+
+impl MetaAnalysis {
+    pub fn new(sites: Vec<SiteEstimate>) -> Result<MetaAnalysis, Box<dyn Error>> {
+        if sites.is_empty() {
+            return Err("The meta-analysis needs at least one site".into());
+        }
+        for s in &sites {
+            if !s.att.is_finite() || !s.att_variance.is_finite() || s.att_variance <= 0.0 {
+                return Err(format!(
+                    "Site `{}` can't be pooled: its ATT is {} with variance {}, but pooling needs a finite ATT \
+                     and a positive, finite variance",
+                    s.site,
+                    s.att,
+                    s.att_variance
+                ).into());
+            }
+        }
+        let w: Vec<f64> = sites.iter().map(|s| 1.0 / s.att_variance).collect();
+        let w_sum: f64 = w.iter().sum();
+        let fixed = sites.iter().zip(&w).map(|(s, w)| w * s.att).sum::<f64>() / w_sum;
+        let q: f64 = sites.iter().zip(&w).map(|(s, w)| w * (s.att - fixed).powi(2)).sum();
+        let df = (sites.len() - 1) as f64;
+        let c = w_sum - w.iter().map(|w| w * w).sum::<f64>() / w_sum;
+        let tau_squared = match c > 0.0 {
+            true => ((q - df) / c).max(0.0),
+            false => 0.0
+        };
+        let i_squared = match q > 0.0 {
+            true => ((q - df) / q).max(0.0),
+            false => 0.0
+        };
+        let w_random: Vec<f64> = sites.iter().map(|s| 1.0 / (s.att_variance + tau_squared)).collect();
+        let w_random_sum: f64 = w_random.iter().sum();
+        let random = sites
+            .iter()
+            .zip(&w_random)
+            .map(|(s, w)| w * s.att)
+            .sum::<f64>() / w_random_sum;
+        Ok(MetaAnalysis {
+            sites,
+            fixed,
+            fixed_variance: 1.0 / w_sum,
+            random,
+            random_variance: 1.0 / w_random_sum,
+            q,
+            tau_squared,
+            i_squared
+        })
+    }
+
+    // Site-level and pooled estimates as (label, ATT, variance) rows.
+    pub fn forest_rows(&self) -> Vec<(String, f64, f64)> {
+        self.sites
+            .iter()
+            .map(|s| (s.site.clone(), s.att, s.att_variance))
+            .chain([
+                ("Pooled (fixed)".to_string(), self.fixed, self.fixed_variance),
+                ("Pooled (random)".to_string(), self.random, self.random_variance)
+            ])
+            .collect()
+    }
+
+    // Export the site-level and pooled estimates for a forest plot
+    //
+    // Writes one CSV row per site followed by the fixed- and random-effects
+    // pooled rows, each with its estimate, standard error and 95%
+    // confidence interval.
+    pub fn write_forest(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "site,att,se,ci_lower,ci_upper")?;
+        for (site, att, variance) in self.forest_rows() {
+            let se = variance.sqrt();
+            writeln!(file, "{},{},{},{},{}", csv_field(&site), att, se, att - 1.96 * se, att + 1.96 * se)?;
+        }
+        Ok(())
+    }
+}
+
+// Quote a CSV field that contains a comma, a quote or a line break,
+// doubling its quotes.
+pub fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string()
+    }
+}
+
+// Implement `Display` for `MetaAnalysis`.
+impl fmt::Display for MetaAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "STRIKE META-ANALYSIS =========================\n")?;
+        writeln!(f, "{:<24}  {:>8}  {:>8}  {:>20}", "Site", "ATT", "Variance", "95% CI")?;
+        for (site, att, variance) in self.forest_rows() {
+            let se = variance.sqrt();
+            let ci = format!("({}, {})", num(att - 1.96 * se), num(att + 1.96 * se));
+            writeln!(f, "{:<24}  {:>8}  {:>8}  {:>20}", site, num(att), num(variance), ci)?;
+        }
+        write!(
+            f,
+            "\nHeterogeneity: Q = {} (df = {}), tau^2 = {}, I^2 = {:.1}%\n",
+            num(self.q),
+            self.sites.len() - 1,
+            num(self.tau_squared),
+            self.i_squared * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(site: &str, att: f64, att_variance: f64) -> SiteEstimate {
+        SiteEstimate { site: site.to_string(), att, att_variance }
+    }
+
+    #[test]
+    fn pools_by_inverse_variance() {
+        // Weights 1 and 1/4: (1 * 1 + 0.25 * 6) / 1.25 = 2, variance 1 / 1.25.
+        // Q = (1 - 2)^2 + 0.25 * (6 - 2)^2 = 5 with 1 degree of freedom, and
+        // c = 1.25 - 1.0625 / 1.25 = 0.4, so tau^2 = (5 - 1) / 0.4 = 10.
+        let meta = MetaAnalysis::new(vec![site("a", 1.0, 1.0), site("b", 6.0, 4.0)]).unwrap();
+        assert!((meta.fixed - 2.0).abs() < 1e-12);
+        assert!((meta.fixed_variance - 0.8).abs() < 1e-12);
+        assert!((meta.q - 5.0).abs() < 1e-12);
+        assert!((meta.tau_squared - 10.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rejects_empty_and_degenerate_sites() {
+        assert!(MetaAnalysis::new(Vec::new()).is_err());
+        let error = MetaAnalysis::new(vec![site("a", 1.0, 1.0), site("b", 2.0, 0.0)]).unwrap_err();
+        assert!(error.to_string().contains("Site `b`"));
+        assert!(MetaAnalysis::new(vec![site("a", 1.0, f64::NAN)]).is_err());
+    }
+}
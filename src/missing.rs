@@ -0,0 +1,139 @@
+use polars::datatypes::DataType::{Float64, Int64};
+use polars::prelude::{DataFrame, IntoSeries, PolarsResult};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::cli::Args;
+use crate::data::column_values;
+use crate::format::num;
+use crate::propensity::{estimate_propensities, LogitOptions, PropensityModel};
+
+// Name of the response of the missingness model.
+const OBSERVED: &str = "outcome_observed";
+
+// How `--missing-outcome` handles units without an outcome.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingOutcome {
+    Ipw
+}
+
+// Fail on missing outcomes unless `--missing-outcome` says how to handle
+// them.
+pub fn check_missing_outcome(data: &DataFrame, args: &Args) -> Result<(), Box<dyn Error>> {
+    let missing = data.column(&args.outcome)?.null_count();
+    if missing > 0 && args.missing_outcome.is_none() {
+        return Err(format!(
+            "The outcome column `{}` has {} missing values; drop them first or weight the complete \
+             pairs with --missing-outcome ipw",
+            args.outcome,
+            missing
+        ).into());
+    }
+    Ok(())
+}
+
+// Probability that each unit's outcome is observed
+//
+// A logistic regression of whether the outcome is observed on the treatment
+// and the covariates, fit on every unit of `data` by Firth's penalized
+// likelihood, which converges even when few outcomes are missing. Returns
+// the fitted probabilities by `strike_id`.
+pub fn observation_probabilities(data: &DataFrame, args: &Args) -> Result<HashMap<i64, f64>, Box<dyn Error>> {
+    let mut frame = data.drop("propensities")?;
+    let mut observed = data.column(&args.outcome)?.is_not_null().into_series().cast(&Int64)?;
+    frame.with_column(observed.rename(OBSERVED).clone())?;
+    frame.as_single_chunk_par();
+    let exclude = [OBSERVED.to_string(), args.outcome.clone()];
+    estimate_propensities(&mut frame, OBSERVED, &exclude, PropensityModel::Firth, &LogitOptions::default())?;
+    let ids = column_values(&frame, "strike_id")?;
+    let probabilities = column_values(&frame, "propensities")?;
+    Ok(ids.iter().map(|id| *id as i64).zip(probabilities).collect())
+}
+
+// The ATT over the complete pairs, weighted for missing outcomes
+//
+// Only pairs whose treated unit and control both have an outcome enter the
+// estimate, each weighted by 1 / (p_t * p_c), the inverse probability that
+// both outcomes are observed, so that units whose outcomes are rarely
+// observed count for the units like them that are missing.
+#[derive(Debug)]
+pub struct IpwAtt {
+    pub att: f64,
+    pub att_variance: f64,
+    pub n_pairs: usize,
+    pub n_complete: usize,
+    pub min_probability: f64
+}
+
+impl IpwAtt {
+    // Average two independent estimates, as in a cross-fit.
+    pub fn combine(self, other: IpwAtt) -> IpwAtt {
+        IpwAtt {
+            att: (self.att + other.att) / 2.0,
+            att_variance: (self.att_variance + other.att_variance) / 4.0,
+            n_pairs: self.n_pairs + other.n_pairs,
+            n_complete: self.n_complete + other.n_complete,
+            min_probability: self.min_probability.min(other.min_probability)
+        }
+    }
+}
+
+// Calculate the inverse-probability-of-missingness weighted ATT
+//
+// `probabilities` are the observation probabilities by `strike_id` (see
+// `observation_probabilities`). The variance is the linearization variance
+// of the weighted mean of the pair differences, with pairs that share a
+// control totalled together because they reuse its outcome. It treats the
+// weights as known.
+pub fn calculate_ipw_att(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    probabilities: &HashMap<i64, f64>
+) -> PolarsResult<IpwAtt> {
+    let treat_y = treat.column(outcome)?.cast(&Float64)?;
+    let control_y = control.column(outcome)?.cast(&Float64)?;
+    let treat_ids = column_values(treat, "strike_id")?;
+    let control_ids = column_values(control, "strike_id")?;
+    let mut pairs = Vec::with_capacity(treat.height());
+    let mut min_probability = f64::INFINITY;
+    for (i, (t, c)) in treat_y.f64()?.into_iter().zip(control_y.f64()?).enumerate() {
+        let (t_id, c_id) = (treat_ids[i] as i64, control_ids[i] as i64);
+        if let (Some(t), Some(c)) = (t, c) {
+            let (p_t, p_c) = (probabilities[&t_id], probabilities[&c_id]);
+            min_probability = min_probability.min(p_t).min(p_c);
+            pairs.push((c_id, 1.0 / (p_t * p_c), t - c));
+        }
+    }
+    let w_total: f64 = pairs.iter().map(|(_, w, _)| w).sum();
+    let att = pairs.iter().map(|(_, w, d)| w * d).sum::<f64>() / w_total;
+    let mut totals: HashMap<i64, f64> = HashMap::new();
+    for (control, w, d) in &pairs {
+        *totals.entry(*control).or_insert(0.0) += w * (d - att) / w_total;
+    }
+    let n = totals.len() as f64;
+    let mean = totals.values().sum::<f64>() / n;
+    let att_variance = n / (n - 1.0) * totals.values().map(|z| (z - mean).powi(2)).sum::<f64>();
+    Ok(IpwAtt {
+        att,
+        att_variance,
+        n_pairs: treat.height(),
+        n_complete: pairs.len(),
+        min_probability
+    })
+}
+
+// Implement `Display` for `IpwAtt`.
+impl fmt::Display for IpwAtt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Missing outcomes: {} of {} pairs complete, weighted by the inverse probability of \
+             observing both outcomes (smallest probability {})",
+            self.n_complete,
+            self.n_pairs,
+            num(self.min_probability)
+        )
+    }
+}
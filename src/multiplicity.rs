@@ -0,0 +1,193 @@
+use polars::prelude::{DataFrame, PolarsResult};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::att::unit_outcomes;
+use crate::balance::mean_variance;
+use crate::cancel::CancelToken;
+use crate::data::{quantile, sorted};
+use crate::format::num;
+use crate::paired::normal_cdf;
+
+// How the p-values of several outcomes are adjusted for multiple testing
+// (`--adjust`)
+//
+// Both control the family-wise error rate, the chance of any false
+// rejection among the outcomes. `Bonferroni` multiplies every p-value by
+// the number of outcomes; `Holm`'s step-down procedure multiplies the k-th
+// smallest by the number of outcomes not yet rejected, which is never less
+// powerful.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Adjustment {
+    #[default]
+    Holm,
+    Bonferroni
+}
+
+// Implement `FromStr` for `Adjustment`.
+impl FromStr for Adjustment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "holm" => Ok(Adjustment::Holm),
+            "bonferroni" => Ok(Adjustment::Bonferroni),
+            _ => Err(format!("Unknown adjustment `{}`; use holm or bonferroni", s))
+        }
+    }
+}
+
+// Implement `Display` for `Adjustment`.
+impl fmt::Display for Adjustment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Adjustment::Holm => write!(f, "holm"),
+            Adjustment::Bonferroni => write!(f, "bonferroni")
+        }
+    }
+}
+
+// Adjust the p-values `p` of a family of tests.
+pub fn adjusted_p_values(p: &[f64], adjustment: Adjustment) -> Vec<f64> {
+    let m = p.len() as f64;
+    match adjustment {
+        Adjustment::Bonferroni => p.iter().map(|p| (p * m).min(1.0)).collect(),
+        Adjustment::Holm => {
+            let mut order: Vec<usize> = (0..p.len()).collect();
+            order.sort_by(|a, b| p[*a].total_cmp(&p[*b]));
+            let mut adjusted = vec![0.0; p.len()];
+            let mut largest = 0.0_f64;
+            for (k, i) in order.into_iter().enumerate() {
+                largest = largest.max(((m - k as f64) * p[i]).min(1.0));
+                adjusted[i] = largest;
+            }
+            adjusted
+        }
+    }
+}
+
+// Critical value of simultaneous 95% confidence intervals
+// (`--joint-bootstrap`)
+//
+// The treated units are resampled with their matches `replicates` times,
+// the same units for every outcome so as to keep the outcomes'
+// correlation. `critical` is the 95th percentile of the largest
+// standardized deviation max_k |ATT*_k - ATT_k| / sd_k over the outcomes,
+// with sd_k the standard deviation of outcome k's resampled ATTs, so that
+// the intervals ATT_k ± critical × se_k cover every outcome's effect at
+// once 95% of the time. It only reflects the correlation of the outcomes;
+// the standard errors se_k are those of the variance estimator.
+#[derive(Clone, Copy, Debug)]
+pub struct JointBand {
+    pub replicates: usize,
+    pub critical: f64
+}
+
+// Bootstrap the critical value of the `outcomes`, resampling with `rng`.
+// Stops with an error if `cancel` is set.
+pub fn joint_band(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcomes: &[String],
+    replicates: usize,
+    rng: &mut StdRng,
+    cancel: &CancelToken
+) -> PolarsResult<JointBand> {
+    let mut differences = Vec::with_capacity(outcomes.len());
+    for outcome in outcomes {
+        let (treated, matched) = unit_outcomes(treat, control, outcome)?;
+        differences.push(treated.iter().zip(&matched).map(|(t, m)| t - m).collect::<Vec<f64>>());
+    }
+    let units = differences.first().map_or(0, |d| d.len());
+    let mean = |d: &[f64], rows: &[usize]| rows.iter().map(|i| d[*i]).sum::<f64>() / rows.len() as f64;
+    let all: Vec<usize> = (0..units).collect();
+    let estimates: Vec<f64> = differences.iter().map(|d| mean(d, &all)).collect();
+    // resampled[k][b] is the ATT of outcome k in resample b
+    let mut resampled = vec![Vec::with_capacity(replicates); outcomes.len()];
+    for b in 0..replicates {
+        cancel.check(|| format!("during the joint bootstrap after {} of {} replicates", b, replicates))?;
+        let rows: Vec<usize> = (0..units).map(|_| rng.gen_range(0..units)).collect();
+        for (k, d) in differences.iter().enumerate() {
+            resampled[k].push(mean(d, &rows));
+        }
+    }
+    let sds: Vec<f64> = resampled.iter().map(|atts| mean_variance(atts).1.sqrt()).collect();
+    let largest: Vec<f64> = (0..replicates)
+        .map(|b| (0..outcomes.len()).map(|k| (resampled[k][b] - estimates[k]).abs() / sds[k]).fold(0.0, f64::max))
+        .collect();
+    Ok(JointBand { replicates, critical: quantile(&sorted(&largest), 0.95) })
+}
+
+// One outcome of a `Simultaneous` table.
+#[derive(Clone, Debug)]
+pub struct AdjustedOutcome {
+    pub outcome: String,
+    pub att: f64,
+    pub se: f64,
+    pub p_value: f64,
+    pub adjusted: f64
+}
+
+// Inference over all the outcomes of a run
+//
+// Every outcome's two-sided p-value from its ATT and variance, adjusted
+// for the number of outcomes, and, with a joint band, simultaneous 95%
+// confidence intervals.
+#[derive(Clone, Debug)]
+pub struct Simultaneous {
+    pub adjustment: Adjustment,
+    pub outcomes: Vec<AdjustedOutcome>,
+    pub band: Option<JointBand>
+}
+
+impl Simultaneous {
+    // The table of the outcome `estimates`, each its name, ATT and variance.
+    pub fn new(estimates: &[(String, f64, f64)], adjustment: Adjustment, band: Option<JointBand>) -> Simultaneous {
+        let p: Vec<f64> = estimates
+            .iter()
+            .map(|(_, att, variance)| 2.0 * (1.0 - normal_cdf((att / variance.sqrt()).abs())))
+            .collect();
+        let adjusted = adjusted_p_values(&p, adjustment);
+        let outcomes = estimates
+            .iter()
+            .zip(p.iter().zip(adjusted))
+            .map(|((outcome, att, variance), (p_value, adjusted))| AdjustedOutcome {
+                outcome: outcome.clone(),
+                att: *att,
+                se: variance.sqrt(),
+                p_value: *p_value,
+                adjusted
+            })
+            .collect();
+        Simultaneous { adjustment, outcomes, band }
+    }
+}
+
+// Implement `Display` for `Simultaneous`.
+impl fmt::Display for Simultaneous {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Simultaneous inference over {} outcomes ({} adjustment)", self.outcomes.len(), self.adjustment)?;
+        write!(f, "{:<24}  {:>12}  {:>12}  {:>12}", "Outcome", "ATT", "p-value", "Adjusted p")?;
+        match self.band {
+            Some(_) => writeln!(f, "  {:>12}  {:>12}", "Joint lower", "Joint upper")?,
+            None => writeln!(f)?
+        }
+        for o in &self.outcomes {
+            write!(f, "{:<24}  {:>12}  {:>12}  {:>12}", o.outcome, num(o.att), num(o.p_value), num(o.adjusted))?;
+            match self.band {
+                Some(band) => {
+                    let (lower, upper) = (o.att - band.critical * o.se, o.att + band.critical * o.se);
+                    writeln!(f, "  {:>12}  {:>12}", num(lower), num(upper))?
+                },
+                None => writeln!(f)?
+            }
+        }
+        if let Some(band) = self.band {
+            let (critical, replicates) = (num(band.critical), band.replicates);
+            writeln!(f, "Joint 95% band: critical value {} from {} bootstrap resamples", critical, replicates)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,579 @@
+use polars::prelude::{BooleanChunked, DataFrame, NamedFrom, PolarsResult, Series};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::data::{column_values, density, quantile, sorted};
+use crate::format::num;
+use crate::formula::predictor_values;
+use crate::propensity::covariate_names;
+use crate::subclass::{subclass_breaks, subclass_of};
+
+const PERCENTILES: [f64; 9] = [1.0, 5.0, 10.0, 25.0, 50.0, 75.0, 90.0, 95.0, 99.0];
+
+// Covariates with at most this many distinct values define the covariate
+// strata of the positivity check.
+const MAX_STRATUM_LEVELS: usize = 10;
+
+// Number of violating covariate strata listed in the positivity report.
+const MAX_LISTED_STRATA: usize = 10;
+
+// Propensity score percentiles by treatment group
+//
+// A numeric view of overlap for users who can't render plots: each column
+// holds the propensity score percentiles of one group, before or after
+// matching.
+#[derive(Debug)]
+pub struct PercentileTable {
+    pub columns: Vec<(String, Vec<f64>)>
+}
+
+impl PercentileTable {
+    // Build the table from (label, propensity scores) pairs.
+    pub fn new(groups: &[(&str, &[f64])]) -> PercentileTable {
+        let columns = groups
+            .iter()
+            .map(|(label, scores)| {
+                let scores = sorted(scores);
+                let values = PERCENTILES.iter().map(|p| quantile(&scores, p / 100.0)).collect();
+                (label.to_string(), values)
+            })
+            .collect();
+        PercentileTable { columns }
+    }
+}
+
+// Implement `Display` for `PercentileTable`.
+impl fmt::Display for PercentileTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Propensity score percentiles")?;
+        write!(f, "{:>6}", "Pctl")?;
+        for (label, _) in &self.columns {
+            write!(f, "  {:>18}", label)?;
+        }
+        writeln!(f)?;
+        for (i, p) in PERCENTILES.iter().enumerate() {
+            write!(f, "{:>5}%", p)?;
+            for (_, values) in &self.columns {
+                write!(f, "  {:>18}", num(values[i]))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Overlapping coefficient
+//
+// The area shared by the treated and control propensity score densities,
+// integral of min(f_t, f_c) over [0, 1], using kernel density estimates on
+// a fine grid. 1 means the distributions are identical and 0 that they
+// don't overlap at all.
+pub fn overlap_coefficient(treat: &[f64], control: &[f64]) -> f64 {
+    let step = 1.0 / 1000.0;
+    let grid: Vec<f64> = (0..=1000).map(|i| i as f64 * step).collect();
+    let treat_density = density(treat, &grid);
+    let control_density = density(control, &grid);
+    treat_density
+        .iter()
+        .zip(&control_density)
+        .map(|(t, c)| t.min(*c) * step)
+        .sum::<f64>()
+        .min(1.0)
+}
+
+// C-statistic
+//
+// The probability that a random treated unit has a higher propensity score
+// than a random control (the area under the ROC curve of the propensity
+// model), computed from the Mann-Whitney rank sum with ties given average
+// ranks. 0.5 means the scores don't separate the groups; values near 1 mean
+// poor overlap.
+pub fn c_statistic(treat: &[f64], control: &[f64]) -> f64 {
+    let mut scores: Vec<(f64, bool)> = treat
+        .iter()
+        .map(|p| (*p, true))
+        .chain(control.iter().map(|p| (*p, false)))
+        .collect();
+    scores.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut rank_sum = 0.0;
+    let mut i = 0;
+    while i < scores.len() {
+        let mut j = i;
+        while j + 1 < scores.len() && scores[j + 1].0 == scores[i].0 {
+            j += 1;
+        }
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        rank_sum += rank * scores[i..=j].iter().filter(|s| s.1).count() as f64;
+        i = j + 1;
+    }
+    let (n_treat, n_control) = (treat.len() as f64, control.len() as f64);
+    (rank_sum - n_treat * (n_treat + 1.0) / 2.0) / (n_treat * n_control)
+}
+
+// What happens to units with extreme propensity scores
+//
+// `Drop` removes them before matching and weighting; `Clip` keeps them
+// but moves their scores to the nearest threshold, so they are matched and
+// weighted as if their scores were only as extreme as the thresholds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Trim {
+    #[default]
+    Drop,
+    Clip
+}
+
+// Implement `FromStr` for `Trim`.
+impl FromStr for Trim {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Trim::Drop),
+            "clip" => Ok(Trim::Clip),
+            _ => Err(format!("Unknown trim method `{}`; use drop or clip", s))
+        }
+    }
+}
+
+// Implement `Display` for `Trim`.
+impl fmt::Display for Trim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trim::Drop => write!(f, "drop"),
+            Trim::Clip => write!(f, "clip")
+        }
+    }
+}
+
+// A unit whose fitted propensity score lies outside the thresholds.
+#[derive(Debug)]
+pub struct ExtremeUnit {
+    pub id: i64,
+    pub treated: bool,
+    pub propensity: f64,
+    pub covariates: Vec<(String, f64)>
+}
+
+// Units with extreme propensity scores
+//
+// Propensities close to 0 or 1 destabilize both matching (there is nobody
+// comparable to match with) and weighting (the weights explode). Units
+// below `lower` or above `upper` are listed with their covariate values;
+// `trim` records whether they were dropped or clipped before matching.
+#[derive(Debug)]
+pub struct ExtremeUnits {
+    pub lower: f64,
+    pub upper: f64,
+    pub trim: Option<Trim>,
+    pub units: Vec<ExtremeUnit>
+}
+
+// Whether each row's propensity score lies within [lower, upper].
+fn within(data: &DataFrame, lower: f64, upper: f64) -> PolarsResult<Vec<bool>> {
+    let scores = column_values(data, "propensities")?;
+    Ok(scores.iter().map(|p| (lower..=upper).contains(p)).collect())
+}
+
+// Find units with extreme propensity scores
+//
+// `data` must already have the `propensities` and `strike_id` columns. The
+// id reported for every unit is its `strike_id`, the 1-based row number in
+// the input data.
+pub fn extreme_units(
+    data: &DataFrame,
+    treatment: &str,
+    outcome: &str,
+    lower: f64,
+    upper: f64,
+    trim: Option<Trim>
+) -> PolarsResult<ExtremeUnits> {
+    let keep = within(data, lower, upper)?;
+    let extreme = data.filter(&!BooleanChunked::new("extreme", &keep))?;
+    let covariates = covariate_names(&extreme, &[treatment, outcome]);
+    let ids = column_values(&extreme, "strike_id")?;
+    let treated = column_values(&extreme, treatment)?;
+    let scores = column_values(&extreme, "propensities")?;
+    let mut values = Vec::with_capacity(covariates.len());
+    for covariate in &covariates {
+        values.push(column_values(&extreme, covariate)?);
+    }
+    let units = (0..extreme.height())
+        .map(|i| ExtremeUnit {
+            id: ids[i] as i64,
+            treated: treated[i] == 1.0,
+            propensity: scores[i],
+            covariates: covariates
+                .iter()
+                .zip(&values)
+                .map(|(covariate, x)| (covariate.clone(), x[i]))
+                .collect()
+        })
+        .collect();
+    Ok(ExtremeUnits { lower, upper, trim, units })
+}
+
+// Drop units whose propensity score lies outside [lower, upper], or with
+// `Trim::Clip` move their scores to the nearest of the two.
+pub fn trim_extreme(data: &DataFrame, lower: f64, upper: f64, trim: Trim) -> PolarsResult<DataFrame> {
+    match trim {
+        Trim::Drop => {
+            let keep = within(data, lower, upper)?;
+            data.filter(&BooleanChunked::new("extreme", &keep))
+        },
+        Trim::Clip => {
+            let scores: Vec<f64> = column_values(data, "propensities")?
+                .into_iter()
+                .map(|p| p.clamp(lower, upper))
+                .collect();
+            let mut data = data.clone();
+            data.with_column(Series::new("propensities", scores))?;
+            Ok(data)
+        }
+    }
+}
+
+impl ExtremeUnits {
+    // Combine the units flagged in the two halves of a cross-fit.
+    pub fn combine(mut self, other: ExtremeUnits) -> ExtremeUnits {
+        self.units.extend(other.units);
+        self
+    }
+}
+
+// Implement `Display` for `ExtremeUnits`.
+impl fmt::Display for ExtremeUnits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n_treat = self.units.iter().filter(|u| u.treated).count();
+        writeln!(
+            f,
+            "Extreme propensity scores (< {} or > {}): {} treated, {} control{}",
+            self.lower,
+            self.upper,
+            n_treat,
+            self.units.len() - n_treat,
+            match self.trim {
+                Some(Trim::Drop) => ", excluded before matching".to_string(),
+                Some(Trim::Clip) => format!(", clipped to [{}, {}] before matching", self.lower, self.upper),
+                None => String::new()
+            }
+        )?;
+        for unit in &self.units {
+            let covariates: Vec<String> = unit
+                .covariates
+                .iter()
+                .map(|(covariate, x)| format!("{}={}", covariate, x))
+                .collect();
+            writeln!(
+                f,
+                "  id {:>6}  {:<7}  ps {:.4}  {}",
+                unit.id,
+                match unit.treated {
+                    true => "treated",
+                    false => "control"
+                },
+                unit.propensity,
+                covariates.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Which units outside the common support are dropped before matching
+// (`--discard`)
+//
+// `OffSupport` drops the units being matched whose propensity score lies
+// outside the range of the scores of the arm they are matched to: treated
+// units outside the control range for the ATT, controls outside the treated
+// range for the ATC, and both for the ATE.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Discard {
+    OffSupport
+}
+
+// Implement `FromStr` for `Discard`.
+impl FromStr for Discard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off-support" => Ok(Discard::OffSupport),
+            _ => Err(format!("Unknown discard rule `{}`; use off-support", s))
+        }
+    }
+}
+
+// Implement `Display` for `Discard`.
+impl fmt::Display for Discard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Discard::OffSupport => write!(f, "off-support")
+        }
+    }
+}
+
+// Common support of the propensity score (`--common-support`)
+//
+// The smallest and largest score of each arm and how many units of each
+// lie outside the range of the other arm's scores: a treated unit above
+// the largest control score has no control that is comparable, and
+// matching it anyway extrapolates. `discarded` counts the treated and
+// control units `--discard off-support` dropped.
+#[derive(Clone, Debug)]
+pub struct CommonSupport {
+    pub treat_range: (f64, f64),
+    pub control_range: (f64, f64),
+    pub n_treat: usize,
+    pub n_control: usize,
+    pub treat_outside: usize,
+    pub control_outside: usize,
+    pub discarded: Option<(usize, usize)>
+}
+
+// Smallest and largest of some scores.
+fn score_range(scores: &[f64]) -> (f64, f64) {
+    scores.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), p| (lo.min(*p), hi.max(*p)))
+}
+
+// Whether each row's score lies outside the range of the other arm's
+// scores.
+fn off_support(treated: &[f64], scores: &[f64], treat_range: (f64, f64), control_range: (f64, f64)) -> Vec<bool> {
+    treated
+        .iter()
+        .zip(scores)
+        .map(|(d, p)| {
+            let (lo, hi) = match *d == 1.0 {
+                true => control_range,
+                false => treat_range
+            };
+            *p < lo || *p > hi
+        })
+        .collect()
+}
+
+// Check the common support of the scores in `data`, which must already
+// have the `propensities` column.
+pub fn common_support(data: &DataFrame, treatment: &str) -> PolarsResult<CommonSupport> {
+    let treated = column_values(data, treatment)?;
+    let scores = column_values(data, "propensities")?;
+    let arm = |group: f64| -> Vec<f64> {
+        treated.iter().zip(&scores).filter(|(d, _)| **d == group).map(|(_, p)| *p).collect()
+    };
+    let (treat_scores, control_scores) = (arm(1.0), arm(0.0));
+    let (treat_range, control_range) = (score_range(&treat_scores), score_range(&control_scores));
+    let outside = off_support(&treated, &scores, treat_range, control_range);
+    let count = |group: f64| treated.iter().zip(&outside).filter(|(d, o)| **d == group && **o).count();
+    Ok(CommonSupport {
+        treat_range,
+        control_range,
+        n_treat: treat_scores.len(),
+        n_control: control_scores.len(),
+        treat_outside: count(1.0),
+        control_outside: count(0.0),
+        discarded: None
+    })
+}
+
+// Drop the units outside the common support
+//
+// Treated units outside the control range are dropped when `treated` are
+// matched and controls outside the treated range when `controls` are, and
+// the numbers dropped are recorded in `support`.
+pub fn discard_off_support(
+    data: &DataFrame,
+    treatment: &str,
+    support: &mut CommonSupport,
+    (treated, controls): (bool, bool)
+) -> PolarsResult<DataFrame> {
+    let d = column_values(data, treatment)?;
+    let scores = column_values(data, "propensities")?;
+    let outside = off_support(&d, &scores, support.treat_range, support.control_range);
+    let drop: Vec<bool> = d
+        .iter()
+        .zip(&outside)
+        .map(|(d, o)| *o && ((*d == 1.0 && treated) || (*d == 0.0 && controls)))
+        .collect();
+    let count = |group: f64| d.iter().zip(&drop).filter(|(d, x)| **d == group && **x).count();
+    support.discarded = Some((count(1.0), count(0.0)));
+    data.filter(&!BooleanChunked::new("off_support", &drop))
+}
+
+impl CommonSupport {
+    // Combine the checks of the two halves of a cross-fit.
+    pub fn combine(self, other: CommonSupport) -> CommonSupport {
+        let union = |a: (f64, f64), b: (f64, f64)| (a.0.min(b.0), a.1.max(b.1));
+        CommonSupport {
+            treat_range: union(self.treat_range, other.treat_range),
+            control_range: union(self.control_range, other.control_range),
+            n_treat: self.n_treat + other.n_treat,
+            n_control: self.n_control + other.n_control,
+            treat_outside: self.treat_outside + other.treat_outside,
+            control_outside: self.control_outside + other.control_outside,
+            discarded: self.discarded.zip(other.discarded).map(|(a, b)| (a.0 + b.0, a.1 + b.1))
+        }
+    }
+}
+
+// Implement `Display` for `CommonSupport`.
+impl fmt::Display for CommonSupport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let share = |k: usize, n: usize| format!("{} of {} ({}%)", k, n, num(100.0 * k as f64 / n as f64));
+        writeln!(f, "Common support of the propensity score")?;
+        writeln!(f, "{:<8}  {:>10}  {:>10}", "", "Min", "Max")?;
+        writeln!(f, "{:<8}  {:>10}  {:>10}", "Treated", num(self.treat_range.0), num(self.treat_range.1))?;
+        writeln!(f, "{:<8}  {:>10}  {:>10}", "Control", num(self.control_range.0), num(self.control_range.1))?;
+        writeln!(f, "Treated outside the control range: {}", share(self.treat_outside, self.n_treat))?;
+        writeln!(f, "Control outside the treated range: {}", share(self.control_outside, self.n_control))?;
+        if let Some((treated, controls)) = self.discarded {
+            writeln!(f, "Discarded before matching: {} treated, {} control", treated, controls)?;
+        }
+        Ok(())
+    }
+}
+
+// Treated and control counts in one cell of the positivity check.
+#[derive(Debug)]
+pub struct PositivityCell {
+    pub label: String,
+    pub n_treat: usize,
+    pub n_control: usize
+}
+
+impl PositivityCell {
+    fn violates(&self) -> bool {
+        self.n_treat == 0 || self.n_control == 0
+    }
+}
+
+// Share of units that fall in cells containing both treated and control
+// units.
+fn positivity_score(cells: &[PositivityCell]) -> f64 {
+    let total: usize = cells.iter().map(|c| c.n_treat + c.n_control).sum();
+    let supported: usize = cells
+        .iter()
+        .filter(|c| !c.violates())
+        .map(|c| c.n_treat + c.n_control)
+        .sum();
+    supported as f64 / total as f64
+}
+
+// Structured positivity check
+//
+// Positivity requires every kind of unit to have some chance of being
+// treated and of being a control. The check looks for cells with only
+// treated or only control units in two ways: propensity score bins (at
+// quantiles of the pooled scores), and strata formed by every combination
+// of the discrete covariates (those with at most `MAX_STRATUM_LEVELS`
+// values). The positivity score of each is the share of units in cells
+// that contain both groups, so 1 means no violations.
+#[derive(Debug)]
+pub struct PositivityCheck {
+    pub bins: Vec<PositivityCell>,
+    pub strata: Vec<PositivityCell>,
+    pub stratum_covariates: Vec<String>
+}
+
+// Run the positivity check on all treated units and the full control pool.
+pub fn positivity_check(
+    treat: &DataFrame,
+    pool: &DataFrame,
+    covariates: &[String],
+    n_bins: usize
+) -> PolarsResult<PositivityCheck> {
+    let treat_ps = column_values(treat, "propensities")?;
+    let pool_ps = column_values(pool, "propensities")?;
+    let all_ps: Vec<f64> = treat_ps.iter().chain(&pool_ps).copied().collect();
+    let breaks = subclass_breaks(&all_ps, n_bins);
+    let mut bins: Vec<PositivityCell> = (0..n_bins)
+        .map(|k| {
+            let lower = if k == 0 { 0.0 } else { breaks[k - 1] };
+            let upper = if k == n_bins - 1 { 1.0 } else { breaks[k] };
+            PositivityCell {
+                label: format!("({}, {}]", num(lower), num(upper)),
+                n_treat: 0,
+                n_control: 0
+            }
+        })
+        .collect();
+    for p in &treat_ps {
+        bins[subclass_of(*p, &breaks)].n_treat += 1;
+    }
+    for p in &pool_ps {
+        bins[subclass_of(*p, &breaks)].n_control += 1;
+    }
+
+    let mut stratum_covariates = Vec::new();
+    let mut treat_values = Vec::new();
+    let mut pool_values = Vec::new();
+    for covariate in covariates {
+        let x_treat = predictor_values(treat, covariate)?;
+        let x_pool = predictor_values(pool, covariate)?;
+        let mut levels: Vec<f64> = x_treat.iter().chain(&x_pool).copied().collect();
+        levels.sort_by(|a, b| a.total_cmp(b));
+        levels.dedup();
+        if levels.len() <= MAX_STRATUM_LEVELS {
+            stratum_covariates.push(covariate.clone());
+            treat_values.push(x_treat);
+            pool_values.push(x_pool);
+        }
+    }
+    let mut counts: BTreeMap<Vec<u64>, (usize, usize)> = BTreeMap::new();
+    for (values, n, treated) in [(&treat_values, treat.height(), true), (&pool_values, pool.height(), false)] {
+        for i in 0..n {
+            let key: Vec<u64> = values.iter().map(|x| x[i].to_bits()).collect();
+            let count = counts.entry(key).or_insert((0, 0));
+            match treated {
+                true => count.0 += 1,
+                false => count.1 += 1
+            }
+        }
+    }
+    let strata = counts
+        .into_iter()
+        .map(|(key, (n_treat, n_control))| {
+            let label: Vec<String> = stratum_covariates
+                .iter()
+                .zip(&key)
+                .map(|(covariate, bits)| format!("{}={}", covariate, f64::from_bits(*bits)))
+                .collect();
+            PositivityCell { label: label.join(", "), n_treat, n_control }
+        })
+        .collect();
+    Ok(PositivityCheck { bins, strata, stratum_covariates })
+}
+
+// Implement `Display` for `PositivityCheck`.
+impl fmt::Display for PositivityCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Positivity check (cells with only treated or only control units flagged with *)")?;
+        writeln!(f, "{:>18}  {:>7}  {:>9}", "PS bin", "# Treat", "# Control")?;
+        for bin in &self.bins {
+            let flag = match bin.violates() {
+                true => "*",
+                false => ""
+            };
+            writeln!(f, "{:>18}  {:>7}  {:>9}{}", bin.label, bin.n_treat, bin.n_control, flag)?;
+        }
+        writeln!(f, "Positivity score (PS bins)         : {}", num(positivity_score(&self.bins)))?;
+        if self.stratum_covariates.is_empty() {
+            return writeln!(f, "No discrete covariates to form covariate strata");
+        }
+        let mut violations: Vec<&PositivityCell> = self.strata.iter().filter(|c| c.violates()).collect();
+        violations.sort_by_key(|c| std::cmp::Reverse(c.n_treat + c.n_control));
+        writeln!(
+            f,
+            "Covariate strata ({}): {} of {} strata violate positivity",
+            self.stratum_covariates.join(", "),
+            violations.len(),
+            self.strata.len()
+        )?;
+        for cell in violations.iter().take(MAX_LISTED_STRATA) {
+            writeln!(f, "  {:>7} treated  {:>7} control  {}", cell.n_treat, cell.n_control, cell.label)?;
+        }
+        if violations.len() > MAX_LISTED_STRATA {
+            writeln!(f, "  ... and {} more", violations.len() - MAX_LISTED_STRATA)?;
+        }
+        writeln!(f, "Positivity score (covariate strata): {}", num(positivity_score(&self.strata)))
+    }
+}
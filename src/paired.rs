@@ -0,0 +1,534 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::data::sorted;
+use crate::format::num;
+
+// Paired t-test on the matched-pair differences.
+#[derive(Debug)]
+pub struct PairedTTest {
+    pub mean: f64,
+    pub std_error: f64,
+    pub t: f64,
+    pub df: f64,
+    pub p_value: f64,
+    pub ci: (f64, f64)
+}
+
+// Wilcoxon signed-rank test on the matched-pair differences
+//
+// `estimate` and `ci` are the Hodges-Lehmann pseudomedian and interval (see
+// `HodgesLehmann`). `statistic` is the sum of the ranks of the positive
+// differences. Pairs with no difference are dropped.
+#[derive(Debug)]
+pub struct SignedRankTest {
+    pub n: usize,
+    pub statistic: f64,
+    pub z: f64,
+    pub p_value: f64,
+    pub estimate: f64,
+    pub ci: (f64, f64)
+}
+
+// The familiar paired analyses of the 1:1 matched sample
+//
+// Both tests treat the pairs as independent, which they are not when a
+// control is matched to several treated units, so their intervals tend to
+// be narrower than the Abadie-Imbens interval. They are reported for
+// comparison only.
+#[derive(Debug)]
+pub struct PairedTests {
+    pub n_pairs: usize,
+    pub t_test: PairedTTest,
+    pub signed_rank: SignedRankTest
+}
+
+// Run the paired t-test and the Wilcoxon signed-rank test on the pair
+// differences.
+pub fn paired_tests(differences: &[f64]) -> PairedTests {
+    PairedTests {
+        n_pairs: differences.len(),
+        t_test: paired_t_test(differences),
+        signed_rank: signed_rank_test(differences)
+    }
+}
+
+fn paired_t_test(d: &[f64]) -> PairedTTest {
+    let n = d.len() as f64;
+    let mean = d.iter().sum::<f64>() / n;
+    let sd = (d.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+    let std_error = sd / n.sqrt();
+    let t = mean / std_error;
+    let df = n - 1.0;
+    let critical = t_critical(0.05, df);
+    PairedTTest {
+        mean,
+        std_error,
+        t,
+        df,
+        p_value: t_two_sided(t, df),
+        ci: (mean - critical * std_error, mean + critical * std_error)
+    }
+}
+
+fn signed_rank_test(d: &[f64]) -> SignedRankTest {
+    let d: Vec<f64> = d.iter().copied().filter(|x| *x != 0.0).collect();
+    let n = d.len() as f64;
+    let (ranks, ties) = absolute_ranks(&d);
+    let statistic: f64 = d.iter().zip(&ranks).filter(|(x, _)| **x > 0.0).map(|(_, r)| r).sum();
+    let expected = n * (n + 1.0) / 4.0;
+    let sd = (n * (n + 1.0) * (2.0 * n + 1.0) / 24.0 - ties / 48.0).sqrt();
+    let deviation = statistic - expected;
+    let z = (deviation - 0.5 * deviation.signum()) / sd;
+    let hodges_lehmann = hodges_lehmann(&d);
+    SignedRankTest {
+        n: d.len(),
+        statistic,
+        z,
+        p_value: 2.0 * (1.0 - normal_cdf(z.abs())),
+        estimate: hodges_lehmann.estimate,
+        ci: hodges_lehmann.ci
+    }
+}
+
+// Average ranks of |d|, with ties sharing their mean rank, and the tie
+// correction sum(t^3 - t) over groups of t tied values.
+pub fn absolute_ranks(d: &[f64]) -> (Vec<f64>, f64) {
+    let mut order: Vec<usize> = (0..d.len()).collect();
+    order.sort_by(|a, b| d[*a].abs().total_cmp(&d[*b].abs()));
+    let mut ranks = vec![0.0; d.len()];
+    let mut ties = 0.0;
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && d[order[end]].abs() == d[order[start]].abs() {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        for i in &order[start..end] {
+            ranks[*i] = rank;
+        }
+        let t = (end - start) as f64;
+        ties += t.powi(3) - t;
+        start = end;
+    }
+    (ranks, ties)
+}
+
+// Largest number of differences for which the interval uses the exact
+// signed-rank distribution.
+pub const EXACT_LIMIT: usize = 50;
+
+// Hodges-Lehmann estimate of the effect on the matched pairs
+//
+// The median of the Walsh averages (d_i + d_j) / 2 over all i <= j of the
+// pair differences. Its 95% interval inverts the signed-rank test, so it
+// is distribution-free: the bounds are order statistics of the Walsh
+// averages whose rank comes from the exact signed-rank distribution when
+// there are fewer than `EXACT_LIMIT` differences without ties, and from
+// its tie-corrected normal approximation otherwise.
+#[derive(Debug)]
+pub struct HodgesLehmann {
+    pub n: usize,
+    pub estimate: f64,
+    pub ci: (f64, f64),
+    pub exact: bool
+}
+
+// Calculate the Hodges-Lehmann estimate and interval of the pair
+// differences.
+pub fn hodges_lehmann(differences: &[f64]) -> HodgesLehmann {
+    let d = sorted(differences);
+    let n = d.len();
+    if n == 0 {
+        return HodgesLehmann { n, estimate: f64::NAN, ci: (f64::NAN, f64::NAN), exact: false };
+    }
+    let m = n * (n + 1) / 2;
+    let estimate = match m % 2 {
+        1 => walsh_order_statistic(&d, m.div_ceil(2)),
+        _ => (walsh_order_statistic(&d, m / 2) + walsh_order_statistic(&d, m / 2 + 1)) / 2.0
+    };
+    let (_, ties) = absolute_ranks(&d);
+    let exact = n < EXACT_LIMIT && ties == 0.0;
+    // Rank of the lower bound among the Walsh averages
+    let k = match exact {
+        true => signed_rank_quantile(n, 0.025).max(1),
+        false => {
+            let n = n as f64;
+            let expected = n * (n + 1.0) / 4.0;
+            let sd = (n * (n + 1.0) * (2.0 * n + 1.0) / 24.0 - ties / 48.0).sqrt();
+            (expected - 1.959964 * sd).floor().max(0.0) as usize + 1
+        }
+    };
+    HodgesLehmann {
+        n,
+        estimate,
+        ci: (walsh_order_statistic(&d, k), walsh_order_statistic(&d, m + 1 - k)),
+        exact
+    }
+}
+
+// Smallest `v` with P(V <= v) >= `p` under the null distribution of the
+// signed-rank statistic V of `n` untied differences.
+fn signed_rank_quantile(n: usize, p: f64) -> usize {
+    let m = n * (n + 1) / 2;
+    // counts[v] is the number of the 2^n sign patterns whose statistic is v
+    let mut counts = vec![0.0; m + 1];
+    counts[0] = 1.0;
+    for rank in 1..=n {
+        for v in (rank..=m).rev() {
+            counts[v] += counts[v - rank];
+        }
+    }
+    let total = 2f64.powi(n as i32);
+    let mut cumulative = 0.0;
+    for (v, count) in counts.iter().enumerate() {
+        cumulative += count / total;
+        if cumulative >= p {
+            return v;
+        }
+    }
+    m
+}
+
+// McNemar's test and the matched-pairs odds ratio for a binary outcome
+//
+// Only discordant pairs carry information: `treated_only` pairs have the
+// outcome for the treated unit alone and `control_only` pairs for the
+// control alone. The odds ratio is their ratio, and under no effect
+// `treated_only` is Binomial(discordant, 1/2). The intervals are for the
+// binomial proportion, exact (Clopper-Pearson) and mid-p, mapped onto the
+// odds ratio. Pairs are treated as independent.
+#[derive(Debug)]
+pub struct McNemar {
+    pub n_pairs: usize,
+    pub treated_only: usize,
+    pub control_only: usize,
+    pub odds_ratio: f64,
+    pub exact_p: f64,
+    pub exact_ci: (f64, f64),
+    pub mid_p: f64,
+    pub mid_p_ci: (f64, f64)
+}
+
+// Run McNemar's test on the outcomes of the treated units and their
+// matched controls, which must all be 0 or 1.
+pub fn mcnemar(treat_y: &[f64], control_y: &[f64]) -> Result<McNemar, Box<dyn Error>> {
+    if treat_y.iter().chain(control_y).any(|y| *y != 0.0 && *y != 1.0) {
+        return Err("`--mcnemar` needs a binary outcome coded 0/1".into());
+    }
+    let pairs = treat_y.iter().zip(control_y);
+    let treated_only = pairs.clone().filter(|(t, c)| **t > **c).count();
+    let control_only = pairs.filter(|(t, c)| **t < **c).count();
+    let (b, n) = (treated_only, treated_only + control_only);
+    let tail = b.min(control_only);
+    let (below, at) = (binomial_cdf(tail, n, 0.5) - binomial_pmf(tail, n, 0.5), binomial_pmf(tail, n, 0.5));
+    let odds = |(lo, hi): (f64, f64)| (lo / (1.0 - lo), hi / (1.0 - hi));
+    Ok(McNemar {
+        n_pairs: treat_y.len(),
+        treated_only,
+        control_only,
+        odds_ratio: b as f64 / control_only as f64,
+        exact_p: (2.0 * (below + at)).min(1.0),
+        exact_ci: odds(proportion_interval(b, n, 1.0)),
+        mid_p: (2.0 * (below + 0.5 * at)).min(1.0),
+        mid_p_ci: odds(proportion_interval(b, n, 0.5))
+    })
+}
+
+// P(X = k) for X ~ Binomial(n, p).
+fn binomial_pmf(k: usize, n: usize, p: f64) -> f64 {
+    let (k, n) = (k as f64, n as f64);
+    let coefficient = ln_gamma(n + 1.0) - ln_gamma(k + 1.0) - ln_gamma(n - k + 1.0);
+    (coefficient + k * p.ln() + (n - k) * (1.0 - p).ln()).exp()
+}
+
+// P(X <= k) for X ~ Binomial(n, p).
+fn binomial_cdf(k: usize, n: usize, p: f64) -> f64 {
+    (0..=k).map(|i| binomial_pmf(i, n, p)).sum()
+}
+
+// 95% interval for a binomial proportion with `k` successes in `n` trials
+//
+// Each bound solves for the proportion at which the tail beyond `k` has
+// probability 0.025, counting `weight` of P(X = k) in the tail: 1 gives the
+// exact (Clopper-Pearson) interval and 0.5 the mid-p interval.
+fn proportion_interval(k: usize, n: usize, weight: f64) -> (f64, f64) {
+    let upper_tail = |p: f64| 1.0 - binomial_cdf(k, n, p) + weight * binomial_pmf(k, n, p);
+    let lower_tail = |p: f64| binomial_cdf(k, n, p) - (1.0 - weight) * binomial_pmf(k, n, p);
+    let lower = match k {
+        0 => 0.0,
+        _ => bisect(|p| upper_tail(p) < 0.025)
+    };
+    let upper = match k == n {
+        true => 1.0,
+        false => bisect(|p| lower_tail(p) > 0.025)
+    };
+    (lower, upper)
+}
+
+// The point in (0, 1) where `below` switches from true to false.
+fn bisect(below: impl Fn(f64) -> bool) -> f64 {
+    let (mut lo, mut hi) = (0.0, 1.0);
+    for _ in 0..60 {
+        let mid = (lo + hi) / 2.0;
+        match below(mid) {
+            true => lo = mid,
+            false => hi = mid
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+// Number of Walsh averages (d_i + d_j) / 2, i <= j, that are at most `x`,
+// for sorted `d`.
+fn walsh_count(d: &[f64], x: f64) -> usize {
+    let mut count = 0;
+    let mut j = d.len();
+    for i in 0..d.len() {
+        while j > i && d[i] + d[j - 1] > 2.0 * x {
+            j -= 1;
+        }
+        if j <= i {
+            break;
+        }
+        count += j - i;
+    }
+    count
+}
+
+// The largest Walsh average that is at most `x`, for sorted `d`.
+fn walsh_floor(d: &[f64], x: f64) -> f64 {
+    let mut best = f64::NEG_INFINITY;
+    let mut j = d.len();
+    for i in 0..d.len() {
+        while j > i && d[i] + d[j - 1] > 2.0 * x {
+            j -= 1;
+        }
+        if j <= i {
+            break;
+        }
+        best = best.max((d[i] + d[j - 1]) / 2.0);
+    }
+    best
+}
+
+// The k-th smallest (1-based) Walsh average of sorted `d`
+//
+// Found by bisection on the value with `walsh_count`, so it takes
+// O(n log n) time without listing all n(n + 1) / 2 averages.
+fn walsh_order_statistic(d: &[f64], k: usize) -> f64 {
+    let (mut lo, mut hi) = (d[0], d[d.len() - 1]);
+    for _ in 0..100 {
+        let mid = (lo + hi) / 2.0;
+        match walsh_count(d, mid) >= k {
+            true => hi = mid,
+            false => lo = mid
+        }
+    }
+    walsh_floor(d, hi)
+}
+
+// Standard normal CDF
+//
+// Through the complementary error function, with the Chebyshev fit of
+// Numerical Recipes (relative error below 1.2e-7).
+pub fn normal_cdf(z: f64) -> f64 {
+    const COEFFICIENTS: [f64; 10] = [
+        -1.26551223,
+        1.00002368,
+        0.37409196,
+        0.09678418,
+        -0.18628806,
+        0.27886807,
+        -1.13520398,
+        1.48851587,
+        -0.82215223,
+        0.17087277
+    ];
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.5 * x);
+    let poly = COEFFICIENTS.iter().rev().fold(0.0, |acc, c| acc * t + c);
+    let erfc = t * (-x * x + poly).exp();
+    match z >= 0.0 {
+        true => 1.0 - erfc / 2.0,
+        false => erfc / 2.0
+    }
+}
+
+// Natural log of the gamma function (Lanczos approximation).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5
+    ];
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let mut series = 1.000000000190015;
+    for (i, c) in COEFFICIENTS.iter().enumerate() {
+        series += c / (x + 1.0 + i as f64);
+    }
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+// Continued fraction of the incomplete beta function (modified Lentz).
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    let tiny = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    d = 1.0 / if d.abs() < tiny { tiny } else { d };
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        for numerator in [
+            m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m)),
+            -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0))
+        ] {
+            d = 1.0 + numerator * d;
+            d = 1.0 / if d.abs() < tiny { tiny } else { d };
+            c = 1.0 + numerator / c;
+            c = if c.abs() < tiny { tiny } else { c };
+            h *= d * c;
+        }
+        if (d * c - 1.0).abs() < 1e-14 {
+            break;
+        }
+    }
+    h
+}
+
+// Regularized incomplete beta function I_x(a, b).
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 || x >= 1.0 {
+        return x.clamp(0.0, 1.0);
+    }
+    let front = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    match x < (a + 1.0) / (a + b + 2.0) {
+        true => front * beta_fraction(a, b, x) / a,
+        false => 1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+// Two-sided p-value of Student's t with `df` degrees of freedom.
+fn t_two_sided(t: f64, df: f64) -> f64 {
+    incomplete_beta(df / 2.0, 0.5, df / (df + t * t))
+}
+
+// The t value whose two-sided p-value is `alpha`, by bisection.
+fn t_critical(alpha: f64, df: f64) -> f64 {
+    let (mut lo, mut hi) = (0.0, 1000.0);
+    for _ in 0..100 {
+        let mid = (lo + hi) / 2.0;
+        match t_two_sided(mid, df) > alpha {
+            true => lo = mid,
+            false => hi = mid
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+// Format a p-value as `= p`, bottoming out at `< 0.001`.
+pub fn p_value(p: f64) -> String {
+    match p < 0.001 {
+        true => "< 0.001".to_string(),
+        false => format!("= {:.3}", p)
+    }
+}
+
+// Implement `Display` for `PairedTests`.
+impl fmt::Display for PairedTests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (t, w) = (&self.t_test, &self.signed_rank);
+        write!(
+            f,
+            "Paired tests ({} pairs, treated as independent)\n\
+            Paired t-test mean      : {} (t = {}, df = {}, p {})\n\
+            95% Confidence Interval : ({}, {})\n\
+            Signed-rank pseudomedian: {} (V = {}, z = {}, p {})\n\
+            95% Confidence Interval : ({}, {})\n",
+            self.n_pairs,
+            num(t.mean),
+            num(t.t),
+            t.df,
+            p_value(t.p_value),
+            num(t.ci.0),
+            num(t.ci.1),
+            num(w.estimate),
+            w.statistic,
+            num(w.z),
+            p_value(w.p_value),
+            num(w.ci.0),
+            num(w.ci.1)
+        )
+    }
+}
+
+// Implement `Display` for `McNemar`.
+impl fmt::Display for McNemar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "McNemar test ({} pairs, treated as independent)\n\
+            Discordant pairs        : {} treated only, {} control only\n\
+            Matched-pairs odds ratio: {}\n\
+            Exact 95% CI            : ({}, {}), p {}\n\
+            Mid-p 95% CI            : ({}, {}), p {}\n",
+            self.n_pairs,
+            self.treated_only,
+            self.control_only,
+            num(self.odds_ratio),
+            num(self.exact_ci.0),
+            num(self.exact_ci.1),
+            p_value(self.exact_p),
+            num(self.mid_p_ci.0),
+            num(self.mid_p_ci.1),
+            p_value(self.mid_p)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_test() {
+        // Mean 3, standard deviation sqrt(2.5), t = 3 / sqrt(0.5) on 4
+        // degrees of freedom; t(0.975, 4) = 2.776
+        let t_test = paired_tests(&[1.0, 2.0, 3.0, 4.0, 5.0]).t_test;
+        assert_eq!(t_test.mean, 3.0);
+        assert!((t_test.t - 4.2426).abs() < 1e-4);
+        assert_eq!(t_test.df, 4.0);
+        assert!((t_test.p_value - 0.01324).abs() < 1e-4);
+        assert!((t_test.ci.0 - (3.0 - 2.7764 * 0.5_f64.sqrt())).abs() < 1e-3);
+    }
+
+    #[test]
+    fn signed_rank_test() {
+        // T = 15 against 7.5 with standard deviation sqrt(13.75), with a
+        // continuity correction of 1/2
+        let signed_rank = paired_tests(&[1.0, 2.0, 3.0, 4.0, 5.0]).signed_rank;
+        assert_eq!(signed_rank.statistic, 15.0);
+        assert!((signed_rank.z - 7.0 / 13.75_f64.sqrt()).abs() < 1e-12);
+        assert!((signed_rank.p_value - 0.05906).abs() < 1e-4);
+        assert_eq!(signed_rank.estimate, 3.0);
+    }
+
+    #[test]
+    fn signed_rank_drops_zero_differences() {
+        let tests = paired_tests(&[0.0, -1.0, 2.0, 0.0, 3.0]);
+        assert_eq!(tests.n_pairs, 5);
+        assert_eq!(tests.signed_rank.n, 3);
+        assert_eq!(tests.signed_rank.statistic, 5.0);
+    }
+
+    #[test]
+    fn tied_ranks_share_their_mean() {
+        let (ranks, ties) = absolute_ranks(&[-1.0, 2.0, 1.0, 3.0]);
+        assert_eq!(ranks, vec![1.5, 3.0, 1.5, 4.0]);
+        assert_eq!(ties, 6.0);
+    }
+}
@@ -0,0 +1,189 @@
+use polars::prelude::{
+    BooleanChunked,
+    CsvReader,
+    DataFrame,
+    IdxCa,
+    IdxSize,
+    NamedFrom,
+    PolarsResult,
+    SerReader,
+    Series
+};
+use rand::Rng;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Instant;
+
+use crate::audit::record;
+use crate::cli::Args;
+use crate::compare::run_parallel;
+use crate::data::column_values;
+use crate::distance::ControlIndex;
+use crate::estimate::{estimate_pairs, fit_propensities, treat_control_split, Strike};
+use crate::filter::population;
+use crate::missing::check_missing_outcome;
+use crate::propensity::Coefficients;
+use crate::split::rng;
+use crate::validate::{validate, validate_propensities};
+
+// Read one partition, a csv or (with the `parquet` feature) Parquet file.
+pub fn read_partition(path: &str) -> PolarsResult<DataFrame> {
+    if !path.ends_with(".parquet") {
+        return CsvReader::from_path(path)?.finish();
+    }
+    read_parquet(path)
+}
+
+#[cfg(feature = "parquet")]
+fn read_parquet(path: &str) -> PolarsResult<DataFrame> {
+    polars::prelude::ParquetReader::new(std::fs::File::open(path)?).finish()
+}
+
+#[cfg(not(feature = "parquet"))]
+fn read_parquet(path: &str) -> PolarsResult<DataFrame> {
+    Err(polars::prelude::PolarsError::ComputeError(
+        format!("Reading {} needs the `parquet` feature: cargo build --features parquet", path).into()
+    ))
+}
+
+// Read partition `i` and keep a random `--fit-fraction` of its rows for
+// fitting, along with the partition's number of rows
+//
+// Each partition draws from its own generator, seeded from `--seed` and the
+// partition number, so the sample doesn't depend on thread scheduling.
+fn fit_sample(path: &str, i: usize, args: &Args) -> PolarsResult<(DataFrame, usize)> {
+    let data = population(read_partition(path)?, args)?;
+    let rows = data.height();
+    let fraction = match args.fit_fraction {
+        Some(fraction) if fraction < 1.0 => fraction,
+        _ => return Ok((data, rows))
+    };
+    let mut rng = rng(args.seed.map(|seed| seed.wrapping_add(i as u64)));
+    let keep: Vec<bool> = (0..rows).map(|_| rng.gen_bool(fraction)).collect();
+    Ok((data.filter(&BooleanChunked::new("sample", &keep))?, rows))
+}
+
+// A scored partition: its treated units and its controls
+//
+// Every row gets a `strike_id` that numbers the rows of all partitions
+// consecutively, in partition order, and a propensity score.
+fn score_partition(
+    path: &str,
+    offset: usize,
+    model: &Coefficients,
+    args: &Args
+) -> Result<(DataFrame, DataFrame), Box<dyn Error>> {
+    let mut data = population(read_partition(path)?, args)?;
+    let ids: Vec<i64> = (offset + 1..=offset + data.height()).map(|id| id as i64).collect();
+    data.with_column(Series::new("strike_id", ids))?;
+    data.with_column(Series::new("propensities", model.score(&data)?))?;
+    Ok(treat_control_split(&data, &args.treatment)?)
+}
+
+// Estimate ATT over a dataset split into partitions
+//
+// The partitions (csv or Parquet files with the same columns) are treated
+// as one dataset, their rows numbered in partition order. Partitions are
+// read by up to `--jobs` worker threads at every step:
+//
+// 1. The propensity model is fit on a random `--fit-fraction` of the rows
+//    of every partition (all rows by default).
+// 2. Every partition is scored with the model and split into treated units
+//    and controls, and all controls go into one shared `ControlIndex`.
+// 3. The treated units of every partition are matched against the index.
+//
+// The pairs are then merged into one estimate. With every row used for
+// fitting, the result equals that of a single file holding the partitions
+// one after another.
+pub fn partitioned(paths: &[String], args: &Args) -> Result<Strike, Box<dyn Error>> {
+    let jobs = args.jobs.unwrap_or(1);
+    let start = Instant::now();
+    let samples = run_parallel(paths.len(), jobs, |i| fit_sample(&paths[i], i, args).map_err(|e| e.to_string()));
+    let mut train = DataFrame::empty();
+    let mut offsets = Vec::with_capacity(paths.len());
+    let mut rows = 0;
+    for (path, sample) in paths.iter().zip(samples) {
+        let (sample, height) = sample.map_err(|e| format!("Failed to read partition {}: {}", path, e))?;
+        train = match train.width() {
+            0 => sample,
+            _ => train.vstack(&sample)?
+        };
+        offsets.push(rows);
+        rows += height;
+    }
+    train.as_single_chunk_par();
+    record(
+        "partitions",
+        Some(rows),
+        Some(train.height()),
+        start.elapsed(),
+        &format!("partitions={} fit_fraction={}", paths.len(), args.fit_fraction.unwrap_or(1.0))
+    );
+    args.cancel.check(|| "before fitting the propensity model".to_string())?;
+    validate(&train, &args.treatment, &args.outcome)?;
+    let (fit, model, separation) = fit_propensities(&train, &mut train.clone(), args)?;
+    let model = model.ok_or("`--partitions` needs a propensity model with coefficients")?;
+    let start = Instant::now();
+    let scored = run_parallel(paths.len(), jobs, |i| {
+        score_partition(&paths[i], offsets[i], &model, args).map_err(|e| e.to_string())
+    });
+    let mut parts = Vec::with_capacity(paths.len());
+    for (path, part) in paths.iter().zip(scored) {
+        parts.push(part.map_err(|e| format!("Failed to score partition {}: {}", path, e))?);
+    }
+    let mut treat = parts[0].0.clone();
+    let mut pool = parts[0].1.clone();
+    for (t, c) in &parts[1..] {
+        treat.vstack_mut(t)?;
+        pool.vstack_mut(c)?;
+    }
+    treat.as_single_chunk_par();
+    pool.as_single_chunk_par();
+    let scored = treat.vstack(&pool)?;
+    validate(&scored, &args.treatment, &args.outcome)?;
+    for outcome in args.extra_outcomes.iter().chain(&args.placebo_outcomes) {
+        validate(&scored, &args.treatment, outcome)?;
+    }
+    check_missing_outcome(&scored, args)?;
+    validate_propensities(&treat)?;
+    validate_propensities(&pool)?;
+    let pool_ps = column_values(&pool, "propensities")?;
+    let pool_ids = column_values(&pool, "strike_id")?;
+    let index = ControlIndex::new(pool_ps.iter().zip(&pool_ids).map(|(p, id)| (*p, *id as i64)).collect());
+    record("score", Some(rows), Some(pool.height()), start.elapsed(), &format!("controls={}", pool.height()));
+    let start = Instant::now();
+    let matched = run_parallel(parts.len(), jobs, |i| -> PolarsResult<Vec<Option<i64>>> {
+        let scores = column_values(&parts[i].0, "propensities")?;
+        let mut controls = Vec::with_capacity(scores.len());
+        for (j, p) in scores.iter().enumerate() {
+            args.cancel.check(|| format!("while matching partition {} after {} of {} units", i + 1, j, scores.len()))?;
+            controls.push(index.nearest(*p));
+        }
+        Ok(controls)
+    });
+    let pool_rows: HashMap<i64, IdxSize> = pool_ids
+        .iter()
+        .enumerate()
+        .map(|(row, id)| (*id as i64, row as IdxSize))
+        .collect();
+    let mut rows_matched = Vec::with_capacity(treat.height());
+    for controls in matched {
+        for control in controls? {
+            let control = control.ok_or("There are no control units to match")?;
+            rows_matched.push(pool_rows[&control]);
+        }
+    }
+    let control = pool.take(&IdxCa::from_vec("matches", rows_matched))?;
+    record(
+        "match",
+        Some(rows),
+        Some(treat.height()),
+        start.elapsed(),
+        &format!("method=indexed ratio=1 replace=true treated={} pool={} jobs={}", treat.height(), pool.height(), jobs)
+    );
+    let mut strike = estimate_pairs(&treat, &control, pool, args)?;
+    strike.fit = Some(fit);
+    strike.model = Some(model);
+    strike.separation = separation;
+    Ok(strike)
+}
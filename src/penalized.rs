@@ -0,0 +1,308 @@
+use ndarray::{Array1, Array2, Axis};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::propensity::LogitOptions;
+
+// Penalty strengths tried by cross-validation, log-spaced from the largest
+// useful strength down to `CV_RATIO` times it.
+pub const CV_GRID: usize = 20;
+
+// Smallest penalty strength of the cross-validation grid relative to its
+// largest.
+pub const CV_RATIO: f64 = 1e-3;
+
+// Folds of the penalty strength's cross-validation.
+pub const CV_FOLDS: usize = 5;
+
+// Smallest IRLS weight p(1 - p), which keeps the quadratic approximation
+// of the log-likelihood from collapsing when fitted probabilities reach 0
+// or 1.
+const MIN_WEIGHT: f64 = 1e-5;
+
+// The kind of penalty of a penalized logistic regression
+//
+// `L2` (ridge) shrinks all coefficients towards zero; `L1` (lasso) shrinks
+// them too but sets many exactly to zero, which selects covariates when
+// there are many of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PenaltyKind {
+    L1,
+    L2
+}
+
+// A penalty on the propensity model's coefficients
+//
+// The objective is the mean negative log-likelihood plus `lambda` times
+// |b|_1 (L1) or |b|^2 / 2 (L2), where b are the slopes of the standardized
+// predictors, so every predictor is penalized on the same scale and the
+// intercept not at all. Without a `lambda` it is chosen by cross-validation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Penalty {
+    pub kind: PenaltyKind,
+    pub lambda: Option<f64>
+}
+
+// Implement `FromStr` for `Penalty`: `l1` or `l2`, optionally followed by
+// `:lambda` or `:cv`.
+impl FromStr for Penalty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, lambda) = s.split_once(':').unwrap_or((s, "cv"));
+        let kind = match kind {
+            "l1" => PenaltyKind::L1,
+            "l2" => PenaltyKind::L2,
+            _ => return Err(format!("Unknown penalty `{}`; use l1 or l2, optionally with `:lambda` or `:cv`", s))
+        };
+        let lambda = match lambda {
+            "cv" => None,
+            lambda => match lambda.parse::<f64>() {
+                Ok(lambda) if lambda >= 0.0 && lambda.is_finite() => Some(lambda),
+                _ => return Err(format!("The penalty strength must be `cv` or non-negative but was {}", lambda))
+            }
+        };
+        Ok(Penalty { kind, lambda })
+    }
+}
+
+// Implement `Display` for `Penalty`.
+impl fmt::Display for Penalty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            PenaltyKind::L1 => "l1",
+            PenaltyKind::L2 => "l2"
+        };
+        match self.lambda {
+            Some(lambda) => write!(f, "{}:{}", kind, lambda),
+            None => write!(f, "{}:cv", kind)
+        }
+    }
+}
+
+fn sigmoid(eta: f64) -> f64 {
+    1.0 / (1.0 + (-eta).exp())
+}
+
+// Soft-thresholding operator of the lasso.
+fn soft_threshold(x: f64, threshold: f64) -> f64 {
+    x.signum() * (x.abs() - threshold).max(0.0)
+}
+
+// Penalized logistic regression
+//
+// Fit by iteratively reweighted least squares, each weighted least squares
+// problem being solved by cyclic coordinate descent (as in glmnet).
+// Predictors are standardized before fitting; constant predictors get a
+// slope of zero.
+#[derive(Debug)]
+pub struct PenalizedLogit {
+    mean: Vec<f64>,
+    sd: Vec<f64>,
+    beta: Array1<f64>,
+    pub lambda: f64
+}
+
+impl PenalizedLogit {
+    // The design matrix: an intercept and the standardized predictors.
+    fn design(&self, x: &Array2<f64>) -> Array2<f64> {
+        let mut z = Array2::<f64>::ones((x.nrows(), x.ncols() + 1));
+        for (k, column) in x.axis_iter(Axis(1)).enumerate() {
+            let column = match self.sd[k] > 0.0 {
+                true => column.mapv(|v| (v - self.mean[k]) / self.sd[k]),
+                false => Array1::zeros(x.nrows())
+            };
+            z.column_mut(k + 1).assign(&column);
+        }
+        z
+    }
+
+    // Standardize the columns of `x`.
+    fn standardize(x: &Array2<f64>) -> (Vec<f64>, Vec<f64>) {
+        x.axis_iter(Axis(1))
+            .map(|column| {
+                let (mean, sd) = (column.mean().unwrap_or(0.0), column.std(0.0));
+                (mean, if sd.is_nan() { 0.0 } else { sd })
+            })
+            .unzip()
+    }
+
+    // Fit with a given penalty strength
+    //
+    // Starts from `start` (on the standardized scale) when given, which
+    // makes a path of decreasing strengths cheap, and otherwise from the
+    // intercept-only model. Stops once no coefficient moves by more than
+    // the tolerance in an IRLS iteration. Returns the model and the number
+    // of iterations.
+    fn fit_lambda(
+        x: &Array2<f64>,
+        y: &Array1<f64>,
+        penalty: (PenaltyKind, f64),
+        start: Option<&Array1<f64>>,
+        options: &LogitOptions
+    ) -> (Self, usize) {
+        let (kind, lambda) = penalty;
+        let (mean, sd) = PenalizedLogit::standardize(x);
+        let mut model = PenalizedLogit { mean, sd, beta: Array1::zeros(x.ncols() + 1), lambda };
+        let z = model.design(x);
+        let n = z.nrows() as f64;
+        match start {
+            Some(beta) => model.beta.assign(beta),
+            None => {
+                let ybar = y.mean().unwrap_or(0.5).clamp(1e-6, 1.0 - 1e-6);
+                model.beta[0] = (ybar / (1.0 - ybar)).ln();
+            }
+        }
+        let mut iterations = 0;
+        while (iterations as u64) < options.max_iterations {
+            iterations += 1;
+            let p = z.dot(&model.beta).mapv(sigmoid);
+            let w = p.mapv(|p| (p * (1.0 - p)).max(MIN_WEIGHT));
+            // Residuals of the working response of the quadratic approximation
+            let mut residual = (y - &p) / &w;
+            let zw = &z * &w.view().insert_axis(Axis(1));
+            let curvature: Vec<f64> = (0..z.ncols()).map(|j| z.column(j).dot(&zw.column(j)) / n).collect();
+            let previous = model.beta.clone();
+            for _ in 0..options.max_iterations {
+                let mut largest = 0.0_f64;
+                for j in (0..z.ncols()).filter(|j| curvature[*j] > 0.0) {
+                    let gradient = zw.column(j).dot(&residual) / n + curvature[j] * model.beta[j];
+                    let updated = match (j, kind) {
+                        (0, _) => gradient / curvature[j],
+                        (_, PenaltyKind::L1) => soft_threshold(gradient, lambda) / curvature[j],
+                        (_, PenaltyKind::L2) => gradient / (curvature[j] + lambda)
+                    };
+                    let change = updated - model.beta[j];
+                    if change != 0.0 {
+                        residual.scaled_add(-change, &z.column(j));
+                        model.beta[j] = updated;
+                        largest = largest.max(change.abs());
+                    }
+                }
+                if largest < options.tolerance {
+                    break;
+                }
+            }
+            let moved = (&model.beta - &previous).iter().fold(0.0_f64, |m, c| m.max(c.abs()));
+            if moved < options.tolerance {
+                break;
+            }
+        }
+        (model, iterations)
+    }
+
+    // Largest penalty strength worth trying: the smallest at which the
+    // lasso sets every slope to zero.
+    fn lambda_max(x: &Array2<f64>, y: &Array1<f64>) -> f64 {
+        let (mean, sd) = PenalizedLogit::standardize(x);
+        let ybar = y.mean().unwrap_or(0.0);
+        x.axis_iter(Axis(1))
+            .enumerate()
+            .filter(|(k, _)| sd[*k] > 0.0)
+            .map(|(k, column)| {
+                let covariance = column.iter().zip(y).map(|(v, y)| (v - mean[k]) / sd[k] * (y - ybar)).sum::<f64>();
+                (covariance / x.nrows() as f64).abs()
+            })
+            .fold(0.0, f64::max)
+    }
+
+    // Choose the penalty strength by cross-validation
+    //
+    // Every strength of the grid is fit on all but one fold and scored by
+    // the deviance of the held-out fold; the strength with the smallest
+    // total deviance wins. Folds are assigned in turn within the treated
+    // units and within the controls, in row order, so each fold has its
+    // share of both and the choice is reproducible. The L2 grid starts ten
+    // times higher, since the ridge penalty never zeroes the slopes. Every
+    // fit starts from the one of the next larger strength.
+    fn cross_validate(x: &Array2<f64>, y: &Array1<f64>, kind: PenaltyKind, options: &LogitOptions) -> f64 {
+        let top = match kind {
+            PenaltyKind::L1 => PenalizedLogit::lambda_max(x, y),
+            PenaltyKind::L2 => 10.0 * PenalizedLogit::lambda_max(x, y)
+        };
+        let grid: Vec<f64> = (0..CV_GRID)
+            .map(|i| top * CV_RATIO.powf(i as f64 / (CV_GRID - 1) as f64))
+            .collect();
+        let mut seen = [0, 0];
+        let folds: Vec<usize> = y
+            .iter()
+            .map(|y| {
+                let group = (*y == 1.0) as usize;
+                seen[group] += 1;
+                seen[group] % CV_FOLDS
+            })
+            .collect();
+        let mut deviance = vec![0.0; grid.len()];
+        for fold in 0..CV_FOLDS {
+            let train: Vec<usize> = (0..y.len()).filter(|i| folds[*i] != fold).collect();
+            let test: Vec<usize> = (0..y.len()).filter(|i| folds[*i] == fold).collect();
+            let (x_train, y_train) = (x.select(Axis(0), &train), y.select(Axis(0), &train));
+            let (x_test, y_test) = (x.select(Axis(0), &test), y.select(Axis(0), &test));
+            let mut start: Option<Array1<f64>> = None;
+            for (total, lambda) in deviance.iter_mut().zip(&grid) {
+                let penalty = (kind, *lambda);
+                let (model, _) = PenalizedLogit::fit_lambda(&x_train, &y_train, penalty, start.as_ref(), options);
+                let p = model.predict_probabilities(&x_test);
+                *total -= y_test
+                    .iter()
+                    .zip(&p)
+                    .map(|(y, p)| {
+                        let p = p.clamp(1e-12, 1.0 - 1e-12);
+                        y * p.ln() + (1.0 - y) * (1.0 - p).ln()
+                    })
+                    .sum::<f64>();
+                start = Some(model.beta);
+            }
+        }
+        let best = (0..grid.len()).min_by(|a, b| deviance[*a].total_cmp(&deviance[*b])).unwrap_or(0);
+        grid[best]
+    }
+
+    // Fit the model to predictors `x` and binary response `d`
+    //
+    // With a cross-validated penalty the strength is chosen first and the
+    // model then refit on all rows. Returns the model, the largest
+    // violation of the optimality conditions of the penalized objective
+    // (on the standardized scale, like `LogitFit::max_gradient`) and the
+    // number of iterations.
+    pub fn fit(x: &Array2<f64>, d: &Array1<i64>, penalty: Penalty, options: &LogitOptions) -> (Self, f64, usize) {
+        let y = d.mapv(|d| d as f64);
+        let lambda = match penalty.lambda {
+            Some(lambda) => lambda,
+            None => PenalizedLogit::cross_validate(x, &y, penalty.kind, options)
+        };
+        let (model, iterations) = PenalizedLogit::fit_lambda(x, &y, (penalty.kind, lambda), None, options);
+        let z = model.design(x);
+        let residuals = &y - &model.predict_probabilities(x);
+        let score = z.t().dot(&residuals) / x.nrows() as f64;
+        let violation = score.iter().enumerate().fold(0.0_f64, |largest, (j, g)| {
+            let b = model.beta[j];
+            let excess = match (j, penalty.kind) {
+                (0, _) => g.abs(),
+                (_, PenaltyKind::L2) => (g - lambda * b).abs(),
+                (_, PenaltyKind::L1) if b != 0.0 => (g - lambda * b.signum()).abs(),
+                (_, PenaltyKind::L1) => (g.abs() - lambda).max(0.0)
+            };
+            largest.max(excess)
+        });
+        (model, violation, iterations)
+    }
+
+    // Intercept and slopes on the scale of the original predictors.
+    pub fn coefficients(&self) -> (f64, Vec<f64>) {
+        let mut intercept = self.beta[0];
+        let mut slopes = vec![0.0; self.sd.len()];
+        for (k, slope) in slopes.iter_mut().enumerate() {
+            if self.sd[k] > 0.0 {
+                *slope = self.beta[k + 1] / self.sd[k];
+                intercept -= *slope * self.mean[k];
+            }
+        }
+        (intercept, slopes)
+    }
+
+    // Predicted probabilities for the rows of `x`.
+    pub fn predict_probabilities(&self, x: &Array2<f64>) -> Array1<f64> {
+        self.design(x).dot(&self.beta).mapv(sigmoid)
+    }
+}
@@ -0,0 +1,96 @@
+use polars::prelude::{DataFrame, PolarsResult};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cancel::CancelToken;
+use crate::data::column_values;
+use crate::format::num;
+
+// Randomization test of the matched ATT (`--permute`)
+//
+// Under the sharp null of no effect for any unit, every member of a
+// matched set (a treated unit and its matches) was equally likely to be
+// the treated one. Each permutation draws the treated member of every set
+// at random and recomputes the ATT; `p_value` is the share of the
+// `permutations` (counting the observed assignment) whose ATT is at least
+// as far from zero as the observed one. Sets are permuted independently,
+// even when they share a reused control.
+#[derive(Clone, Debug)]
+pub struct PermutationTest {
+    pub permutations: usize,
+    pub n_sets: usize,
+    pub att: f64,
+    pub p_value: f64
+}
+
+// Run the randomization test with `permutations` draws from `rng`
+//
+// `treat` and `control` are the matched samples, row by row, with a
+// treated unit repeated once per match. A set's contribution to the ATT
+// is the outcome of its treated member minus the mean outcome of the
+// others, so the observed ATT is that of `calculate_att`. Stops with an
+// error if `cancel` is set.
+pub fn permutation_test(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    permutations: usize,
+    rng: &mut StdRng,
+    cancel: &CancelToken
+) -> PolarsResult<PermutationTest> {
+    let ids = column_values(treat, "strike_id")?;
+    let treat_y = column_values(treat, outcome)?;
+    let control_y = column_values(control, outcome)?;
+    // Outcomes of every matched set, its treated member first
+    let mut sets: Vec<Vec<f64>> = Vec::new();
+    let mut set_of: HashMap<i64, usize> = HashMap::new();
+    for ((id, t), c) in ids.iter().zip(&treat_y).zip(&control_y) {
+        let set = *set_of.entry(*id as i64).or_insert_with(|| {
+            sets.push(vec![*t]);
+            sets.len() - 1
+        });
+        sets[set].push(*c);
+    }
+    let totals: Vec<f64> = sets.iter().map(|set| set.iter().sum()).collect();
+    let contribution = |set: &[f64], total: f64, member: usize| {
+        let others = (set.len() - 1) as f64;
+        set[member] - (total - set[member]) / others
+    };
+    let n_sets = sets.len();
+    let att = sets.iter().zip(&totals).map(|(set, total)| contribution(set, *total, 0)).sum::<f64>() / n_sets as f64;
+    let mut extreme = 0;
+    for i in 0..permutations {
+        cancel.check(|| format!("during the permutation test after {} of {} permutations", i, permutations))?;
+        let permuted = sets
+            .iter()
+            .zip(&totals)
+            .map(|(set, total)| contribution(set, *total, rng.gen_range(0..set.len())))
+            .sum::<f64>()
+            / n_sets as f64;
+        // A relative tolerance keeps rounding from breaking ties with the observed ATT
+        if permuted.abs() >= att.abs() * (1.0 - 1e-12) {
+            extreme += 1;
+        }
+    }
+    Ok(PermutationTest {
+        permutations,
+        n_sets,
+        att,
+        p_value: (extreme + 1) as f64 / (permutations + 1) as f64
+    })
+}
+
+// Implement `Display` for `PermutationTest`.
+impl fmt::Display for PermutationTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Permutation p-value     : {} ({} permutations within {} matched sets)",
+            num(self.p_value),
+            self.permutations,
+            self.n_sets
+        )
+    }
+}
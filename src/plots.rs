@@ -0,0 +1,397 @@
+use std::error::Error;
+
+// Render a plot to `path`
+//
+// Draws with the given function on a bitmap if the path ends in `.png` and
+// on an SVG otherwise. Without the `plots` feature there is no renderer and
+// every plot request is an error.
+#[cfg(feature = "plots")]
+macro_rules! render {
+    ($path:expr, $draw:ident($($arg:expr),*)) => {{
+        use plotters::prelude::{BitMapBackend, IntoDrawingArea, SVGBackend};
+        match $path.ends_with(".png") {
+            true => draw::$draw(BitMapBackend::new($path, draw::SIZE).into_drawing_area(), $($arg),*),
+            false => draw::$draw(SVGBackend::new($path, draw::SIZE).into_drawing_area(), $($arg),*)
+        }
+    }};
+}
+
+#[cfg(not(feature = "plots"))]
+macro_rules! render {
+    ($path:expr, $draw:ident($($arg:expr),*)) => {{
+        let _ = ($path, $($arg),*);
+        Err("Strike was built without plotting support; rebuild with `--features plots`".into())
+    }};
+}
+
+#[cfg(feature = "plots")]
+mod draw {
+    use plotters::coord::Shift;
+    use plotters::prelude::*;
+    use std::error::Error;
+
+    use crate::data::{density, quantile, sorted};
+
+    pub const SIZE: (u32, u32) = (800, 600);
+
+    pub fn draw_overlap<DB: DrawingBackend>(
+        root: DrawingArea<DB, Shift>,
+        treat: &[f64],
+        control: &[f64]
+    ) -> Result<(), Box<dyn Error>>
+    where
+        DB::ErrorType: 'static
+    {
+        root.fill(&WHITE)?;
+        let grid: Vec<f64> = (0..=200).map(|i| i as f64 / 200.0).collect();
+        let treat_density = density(treat, &grid);
+        let control_density = density(control, &grid);
+        let y_max = treat_density
+            .iter()
+            .chain(&control_density)
+            .fold(0.0_f64, |m, d| m.max(*d)) * 1.05;
+        let mut chart = ChartBuilder::on(&root)
+            .caption("Propensity score overlap", ("sans-serif", 24))
+            .margin(15)
+            .x_label_area_size(40)
+            .y_label_area_size(50)
+            .build_cartesian_2d(0f64..1f64, 0f64..y_max)?;
+        chart
+            .configure_mesh()
+            .x_desc("Propensity score")
+            .y_desc("Density")
+            .draw()?;
+        for (label, values, color) in [("Treated", &treat_density, RED), ("Control", &control_density, BLUE)] {
+            chart
+                .draw_series(LineSeries::new(
+                    grid.iter().copied().zip(values.iter().copied()),
+                    color.stroke_width(2)
+                ))?
+                .label(label)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+        }
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+        root.present()?;
+        Ok(())
+    }
+
+    pub fn draw_love<DB: DrawingBackend>(
+        root: DrawingArea<DB, Shift>,
+        balance: &[(String, f64, f64)],
+        threshold: f64
+    ) -> Result<(), Box<dyn Error>>
+    where
+        DB::ErrorType: 'static
+    {
+        root.fill(&WHITE)?;
+        // Largest imbalance before matching at the top
+        let mut balance = balance.to_vec();
+        balance.sort_by(|a, b| a.1.abs().total_cmp(&b.1.abs()));
+        let balance = &balance;
+        let x_max = balance
+            .iter()
+            .fold(threshold, |m, (_, before, after)| m.max(before.abs()).max(after.abs())) * 1.1;
+        let mut chart = ChartBuilder::on(&root)
+            .caption("Covariate balance", ("sans-serif", 24))
+            .margin(15)
+            .x_label_area_size(40)
+            .y_label_area_size(140)
+            .build_cartesian_2d(0f64..x_max, (0..balance.len()).into_segmented())?;
+        chart
+            .configure_mesh()
+            .disable_y_mesh()
+            .x_desc("Absolute standardized mean difference")
+            .y_labels(balance.len())
+            .y_label_formatter(&|y| match y {
+                SegmentValue::CenterOf(i) => balance.get(*i).map_or(String::new(), |b| b.0.clone()),
+                _ => String::new()
+            })
+            .draw()?;
+        chart.draw_series(LineSeries::new(
+            vec![(threshold, SegmentValue::Exact(0)), (threshold, SegmentValue::Last)],
+            BLACK.mix(0.5)
+        ))?;
+        chart.draw_series(balance.iter().enumerate().map(|(i, (_, before, after))| {
+            let (before, after) = ((before.abs(), SegmentValue::CenterOf(i)), (after.abs(), SegmentValue::CenterOf(i)));
+            PathElement::new(vec![before, after], BLACK.mix(0.3))
+        }))?;
+        for (label, color, use_after) in [("Before matching", RED, false), ("After matching", BLUE, true)] {
+            chart
+                .draw_series(balance.iter().enumerate().map(|(i, (_, before, after))| {
+                    let smd = match use_after {
+                        true => after,
+                        false => before
+                    };
+                    Circle::new((smd.abs(), SegmentValue::CenterOf(i)), 4, color.filled())
+                }))?
+                .label(label)
+                .legend(move |(x, y)| Circle::new((x + 10, y), 4, color.filled()));
+        }
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+        root.present()?;
+        Ok(())
+    }
+
+    pub fn draw_eqq<DB: DrawingBackend>(
+        root: DrawingArea<DB, Shift>,
+        covariate: &str,
+        treat: &[f64],
+        control: &[f64]
+    ) -> Result<(), Box<dyn Error>>
+    where
+        DB::ErrorType: 'static
+    {
+        root.fill(&WHITE)?;
+        let (treat, control) = (sorted(treat), sorted(control));
+        let points: Vec<(f64, f64)> = (0..=100)
+            .map(|i| i as f64 / 100.0)
+            .map(|p| (quantile(&control, p), quantile(&treat, p)))
+            .collect();
+        let lo = treat[0].min(control[0]);
+        let hi = treat[treat.len() - 1].max(control[control.len() - 1]);
+        let pad = ((hi - lo) * 0.05).max(1e-6);
+        let mut chart = ChartBuilder::on(&root)
+            .caption(format!("eQQ plot: {}", covariate), ("sans-serif", 24))
+            .margin(15)
+            .x_label_area_size(40)
+            .y_label_area_size(50)
+            .build_cartesian_2d((lo - pad)..(hi + pad), (lo - pad)..(hi + pad))?;
+        chart
+            .configure_mesh()
+            .x_desc("Control quantiles")
+            .y_desc("Treated quantiles")
+            .draw()?;
+        chart.draw_series(LineSeries::new(vec![(lo - pad, lo - pad), (hi + pad, hi + pad)], BLACK.mix(0.5)))?;
+        chart.draw_series(points.iter().map(|p| Circle::new(*p, 3, BLUE.filled())))?;
+        root.present()?;
+        Ok(())
+    }
+
+    // One panel of overlaid treated and control densities of a covariate,
+    // evaluated on a common grid so that panels can be compared.
+    fn density_panel<DB: DrawingBackend>(
+        area: &DrawingArea<DB, Shift>,
+        caption: &str,
+        grid: &[f64],
+        treat: &[f64],
+        control: &[f64]
+    ) -> Result<(), Box<dyn Error>>
+    where
+        DB::ErrorType: 'static
+    {
+        let treat_density = density(treat, grid);
+        let control_density = density(control, grid);
+        let y_max = treat_density
+            .iter()
+            .chain(&control_density)
+            .fold(0.0_f64, |m, d| m.max(*d)) * 1.05;
+        let mut chart = ChartBuilder::on(area)
+            .caption(caption, ("sans-serif", 20))
+            .margin(15)
+            .x_label_area_size(40)
+            .y_label_area_size(50)
+            .build_cartesian_2d(grid[0]..grid[grid.len() - 1], 0f64..y_max.max(1e-6))?;
+        chart.configure_mesh().y_desc("Density").draw()?;
+        for (label, values, color) in [("Treated", &treat_density, RED), ("Control", &control_density, BLUE)] {
+            chart
+                .draw_series(LineSeries::new(
+                    grid.iter().copied().zip(values.iter().copied()),
+                    color.stroke_width(2)
+                ))?
+                .label(label)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+        }
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+        Ok(())
+    }
+
+    pub fn draw_distribution<DB: DrawingBackend>(
+        root: DrawingArea<DB, Shift>,
+        covariate: &str,
+        treat: &[f64],
+        pool: &[f64],
+        control: &[f64]
+    ) -> Result<(), Box<dyn Error>>
+    where
+        DB::ErrorType: 'static
+    {
+        root.fill(&WHITE)?;
+        let root = root.titled(covariate, ("sans-serif", 24))?;
+        let lo = treat.iter().chain(pool).fold(f64::INFINITY, |m, v| m.min(*v));
+        let hi = treat.iter().chain(pool).fold(f64::NEG_INFINITY, |m, v| m.max(*v));
+        let pad = ((hi - lo) * 0.1).max(1e-3);
+        let grid: Vec<f64> = (0..=200).map(|i| lo - pad + (hi - lo + 2.0 * pad) * i as f64 / 200.0).collect();
+        let panels = root.split_evenly((1, 2));
+        density_panel(&panels[0], "Before matching", &grid, treat, pool)?;
+        density_panel(&panels[1], "After matching", &grid, treat, control)?;
+        root.present()?;
+        Ok(())
+    }
+
+    pub fn draw_caliper<DB: DrawingBackend>(
+        root: DrawingArea<DB, Shift>,
+        rows: &[(f64, f64, f64, usize)]
+    ) -> Result<(), Box<dyn Error>>
+    where
+        DB::ErrorType: 'static
+    {
+        root.fill(&WHITE)?;
+        let rows: Vec<&(f64, f64, f64, usize)> = rows
+            .iter()
+            .filter(|(caliper, att, _, _)| caliper.is_finite() && att.is_finite())
+            .collect();
+        let x_lo = rows.iter().fold(f64::INFINITY, |m, r| m.min(r.0)) / 1.5;
+        let x_hi = rows.iter().fold(0.0_f64, |m, r| m.max(r.0)) * 1.5;
+        let intervals: Vec<(f64, f64, f64)> = rows
+            .iter()
+            .map(|(caliper, att, variance, _)| (*caliper, att - 1.96 * variance.sqrt(), att + 1.96 * variance.sqrt()))
+            .collect();
+        let lo = intervals.iter().fold(f64::INFINITY, |m, (_, l, _)| m.min(*l));
+        let hi = intervals.iter().fold(f64::NEG_INFINITY, |m, (_, _, h)| m.max(*h));
+        let pad = ((hi - lo) * 0.05).max(1e-6);
+        let n_max = rows.iter().fold(0, |m, r| m.max(r.3)) as f64 * 1.05;
+        let panels = root.split_evenly((2, 1));
+        let mut chart = ChartBuilder::on(&panels[0])
+            .caption("Caliper sensitivity", ("sans-serif", 24))
+            .margin(15)
+            .x_label_area_size(40)
+            .y_label_area_size(50)
+            .build_cartesian_2d((x_lo..x_hi).log_scale(), (lo - pad)..(hi + pad))?;
+        chart.configure_mesh().y_desc("ATT (95% CI)").draw()?;
+        chart.draw_series(intervals.iter().map(|(x, l, h)| PathElement::new(vec![(*x, *l), (*x, *h)], BLACK)))?;
+        chart.draw_series(LineSeries::new(rows.iter().map(|r| (r.0, r.1)), BLUE.stroke_width(2)))?;
+        chart.draw_series(rows.iter().map(|r| Circle::new((r.0, r.1), 4, BLUE.filled())))?;
+        let mut chart = ChartBuilder::on(&panels[1])
+            .margin(15)
+            .x_label_area_size(40)
+            .y_label_area_size(50)
+            .build_cartesian_2d((x_lo..x_hi).log_scale(), 0f64..n_max.max(1.0))?;
+        chart
+            .configure_mesh()
+            .x_desc("Caliper (SDs of the propensity score)")
+            .y_desc("# Treated retained")
+            .draw()?;
+        chart.draw_series(LineSeries::new(rows.iter().map(|r| (r.0, r.3 as f64)), BLACK.stroke_width(2)))?;
+        root.present()?;
+        Ok(())
+    }
+
+    pub fn draw_forest<DB: DrawingBackend>(
+        root: DrawingArea<DB, Shift>,
+        rows: &[(String, f64, f64)]
+    ) -> Result<(), Box<dyn Error>>
+    where
+        DB::ErrorType: 'static
+    {
+        root.fill(&WHITE)?;
+        let intervals: Vec<(f64, f64)> = rows
+            .iter()
+            .map(|(_, att, variance)| (att - 1.96 * variance.sqrt(), att + 1.96 * variance.sqrt()))
+            .collect();
+        let lo = intervals.iter().fold(0.0_f64, |m, (l, _)| m.min(*l));
+        let hi = intervals.iter().fold(0.0_f64, |m, (_, h)| m.max(*h));
+        let pad = ((hi - lo) * 0.05).max(1e-6);
+        let mut chart = ChartBuilder::on(&root)
+            .caption("Treatment effects", ("sans-serif", 24))
+            .margin(15)
+            .x_label_area_size(40)
+            .y_label_area_size(140)
+            .build_cartesian_2d((lo - pad)..(hi + pad), (0..rows.len()).into_segmented())?;
+        chart
+            .configure_mesh()
+            .disable_y_mesh()
+            .x_desc("ATT (95% CI)")
+            .y_labels(rows.len())
+            .y_label_formatter(&|y| match y {
+                SegmentValue::CenterOf(i) => rows.get(*i).map_or(String::new(), |r| r.0.clone()),
+                _ => String::new()
+            })
+            .draw()?;
+        chart.draw_series(LineSeries::new(
+            vec![(0.0, SegmentValue::Exact(0)), (0.0, SegmentValue::Last)],
+            BLACK.mix(0.5)
+        ))?;
+        chart.draw_series(intervals.iter().enumerate().map(|(i, (l, h))| {
+            PathElement::new(vec![(*l, SegmentValue::CenterOf(i)), (*h, SegmentValue::CenterOf(i))], BLACK)
+        }))?;
+        chart.draw_series(rows.iter().enumerate().map(|(i, (_, att, _))| {
+            Circle::new((*att, SegmentValue::CenterOf(i)), 5, BLACK.filled())
+        }))?;
+        root.present()?;
+        Ok(())
+    }
+}
+
+// Overlap plot
+//
+// Kernel density estimates of the propensity scores of the treated units
+// and of the full control pool.
+pub fn overlap_plot(path: &str, treat: &[f64], control: &[f64]) -> Result<(), Box<dyn Error>> {
+    render!(path, draw_overlap(treat, control))
+}
+
+// Love plot
+//
+// Absolute standardized mean differences of every covariate before and
+// after matching, given as (covariate, SMD before, SMD after) rows, with a
+// reference line at `threshold`. Each covariate's two points are joined by
+// a line and covariates are ordered by their imbalance before matching,
+// the largest at the top.
+pub fn love_plot(path: &str, balance: &[(String, f64, f64)], threshold: f64) -> Result<(), Box<dyn Error>> {
+    render!(path, draw_love(balance, threshold))
+}
+
+// Empirical quantile-quantile plot
+//
+// Quantiles of a covariate among treated units against the same quantiles
+// among controls. Points on the 45 degree line mean the two distributions
+// agree.
+pub fn eqq_plot(path: &str, covariate: &str, treat: &[f64], control: &[f64]) -> Result<(), Box<dyn Error>> {
+    render!(path, draw_eqq(covariate, treat, control))
+}
+
+// Covariate distribution plot
+//
+// Overlaid kernel density estimates of a covariate among treated and
+// control units, before matching (against the full control pool) and after
+// matching (against the matched controls), side by side. Shows imbalance
+// that a single SMD can hide, such as differences in shape or modes.
+pub fn distribution_plot(
+    path: &str,
+    covariate: &str,
+    treat: &[f64],
+    pool: &[f64],
+    control: &[f64]
+) -> Result<(), Box<dyn Error>> {
+    render!(path, draw_distribution(covariate, treat, pool, control))
+}
+
+// Caliper sensitivity plot
+//
+// The ATT with its 95% confidence interval (top) and the number of treated
+// units retained (bottom) against the caliper width, given as
+// (caliper, ATT, variance, # treated) rows. The row without a caliper is
+// not drawn.
+pub fn caliper_plot(path: &str, rows: &[(f64, f64, f64, usize)]) -> Result<(), Box<dyn Error>> {
+    render!(path, draw_caliper(rows))
+}
+
+// Forest plot
+//
+// One row per estimate, given as (label, ATT, variance), drawn as the point
+// estimate with its 95% confidence interval.
+pub fn forest_plot(path: &str, rows: &[(String, f64, f64)]) -> Result<(), Box<dyn Error>> {
+    render!(path, draw_forest(rows))
+}
@@ -0,0 +1,104 @@
+use ndarray::{Array1, Array2};
+use polars::prelude::{DataFrame, IdxCa, PolarsResult};
+use std::fmt;
+
+use crate::balance::mean_variance;
+use crate::cancel::CancelToken;
+use crate::data::column_values;
+use crate::distance::nearest_rows;
+use crate::formula::predictor_values;
+use crate::regression::wls;
+
+// Outcome model of the controls
+//
+// A linear regression of the outcome on an intercept and the covariates,
+// fit by least squares on the control pool only, so that it predicts the
+// outcome every unit would have without treatment.
+#[derive(Debug)]
+pub struct PrognosticModel {
+    pub covariates: Vec<String>,
+    pub coefficients: Array1<f64>,
+    pub r_squared: f64
+}
+
+impl fmt::Display for PrognosticModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = std::iter::once("intercept").chain(self.covariates.iter().map(|c| c.as_str()));
+        let coefficients: Vec<String> = names
+            .zip(self.coefficients.iter())
+            .map(|(name, b)| format!("{}={:.4}", name, b))
+            .collect();
+        write!(f, "r_squared={:.4} coefficients={}", self.r_squared, coefficients.join(","))
+    }
+}
+
+// The design matrix of an intercept and `covariates`.
+fn design(data: &DataFrame, covariates: &[String]) -> PolarsResult<Array2<f64>> {
+    let mut x = Array2::ones((data.height(), covariates.len() + 1));
+    for (k, covariate) in covariates.iter().enumerate() {
+        x.column_mut(k + 1).assign(&Array1::from(predictor_values(data, covariate)?));
+    }
+    Ok(x)
+}
+
+impl PrognosticModel {
+    // Fit the outcome model on `controls`
+    //
+    // Covariates that are linear combinations of others get a coefficient
+    // of 0, as in `wls`.
+    pub fn fit(controls: &DataFrame, outcome: &str, covariates: &[String]) -> PolarsResult<PrognosticModel> {
+        let x = design(controls, covariates)?;
+        let y = Array1::from(column_values(controls, outcome)?);
+        let fit = wls(&x, &y, &Array1::ones(controls.height()));
+        let (_, residual_variance) = mean_variance(&fit.residuals.to_vec());
+        let (_, outcome_variance) = mean_variance(&y.to_vec());
+        Ok(PrognosticModel {
+            covariates: covariates.to_vec(),
+            coefficients: fit.coefficients,
+            r_squared: 1.0 - residual_variance / outcome_variance
+        })
+    }
+
+    // Prognostic scores, the predicted control outcomes, of every row of
+    // `data`.
+    pub fn score(&self, data: &DataFrame) -> PolarsResult<Vec<f64>> {
+        Ok(design(data, &self.covariates)?.dot(&self.coefficients).to_vec())
+    }
+}
+
+// Nearest neighbor matching on the prognostic and propensity scores
+//
+// Every row of `main` is matched to the `k` rows of `target` nearest in
+// the Euclidean distance between (prognostic score, propensity score)
+// pairs, each score divided by its standard deviation over `main` and
+// `target` together so that neither dominates by its units. Matching on
+// both balances the covariates that predict treatment and those that
+// predict the outcome. Laid out like `knn_match`. Stops with an error if
+// `cancel` is set.
+pub fn prognostic_match(
+    main: &DataFrame,
+    target: &DataFrame,
+    model: &PrognosticModel,
+    k: usize,
+    cancel: &CancelToken
+) -> PolarsResult<DataFrame> {
+    let standardize = |main: Vec<f64>, target: Vec<f64>| {
+        let all: Vec<f64> = main.iter().chain(&target).cloned().collect();
+        let sd = match mean_variance(&all).1.sqrt() {
+            sd if sd > 0.0 => sd,
+            _ => 1.0
+        };
+        let scale = |x: Vec<f64>| x.into_iter().map(|v| v / sd).collect::<Vec<f64>>();
+        (scale(main), scale(target))
+    };
+    let (main_prognostic, target_prognostic) = standardize(model.score(main)?, model.score(target)?);
+    let (main_ps, target_ps) = standardize(
+        column_values(main, "propensities")?,
+        column_values(target, "propensities")?
+    );
+    let distance = |i: usize, j: usize| {
+        ((main_prognostic[i] - target_prognostic[j]).powi(2) + (main_ps[i] - target_ps[j]).powi(2)).sqrt()
+    };
+    let rows = nearest_rows(main.height(), target.height(), k, distance, cancel)?;
+    target.take(&IdxCa::from_vec("matches", rows))
+}
@@ -1,54 +1,479 @@
 use linfa::dataset::Dataset;
-use linfa::traits::{Fit}; //, Transformer};
-use linfa_logistic::LogisticRegression;
-// use linfa_preprocessing::linear_scaling::LinearScaler;
-use ndarray::{Array1, Array2};
+use linfa::traits::{Fit, Transformer};
+use linfa_logistic::{FittedLogisticRegression, LogisticRegression};
+use linfa_preprocessing::linear_scaling::LinearScaler;
+use ndarray::{Array1, Array2, Axis};
 use polars::datatypes::DataType::Int64;
-use polars::prelude::{DataFrame, Float64Type, NamedFrom, PolarsResult, Series};
+use polars::prelude::{BooleanChunked, DataFrame, NamedFrom, PolarsResult, Series};
 use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::audit::record;
+use crate::calibration::{Calibration, CalibrationMap, CALIBRATION_FOLDS};
+use crate::data::column_values;
+use crate::firth::FirthLogit;
+use crate::formula::{encoded_names, predictor_matrix, predictor_values, Formula};
+use crate::penalized::{PenalizedLogit, Penalty, PenaltyKind};
+use crate::split::{assign_folds, rng};
+use crate::survey::{fit_weights, WeightedLogit};
+use crate::trees::{Forest, Gbm};
+
+// How propensity scores are estimated
+//
+// `Logit` is plain maximum likelihood logistic regression and `Firth`
+// Firth's bias-reduced logistic regression, which stays finite under
+// separation and with small samples. `Gbm` is gradient boosted trees and
+// `Forest` a random forest, which need no functional form. `Ensemble` is a
+// super learner that stacks several of them (see `fit_ensemble`). Tree
+// models and ensembles have no coefficients, so they can't score new data
+// (sessions, partitions) or feed a sandwich variance.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PropensityModel {
+    #[default]
+    Logit,
+    Firth,
+    Gbm,
+    Forest,
+    Ensemble
+}
+
+// Implement `FromStr` for `PropensityModel`.
+impl FromStr for PropensityModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "logit" => Ok(PropensityModel::Logit),
+            "firth" => Ok(PropensityModel::Firth),
+            "gbm" => Ok(PropensityModel::Gbm),
+            "rf" => Ok(PropensityModel::Forest),
+            "ensemble" => Ok(PropensityModel::Ensemble),
+            _ => Err(format!("Unknown propensity model `{}`", s))
+        }
+    }
+}
+
+// Implement `Display` for `PropensityModel`.
+impl fmt::Display for PropensityModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PropensityModel::Logit => write!(f, "logit"),
+            PropensityModel::Firth => write!(f, "firth"),
+            PropensityModel::Gbm => write!(f, "gbm"),
+            PropensityModel::Forest => write!(f, "rf"),
+            PropensityModel::Ensemble => write!(f, "ensemble")
+        }
+    }
+}
+
+// Largest scaled score component at which the logistic fit is considered
+// converged.
+const GRADIENT_TOLERANCE: f64 = 1e-3;
+
+// Folds of the cross-validation that chooses the ensemble's weights.
+pub const ENSEMBLE_FOLDS: usize = 5;
+
+// Largest number of EM iterations for the ensemble's weights.
+const STACKING_ITERATIONS: usize = 1000;
+
+// A candidate model of the ensemble
+//
+// A propensity model under a name, with the penalty of a penalized logit.
+// Every learner is fit with the other settings of the propensity model
+// (`--ps-max-iter`, `--ps-trees`, ...).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Learner {
+    pub name: &'static str,
+    pub model: PropensityModel,
+    pub penalty: Option<Penalty>
+}
+
+// Lasso and ridge penalties with cross-validated strengths.
+const CV_PENALTY: [Penalty; 2] = [
+    Penalty { kind: PenaltyKind::L1, lambda: None },
+    Penalty { kind: PenaltyKind::L2, lambda: None }
+];
+
+// Every learner the ensemble can combine.
+pub const LEARNERS: [Learner; 6] = [
+    Learner { name: "logit", model: PropensityModel::Logit, penalty: None },
+    Learner { name: "firth", model: PropensityModel::Firth, penalty: None },
+    Learner { name: "l1", model: PropensityModel::Logit, penalty: Some(CV_PENALTY[0]) },
+    Learner { name: "l2", model: PropensityModel::Logit, penalty: Some(CV_PENALTY[1]) },
+    Learner { name: "gbm", model: PropensityModel::Gbm, penalty: None },
+    Learner { name: "rf", model: PropensityModel::Forest, penalty: None }
+];
+
+// Learners of `--ps-model ensemble` unless `--ps-learners` says otherwise.
+pub const DEFAULT_LEARNERS: [&str; 4] = ["logit", "l1", "gbm", "rf"];
+
+// Implement `FromStr` for `Learner`: one of the names of `LEARNERS`.
+impl FromStr for Learner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LEARNERS.into_iter().find(|l| l.name == s).ok_or_else(|| {
+            let names: Vec<&str> = LEARNERS.iter().map(|l| l.name).collect();
+            format!("Unknown learner `{}`; use {}", s, names.join(", "))
+        })
+    }
+}
+
+// Implement `Display` for `Learner`.
+impl fmt::Display for Learner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+// Names of the predictors of the propensity model
+//
+// Every column except the treatment and the columns Strike adds itself
+// (`propensities` and `strike_id`), with categorical columns dummy coded
+// (see `encoded_names`). Columns and dummies listed in `exclude` are left
+// out.
+pub fn predictor_names(data: &DataFrame, treatment: &str, exclude: &[String]) -> PolarsResult<Vec<String>> {
+    let mut names = Vec::new();
+    for column in data.get_column_names() {
+        let excluded = exclude.iter().any(|e| e == column);
+        if column != treatment && column != "propensities" && column != "strike_id" && !excluded {
+            names.extend(encoded_names(data, column)?.into_iter().filter(|name| !exclude.contains(name)));
+        }
+    }
+    Ok(names)
+}
 
 // Prep a DataFrame for logistic regression with Linfa
 //
 // Given a Polars DataFrame and a string specifying a binary treatment variable
 // this function returns a tuple containing predictors as a 2D ndarray, binary
-// response as a 1D ndarray, and predictor names as a Vec.
-fn construct<'a>(
-    data: &'a DataFrame,
-    treatment: &'a str
-) -> PolarsResult<(Array2<f64>, Array1<i64>, Vec<&'a str>)> {
+// response as a 1D ndarray, and predictor names as a Vec. The treatment and
+// the columns listed in `exclude` are left out of the predictors. With a
+// `formula` the predictors are its terms instead.
+fn construct(
+    data: &DataFrame,
+    treatment: &str,
+    exclude: &[String],
+    formula: Option<&Formula>
+) -> PolarsResult<(Array2<f64>, Array1<i64>, Vec<String>)> {
     let d = data.column(treatment)?
         .cast(&Int64)?
         .i64()?
         .to_ndarray()?
         .to_owned();
-    let x = data.to_ndarray::<Float64Type>()?;
-    let feat_names = data.get_column_names();
-    Ok((x, d, feat_names))
+    if let Some(formula) = formula {
+        let (x, names) = formula.design(data)?;
+        return Ok((x, d, names));
+    }
+    let names = predictor_names(data, treatment, exclude)?;
+    Ok((predictor_matrix(data, &names)?, d, names))
+}
+
+// The design matrix of the propensity model
+//
+// Exactly the predictors `construct` hands to the optimizer, in the same
+// order and cast to floats, preceded by `strike_id` and the treatment.
+pub fn design_matrix(
+    data: &DataFrame,
+    treatment: &str,
+    exclude: &[String],
+    formula: Option<&Formula>
+) -> PolarsResult<DataFrame> {
+    let mut data = data.clone();
+    add_ids(&mut data)?;
+    let (x, d, feat_names) = construct(&data, treatment, exclude, formula)?;
+    let mut columns = vec![
+        data.column("strike_id")?.clone(),
+        Series::new(treatment, d.to_vec())
+    ];
+    for (name, values) in feat_names.iter().zip(x.axis_iter(Axis(1))) {
+        columns.push(Series::new(name, values.to_vec()));
+    }
+    DataFrame::new(columns)
+}
+
+// A predictor that separates treated from control units
+//
+// With complete separation the predictor's treated and control values don't
+// overlap at all; with quasi-complete separation they only share a single
+// boundary value (e.g. a dummy that is 1 for every treated unit). Either way
+// the logistic likelihood has no finite maximum and the fitted propensities
+// are pushed towards 0 or 1. `dropped` records whether the predictor was
+// left out of the model (`--drop-separating`).
+#[derive(Clone, Debug)]
+pub struct Separation {
+    pub covariate: String,
+    pub complete: bool,
+    pub dropped: bool
+}
+
+// Smallest and largest non-missing value.
+fn range(x: impl Iterator<Item = f64>) -> (f64, f64) {
+    x.filter(|v| !v.is_nan())
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)))
+}
+
+// Find the predictors that separate treated from control units
+//
+// Each predictor of the propensity model (every dummy of a categorical
+// column) but those in `exclude` is checked on its own by comparing
+// the ranges of its treated and control values. This catches the usual
+// culprits (rare dummies, variables that define treatment) but not
+// separation by a combination of predictors, which still shows up as
+// non-convergence.
+pub fn separation(data: &DataFrame, treatment: &str, exclude: &[String]) -> PolarsResult<Vec<Separation>> {
+    let d = column_values(data, treatment)?;
+    let mut separated = Vec::new();
+    for name in predictor_names(data, treatment, exclude)? {
+        let x = predictor_values(data, &name)?;
+        let (t_lo, t_hi) = range(x.iter().zip(&d).filter(|(_, d)| **d == 1.0).map(|(x, _)| *x));
+        let (c_lo, c_hi) = range(x.iter().zip(&d).filter(|(_, d)| **d == 0.0).map(|(x, _)| *x));
+        let constant = t_lo == t_hi && c_lo == c_hi && t_lo == c_lo;
+        let complete = t_lo > c_hi || t_hi < c_lo;
+        let quasi = t_lo == c_hi || t_hi == c_lo;
+        if !constant && (complete || quasi) {
+            separated.push(Separation { covariate: name, complete, dropped: false });
+        }
+    }
+    Ok(separated)
+}
+
+// Convergence diagnostics of a logistic fit
+//
+// `max_gradient` is the largest absolute component of the mean score,
+// (1/n) Z'(d - p), where Z holds the intercept and the standardized
+// predictors, so that it doesn't depend on the predictors' units. At the
+// maximum likelihood estimate the score is zero; tree models, which don't
+// converge to an optimum, report 0. A model without an intercept has no
+// score for it and, since centring would bring the intercept back, scales
+// its predictors by their root mean squares instead. `log_likelihood`
+// is the (unpenalized) log-likelihood at the estimates and `iterations` the
+// number of optimizer iterations, when the optimizer reports it (linfa
+// doesn't).
+#[derive(Clone, Copy, Debug)]
+pub struct LogitFit {
+    pub max_gradient: f64,
+    pub log_likelihood: f64,
+    pub iterations: Option<usize>
+}
+
+impl LogitFit {
+    pub fn converged(&self) -> bool {
+        self.max_gradient < GRADIENT_TOLERANCE
+    }
+
+    // The worse of two fits, e.g. the two halves of a cross-fit.
+    pub fn worst(self, other: LogitFit) -> LogitFit {
+        match self.max_gradient >= other.max_gradient {
+            true => self,
+            false => other
+        }
+    }
+}
+
+// Optimizer settings of the propensity model
+//
+// `max_iterations` caps the optimizer iterations, `tolerance` is the
+// gradient norm at which the optimizer stops, and `init` holds starting
+// values for named coefficients (`intercept` or a predictor name); the
+// others start at 0. `trees` and `depth` override the number and depth of
+// the trees of a tree model, and `seed` seeds a random forest. A `formula`
+// replaces the columns of the data as predictors by its terms, and
+// `learners` are the models of an ensemble (`DEFAULT_LEARNERS` if empty).
+// `calibrate` recalibrates the scores of the model (see
+// `estimate_propensities_from`).
+// `standardize` fits the logistic regression on standardized predictors,
+// which helps the optimizer when predictors differ widely in scale; the
+// coefficients are reported on the original scale either way. `weights`
+// names a column of sampling weights that weights every unit's
+// contribution to the fit (see `WeightedLogit`). Without `intercept` the
+// logistic regression is fit through the origin: the log odds are zero
+// where every predictor is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogitOptions {
+    pub max_iterations: u64,
+    pub tolerance: f64,
+    pub init: Vec<(String, f64)>,
+    pub penalty: Option<Penalty>,
+    pub trees: Option<usize>,
+    pub depth: Option<usize>,
+    pub seed: Option<u64>,
+    pub formula: Option<Formula>,
+    pub standardize: bool,
+    pub weights: Option<String>,
+    pub learners: Vec<Learner>,
+    pub calibrate: Option<Calibration>,
+    pub intercept: bool
+}
+
+// linfa's defaults.
+impl Default for LogitOptions {
+    fn default() -> Self {
+        LogitOptions {
+            max_iterations: 100,
+            tolerance: 1e-4,
+            init: Vec::new(),
+            penalty: None,
+            trees: None,
+            depth: None,
+            seed: None,
+            formula: None,
+            standardize: false,
+            weights: None,
+            learners: Vec::new(),
+            calibrate: None,
+            intercept: true
+        }
+    }
+}
+
+impl LogitOptions {
+    // Starting value of a coefficient.
+    pub fn initial(&self, name: &str) -> f64 {
+        self.init.iter().find(|(n, _)| n == name).map_or(0.0, |(_, v)| *v)
+    }
+}
+
+// Parse starting values given as `name=value,name=value`.
+pub fn parse_init(value: &str) -> Result<Vec<(String, f64)>, String> {
+    value
+        .split(',')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, v)) => v
+                .trim()
+                .parse::<f64>()
+                .map(|v| (name.trim().to_string(), v))
+                .map_err(|e| format!("Invalid starting value `{}`: {}", pair, e)),
+            None => Err(format!("Starting values must look like `name=value` but got `{}`", pair))
+        })
+        .collect()
+}
+
+// Scaled score of a fitted logistic regression at its estimates
+//
+// `p` are the fitted probabilities and `residuals` are d - p for plain
+// maximum likelihood, or the modified residuals of a penalized fit.
+fn logit_fit(
+    x: &Array2<f64>,
+    d: &Array1<i64>,
+    p: &Array1<f64>,
+    residuals: &Array1<f64>,
+    iterations: Option<usize>
+) -> LogitFit {
+    let n = x.nrows() as f64;
+    let mut max_gradient = (residuals.sum() / n).abs();
+    for column in x.axis_iter(Axis(1)) {
+        let (mean, sd) = (column.mean().unwrap_or(0.0), column.std(1.0));
+        if sd > 0.0 {
+            let gradient = column.mapv(|v| (v - mean) / sd).dot(residuals) / n;
+            max_gradient = max_gradient.max(gradient.abs());
+        }
+    }
+    let log_likelihood = d
+        .iter()
+        .zip(p)
+        .map(|(d, p)| match d {
+            1 => p.ln(),
+            _ => (1.0 - p).ln()
+        })
+        .sum();
+    LogitFit { max_gradient, log_likelihood, iterations }
+}
+
+// Largest scaled score of a logistic regression fit through the origin (see
+// `LogitFit`).
+fn origin_gradient(x: &Array2<f64>, residuals: &Array1<f64>) -> f64 {
+    let n = x.nrows() as f64;
+    let mut max_gradient: f64 = 0.0;
+    for column in x.axis_iter(Axis(1)) {
+        let rms = (column.dot(&column) / n).sqrt();
+        if rms > 0.0 {
+            max_gradient = max_gradient.max((column.dot(residuals) / (n * rms)).abs());
+        }
+    }
+    max_gradient
+}
+
+// Coefficients of a fitted propensity model
+//
+// On the scale of the original predictors, so that the model can score new
+// observations without refitting (e.g. rows appended to a saved session).
+// Tree models have none: their intercept is NaN and `slopes` empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coefficients {
+    pub intercept: f64,
+    pub slopes: Vec<(String, f64)>
+}
+
+impl Coefficients {
+    // Propensity scores of every row of `data`.
+    pub fn score(&self, data: &DataFrame) -> PolarsResult<Vec<f64>> {
+        let mut eta = vec![self.intercept; data.height()];
+        for (name, slope) in &self.slopes {
+            for (e, x) in eta.iter_mut().zip(predictor_values(data, name)?) {
+                *e += slope * x;
+            }
+        }
+        Ok(eta.into_iter().map(|e| 1.0 / (1.0 + (-e).exp())).collect())
+    }
+}
+
+// Add the `strike_id` column
+//
+// Every observation gets a unique id, its 1-based row number. Does nothing
+// if `data` already has ids, so ids assigned before splitting the sample
+// stay unique across the halves.
+pub fn add_ids(data: &mut DataFrame) -> PolarsResult<()> {
+    if data.column("strike_id").is_err() {
+        let idx: Vec<i64> = (1..=data.height() as i64).collect();
+        data.with_column(Series::new("strike_id", idx))?;
+    }
+    Ok(())
+}
+
+// Covariate column names
+//
+// Every column of `data` except those listed in `exclude` and the columns
+// Strike adds itself (`propensities` and `strike_id`), sorted by name. This
+// knows nothing of the covariate selection, so it only serves the checks
+// of the input data and the listing of extreme units; the matchers and the
+// balance diagnostics use `adjustment_covariates` and `balance_covariates`.
+pub fn covariate_names(data: &DataFrame, exclude: &[&str]) -> Vec<String> {
+    let mut names: Vec<String> = data
+        .get_column_names()
+        .into_iter()
+        .filter(|name| !exclude.contains(name) && *name != "propensities" && *name != "strike_id")
+        .map(|name| name.to_string())
+        .collect();
+    names.sort();
+    names
 }
 
 // Estimate logistic regression with Linfa
 //
 // This function takes the output of `construct` and creates a Linfa Dataset.
-// A logistic regression is fit on the full dataset. The estimated
-// propensities are simply the fitted values, and are returned as a 1D ndarray.
+// A logistic regression is fit on the full dataset and returned so that it
+// can score either the same or new observations.
 fn estimate_logit(
     x: Array2<f64>,
     d: Array1<i64>,
-    feat_names: Vec<&str>
-) -> Result<Array1<f64>, Box<dyn Error>> {
-    let train = Dataset::new(x.clone(), d).with_feature_names(feat_names);
-    // The lines below normalize predictors but this seems to cause severe
-    // overfitting so am dropping for now.
-    // ===================================================
-    // let scaler = LinearScaler::standard().fit(&train)?;
-    // let train = scaler.transform(train);
-    // let x = scaler.transform(x);
+    feat_names: Vec<&str>,
+    options: &LogitOptions
+) -> Result<FittedLogisticRegression<f64, i64>, Box<dyn Error>> {
+    // linfa puts the intercept, if any, after the predictors
+    let intercept = options.intercept.then(|| options.initial("intercept"));
+    let init: Array1<f64> = feat_names.iter().map(|name| options.initial(name)).chain(intercept).collect();
+    let train = Dataset::new(x, d).with_feature_names(feat_names);
     let model = LogisticRegression::default()
-        .with_intercept(true)
+        .with_intercept(options.intercept)
         .alpha(0.0)
+        .max_iterations(options.max_iterations)
+        .gradient_tolerance(options.tolerance)
+        .initial_params(init)
         .fit(&train)?;
-    let propensities = model.predict_probabilities(&x);
-    Ok(propensities)
+    Ok(model)
 }
 
 // Estimate propensity scores
@@ -57,19 +482,325 @@ fn estimate_logit(
 // Given a DataFrame and the treatment column name it estimates
 // propensity scores and appends them to the original DataFrame. It also
 // appends a unique id to each observation, which is essential for
-// downstream processing.
-pub fn estimate_propensities<'a>(
-    data: &'a mut DataFrame,
-    treatment: &str
-) -> Result<&'a mut DataFrame, Box<dyn Error>> {
-    let (x, treat, feat_names) = construct(data, treatment)?;
-    let propensities = Series::new(
-        "propensities",
-        estimate_logit(x, treat, feat_names)?.to_vec()
-    );
+// downstream processing. Columns listed in `exclude` are left out of the
+// model. Returns the convergence diagnostics and coefficients of the fit;
+// tree and ensemble models have no coefficients.
+pub fn estimate_propensities(
+    data: &mut DataFrame,
+    treatment: &str,
+    exclude: &[String],
+    model: PropensityModel,
+    options: &LogitOptions
+) -> Result<(LogitFit, Option<Coefficients>), Box<dyn Error>> {
+    let train = data.clone();
+    estimate_propensities_from(&train, data, treatment, exclude, model, options)
+}
+
+// Estimate propensity scores with a model fit on separate data
+//
+// Identical to `estimate_propensities`, except that the logistic regression
+// is fit on `train` and only used to score the observations in `data`. This
+// is what allows honest, sample-split estimation. With sampling weights
+// the logit and tree models weight every unit of `train` by its weight;
+// Firth and penalized fits don't take weights. With a calibration the
+// model is also cross-fitted on `train` over `CALIBRATION_FOLDS` random
+// folds, a calibration map is fit to those out-of-fold scores, which unlike
+// in-sample scores don't overfit, and the scores of `data` are mapped.
+pub fn estimate_propensities_from(
+    train: &DataFrame,
+    data: &mut DataFrame,
+    treatment: &str,
+    exclude: &[String],
+    model: PropensityModel,
+    options: &LogitOptions
+) -> Result<(LogitFit, Option<Coefficients>), Box<dyn Error>> {
+    let formula = options.formula.as_ref();
+    let (x_train, treat, names) = construct(train, treatment, exclude, formula)?;
+    let x = predictor_matrix(data, &names)?;
+    let feat_names: Vec<&str> = names.iter().map(String::as_str).collect();
+    for (name, _) in &options.init {
+        if name != "intercept" && !feat_names.contains(&name.as_str()) {
+            return Err(format!("`{}` is not a predictor of the propensity model", name).into());
+        }
+    }
+    let weights = match &options.weights {
+        Some(column) => fit_weights(train, column)?,
+        None => Array1::ones(train.height())
+    };
+    let (fit, propensities, coefficients) = match (model, options.penalty) {
+        (PropensityModel::Logit, Some(penalty)) => {
+            let start = Instant::now();
+            let (model, violation, iterations) = PenalizedLogit::fit(&x_train, &treat, penalty, options);
+            let p = model.predict_probabilities(&x_train);
+            let residuals = treat.mapv(|d| d as f64) - &p;
+            let (intercept, slopes) = model.coefficients();
+            let coefficients = Coefficients {
+                intercept,
+                slopes: names.into_iter().zip(slopes).collect()
+            };
+            let kept = coefficients.slopes.iter().filter(|(_, b)| *b != 0.0).count();
+            record(
+                "ps-penalty",
+                Some(x_train.nrows()),
+                None,
+                start.elapsed(),
+                &format!("penalty={} lambda={} nonzero={}", penalty, model.lambda, kept)
+            );
+            let fit = LogitFit {
+                max_gradient: violation,
+                ..logit_fit(&x_train, &treat, &p, &residuals, Some(iterations))
+            };
+            (fit, model.predict_probabilities(&x), Some(coefficients))
+        },
+        (PropensityModel::Logit, None) if options.weights.is_some() => {
+            let (model, residuals, iterations) = WeightedLogit::fit(&x_train, &treat, &weights, &feat_names, options);
+            let p = model.predict_probabilities(&x_train);
+            let (intercept, slopes) = model.coefficients();
+            let coefficients = Coefficients { intercept, slopes: names.into_iter().zip(slopes).collect() };
+            (
+                logit_fit(&x_train, &treat, &p, &residuals, Some(iterations)),
+                model.predict_probabilities(&x),
+                Some(coefficients)
+            )
+        },
+        (PropensityModel::Logit, None) => {
+            // With `--standardize` the model is fit on predictors centred and
+            // scaled by their means and standard deviations in `train`, and
+            // `data` is scaled the same way
+            let scaler = match options.standardize {
+                true => Some(LinearScaler::standard().fit(&Dataset::new(x_train.clone(), treat.clone()))?),
+                false => None
+            };
+            let scale = |x: &Array2<f64>| scaler.as_ref().map_or(x.clone(), |s| s.transform(x.clone()));
+            let model = estimate_logit(scale(&x_train), treat.clone(), feat_names, options)?;
+            let p = model.predict_probabilities(&scale(&x_train));
+            let residuals = treat.mapv(|d| d as f64) - &p;
+            let (mut intercept, mut slopes) = (model.intercept(), model.params().to_owned());
+            if let Some(s) = &scaler {
+                slopes *= s.scales();
+                intercept -= slopes.dot(s.offsets());
+            }
+            let coefficients = Coefficients { intercept, slopes: names.into_iter().zip(slopes).collect() };
+            let mut fit = logit_fit(&x_train, &treat, &p, &residuals, None);
+            if !options.intercept {
+                fit.max_gradient = origin_gradient(&x_train, &residuals);
+            }
+            (fit, model.predict_probabilities(&scale(&x)), Some(coefficients))
+        },
+        (PropensityModel::Gbm, _) => {
+            let model = Gbm::fit(&x_train, &treat, &weights, options);
+            let p = model.predict_probabilities(&x_train);
+            let residuals = treat.mapv(|d| d as f64) - &p;
+            // Boosting runs a fixed number of rounds rather than converging
+            let fit = LogitFit {
+                max_gradient: 0.0,
+                ..logit_fit(&x_train, &treat, &p, &residuals, Some(model.rounds()))
+            };
+            (fit, model.predict_probabilities(&x), None)
+        },
+        (PropensityModel::Forest, _) => {
+            let model = Forest::fit(&x_train, &treat, &weights, options);
+            // Out-of-bag scores for the units the forest was fit on
+            let p = model.out_of_bag();
+            let residuals = treat.mapv(|d| d as f64) - &p;
+            let fit = LogitFit {
+                max_gradient: 0.0,
+                ..logit_fit(&x_train, &treat, &p, &residuals, Some(model.size()))
+            };
+            let propensities = match x == x_train {
+                true => p,
+                false => model.predict_probabilities(&x)
+            };
+            (fit, propensities, None)
+        },
+        (PropensityModel::Ensemble, _) => {
+            let start = Instant::now();
+            let ensemble = fit_ensemble(train, data, treatment, exclude, options, &weights)?;
+            let stack: Vec<String> = ensemble.stack.iter().map(|(l, a)| format!("{}={:.3}", l, a)).collect();
+            record("ps-ensemble", Some(x_train.nrows()), None, start.elapsed(), &stack.join(" "));
+            // The fit is judged by the cross-validated predictions, which
+            // chose the weights
+            let p = ensemble.cross_validated;
+            let residuals = treat.mapv(|d| d as f64) - &p;
+            let fit = LogitFit {
+                max_gradient: 0.0,
+                ..logit_fit(&x_train, &treat, &p, &residuals, None)
+            };
+            (fit, ensemble.propensities, None)
+        },
+        (PropensityModel::Firth, _) => {
+            let (model, residuals, iterations) = FirthLogit::fit(&x_train, &treat, &feat_names, options)?;
+            let p = model.predict_probabilities(&x_train);
+            let (intercept, slopes) = model.coefficients();
+            let coefficients = Coefficients {
+                intercept,
+                slopes: names.into_iter().zip(slopes).collect()
+            };
+            (
+                logit_fit(&x_train, &treat, &p, &residuals, Some(iterations)),
+                model.predict_probabilities(&x),
+                Some(coefficients)
+            )
+        }
+    };
+    let propensities = match options.calibrate {
+        Some(method) => {
+            let start = Instant::now();
+            let uncalibrated = LogitOptions { calibrate: None, ..options.clone() };
+            let folds = assign_folds(train.height(), CALIBRATION_FOLDS, &mut rng(options.seed));
+            let (scores, _) = cross_fit_propensities(train, treatment, exclude, model, &uncalibrated, &folds)?;
+            let d: Vec<f64> = treat.iter().map(|d| *d as f64).collect();
+            let map = CalibrationMap::fit(method, &scores, &d, &weights);
+            record("ps-calibrate", Some(train.height()), Some(data.height()), start.elapsed(), &map.to_string());
+            propensities.mapv(|p| map.apply(p))
+        },
+        None => propensities
+    };
+    let propensities = Series::new("propensities", propensities.to_vec());
     data.with_column(propensities)?;
-    let idx:Vec<i64> = (1..=data.height() as i64).collect();
-    let ids = Series::new("strike_id", idx);
-    data.with_column(ids)?;
-    Ok(data)
-}
\ No newline at end of file
+    add_ids(data)?;
+    Ok((fit, coefficients))
+}
+
+// Use propensity scores supplied in `column` of `data`
+//
+// For scores estimated elsewhere: the column becomes the `propensities`
+// column, so it is no longer treated as a covariate, and ids are added as
+// for estimated scores. There is no model, so there are no coefficients
+// and the fit only reports the scores' log-likelihood.
+pub fn supplied_propensities(
+    data: &mut DataFrame,
+    treatment: &str,
+    column: &str
+) -> PolarsResult<(LogitFit, Option<Coefficients>)> {
+    let p = Array1::from(column_values(data, column)?);
+    let treat: Array1<i64> = column_values(data, treatment)?.into_iter().map(|d| d as i64).collect();
+    let residuals = treat.mapv(|d| d as f64) - &p;
+    let fit = LogitFit {
+        max_gradient: 0.0,
+        ..logit_fit(&Array2::zeros((p.len(), 0)), &treat, &p, &residuals, None)
+    };
+    if column != "propensities" {
+        let _ = data.drop_in_place(column)?;
+    }
+    data.with_column(Series::new("propensities", p.to_vec()))?;
+    add_ids(data)?;
+    Ok((fit, None))
+}
+
+// A fitted super learner
+//
+// The learners with their weights, the cross-validated mixture predictions
+// for the rows it was fit on and the scores of the data.
+#[derive(Debug)]
+pub struct EnsembleFit {
+    pub stack: Vec<(Learner, f64)>,
+    pub cross_validated: Array1<f64>,
+    pub propensities: Array1<f64>
+}
+
+// Fit a super learner on `train` and score `data`
+//
+// Every learner is cross-fitted over `ENSEMBLE_FOLDS` random folds, seeded
+// with `options.seed`, and the weights of the learners, non-negative and
+// summing to one, maximize the (sampling weighted) log-likelihood of their
+// out-of-fold predictions combined as the mixture sum_l a_l p_l (van der
+// Laan, Polley and Hubbard, 2007). The weights are found by EM: with f_l
+// the likelihood of a unit's treatment under learner l, every update
+// a_l <- mean of a_l f_l / sum_k a_k f_k raises the likelihood, which is
+// concave in the weights. Every learner is then refit on all of `train`
+// and `data` scored with the mixture of their predictions.
+pub fn fit_ensemble(
+    train: &DataFrame,
+    data: &DataFrame,
+    treatment: &str,
+    exclude: &[String],
+    options: &LogitOptions,
+    weights: &Array1<f64>
+) -> Result<EnsembleFit, Box<dyn Error>> {
+    let learners: Vec<Learner> = match options.learners.is_empty() {
+        true => DEFAULT_LEARNERS.iter().map(|name| name.parse()).collect::<Result<_, _>>()?,
+        false => options.learners.clone()
+    };
+    let d = column_values(train, treatment)?;
+    let folds = assign_folds(train.height(), ENSEMBLE_FOLDS, &mut rng(options.seed));
+    let learner_options = |learner: &Learner| LogitOptions {
+        penalty: learner.penalty,
+        calibrate: None,
+        ..options.clone()
+    };
+    let mut cv = Vec::new();
+    for learner in &learners {
+        let (scores, _) =
+            cross_fit_propensities(train, treatment, exclude, learner.model, &learner_options(learner), &folds)
+                .map_err(|e| format!("Learner `{}`: {}", learner, e))?;
+        cv.push(scores);
+    }
+    // Likelihood of every unit's treatment under every learner
+    let likelihood = |l: usize, i: usize| {
+        let p = cv[l][i].clamp(1e-6, 1.0 - 1e-6);
+        if d[i] == 1.0 { p } else { 1.0 - p }
+    };
+    let mut a = vec![1.0 / learners.len() as f64; learners.len()];
+    for _ in 0..STACKING_ITERATIONS {
+        let mut next = vec![0.0; a.len()];
+        for i in 0..d.len() {
+            let total: f64 = (0..a.len()).map(|l| a[l] * likelihood(l, i)).sum();
+            for (l, next) in next.iter_mut().enumerate() {
+                *next += weights[i] * a[l] * likelihood(l, i) / total;
+            }
+        }
+        let sum: f64 = next.iter().sum();
+        let change = next.iter().zip(&a).fold(0.0_f64, |m, (next, a)| m.max((next / sum - a).abs()));
+        a = next.into_iter().map(|a| a / sum).collect();
+        if change < 1e-8 {
+            break;
+        }
+    }
+    let mut propensities = Array1::<f64>::zeros(data.height());
+    for (learner, a) in learners.iter().zip(&a).filter(|(_, a)| **a > 0.0) {
+        let mut scored = data.clone();
+        estimate_propensities_from(train, &mut scored, treatment, exclude, learner.model, &learner_options(learner))
+            .map_err(|e| format!("Learner `{}`: {}", learner, e))?;
+        propensities.scaled_add(*a, &Array1::from(column_values(&scored, "propensities")?));
+    }
+    let cross_validated = (0..d.len()).map(|i| (0..a.len()).map(|l| a[l] * cv[l][i]).sum()).collect();
+    Ok(EnsembleFit { stack: learners.into_iter().zip(a).collect(), cross_validated, propensities })
+}
+
+// Cross-fitted propensity scores
+//
+// Every row of `data` is scored by a model fit on the rows of the other
+// folds, so no unit's score comes from a model that saw it. In-sample
+// scores of a flexible model overfit, pushing treated units towards 1 and
+// controls towards 0; out-of-fold scores don't. `folds` gives every row's
+// fold, numbered from 0. Returns the scores and the convergence diagnostics
+// of the worst of the folds' fits, so that a fold whose model didn't
+// converge isn't hidden by the others.
+pub fn cross_fit_propensities(
+    data: &DataFrame,
+    treatment: &str,
+    exclude: &[String],
+    model: PropensityModel,
+    options: &LogitOptions,
+    folds: &[usize]
+) -> Result<(Vec<f64>, LogitFit), Box<dyn Error>> {
+    let k = folds.iter().max().map_or(0, |f| f + 1);
+    let mut propensities = vec![f64::NAN; data.height()];
+    let mut worst: Option<LogitFit> = None;
+    for fold in 0..k {
+        let in_fold: Vec<bool> = folds.iter().map(|f| *f == fold).collect();
+        let mask = BooleanChunked::new("fold", &in_fold);
+        let train = data.filter(&!mask.clone())?;
+        let mut held_out = data.filter(&mask)?;
+        let (fit, _) = estimate_propensities_from(&train, &mut held_out, treatment, exclude, model, options)
+            .map_err(|e| format!("Fitting the propensity model without fold {} of {}: {}", fold + 1, k, e))?;
+        worst = Some(worst.map_or(fit, |w| w.worst(fit)));
+        let rows = (0..data.height()).filter(|i| in_fold[*i]);
+        for (i, p) in rows.zip(column_values(&held_out, "propensities")?) {
+            propensities[i] = p;
+        }
+    }
+    let fit = worst.ok_or("Cross-fitting needs at least one fold")?;
+    Ok((propensities, fit))
+}
@@ -0,0 +1,87 @@
+use polars::prelude::{DataFrame, PolarsResult};
+use std::fmt;
+
+use crate::att::calculate_subset_att;
+use crate::cancel::CancelToken;
+use crate::distance::pair_distances;
+use crate::format::num;
+
+// Number of steps of the curve, each adding a tenth of the pairs.
+pub const QUALITY_STEPS: usize = 10;
+
+// The ATT over the best matched fraction of pairs.
+#[derive(Debug)]
+pub struct QualityPoint {
+    pub fraction: f64,
+    pub n_pairs: usize,
+    pub max_distance: f64,
+    pub att: f64,
+    pub att_variance: f64
+}
+
+// Cumulative match-quality curve
+//
+// Pairs are ordered by their propensity score distance and the ATT is
+// computed over the best 10%, 20%, ..., 100% of pairs. An estimate that
+// drifts as the worse matched pairs are added means those pairs are
+// driving the result.
+#[derive(Debug)]
+pub struct MatchQualityCurve {
+    pub points: Vec<QualityPoint>
+}
+
+// Calculate the cumulative match-quality curve
+//
+// Ties in match distance are broken by row order, so every step adds
+// exactly its share of pairs.
+pub fn match_quality_curve(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    treatment: &str,
+    cancel: &CancelToken
+) -> PolarsResult<MatchQualityCurve> {
+    let distances = pair_distances(treat, control)?;
+    let mut order: Vec<usize> = (0..distances.len()).collect();
+    order.sort_by(|a, b| distances[*a].total_cmp(&distances[*b]));
+    let mut points = Vec::with_capacity(QUALITY_STEPS);
+    for step in 1..=QUALITY_STEPS {
+        let fraction = step as f64 / QUALITY_STEPS as f64;
+        let n_pairs = (fraction * distances.len() as f64).round() as usize;
+        let mut keep = vec![false; distances.len()];
+        for i in &order[..n_pairs] {
+            keep[*i] = true;
+        }
+        let max_distance = order[..n_pairs].last().map_or(f64::NAN, |i| distances[*i]);
+        let (att, att_variance) = calculate_subset_att(treat, control, &keep, outcome, treatment, cancel)?;
+        points.push(QualityPoint { fraction, n_pairs, max_distance, att, att_variance });
+    }
+    Ok(MatchQualityCurve { points })
+}
+
+// Implement `Display` for `MatchQualityCurve`.
+impl fmt::Display for MatchQualityCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Cumulative match quality (pairs ordered by propensity distance)")?;
+        writeln!(
+            f,
+            "{:>6}  {:>7}  {:>12}  {:>8}  {:>8}  {:>20}",
+            "Best", "# Pairs", "Max distance", "ATT", "Variance", "95% CI"
+        )?;
+        for p in &self.points {
+            let se = p.att_variance.sqrt();
+            let ci = format!("({}, {})", num(p.att - 1.96 * se), num(p.att + 1.96 * se));
+            writeln!(
+                f,
+                "{:>5.0}%  {:>7}  {:>12.2e}  {:>8}  {:>8}  {:>20}",
+                p.fraction * 100.0,
+                p.n_pairs,
+                p.max_distance,
+                num(p.att),
+                num(p.att_variance),
+                ci
+            )?;
+        }
+        Ok(())
+    }
+}
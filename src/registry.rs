@@ -0,0 +1,316 @@
+use polars::frame::UniqueKeepStrategy;
+use polars::prelude::{DataFrame, PolarsError, PolarsResult};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use crate::att::{
+    calculate_att,
+    calculate_bias_corrected_att,
+    calculate_location_att,
+    calculate_paired_variance,
+    calculate_variance_with,
+    Location,
+    Variance,
+    VarianceOptions,
+    DEFAULT_VARIANCE_NEIGHBORS
+};
+use crate::audit::record;
+use crate::budget::PROBE_UNITS;
+use crate::cli::Args;
+use crate::distance::{
+    greedy_match,
+    hybrid_match,
+    knn_match,
+    nn_match,
+    optimal_match,
+    tied_match,
+    HybridDistance,
+    Ties
+};
+use crate::estimate::adjustment_covariates;
+use crate::genetic::genetic_match;
+use crate::prognostic::{prognostic_match, PrognosticModel};
+use crate::split::rng;
+use crate::subclass::{calculate_subclass_att, DEFAULT_N_STRATA};
+
+// Names of the built-in methods, used when none is selected.
+pub const DEFAULT_MATCHER: &str = "nearest-neighbor";
+pub const DEFAULT_ESTIMATOR: &str = "abadie-imbens";
+
+// Names of the other built-in matchers.
+pub const OPTIMAL_MATCHER: &str = "optimal";
+pub const GENETIC_MATCHER: &str = "genetic";
+
+// Name of the other built-in estimator.
+pub const SUBCLASS_ESTIMATOR: &str = "subclass";
+
+// A method of matching treated units to controls
+//
+// `treat` and `pool` are the treated units and the control pool, both with
+// `propensities` and `strike_id` columns. Returns one row of `pool` per row
+// of `treat`, in the same order, so that row i of both frames is a matched
+// pair. Long-running matchers should check `args.cancel` regularly. Only
+// the built-in matcher supports `--ratio`, for which it returns k rows per
+// row of `treat`.
+pub trait Matcher: Send + Sync {
+    fn match_controls(&self, treat: &DataFrame, pool: &DataFrame, args: &Args) -> PolarsResult<DataFrame>;
+}
+
+// An estimator of the ATT and its variance from matched pairs
+//
+// Row i of `control` is the match of row i of `treat`; the outcome is
+// already on the `--outcome-transform` scale. With `--ratio k` every
+// treated unit fills k rows of `treat`, one per match. Returns the ATT and
+// its variance.
+pub trait Estimator: Send + Sync {
+    fn estimate(&self, treat: &DataFrame, control: &DataFrame, args: &Args) -> PolarsResult<(f64, f64)>;
+
+    // Estimate with the whole control `pool` at hand
+    //
+    // `pool` holds every control unit that could be matched, with its
+    // outcome on the same scale as `control`. Estimators that compare the
+    // treated units with the pool rather than with their matches override
+    // this; by default it estimates from the pairs.
+    fn estimate_with_pool(
+        &self,
+        treat: &DataFrame,
+        control: &DataFrame,
+        _pool: &DataFrame,
+        args: &Args
+    ) -> PolarsResult<(f64, f64)> {
+        self.estimate(treat, control, args)
+    }
+}
+
+// Nearest neighbor matching on the propensity score
+//
+// 1:1 by default; with `--ratio k` every treated unit gets its k nearest
+// controls, k rows per treated unit (see `knn_match`). With `--no-replace`
+// a control is matched at most once and with `--max-reuse n` at most n
+// times (see `greedy_match`). `--ties random` picks among equally close
+// controls at random; `--ties all-average`, which gives a treated unit
+// several matches, is handled by the caller. `--covariate-penalty` adds the
+// covariates' Mahalanobis distance to the propensity score distance (see
+// `HybridDistance`), and `--prognostic` matches on the prognostic score of
+// an outcome model of the controls as well (see `prognostic_match`).
+struct NearestNeighbor;
+
+impl Matcher for NearestNeighbor {
+    fn match_controls(&self, treat: &DataFrame, pool: &DataFrame, args: &Args) -> PolarsResult<DataFrame> {
+        let max_uses = match args.no_replace {
+            true => Some(1),
+            false => args.max_reuse
+        };
+        if args.prognostic {
+            let start = Instant::now();
+            let covariates = adjustment_covariates(treat, pool, args)?;
+            let mut controls = pool.clone();
+            if let Some(t) = args.outcome_transform {
+                t.apply(&mut controls, &args.outcome)?;
+            }
+            let model = PrognosticModel::fit(&controls, &args.outcome, &covariates)?;
+            record("prognostic", Some(pool.height()), None, start.elapsed(), &model.to_string());
+            return prognostic_match(treat, pool, &model, args.ratio.unwrap_or(1), &args.cancel);
+        }
+        if let Some(penalty) = args.covariate_penalty {
+            let covariates = adjustment_covariates(treat, pool, args)?;
+            let distance = HybridDistance::new(treat, pool, &covariates, penalty)?;
+            return hybrid_match(treat, pool, &distance, args.ratio.unwrap_or(1), &args.cancel);
+        }
+        match (args.ratio.unwrap_or(1), max_uses) {
+            (k, Some(n)) => greedy_match(treat, pool, k, n, args.match_order, &mut rng(args.seed), &args.cancel),
+            (1, None) if args.ties == Ties::Random => {
+                Ok(tied_match(treat, pool, args.ties, &mut rng(args.seed), &args.cancel)?.0)
+            },
+            (1, None) => nn_match(treat, &mut pool.clone(), &args.cancel),
+            (k, None) => knn_match(treat, pool, k, &args.cancel)
+        }
+    }
+}
+
+// Optimal matching on the propensity score, without replacement
+//
+// Minimizes the total distance over all pairs (see `optimal_match`);
+// `--ratio k` assigns k controls to every treated unit.
+struct Optimal;
+
+impl Matcher for Optimal {
+    fn match_controls(&self, treat: &DataFrame, pool: &DataFrame, args: &Args) -> PolarsResult<DataFrame> {
+        optimal_match(treat, pool, args.ratio.unwrap_or(1), &args.cancel)
+    }
+}
+
+// Genetic matching on the covariates and the propensity score
+//
+// 1:1 with replacement, on the covariate distance whose weights balance
+// the covariates best (see `genetic_match`). The search draws from
+// `--seed` and the audit log records the weights it found.
+struct Genetic;
+
+impl Matcher for Genetic {
+    fn match_controls(&self, treat: &DataFrame, pool: &DataFrame, args: &Args) -> PolarsResult<DataFrame> {
+        let start = Instant::now();
+        let mut variables = adjustment_covariates(treat, pool, args)?;
+        variables.push("propensities".to_string());
+        let mut rng = rng(args.seed);
+        let (control, weights) = genetic_match(treat, pool, &variables, &args.genetic, &mut rng, &args.cancel)?;
+        record("genetic", Some(pool.height()), Some(control.height()), start.elapsed(), &weights.to_string());
+        Ok(control)
+    }
+}
+
+// The mean difference of the pairs with the Abadie-Imbens variance
+//
+// With `--bias-correct` the pair differences are regression adjusted for
+// their gaps in the propensity model's covariates (see
+// `calculate_bias_corrected_att` and `adjustment_covariates`); the variance
+// is the same, since the correction doesn't change the estimator's
+// asymptotic variance and the Abadie-Imbens (2006) estimator stays
+// consistent for it (Abadie and Imbens, 2011, Theorem 2). `--variance`
+// picks the homoskedastic or paired variance instead (see `Variance`). A
+// `--location` other than the mean takes the median or trimmed mean of the
+// differences instead, with a bootstrap variance (see
+// `calculate_location_att`). With a `--time-budget`, the self-matches of
+// the variance are timed on a few units and, if scanning every group would
+// take too long, found in propensity score order instead.
+struct AbadieImbens;
+
+impl Estimator for AbadieImbens {
+    fn estimate(&self, treat: &DataFrame, control: &DataFrame, args: &Args) -> PolarsResult<(f64, f64)> {
+        if args.location != Location::Mean {
+            let mut rng = rng(args.seed);
+            return calculate_location_att(treat, control, &args.outcome, args.location, &mut rng, &args.cancel);
+        }
+        let att = match args.bias_correct {
+            true => {
+                let covariates = adjustment_covariates(treat, control, args)?;
+                calculate_bias_corrected_att(treat, control, &args.outcome, &covariates)?
+            },
+            false => calculate_att(treat, control, &args.outcome)?
+        };
+        if args.variance == Variance::Paired {
+            return Ok((att, calculate_paired_variance(treat, control, &args.outcome)?));
+        }
+        // Several neighbors are always found in propensity score order
+        let neighbors = args.variance_neighbors.unwrap_or(DEFAULT_VARIANCE_NEIGHBORS);
+        let sorted = match &args.budget {
+            Some(budget) if neighbors == 1 => {
+                let start = Instant::now();
+                let probe = treat.head(Some(PROBE_UNITS));
+                nn_match(&probe, &mut treat.clone(), &args.cancel)?;
+                let (n_treat, n_control) = (treat.height() as f64, control.height() as f64);
+                let scans = (n_treat * n_treat + n_control * n_control) / (probe.height() as f64 * n_treat);
+                let fits = budget.fits(start.elapsed().mul_f64(scans));
+                if !fits {
+                    budget.apply(
+                        "variance",
+                        "self-matches from propensity score order; identical up to how ties are broken"
+                    );
+                }
+                !fits
+            },
+            _ => false
+        };
+        let options = VarianceOptions { neighbors, sorted, homoskedastic: args.variance == Variance::AiHomo };
+        let (outcome, treatment) = (&args.outcome, &args.treatment);
+        let att_variance = calculate_variance_with(treat, control, outcome, treatment, &args.cancel, options)?;
+        Ok((att, att_variance))
+    }
+}
+
+// Subclassification on the propensity score
+//
+// The ATT and variance of `calculate_subclass_att` in place of the
+// matching estimate: every treated unit and the whole control pool are
+// split into `--n-strata` (or `DEFAULT_N_STRATA`) strata of the
+// propensity score. The matches themselves are not used.
+struct Subclass;
+
+impl Estimator for Subclass {
+    fn estimate(&self, _treat: &DataFrame, _control: &DataFrame, _args: &Args) -> PolarsResult<(f64, f64)> {
+        Err(PolarsError::ComputeError("The subclassification estimator needs the control pool".into()))
+    }
+
+    fn estimate_with_pool(
+        &self,
+        treat: &DataFrame,
+        _control: &DataFrame,
+        pool: &DataFrame,
+        args: &Args
+    ) -> PolarsResult<(f64, f64)> {
+        // One row per treated unit, which k:1 matching repeats once per match
+        let units = treat.unique_stable(Some(&["strike_id".to_string()]), UniqueKeepStrategy::First, None)?;
+        let n_strata = args.n_strata.unwrap_or(DEFAULT_N_STRATA);
+        let subclass = calculate_subclass_att(&units, pool, &args.outcome, n_strata)?;
+        Ok((subclass.att, subclass.att_variance))
+    }
+}
+
+// Matchers and estimators registered by other crates.
+static MATCHERS: RwLock<Vec<(String, Arc<dyn Matcher>)>> = RwLock::new(Vec::new());
+static ESTIMATORS: RwLock<Vec<(String, Arc<dyn Estimator>)>> = RwLock::new(Vec::new());
+
+// Make a matcher selectable with `--matcher name`
+//
+// Register at startup, before parsing the command line (see
+// `strike::app::main`). Fails if the name is already taken.
+pub fn register_matcher(name: &str, matcher: impl Matcher + 'static) -> Result<(), String> {
+    let mut matchers = MATCHERS.write().unwrap();
+    let built_in = [DEFAULT_MATCHER, OPTIMAL_MATCHER, GENETIC_MATCHER];
+    if built_in.contains(&name) || matchers.iter().any(|(n, _)| n == name) {
+        return Err(format!("A matcher named `{}` is already registered", name));
+    }
+    matchers.push((name.to_string(), Arc::new(matcher)));
+    Ok(())
+}
+
+// Make an estimator selectable with `--estimator name`
+//
+// Register at startup, before parsing the command line. Fails if the name
+// is already taken.
+pub fn register_estimator(name: &str, estimator: impl Estimator + 'static) -> Result<(), String> {
+    let mut estimators = ESTIMATORS.write().unwrap();
+    let built_in = [DEFAULT_ESTIMATOR, SUBCLASS_ESTIMATOR];
+    if built_in.contains(&name) || estimators.iter().any(|(n, _)| n == name) {
+        return Err(format!("An estimator named `{}` is already registered", name));
+    }
+    estimators.push((name.to_string(), Arc::new(estimator)));
+    Ok(())
+}
+
+// The matcher called `name`, built-in or registered.
+pub fn matcher(name: &str) -> Option<Arc<dyn Matcher>> {
+    match name {
+        DEFAULT_MATCHER => return Some(Arc::new(NearestNeighbor)),
+        OPTIMAL_MATCHER => return Some(Arc::new(Optimal)),
+        GENETIC_MATCHER => return Some(Arc::new(Genetic)),
+        _ => {}
+    }
+    let matchers = MATCHERS.read().unwrap();
+    matchers.iter().find(|(n, _)| n == name).map(|(_, m)| Arc::clone(m))
+}
+
+// The estimator called `name`, built-in or registered.
+pub fn estimator(name: &str) -> Option<Arc<dyn Estimator>> {
+    match name {
+        DEFAULT_ESTIMATOR => return Some(Arc::new(AbadieImbens)),
+        SUBCLASS_ESTIMATOR => return Some(Arc::new(Subclass)),
+        _ => {}
+    }
+    let estimators = ESTIMATORS.read().unwrap();
+    estimators.iter().find(|(n, _)| n == name).map(|(_, e)| Arc::clone(e))
+}
+
+// Names of every available matcher, the built-in ones first.
+pub fn matcher_names() -> Vec<String> {
+    let mut names = vec![DEFAULT_MATCHER.to_string(), OPTIMAL_MATCHER.to_string(), GENETIC_MATCHER.to_string()];
+    names.extend(MATCHERS.read().unwrap().iter().map(|(n, _)| n.clone()));
+    names
+}
+
+// Names of every available estimator, the built-in ones first.
+pub fn estimator_names() -> Vec<String> {
+    let mut names = vec![DEFAULT_ESTIMATOR.to_string(), SUBCLASS_ESTIMATOR.to_string()];
+    names.extend(ESTIMATORS.read().unwrap().iter().map(|(n, _)| n.clone()));
+    names
+}
@@ -0,0 +1,93 @@
+use ndarray::{Array1, Array2, Axis};
+
+// Generalized inverse of a symmetric positive semi-definite matrix
+//
+// Gauss-Jordan elimination pivoting down the diagonal. A pivot that has
+// (numerically) vanished relative to the original diagonal element means the
+// column is a linear combination of earlier columns; it is marked as aliased
+// and skipped, the same way R's `lm` drops collinear terms. The returned
+// matrix is the inverse of the non-aliased block, padded with zeros.
+pub fn generalized_inverse(a: &Array2<f64>) -> (Array2<f64>, Vec<bool>) {
+    let p = a.nrows();
+    let mut left = a.clone();
+    let mut right = Array2::<f64>::eye(p);
+    let mut aliased = vec![false; p];
+    for k in 0..p {
+        let pivot = left[[k, k]];
+        if pivot.abs() <= 1e-10 * a[[k, k]].abs().max(f64::MIN_POSITIVE) {
+            aliased[k] = true;
+            continue;
+        }
+        for j in 0..p {
+            left[[k, j]] /= pivot;
+            right[[k, j]] /= pivot;
+        }
+        for i in 0..p {
+            let factor = left[[i, k]];
+            if i == k || factor == 0.0 {
+                continue;
+            }
+            for j in 0..p {
+                left[[i, j]] -= factor * left[[k, j]];
+                right[[i, j]] -= factor * right[[k, j]];
+            }
+        }
+    }
+    for (k, is_aliased) in aliased.iter().enumerate() {
+        if *is_aliased {
+            right.row_mut(k).fill(0.0);
+            right.column_mut(k).fill(0.0);
+        }
+    }
+    (right, aliased)
+}
+
+// A weighted least squares fit
+//
+// `bread` is the generalized inverse of X'WX; coefficients of aliased
+// columns are zero.
+#[derive(Debug)]
+pub struct LinearFit {
+    pub coefficients: Array1<f64>,
+    pub aliased: Vec<bool>,
+    pub bread: Array2<f64>,
+    pub residuals: Array1<f64>
+}
+
+// Fit a weighted least squares regression of `y` on `x`
+//
+// `x` should already contain an intercept column if one is wanted.
+pub fn wls(x: &Array2<f64>, y: &Array1<f64>, w: &Array1<f64>) -> LinearFit {
+    let xw = x * &w.view().insert_axis(Axis(1));
+    let xtwx = xw.t().dot(x);
+    let xtwy = xw.t().dot(y);
+    let (bread, aliased) = generalized_inverse(&xtwx);
+    let coefficients = bread.dot(&xtwy);
+    let residuals = y - &x.dot(&coefficients);
+    LinearFit { coefficients, aliased, bread, residuals }
+}
+
+impl LinearFit {
+    // Cluster-robust variance-covariance matrix of the coefficients
+    //
+    // The sandwich (X'WX)^- M (X'WX)^- where the meat M sums the outer
+    // products of each cluster's score, sum_i w_i x_i e_i. `clusters` maps
+    // every observation to a cluster index in 0..G. Uses the usual
+    // G / (G - 1) * (n - 1) / (n - k) small-sample correction, so giving
+    // every observation its own cluster yields the HC1 estimator.
+    pub fn cluster_vcov(&self, x: &Array2<f64>, w: &Array1<f64>, clusters: &[usize]) -> Array2<f64> {
+        let (n, p) = x.dim();
+        let g = clusters.iter().max().map_or(0, |m| m + 1);
+        let mut scores = Array2::<f64>::zeros((g, p));
+        for i in 0..n {
+            let s = &x.row(i) * (w[i] * self.residuals[i]);
+            let mut row = scores.row_mut(clusters[i]);
+            row += &s;
+        }
+        let meat = scores.t().dot(&scores);
+        let k = self.aliased.iter().filter(|a| !**a).count() as f64;
+        let (n, g) = (n as f64, g as f64);
+        let correction = g / (g - 1.0) * (n - 1.0) / (n - k);
+        self.bread.dot(&meat).dot(&self.bread) * correction
+    }
+}
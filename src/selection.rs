@@ -0,0 +1,268 @@
+use ndarray::{Array1, Array2, Axis};
+use polars::prelude::DataFrame;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cli::Args;
+use crate::data::column_values;
+use crate::formula::{is_categorical, predictor_matrix};
+use crate::penalized::{PenalizedLogit, Penalty, PenaltyKind, CV_FOLDS, CV_GRID, CV_RATIO};
+use crate::propensity::predictor_names;
+
+// Coordinate descent sweeps of the outcome lasso at one penalty strength.
+const LASSO_SWEEPS: usize = 1000;
+
+// Largest coefficient change (on the standardized scale) at which the
+// outcome lasso has converged.
+const LASSO_TOLERANCE: f64 = 1e-6;
+
+// Which lasso regressions select the covariates of the propensity model
+// (`--select`)
+//
+// `Treatment` keeps the covariates an L1 logit of the treatment selects,
+// `Outcome` those a lasso regression of the outcome selects, and `Double`
+// the union of both (the double selection of Belloni, Chernozhukov and
+// Hansen, 2014), which keeps a confounder that only one of the two
+// regressions picks up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Selection {
+    Treatment,
+    Outcome,
+    Double
+}
+
+// Implement `FromStr` for `Selection`.
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "treatment" => Ok(Selection::Treatment),
+            "outcome" => Ok(Selection::Outcome),
+            "double" => Ok(Selection::Double),
+            _ => Err(format!("Unknown selection `{}`; use treatment, outcome or double", s))
+        }
+    }
+}
+
+// Implement `Display` for `Selection`.
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Selection::Treatment => write!(f, "treatment"),
+            Selection::Outcome => write!(f, "outcome"),
+            Selection::Double => write!(f, "double")
+        }
+    }
+}
+
+// The covariates kept by the selection pre-step
+//
+// `candidates` are the columns the propensity model would otherwise use,
+// `treatment` and `outcome` the columns each lasso selected (none when it
+// wasn't run) with its cross-validated penalty strength, and `dropped` the
+// candidates neither kept. A categorical column is kept when the lasso
+// selects any of its dummies.
+#[derive(Clone, Debug)]
+pub struct CovariateSelection {
+    pub method: Selection,
+    pub candidates: Vec<String>,
+    pub treatment: Option<(Vec<String>, f64)>,
+    pub outcome: Option<(Vec<String>, f64)>,
+    pub dropped: Vec<String>
+}
+
+// A lasso regression of a continuous response
+//
+// Fit by cyclic coordinate descent on standardized predictors and a
+// standardized response, so that the penalty strength is on the same scale
+// as the L1 logit's. Constant predictors get a slope of zero.
+pub struct Lasso {
+    mean: Vec<f64>,
+    sd: Vec<f64>,
+    y_mean: f64,
+    y_sd: f64,
+    beta: Array1<f64>
+}
+
+impl Lasso {
+    // The model with every slope zero, standardizing by the means and
+    // standard deviations of `x` and `y`.
+    fn new(x: &Array2<f64>, y: &Array1<f64>) -> Lasso {
+        let (mean, sd): (Vec<f64>, Vec<f64>) = x
+            .axis_iter(Axis(1))
+            .map(|column| (column.mean().unwrap_or(0.0), column.std(0.0)))
+            .unzip();
+        let (y_mean, y_sd) = (y.mean().unwrap_or(0.0), y.std(0.0));
+        Lasso { mean, sd, y_mean, y_sd, beta: Array1::zeros(x.ncols()) }
+    }
+
+    // The standardized response.
+    fn response(&self, y: &Array1<f64>) -> Array1<f64> {
+        y.mapv(|y| if self.y_sd > 0.0 { (y - self.y_mean) / self.y_sd } else { 0.0 })
+    }
+
+    // Fit with penalty strength `lambda`, starting from `start` when given.
+    pub fn fit(x: &Array2<f64>, y: &Array1<f64>, lambda: f64, start: Option<&Array1<f64>>) -> Lasso {
+        let mut model = Lasso::new(x, y);
+        if let Some(start) = start {
+            model.beta.assign(start);
+        }
+        let z = model.design(x);
+        let n = x.nrows() as f64;
+        let mut residual = model.response(y) - z.dot(&model.beta);
+        for _ in 0..LASSO_SWEEPS {
+            let mut largest = 0.0_f64;
+            for j in (0..z.ncols()).filter(|j| model.sd[*j] > 0.0) {
+                let gradient = z.column(j).dot(&residual) / n + model.beta[j];
+                let updated = gradient.signum() * (gradient.abs() - lambda).max(0.0);
+                let change = updated - model.beta[j];
+                if change != 0.0 {
+                    residual.scaled_add(-change, &z.column(j));
+                    model.beta[j] = updated;
+                    largest = largest.max(change.abs());
+                }
+            }
+            if largest < LASSO_TOLERANCE {
+                break;
+            }
+        }
+        model
+    }
+
+    // The standardized predictors.
+    fn design(&self, x: &Array2<f64>) -> Array2<f64> {
+        let mut z = Array2::<f64>::zeros(x.raw_dim());
+        for (k, column) in x.axis_iter(Axis(1)).enumerate().filter(|(k, _)| self.sd[*k] > 0.0) {
+            z.column_mut(k).assign(&column.mapv(|v| (v - self.mean[k]) / self.sd[k]));
+        }
+        z
+    }
+
+    // Predicted responses for the rows of `x`.
+    pub fn predict(&self, x: &Array2<f64>) -> Array1<f64> {
+        self.design(x).dot(&self.beta).mapv(|v| self.y_mean + self.y_sd * v)
+    }
+
+    // Fit with the penalty strength of the smallest cross-validated squared
+    // error, from the same grid and folds (assigned in turn, in row order)
+    // as the L1 logit's. Returns the model and the strength.
+    pub fn cross_validated(x: &Array2<f64>, y: &Array1<f64>) -> (Lasso, f64) {
+        // The smallest strength that sets every slope to zero tops the grid
+        let null = Lasso::new(x, y);
+        let gradient = null.design(x).t().dot(&null.response(y)) / x.nrows() as f64;
+        let lambda_max = gradient.iter().fold(0.0_f64, |m, g| m.max(g.abs()));
+        let grid: Vec<f64> = (0..CV_GRID)
+            .map(|i| lambda_max * CV_RATIO.powf(i as f64 / (CV_GRID - 1) as f64))
+            .collect();
+        let folds: Vec<usize> = (0..y.len()).map(|i| i % CV_FOLDS).collect();
+        let mut error = vec![0.0; grid.len()];
+        for fold in 0..CV_FOLDS {
+            let train: Vec<usize> = (0..y.len()).filter(|i| folds[*i] != fold).collect();
+            let test: Vec<usize> = (0..y.len()).filter(|i| folds[*i] == fold).collect();
+            let (x_train, y_train) = (x.select(Axis(0), &train), y.select(Axis(0), &train));
+            let (x_test, y_test) = (x.select(Axis(0), &test), y.select(Axis(0), &test));
+            let mut start: Option<Array1<f64>> = None;
+            for (total, lambda) in error.iter_mut().zip(&grid) {
+                let model = Lasso::fit(&x_train, &y_train, *lambda, start.as_ref());
+                *total += (&y_test - &model.predict(&x_test)).mapv(|e| e * e).sum();
+                start = Some(model.beta);
+            }
+        }
+        let best = (0..grid.len()).min_by(|a, b| error[*a].total_cmp(&error[*b])).unwrap_or(0);
+        (Lasso::fit(x, y, grid[best], None), grid[best])
+    }
+}
+
+// The column a predictor comes from: the column itself or, for the dummy
+// `column=level` of a categorical column, that column.
+fn column_of(data: &DataFrame, predictor: &str) -> String {
+    match predictor.split_once('=') {
+        Some((column, _)) if data.column(predictor).is_err() && is_categorical(data, column) => column.to_string(),
+        _ => predictor.to_string()
+    }
+}
+
+// Columns of the predictors `names` whose coefficient in `slopes` is
+// nonzero, each listed once.
+fn selected_columns(data: &DataFrame, names: &[String], slopes: &[f64]) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    for (name, _) in names.iter().zip(slopes).filter(|(_, b)| **b != 0.0) {
+        let column = column_of(data, name);
+        if !columns.contains(&column) {
+            columns.push(column);
+        }
+    }
+    columns
+}
+
+// Select the covariates of the propensity model by the lasso
+//
+// `exclude` are the columns the propensity model leaves out anyway. The
+// L1 logit of the treatment is the `--ps-penalty l1` model, its strength
+// chosen by cross-validated deviance. The outcome lasso is fit on every
+// unit with an observed outcome, its strength chosen by cross-validated
+// squared error. Both use every predictor the propensity model would.
+pub fn select_covariates(
+    data: &DataFrame,
+    method: Selection,
+    exclude: &[String],
+    args: &Args
+) -> Result<CovariateSelection, Box<dyn Error>> {
+    let names = predictor_names(data, &args.treatment, exclude)?;
+    if names.is_empty() {
+        return Err("`--select` needs at least one covariate to select from".into());
+    }
+    let x = predictor_matrix(data, &names)?;
+    let mut candidates: Vec<String> = Vec::new();
+    for column in names.iter().map(|name| column_of(data, name)) {
+        if !candidates.contains(&column) {
+            candidates.push(column);
+        }
+    }
+    let treatment = match method {
+        Selection::Treatment | Selection::Double => {
+            let d: Array1<i64> = column_values(data, &args.treatment)?.into_iter().map(|d| d as i64).collect();
+            let penalty = Penalty { kind: PenaltyKind::L1, lambda: None };
+            let (model, _, _) = PenalizedLogit::fit(&x, &d, penalty, &args.logit);
+            Some((selected_columns(data, &names, &model.coefficients().1), model.lambda))
+        },
+        Selection::Outcome => None
+    };
+    let outcome = match method {
+        Selection::Outcome | Selection::Double => {
+            let y = column_values(data, &args.outcome)?;
+            let rows: Vec<usize> = (0..y.len()).filter(|i| !y[*i].is_nan()).collect();
+            let y: Array1<f64> = rows.iter().map(|i| y[*i]).collect();
+            let (model, lambda) = Lasso::cross_validated(&x.select(Axis(0), &rows), &y);
+            Some((selected_columns(data, &names, &model.beta.to_vec()), lambda))
+        },
+        Selection::Treatment => None
+    };
+    let kept = |column: &String| {
+        [&treatment, &outcome].into_iter().flatten().any(|(columns, _)| columns.contains(column))
+    };
+    let dropped = candidates.iter().filter(|c| !kept(c)).cloned().collect();
+    Ok(CovariateSelection { method, candidates, treatment, outcome, dropped })
+}
+
+// Implement `Display` for `CovariateSelection`.
+impl fmt::Display for CovariateSelection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kept = self.candidates.len() - self.dropped.len();
+        writeln!(f, "Covariate selection ({} lasso)", self.method)?;
+        writeln!(f, "Kept {} of {} covariates for the propensity model", kept, self.candidates.len())?;
+        let list = |columns: &[String]| match columns.is_empty() {
+            true => "(none)".to_string(),
+            false => columns.join(", ")
+        };
+        if let Some((columns, lambda)) = &self.treatment {
+            writeln!(f, "  Treatment lasso (lambda {:.3e}): {}", lambda, list(columns))?;
+        }
+        if let Some((columns, lambda)) = &self.outcome {
+            writeln!(f, "  Outcome lasso (lambda {:.3e}): {}", lambda, list(columns))?;
+        }
+        writeln!(f, "  Dropped: {}", list(&self.dropped))
+    }
+}
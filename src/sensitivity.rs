@@ -0,0 +1,228 @@
+use std::fmt;
+
+use crate::format::num;
+use crate::paired::{absolute_ranks, normal_cdf};
+
+// Rows of the Rosenbaum bounds table, from Γ = 1 to the largest Γ asked for.
+pub const GAMMA_STEPS: usize = 10;
+
+// Significance level at which the critical Γ is found.
+pub const SENSITIVITY_ALPHA: f64 = 0.05;
+
+// Bounds on the signed-rank p-value at one value of Γ.
+#[derive(Clone, Debug)]
+pub struct GammaBound {
+    pub gamma: f64,
+    pub lower: f64,
+    pub upper: f64
+}
+
+// Rosenbaum bounds for the matched pairs (`--rosenbaum`)
+//
+// A hidden confounder that multiplies the odds of treatment of one unit of
+// a pair over the other by at most Γ makes the chance that a pair's
+// difference counts as positive anywhere between 1 / (1 + Γ) and
+// Γ / (1 + Γ) instead of 1/2. `bounds` holds the smallest and largest
+// one-sided p-value of the Wilcoxon signed-rank test over that range, by
+// the normal approximation (Rosenbaum, 2002), in the direction of the
+// observed effect. `critical` is the Γ at which the largest p-value reaches
+// `SENSITIVITY_ALPHA`, that is how strong hidden bias would have to be to
+// explain the effect away; none when the effect isn't significant even
+// without hidden bias. Pairs with no difference are dropped.
+#[derive(Clone, Debug)]
+pub struct RosenbaumBounds {
+    pub n_pairs: usize,
+    pub bounds: Vec<GammaBound>,
+    pub critical: Option<f64>
+}
+
+// The signed-rank statistic of the pair differences `d`, in the direction
+// of the effect, with the ranks of the absolute differences.
+fn signed_rank_statistic(d: &[f64]) -> (f64, Vec<f64>) {
+    let (ranks, _) = absolute_ranks(d);
+    let positive: f64 = d.iter().zip(&ranks).filter(|(x, _)| **x > 0.0).map(|(_, r)| r).sum();
+    let total: f64 = ranks.iter().sum();
+    (positive.max(total - positive), ranks)
+}
+
+// One-sided p-value of the signed-rank `statistic` when every pair is
+// positive with probability `p`. Ties share their average rank, so the
+// variance is p (1 - p) times the sum of the squared ranks.
+fn bounded_p_value(statistic: f64, ranks: &[f64], p: f64) -> f64 {
+    let expected = p * ranks.iter().sum::<f64>();
+    let variance = p * (1.0 - p) * ranks.iter().map(|r| r * r).sum::<f64>();
+    1.0 - normal_cdf((statistic - expected) / variance.sqrt())
+}
+
+// Calculate the Rosenbaum bounds of the pair differences for Γ from 1 to
+// `max_gamma`
+//
+// The critical Γ is found by bisection on log Γ, so it needn't lie in the
+// range of the table.
+pub fn rosenbaum_bounds(differences: &[f64], max_gamma: f64) -> RosenbaumBounds {
+    let d: Vec<f64> = differences.iter().copied().filter(|x| *x != 0.0).collect();
+    let (statistic, ranks) = signed_rank_statistic(&d);
+    let upper = |gamma: f64| bounded_p_value(statistic, &ranks, gamma / (1.0 + gamma));
+    let bounds = (0..=GAMMA_STEPS)
+        .map(|k| {
+            let gamma = 1.0 + (max_gamma - 1.0) * k as f64 / GAMMA_STEPS as f64;
+            GammaBound { gamma, lower: bounded_p_value(statistic, &ranks, 1.0 / (1.0 + gamma)), upper: upper(gamma) }
+        })
+        .collect();
+    let critical = match d.is_empty() || upper(1.0) >= SENSITIVITY_ALPHA {
+        true => None,
+        false => {
+            let mut hi = 2.0;
+            while upper(hi) < SENSITIVITY_ALPHA && hi < 1e6 {
+                hi *= 2.0;
+            }
+            let (mut lo, mut hi) = (0.0_f64, hi.ln());
+            for _ in 0..60 {
+                let mid = (lo + hi) / 2.0;
+                match upper(mid.exp()) < SENSITIVITY_ALPHA {
+                    true => lo = mid,
+                    false => hi = mid
+                }
+            }
+            Some(((lo + hi) / 2.0).exp())
+        }
+    };
+    RosenbaumBounds { n_pairs: d.len(), bounds, critical }
+}
+
+// Implement `Display` for `RosenbaumBounds`.
+impl fmt::Display for RosenbaumBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Rosenbaum bounds (Wilcoxon signed-rank, {} pairs)", self.n_pairs)?;
+        writeln!(f, "{:>8}  {:>12}  {:>12}", "Gamma", "Lower p", "Upper p")?;
+        for bound in &self.bounds {
+            writeln!(f, "{:>8.2}  {:>12}  {:>12}", bound.gamma, num(bound.lower), num(bound.upper))?;
+        }
+        match self.critical {
+            Some(gamma) => writeln!(f, "Critical Gamma (p = {}): {}", SENSITIVITY_ALPHA, num(gamma)),
+            None => writeln!(f, "Critical Gamma (p = {}): not significant at Gamma = 1", SENSITIVITY_ALPHA)
+        }
+    }
+}
+
+// Factor of the approximate risk ratio exp(SMD_RISK_RATIO d) of a
+// standardized mean difference d (VanderWeele and Ding, 2017).
+pub const SMD_RISK_RATIO: f64 = 0.91;
+
+// E-values of the estimate and its 95% confidence interval
+//
+// The E-value is the smallest risk ratio that a hidden confounder would
+// need with both the treatment and the outcome to explain the effect away
+// (VanderWeele and Ding, 2017); that of the interval is the E-value of its
+// bound closer to no effect, 1 when the interval includes no effect. For a
+// binary outcome the effect is the risk ratio of the matched sample; for
+// any other outcome (`approximate`) it is exp(0.91 d), with d the effect
+// and its bounds over the pooled standard deviation of the outcome.
+#[derive(Clone, Debug)]
+pub struct EValues {
+    pub risk_ratio: f64,
+    pub risk_ratio_ci: (f64, f64),
+    pub approximate: bool,
+    pub estimate: f64,
+    pub ci_bound: f64
+}
+
+// E-value of a risk ratio, on the inverse of ratios below 1.
+pub fn e_value(risk_ratio: f64) -> f64 {
+    let rr = match risk_ratio < 1.0 {
+        true => 1.0 / risk_ratio,
+        false => risk_ratio
+    };
+    rr + (rr * (rr - 1.0)).sqrt()
+}
+
+// Calculate the E-values of the risk ratio `risk_ratio` with interval
+// `ci`.
+pub fn e_values(risk_ratio: f64, ci: (f64, f64), approximate: bool) -> EValues {
+    let ci_bound = match (ci.0 > 1.0, ci.1 < 1.0) {
+        (true, _) => e_value(ci.0),
+        (_, true) => e_value(ci.1),
+        _ => 1.0
+    };
+    EValues { risk_ratio, risk_ratio_ci: ci, approximate, estimate: e_value(risk_ratio), ci_bound }
+}
+
+// Calculate the E-values of a mean difference `effect` with interval `ci`
+// on the approximate risk ratio scale of the outcome's standard deviation
+// `sd`.
+pub fn smd_e_values(effect: f64, ci: (f64, f64), sd: f64) -> EValues {
+    let ratio = |x: f64| (SMD_RISK_RATIO * x / sd).exp();
+    e_values(ratio(effect), (ratio(ci.0), ratio(ci.1)), true)
+}
+
+// Implement `Display` for `EValues`.
+impl fmt::Display for EValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.approximate {
+            true => writeln!(f, "E-values (risk ratio approximated by exp({} SMD))", SMD_RISK_RATIO)?,
+            false => writeln!(f, "E-values (risk ratio)")?
+        }
+        writeln!(
+            f,
+            "Risk ratio              : {} ({}, {})",
+            num(self.risk_ratio),
+            num(self.risk_ratio_ci.0),
+            num(self.risk_ratio_ci.1)
+        )?;
+        writeln!(f, "E-value                 : {}", num(self.estimate))?;
+        writeln!(f, "E-value (CI bound)      : {}", num(self.ci_bound))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_hidden_bias_is_the_signed_rank_test() {
+        // All five differences positive: T = 15 with mean 7.5 and variance
+        // 55 / 4 at Γ = 1, so z = 7.5 / 3.708 = 2.023 and p = 0.0216.
+        let bounds = rosenbaum_bounds(&[1.0, 2.0, 3.0, 4.0, 5.0], 3.0);
+        let first = &bounds.bounds[0];
+        assert_eq!(first.gamma, 1.0);
+        assert_eq!(first.lower, first.upper);
+        assert!((first.upper - 0.02156).abs() < 1e-4);
+        assert_eq!(bounds.bounds.len(), GAMMA_STEPS + 1);
+        assert_eq!(bounds.bounds[GAMMA_STEPS].gamma, 3.0);
+    }
+
+    #[test]
+    fn bounds_widen_with_gamma() {
+        let bounds = rosenbaum_bounds(&[1.0, 2.0, -0.5, 3.0, 4.0, 5.0, 2.5], 4.0);
+        for pair in bounds.bounds.windows(2) {
+            assert!(pair[1].lower < pair[0].lower);
+            assert!(pair[1].upper > pair[0].upper);
+        }
+    }
+
+    #[test]
+    fn critical_gamma_reaches_alpha() {
+        let d = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, -0.5];
+        let critical = rosenbaum_bounds(&d, 2.0).critical.unwrap();
+        assert!(critical > 1.0);
+        let (statistic, ranks) = signed_rank_statistic(&d);
+        let upper = bounded_p_value(statistic, &ranks, critical / (1.0 + critical));
+        assert!((upper - SENSITIVITY_ALPHA).abs() < 1e-9);
+    }
+
+    #[test]
+    fn no_critical_gamma_without_an_effect() {
+        assert!(rosenbaum_bounds(&[1.0, -1.0, 2.0, -2.0], 2.0).critical.is_none());
+        assert!(rosenbaum_bounds(&[0.0, 0.0], 2.0).critical.is_none());
+    }
+
+    #[test]
+    fn zero_differences_are_dropped_and_sign_is_ignored() {
+        let bounds = rosenbaum_bounds(&[1.0, 0.0, 2.0, 3.0, 0.0], 2.0);
+        let flipped = rosenbaum_bounds(&[-1.0, -2.0, -3.0], 2.0);
+        assert_eq!(bounds.n_pairs, 3);
+        for (a, b) in bounds.bounds.iter().zip(&flipped.bounds) {
+            assert_eq!(a.upper, b.upper);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::error::Error;
+use std::fs;
+
+use crate::cli::Args;
+use crate::data::column_values;
+use crate::estimate::Strike;
+use crate::propensity::Coefficients;
+
+// First line of every session file.
+const HEADER: &str = "# strike session";
+
+// A saved analysis that can be updated when rows are appended to its data
+//
+// Holds the number of rows the analysis covered, the treatment and outcome
+// columns, the propensity model's coefficients and the matched pairs (the
+// `strike_id`s, i.e. row numbers, of every treated unit and its control).
+// Updating scores and matches only what the appended rows change; see
+// `rematch`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub rows: usize,
+    pub treatment: String,
+    pub outcome: String,
+    pub model: Coefficients,
+    pub pairs: Vec<(i64, i64)>
+}
+
+impl Session {
+    // The session of an estimate on the first `rows` rows of the data
+    //
+    // Needs the estimate's propensity model, which a sample-split or
+    // cross-fit estimate doesn't have.
+    pub fn new(strike: &Strike, args: &Args, rows: usize) -> Result<Session, Box<dyn Error>> {
+        let model = strike.model.clone().ok_or("The estimate has no single propensity model to save")?;
+        let treat_ids = column_values(&strike.treat, "strike_id")?;
+        let control_ids = column_values(&strike.control, "strike_id")?;
+        Ok(Session {
+            rows,
+            treatment: args.treatment.clone(),
+            outcome: args.outcome.clone(),
+            model,
+            pairs: treat_ids.iter().zip(&control_ids).map(|(t, c)| (*t as i64, *c as i64)).collect()
+        })
+    }
+
+    // Read a session file
+    //
+    // Session files are plain text, one tab-separated record per line:
+    // `rows`, `treatment`, `outcome`, `intercept`, one `slope` per
+    // predictor and one `pair` per treated unit.
+    pub fn read(path: &str) -> Result<Session, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(format!("{} is not a Strike session file", path).into());
+        }
+        let mut session = Session {
+            rows: 0,
+            treatment: String::new(),
+            outcome: String::new(),
+            model: Coefficients { intercept: 0.0, slopes: Vec::new() },
+            pairs: Vec::new()
+        };
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields[..] {
+                ["rows", rows] => session.rows = rows.parse()?,
+                ["treatment", name] => session.treatment = name.to_string(),
+                ["outcome", name] => session.outcome = name.to_string(),
+                ["intercept", value] => session.model.intercept = value.parse()?,
+                ["slope", name, value] => session.model.slopes.push((name.to_string(), value.parse()?)),
+                ["pair", treated, control] => session.pairs.push((treated.parse()?, control.parse()?)),
+                _ => return Err(format!("Malformed line in session file {}: `{}`", path, line).into())
+            }
+        }
+        Ok(session)
+    }
+
+    // Write the session file, replacing any existing one.
+    pub fn write(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut text = format!("{}\n", HEADER);
+        text.push_str(&format!("rows\t{}\n", self.rows));
+        text.push_str(&format!("treatment\t{}\n", self.treatment));
+        text.push_str(&format!("outcome\t{}\n", self.outcome));
+        text.push_str(&format!("intercept\t{:e}\n", self.model.intercept));
+        for (name, slope) in &self.model.slopes {
+            text.push_str(&format!("slope\t{}\t{:e}\n", name, slope));
+        }
+        for (treated, control) in &self.pairs {
+            text.push_str(&format!("pair\t{}\t{}\n", treated, control));
+        }
+        fs::write(path, text)?;
+        Ok(())
+    }
+}
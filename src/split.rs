@@ -0,0 +1,60 @@
+use polars::prelude::{BooleanChunked, DataFrame, NamedFrom, PolarsResult};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+// How the sample is split for honest inference
+//
+// With `Split` the propensity model is fit on one random half of the data
+// and the ATT is estimated on the other half. `CrossFit` additionally swaps
+// the roles of the two halves and averages the two estimates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleSplit {
+    Split,
+    CrossFit
+}
+
+// Seeded random number generator
+//
+// Every source of randomness in Strike goes through this so that a given
+// `--seed` always reproduces the same results. Without a seed the generator
+// is seeded from system entropy.
+pub fn rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy()
+    }
+}
+
+// Randomly split a DataFrame into two halves
+//
+// Rows are shuffled and the first half (rounded down) of the shuffled rows
+// forms the first sub-frame, the rest forms the second. Row order within
+// each half follows the original data.
+pub fn split_halves(data: &DataFrame, rng: &mut StdRng) -> PolarsResult<(DataFrame, DataFrame)> {
+    let n = data.height();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.shuffle(rng);
+    let mut in_first = vec![false; n];
+    for i in &order[..(n / 2)] {
+        in_first[*i] = true;
+    }
+    let mask_first = BooleanChunked::new("split", &in_first);
+    let first = data.filter(&mask_first)?;
+    let second = data.filter(&!mask_first)?;
+    Ok((first, second))
+}
+
+// Randomly assign `n` rows to `k` folds of (nearly) equal size
+//
+// Rows are shuffled and dealt out to the folds in turn, so fold sizes
+// differ by at most one. Returns every row's fold, in the original order.
+pub fn assign_folds(n: usize, k: usize, rng: &mut StdRng) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..n).collect();
+    order.shuffle(rng);
+    let mut folds = vec![0; n];
+    for (position, i) in order.into_iter().enumerate() {
+        folds[i] = position % k;
+    }
+    folds
+}
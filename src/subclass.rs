@@ -0,0 +1,152 @@
+use polars::prelude::{DataFrame, PolarsResult};
+use std::fmt;
+
+use crate::balance::mean_variance;
+use crate::data::{column_values, quantile, sorted};
+use crate::format::num;
+
+// Propensity score subclass boundaries
+//
+// Splits the propensity scores into `n_strata` groups of (roughly) equal
+// size. Returns the `n_strata - 1` interior boundaries, which are quantiles
+// of the scores.
+pub fn subclass_breaks(scores: &[f64], n_strata: usize) -> Vec<f64> {
+    let scores = sorted(scores);
+    (1..n_strata)
+        .map(|k| quantile(&scores, k as f64 / n_strata as f64))
+        .collect()
+}
+
+// The subclass (0-based) a propensity score falls into given the interior
+// boundaries from `subclass_breaks`. Scores equal to a boundary go to the
+// lower subclass.
+pub fn subclass_of(score: f64, breaks: &[f64]) -> usize {
+    breaks.partition_point(|b| *b < score)
+}
+
+// Number of strata of `--subclassify` without `--n-strata`.
+pub const DEFAULT_N_STRATA: usize = 5;
+
+// One propensity score stratum of the subclassification estimate.
+#[derive(Debug)]
+pub struct Stratum {
+    pub lower: f64,
+    pub upper: f64,
+    pub n_treat: usize,
+    pub n_control: usize,
+    pub difference: f64
+}
+
+// The ATT by subclassification on the propensity score
+//
+// All treated units and the full control pool are split into strata at
+// quantiles of the pooled propensity scores. The ATT combines the
+// within-stratum differences in mean outcome, weighted by each stratum's
+// share of the treated units, and its variance combines the within-stratum
+// variances of those differences with the squared weights. Treated units in
+// strata without controls can't be compared with anyone; they are left out
+// and counted in `dropped_treat`. A group with a single unit in a stratum
+// adds no variance.
+#[derive(Debug)]
+pub struct SubclassAtt {
+    pub att: f64,
+    pub att_variance: f64,
+    pub strata: Vec<Stratum>,
+    pub dropped_treat: usize
+}
+
+// Calculate the ATT by subclassification into `n_strata` strata.
+pub fn calculate_subclass_att(
+    treat: &DataFrame,
+    pool: &DataFrame,
+    outcome: &str,
+    n_strata: usize
+) -> PolarsResult<SubclassAtt> {
+    let treat_ps = column_values(treat, "propensities")?;
+    let pool_ps = column_values(pool, "propensities")?;
+    let treat_y = column_values(treat, outcome)?;
+    let pool_y = column_values(pool, outcome)?;
+    let all_ps: Vec<f64> = treat_ps.iter().chain(&pool_ps).copied().collect();
+    let breaks = subclass_breaks(&all_ps, n_strata);
+    let in_stratum = |ps: &[f64], y: &[f64], k: usize| -> Vec<f64> {
+        ps.iter()
+            .zip(y)
+            .filter(|(p, _)| subclass_of(**p, &breaks) == k)
+            .map(|(_, y)| *y)
+            .collect()
+    };
+    let variance_of_mean = |y: &[f64]| match y.len() {
+        0 | 1 => 0.0,
+        n => mean_variance(y).1 / n as f64
+    };
+    let mut strata = Vec::with_capacity(n_strata);
+    let (mut att, mut att_variance, mut dropped_treat) = (0.0, 0.0, 0);
+    for k in 0..n_strata {
+        let (y_treat, y_control) = (in_stratum(&treat_ps, &treat_y, k), in_stratum(&pool_ps, &pool_y, k));
+        let difference = match (y_treat.is_empty(), y_control.is_empty()) {
+            (false, false) => mean_variance(&y_treat).0 - mean_variance(&y_control).0,
+            _ => f64::NAN
+        };
+        match difference.is_nan() {
+            true => dropped_treat += y_treat.len(),
+            false => {
+                let weight = y_treat.len() as f64;
+                att += weight * difference;
+                att_variance += weight * weight * (variance_of_mean(&y_treat) + variance_of_mean(&y_control));
+            }
+        }
+        strata.push(Stratum {
+            lower: if k == 0 { f64::NEG_INFINITY } else { breaks[k - 1] },
+            upper: if k == n_strata - 1 { f64::INFINITY } else { breaks[k] },
+            n_treat: y_treat.len(),
+            n_control: y_control.len(),
+            difference
+        });
+    }
+    let n_compared = (treat_ps.len() - dropped_treat) as f64;
+    Ok(SubclassAtt {
+        att: att / n_compared,
+        att_variance: att_variance / (n_compared * n_compared),
+        strata,
+        dropped_treat
+    })
+}
+
+// Implement `Display` for `SubclassAtt`.
+impl fmt::Display for SubclassAtt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let se = self.att_variance.sqrt();
+        write!(
+            f,
+            "Subclassification ({} propensity score strata)\n\
+            ATT                     : {}\n\
+            Variance                : {}\n\
+            95% Confidence Interval : ({}, {})\n",
+            self.strata.len(),
+            num(self.att),
+            num(self.att_variance),
+            num(self.att - 1.96 * se),
+            num(self.att + 1.96 * se)
+        )?;
+        writeln!(
+            f,
+            "{:>8}  {:>18}  {:>7}  {:>9}  {:>10}",
+            "Stratum", "PS range", "# Treat", "# Control", "Difference"
+        )?;
+        for (k, stratum) in self.strata.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>8}  {:>18}  {:>7}  {:>9}  {:>10}",
+                k + 1,
+                format!("({}, {}]", num(stratum.lower), num(stratum.upper)),
+                stratum.n_treat,
+                stratum.n_control,
+                num(stratum.difference)
+            )?;
+        }
+        if self.dropped_treat > 0 {
+            writeln!(f, "{} treated units in strata without controls were left out", self.dropped_treat)?;
+        }
+        Ok(())
+    }
+}
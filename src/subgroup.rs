@@ -0,0 +1,185 @@
+use polars::prelude::{BooleanChunked, DataFrame, NamedFrom, PolarsResult};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::att::calculate_subset_att;
+use crate::cli::Args;
+use crate::data::column_values;
+use crate::estimate::{matches, n_treat_control};
+use crate::format::num;
+
+// Most distinct values a `--by` column may have.
+pub const MAX_SUBGROUPS: usize = 50;
+
+// How the units of each subgroup are matched
+//
+// `Pooled` keeps the matches of the full sample, made on a propensity model
+// fit to everyone (with the subgroup column among its covariates), and
+// splits the pairs by the subgroup of the treated unit. `Within` fits the
+// propensity model and matches separately inside every subgroup, so that
+// treated units are only ever matched to controls of their own subgroup.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SubgroupMatching {
+    #[default]
+    Pooled,
+    Within
+}
+
+// Implement `FromStr` for `SubgroupMatching`.
+impl FromStr for SubgroupMatching {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pooled" => Ok(SubgroupMatching::Pooled),
+            "within" => Ok(SubgroupMatching::Within),
+            _ => Err(format!("Unknown subgroup matching `{}`; use pooled or within", s))
+        }
+    }
+}
+
+// Implement `Display` for `SubgroupMatching`.
+impl fmt::Display for SubgroupMatching {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubgroupMatching::Pooled => write!(f, "pooled"),
+            SubgroupMatching::Within => write!(f, "within")
+        }
+    }
+}
+
+// The ATT within one level of the subgroup column.
+#[derive(Debug)]
+pub struct SubgroupEstimate {
+    pub level: f64,
+    pub n_treat: i64,
+    pub n_control: i64,
+    pub att: f64,
+    pub att_variance: f64
+}
+
+// ATTs by subgroup
+//
+// One estimate per level of `column`, in increasing order of the level.
+// Subgroups with fewer than two distinct treated or matched control units
+// have no estimate and report NaN.
+#[derive(Debug)]
+pub struct Subgroups {
+    pub column: String,
+    pub matching: SubgroupMatching,
+    pub estimates: Vec<SubgroupEstimate>
+}
+
+// Distinct values of `column`, in increasing order
+//
+// Missing values are left out. Columns with more than `MAX_SUBGROUPS`
+// distinct values are rejected, as they are unlikely to be categorical.
+fn levels(data: &DataFrame, column: &str) -> Result<Vec<f64>, Box<dyn Error>> {
+    let mut levels: Vec<f64> = column_values(data, column)?.into_iter().filter(|v| !v.is_nan()).collect();
+    levels.sort_by(|a, b| a.total_cmp(b));
+    levels.dedup();
+    if levels.len() > MAX_SUBGROUPS {
+        return Err(format!(
+            "`--by {}` has {} distinct values; subgroups need a categorical column with at most {}",
+            column,
+            levels.len(),
+            MAX_SUBGROUPS
+        ).into());
+    }
+    Ok(levels)
+}
+
+// Rows of `data` whose `column` equals `level`.
+fn in_level(data: &DataFrame, column: &str, level: f64) -> PolarsResult<Vec<bool>> {
+    Ok(column_values(data, column)?.into_iter().map(|v| v == level).collect())
+}
+
+// Split the matched pairs by the subgroup of the treated unit
+//
+// Every pair keeps the match it got in the full sample; the ATT and
+// variance of a subgroup are those of its pairs, as in
+// `calculate_subset_att`.
+pub fn pooled_subgroups(
+    data: &DataFrame,
+    treat: &DataFrame,
+    control: &DataFrame,
+    column: &str,
+    args: &Args
+) -> Result<Subgroups, Box<dyn Error>> {
+    let mut estimates = Vec::new();
+    for level in levels(data, column)? {
+        let keep = in_level(treat, column, level)?;
+        let mask = BooleanChunked::new("subgroup", keep.as_slice());
+        let (n_treat, n_control) = n_treat_control(&treat.filter(&mask)?, &control.filter(&mask)?)?;
+        let (att, att_variance) = match n_treat < 2 || n_control < 2 {
+            true => (f64::NAN, f64::NAN),
+            false => calculate_subset_att(treat, control, &keep, &args.outcome, &args.treatment, &args.cancel)?
+        };
+        estimates.push(SubgroupEstimate { level, n_treat, n_control, att, att_variance });
+    }
+    Ok(Subgroups { column: column.to_string(), matching: SubgroupMatching::Pooled, estimates })
+}
+
+// Run the whole analysis separately within every subgroup
+//
+// The subgroup column is constant within a subgroup, so it is dropped
+// before the propensity model is fit. Subgroups without two treated and
+// two control units are not matched and report their unit counts. Stops
+// with an error if the run is cancelled.
+pub fn within_subgroups(data: &DataFrame, column: &str, args: &Args) -> Result<Subgroups, Box<dyn Error>> {
+    let args = Args { by: None, ..args.clone() };
+    let mut estimates = Vec::new();
+    for level in levels(data, column)? {
+        args.cancel.check(|| format!("while matching subgroup {}={}", column, level))?;
+        let mask = BooleanChunked::new("subgroup", in_level(data, column, level)?.as_slice());
+        let subset = data.filter(&mask)?.drop(column)?;
+        let treated = column_values(&subset, &args.treatment)?.iter().filter(|d| **d == 1.0).count();
+        if treated < 2 || subset.height() - treated < 2 {
+            let (n_treat, n_control) = (treated as i64, (subset.height() - treated) as i64);
+            estimates.push(SubgroupEstimate { level, n_treat, n_control, att: f64::NAN, att_variance: f64::NAN });
+            continue;
+        }
+        let strike = matches(&subset, &args).map_err(|e| format!("Subgroup {}={}: {}", column, level, e))?;
+        let (n_treat, n_control) = n_treat_control(&strike.treat, &strike.control)?;
+        estimates.push(SubgroupEstimate {
+            level,
+            n_treat,
+            n_control,
+            att: strike.att,
+            att_variance: strike.att_variance
+        });
+    }
+    Ok(Subgroups { column: column.to_string(), matching: SubgroupMatching::Within, estimates })
+}
+
+// Implement `Display` for `Subgroups`.
+impl fmt::Display for Subgroups {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let matching = match self.matching {
+            SubgroupMatching::Pooled => "matched on the pooled propensity score",
+            SubgroupMatching::Within => "matched within subgroups"
+        };
+        writeln!(f, "Subgroups by {} ({})", self.column, matching)?;
+        writeln!(
+            f,
+            "{:>12}  {:>7}  {:>9}  {:>8}  {:>8}  {:>20}",
+            "Level", "# Treat", "# Control", "ATT", "Variance", "95% CI"
+        )?;
+        for s in &self.estimates {
+            let se = s.att_variance.sqrt();
+            let ci = format!("({}, {})", num(s.att - 1.96 * se), num(s.att + 1.96 * se));
+            writeln!(
+                f,
+                "{:>12}  {:>7}  {:>9}  {:>8}  {:>8}  {:>20}",
+                s.level,
+                s.n_treat,
+                s.n_control,
+                num(s.att),
+                num(s.att_variance),
+                ci
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use ndarray::{Array1, Array2, Axis};
+use polars::datatypes::DataType::{Float64, Utf8};
+use polars::prelude::{DataFrame, PolarsResult};
+use std::collections::BTreeMap;
+
+use crate::att::pair_differences;
+use crate::data::column_values;
+use crate::firth::MAX_STEP;
+use crate::propensity::LogitOptions;
+use crate::regression::generalized_inverse;
+
+// Survey design columns
+//
+// Names of the sampling weight, stratum and primary sampling unit (PSU)
+// columns. Any of them may be missing: no weights means equal weights, no
+// strata means a single stratum, and no PSU means every observation is its
+// own PSU.
+#[derive(Clone, Debug, Default)]
+pub struct SurveyDesign {
+    pub weights: Option<String>,
+    pub strata: Option<String>,
+    pub psu: Option<String>
+}
+
+impl SurveyDesign {
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_none() && self.strata.is_none() && self.psu.is_none()
+    }
+}
+
+// A survey-weighted ATT with its design-based variance.
+#[derive(Debug)]
+pub struct SurveyAtt {
+    pub att: f64,
+    pub att_variance: f64,
+    pub n_strata: usize,
+    pub n_psu: usize
+}
+
+// Pull a column out as strings so that any id type can be used to group.
+fn design_ids(data: &DataFrame, column: &Option<String>) -> PolarsResult<Option<Vec<String>>> {
+    match column {
+        Some(c) => {
+            let ids = data.column(c)?.cast(&Utf8)?;
+            let ids = ids
+                .utf8()?
+                .into_iter()
+                .map(|id| id.unwrap_or("").to_string())
+                .collect();
+            Ok(Some(ids))
+        },
+        None => Ok(None)
+    }
+}
+
+// Calculate the survey-weighted ATT and its design-based variance
+//
+// The ATT is the weighted mean of the matched-pair differences using the
+// treated units' sampling weights. Its variance is estimated by Taylor
+// linearization: each pair contributes z_i = w_i * (d_i - ATT) / sum(w),
+// the z_i are totalled within PSUs, and the variance is the sum over strata
+// of n_h / (n_h - 1) times the sum of squared deviations of the PSU totals
+// from their stratum mean. Strata with a single PSU contribute nothing.
+// The design information is taken from the treated units, so the variance
+// reflects the sampling of treated units only.
+pub fn calculate_survey_att(
+    treat: &DataFrame,
+    control: &DataFrame,
+    outcome: &str,
+    design: &SurveyDesign
+) -> PolarsResult<SurveyAtt> {
+    let y_diff = pair_differences(treat, control, outcome)?;
+    let weights: Vec<f64> = match &design.weights {
+        Some(w) => treat
+            .column(w)?
+            .cast(&Float64)?
+            .f64()?
+            .into_iter()
+            .map(|w| w.unwrap_or(0.0))
+            .collect(),
+        None => vec![1.0; y_diff.len()]
+    };
+    let strata = design_ids(treat, &design.strata)?;
+    let psu = design_ids(treat, &design.psu)?;
+    let w_total: f64 = weights.iter().sum();
+    let att = weights.iter().zip(&y_diff).map(|(w, d)| w * d).sum::<f64>() / w_total;
+
+    // Total the linearized contributions within each PSU of each stratum
+    let mut totals: BTreeMap<&str, BTreeMap<String, f64>> = BTreeMap::new();
+    for (i, (w, d)) in weights.iter().zip(&y_diff).enumerate() {
+        let stratum = strata.as_ref().map_or("", |s| s[i].as_str());
+        let unit = psu.as_ref().map_or(i.to_string(), |p| p[i].clone());
+        *totals.entry(stratum).or_default().entry(unit).or_insert(0.0) += w * (d - att) / w_total;
+    }
+    let mut att_variance = 0.0;
+    for units in totals.values() {
+        let n_h = units.len() as f64;
+        if n_h < 2.0 {
+            continue;
+        }
+        let mean_h = units.values().sum::<f64>() / n_h;
+        let ss_h: f64 = units.values().map(|z| (z - mean_h).powi(2)).sum();
+        att_variance += n_h / (n_h - 1.0) * ss_h;
+    }
+    Ok(SurveyAtt {
+        att,
+        att_variance,
+        n_strata: totals.len(),
+        n_psu: totals.values().map(|units| units.len()).sum()
+    })
+}
+
+// Sampling weights of the rows of `data` for fitting a model
+//
+// The values of `column`, scaled to a mean of 1, which leaves every
+// weighted fit unchanged but keeps mean-based stopping rules on the scale
+// of the unweighted fit. Missing, negative or all-zero weights are an
+// error.
+pub fn fit_weights(data: &DataFrame, column: &str) -> Result<Array1<f64>, String> {
+    let weights = column_values(data, column).map_err(|e| format!("Survey weights `{}`: {}", column, e))?;
+    if weights.iter().any(|w| !(*w >= 0.0 && w.is_finite())) {
+        return Err(format!("The survey weights in `{}` must be non-negative and not missing", column));
+    }
+    let mean = weights.iter().sum::<f64>() / weights.len() as f64;
+    if mean <= 0.0 {
+        return Err(format!("The survey weights in `{}` are all zero", column));
+    }
+    Ok(weights.into_iter().map(|w| w / mean).collect())
+}
+
+fn sigmoid(eta: f64) -> f64 {
+    1.0 / (1.0 + (-eta).exp())
+}
+
+// Survey-weighted logistic regression
+//
+// Maximizes the weighted log-likelihood sum w [d log p + (1 - d) log(1 - p)],
+// the pseudo-likelihood of R's `svyglm`, so that the propensity model
+// describes the population the sample was drawn from rather than the
+// sample itself. Fit by Newton-Raphson on standardized predictors;
+// constant predictors and predictors aliased with earlier ones get a slope
+// of zero.
+#[derive(Debug)]
+pub struct WeightedLogit {
+    mean: Vec<f64>,
+    sd: Vec<f64>,
+    beta: Array1<f64>
+}
+
+impl WeightedLogit {
+    // The design matrix: an intercept and the standardized predictors.
+    fn design(&self, x: &Array2<f64>) -> Array2<f64> {
+        let mut z = Array2::<f64>::ones((x.nrows(), x.ncols() + 1));
+        for (k, column) in x.axis_iter(Axis(1)).enumerate() {
+            let column = match self.sd[k] > 0.0 {
+                true => column.mapv(|v| (v - self.mean[k]) / self.sd[k]),
+                false => Array1::zeros(x.nrows())
+            };
+            z.column_mut(k + 1).assign(&column);
+        }
+        z
+    }
+
+    // Fit the model to predictors `x` (named `names`), binary response `d`
+    // and weights `w`
+    //
+    // Stops once the largest component of the weighted score
+    // Z'w(d - p) / n falls below the tolerance. Returns the model, the
+    // weighted residuals w(d - p) at the estimates and the number of
+    // iterations.
+    pub fn fit(
+        x: &Array2<f64>,
+        d: &Array1<i64>,
+        w: &Array1<f64>,
+        names: &[&str],
+        options: &LogitOptions
+    ) -> (WeightedLogit, Array1<f64>, usize) {
+        let n = x.nrows();
+        let y = d.mapv(|d| d as f64);
+        let (mean, sd): (Vec<f64>, Vec<f64>) = x
+            .axis_iter(Axis(1))
+            .map(|column| {
+                let (mean, sd) = (column.mean().unwrap_or(0.0), column.std(1.0));
+                (mean, if sd.is_nan() { 0.0 } else { sd })
+            })
+            .unzip();
+        let mut model = WeightedLogit { mean, sd, beta: Array1::zeros(x.ncols() + 1) };
+        let z = model.design(x);
+        // Starting values on the standardized scale
+        model.beta[0] = options.initial("intercept");
+        for (k, name) in names.iter().enumerate().filter(|(k, _)| model.sd[*k] > 0.0) {
+            let b = options.initial(name);
+            model.beta[0] += b * model.mean[k];
+            model.beta[k + 1] = b * model.sd[k];
+        }
+        let mut iterations = 0;
+        let residuals = loop {
+            let p = z.dot(&model.beta).mapv(sigmoid);
+            let residuals = w * &(&y - &p);
+            let score = z.t().dot(&residuals);
+            let largest_score = score.iter().fold(0.0_f64, |m, s| m.max(s.abs())) / n as f64;
+            if largest_score < options.tolerance || iterations as u64 >= options.max_iterations {
+                break residuals;
+            }
+            let v = w * &p.mapv(|p| p * (1.0 - p));
+            let zv = &z * &v.view().insert_axis(Axis(1));
+            let (inverse, _) = generalized_inverse(&z.t().dot(&zv));
+            let mut step = inverse.dot(&score);
+            let largest = step.iter().fold(0.0_f64, |m, s| m.max(s.abs()));
+            if largest > MAX_STEP {
+                step *= MAX_STEP / largest;
+            }
+            model.beta += &step;
+            iterations += 1;
+        };
+        (model, residuals, iterations)
+    }
+
+    // Intercept and slopes on the scale of the original predictors.
+    pub fn coefficients(&self) -> (f64, Vec<f64>) {
+        let mut intercept = self.beta[0];
+        let mut slopes = vec![0.0; self.sd.len()];
+        for (k, slope) in slopes.iter_mut().enumerate() {
+            if self.sd[k] > 0.0 {
+                *slope = self.beta[k + 1] / self.sd[k];
+                intercept -= *slope * self.mean[k];
+            }
+        }
+        (intercept, slopes)
+    }
+
+    // Predicted probabilities for the rows of `x`.
+    pub fn predict_probabilities(&self, x: &Array2<f64>) -> Array1<f64> {
+        self.design(x).dot(&self.beta).mapv(sigmoid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::df;
+    use polars::prelude::NamedFrom;
+
+    // Four pairs with differences 1, 3, 2 and 6.
+    fn pairs() -> (DataFrame, DataFrame) {
+        let treat = df!(
+            "y" => [1.0, 4.0, 2.0, 7.0],
+            "w" => [1.0, 1.0, 2.0, 2.0],
+            "stratum" => ["a", "a", "b", "b"],
+            "psu" => [1i64, 1, 2, 2]
+        ).unwrap();
+        let control = df!("y" => [0.0, 1.0, 0.0, 1.0]).unwrap();
+        (treat, control)
+    }
+
+    fn survey_att(design: SurveyDesign) -> SurveyAtt {
+        let (treat, control) = pairs();
+        calculate_survey_att(&treat, &control, "y", &design).unwrap()
+    }
+
+    #[test]
+    fn no_design_is_the_sample_mean() {
+        // With every pair its own PSU the variance is s^2 / n = (14 / 3) / 4
+        let survey = survey_att(SurveyDesign::default());
+        assert_eq!(survey.att, 3.0);
+        assert!((survey.att_variance - 7.0 / 6.0).abs() < 1e-12);
+        assert_eq!((survey.n_strata, survey.n_psu), (1, 4));
+    }
+
+    #[test]
+    fn weights_and_strata() {
+        // ATT = (1 + 3 + 2 * 2 + 2 * 6) / 6. The linearized contributions
+        // are -7, -1 (stratum a) and -8, 16 (stratum b) over 18, so the
+        // variance is 2 * (2 * 3^2 + 2 * 12^2) / 18^2.
+        let survey = survey_att(SurveyDesign {
+            weights: Some("w".to_string()),
+            strata: Some("stratum".to_string()),
+            psu: None
+        });
+        assert!((survey.att - 10.0 / 3.0).abs() < 1e-12);
+        assert!((survey.att_variance - 17.0 / 9.0).abs() < 1e-12);
+        assert_eq!((survey.n_strata, survey.n_psu), (2, 4));
+    }
+
+    #[test]
+    fn clusters_are_totalled() {
+        // PSU totals (-2 + 0) / 4 and (-1 + 3) / 4 around their mean of 0
+        let survey = survey_att(SurveyDesign { psu: Some("psu".to_string()), ..Default::default() });
+        assert!((survey.att_variance - 1.0).abs() < 1e-12);
+        assert_eq!(survey.n_psu, 2);
+    }
+
+    #[test]
+    fn single_psu_strata_add_nothing() {
+        let survey = survey_att(SurveyDesign {
+            weights: None,
+            strata: Some("stratum".to_string()),
+            psu: Some("psu".to_string())
+        });
+        assert_eq!(survey.att_variance, 0.0);
+        assert_eq!((survey.n_strata, survey.n_psu), (2, 2));
+    }
+}
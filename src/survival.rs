@@ -0,0 +1,191 @@
+use polars::prelude::DataFrame;
+use std::error::Error;
+use std::fmt;
+
+use crate::att::match_weights;
+use crate::data::column_values;
+use crate::format::num;
+use crate::paired::{normal_cdf, p_value};
+
+// Kaplan-Meier survival of both groups at a horizon and their difference.
+#[derive(Debug)]
+pub struct SurvivalAtHorizon {
+    pub horizon: f64,
+    pub treat: f64,
+    pub control: f64,
+    pub difference: f64,
+    pub variance: f64
+}
+
+// Time-to-event contrast of the matched samples
+//
+// The log-rank test compares the hazards of the treated units and their
+// matched controls; `log_hazard_ratio` is its one-step (Peto) estimate,
+// (observed - expected treated events) / variance, with variance
+// 1 / variance. With a horizon the Kaplan-Meier survival of both groups
+// is compared there as well.
+#[derive(Debug)]
+pub struct SurvivalContrast {
+    pub event: String,
+    pub z: f64,
+    pub p_value: f64,
+    pub log_hazard_ratio: f64,
+    pub log_hazard_ratio_variance: f64,
+    pub horizon: Option<SurvivalAtHorizon>
+}
+
+impl SurvivalContrast {
+    // Average two independent contrasts, as in a cross-fit
+    //
+    // The log hazard ratios and the survival at the horizon are averaged,
+    // their variances added and divided by 4. The test of the average is
+    // the Wald test of its log hazard ratio, which is what the log-rank
+    // test of a single sample amounts to.
+    pub fn combine(self, other: SurvivalContrast) -> SurvivalContrast {
+        let log_hazard_ratio = (self.log_hazard_ratio + other.log_hazard_ratio) / 2.0;
+        let log_hazard_ratio_variance = (self.log_hazard_ratio_variance + other.log_hazard_ratio_variance) / 4.0;
+        let z = log_hazard_ratio / log_hazard_ratio_variance.sqrt();
+        SurvivalContrast {
+            event: self.event,
+            z,
+            p_value: 2.0 * (1.0 - normal_cdf(z.abs())),
+            log_hazard_ratio,
+            log_hazard_ratio_variance,
+            horizon: self.horizon.zip(other.horizon).map(|(a, b)| SurvivalAtHorizon {
+                horizon: a.horizon,
+                treat: (a.treat + b.treat) / 2.0,
+                control: (a.control + b.control) / 2.0,
+                difference: (a.difference + b.difference) / 2.0,
+                variance: (a.variance + b.variance) / 4.0
+            })
+        }
+    }
+}
+
+// Check the time and event columns of a survival outcome
+//
+// Times must be non-negative and complete, and the event indicator 0/1
+// (1 for an event, 0 for censoring) and complete.
+pub fn validate_survival(data: &DataFrame, time: &str, event: &str) -> Result<(), Box<dyn Error>> {
+    if column_values(data, time)?.iter().any(|t| t.is_nan() || *t < 0.0) {
+        return Err(format!("The time column `{}` must be non-negative without missing values", time).into());
+    }
+    let events = column_values(data, event).map_err(|e| format!("The event column `{}`: {}", event, e))?;
+    if events.iter().any(|e| *e != 0.0 && *e != 1.0) {
+        return Err(format!("The event column `{}` must be 0/1 (1 for an event) without missing values", event).into());
+    }
+    Ok(())
+}
+
+// Calculate the survival contrast of the matched samples
+//
+// Every row of `treat` and `control` is weighted as in `calculate_att`, so
+// a treated unit counts once and its k matches a total of once. At every
+// distinct event time the weighted events and numbers at risk of both
+// groups enter the log-rank statistic and the Kaplan-Meier curves, whose
+// variance is Greenwood's. Controls matched to several treated units
+// count once per use and the pairs are treated as independent, so the
+// intervals ignore control reuse.
+pub fn survival_contrast(
+    treat: &DataFrame,
+    control: &DataFrame,
+    time: &str,
+    event: &str,
+    horizon: Option<f64>
+) -> Result<SurvivalContrast, Box<dyn Error>> {
+    let (weights, _) = match_weights(treat)?;
+    let mut rows: Vec<(f64, f64, usize, f64)> = Vec::with_capacity(2 * weights.len());
+    for (group, data) in [(1, treat), (0, control)] {
+        let times = column_values(data, time)?;
+        let events = column_values(data, event)?;
+        for ((t, e), w) in times.into_iter().zip(events).zip(&weights) {
+            rows.push((t, e, group, *w));
+        }
+    }
+    rows.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut at_risk = [0.0; 2];
+    for (_, _, group, w) in &rows {
+        at_risk[*group] += w;
+    }
+    let (mut observed_minus_expected, mut variance) = (0.0, 0.0);
+    let (mut survival, mut greenwood) = ([1.0; 2], [0.0; 2]);
+    let mut start = 0;
+    while start < rows.len() {
+        let t = rows[start].0;
+        let end = (start..rows.len()).find(|i| rows[*i].0 != t).unwrap_or(rows.len());
+        let mut events = [0.0; 2];
+        for (_, e, group, w) in &rows[start..end] {
+            events[*group] += e * w;
+        }
+        let (d, r) = (events[0] + events[1], at_risk[0] + at_risk[1]);
+        if d > 0.0 && r > 0.0 {
+            observed_minus_expected += events[1] - d * at_risk[1] / r;
+            variance += d * at_risk[1] * at_risk[0] / (r * r);
+            if horizon.is_some_and(|h| t <= h) {
+                for g in 0..2 {
+                    if at_risk[g] > 0.0 {
+                        survival[g] *= 1.0 - events[g] / at_risk[g];
+                    }
+                    if at_risk[g] > events[g] {
+                        greenwood[g] += events[g] / (at_risk[g] * (at_risk[g] - events[g]));
+                    }
+                }
+            }
+        }
+        for (_, _, group, w) in &rows[start..end] {
+            at_risk[*group] -= w;
+        }
+        start = end;
+    }
+    let z = observed_minus_expected / variance.sqrt();
+    Ok(SurvivalContrast {
+        event: event.to_string(),
+        z,
+        p_value: 2.0 * (1.0 - normal_cdf(z.abs())),
+        log_hazard_ratio: observed_minus_expected / variance,
+        log_hazard_ratio_variance: 1.0 / variance,
+        horizon: horizon.map(|horizon| SurvivalAtHorizon {
+            horizon,
+            treat: survival[1],
+            control: survival[0],
+            difference: survival[1] - survival[0],
+            variance: survival[1].powi(2) * greenwood[1] + survival[0].powi(2) * greenwood[0]
+        })
+    })
+}
+
+// Implement `Display` for `SurvivalContrast`.
+impl fmt::Display for SurvivalContrast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let se = self.log_hazard_ratio_variance.sqrt();
+        write!(
+            f,
+            "Survival outcome (event: {})\n\
+            Log-rank test           : z = {}, p {}\n\
+            Hazard ratio            : {}\n\
+            95% Confidence Interval : ({}, {})\n",
+            self.event,
+            num(self.z),
+            p_value(self.p_value),
+            num(self.log_hazard_ratio.exp()),
+            num((self.log_hazard_ratio - 1.96 * se).exp()),
+            num((self.log_hazard_ratio + 1.96 * se).exp())
+        )?;
+        if let Some(h) = &self.horizon {
+            let se = h.variance.sqrt();
+            write!(
+                f,
+                "{:<24}: {} | {}\n\
+                Survival difference     : {}\n\
+                95% Confidence Interval : ({}, {})\n",
+                format!("Survival at {} (T | C)", h.horizon),
+                num(h.treat),
+                num(h.control),
+                num(h.difference),
+                num(h.difference - 1.96 * se),
+                num(h.difference + 1.96 * se)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use polars::prelude::{DataFrame, DataType, PolarsError, PolarsResult, Series, NamedFrom};
+use std::fmt;
+use std::str::FromStr;
+
+// Outcome transformations
+//
+// Skewed outcomes (costs, counts, utilization) are usually better analysed on
+// the log scale. The ATT is then a difference in mean logs, which
+// back-transforms to a ratio of geometric means. `Log1p` uses log(1 + y) so
+// that outcomes with zeros can still be analysed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutcomeTransform {
+    Log,
+    Log1p
+}
+
+impl FromStr for OutcomeTransform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(OutcomeTransform::Log),
+            "log1p" => Ok(OutcomeTransform::Log1p),
+            _ => Err(format!("Unknown outcome transform `{}`; expected `log` or `log1p`", s))
+        }
+    }
+}
+
+impl fmt::Display for OutcomeTransform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutcomeTransform::Log => write!(f, "log"),
+            OutcomeTransform::Log1p => write!(f, "log1p")
+        }
+    }
+}
+
+impl OutcomeTransform {
+    // Transform a single outcome value, returning `None` when the value is
+    // outside the domain of the transformation.
+    fn apply_value(&self, y: f64) -> Option<f64> {
+        match self {
+            OutcomeTransform::Log if y > 0.0 => Some(y.ln()),
+            OutcomeTransform::Log1p if y > -1.0 => Some(y.ln_1p()),
+            _ => None
+        }
+    }
+
+    // Replace the outcome column of `data` with its transformed values.
+    pub fn apply(&self, data: &mut DataFrame, outcome: &str) -> PolarsResult<()> {
+        let y = data.column(outcome)?.cast(&DataType::Float64)?;
+        let mut transformed: Vec<Option<f64>> = Vec::with_capacity(y.len());
+        for value in y.f64()?.into_iter() {
+            match value {
+                Some(v) => match self.apply_value(v) {
+                    Some(t) => transformed.push(Some(t)),
+                    None => return Err(PolarsError::ComputeError(format!(
+                        "Outcome value {} in `{}` is outside the domain of the {} transform",
+                        v, outcome, self
+                    ).into()))
+                },
+                None => transformed.push(None)
+            }
+        }
+        data.with_column(Series::new(outcome, transformed))?;
+        Ok(())
+    }
+
+    // Back-transform an ATT estimated on the transformed scale
+    //
+    // For both log transforms exp(ATT) is a ratio of geometric means (of y
+    // or 1 + y). Its 95% confidence interval uses the delta method, i.e.
+    // Var(exp(ATT)) ~= exp(ATT)^2 * Var(ATT). Returns the ratio and the
+    // lower and upper confidence limits.
+    pub fn back_transform(&self, att: f64, att_variance: f64) -> (f64, f64, f64) {
+        let ratio = att.exp();
+        let se = ratio * att_variance.sqrt();
+        (ratio, ratio - 1.96 * se, ratio + 1.96 * se)
+    }
+}
+
+// Replace the outcome column of `data` with its change since `pre`
+//
+// Used for difference-in-differences: with the post-period outcome minus
+// the pre-period outcome as the outcome, the matched difference of the
+// changes, and its variance, follow from the usual estimators.
+pub fn difference_outcome(data: &mut DataFrame, outcome: &str, pre: &str) -> PolarsResult<()> {
+    let post = data.column(outcome)?.cast(&DataType::Float64)?;
+    let before = data.column(pre)?.cast(&DataType::Float64)?;
+    let mut change = &post - &before;
+    change.rename(outcome);
+    data.with_column(change)?;
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use ndarray::{Array1, Array2, ArrayView1, Axis};
+use rand::rngs::StdRng;
+use rand::seq::index::sample;
+use rand::Rng;
+
+use crate::propensity::LogitOptions;
+use crate::split::rng;
+
+// Boosting rounds of `--ps-model gbm` unless `--ps-trees` says otherwise.
+pub const GBM_TREES: usize = 100;
+
+// Depth of every boosted tree unless `--ps-depth` says otherwise.
+pub const GBM_DEPTH: usize = 3;
+
+// Shrinkage of every boosted tree's contribution.
+pub const GBM_RATE: f64 = 0.1;
+
+// Trees of `--ps-model rf` unless `--ps-trees` says otherwise.
+pub const FOREST_TREES: usize = 200;
+
+// Depth of every tree of the forest unless `--ps-depth` says otherwise.
+pub const FOREST_DEPTH: usize = 10;
+
+// Fewest units in a leaf of any tree.
+pub const MIN_LEAF: usize = 10;
+
+#[derive(Debug)]
+enum Node {
+    Split { feature: usize, threshold: f64, left: usize, right: usize },
+    Leaf(f64)
+}
+
+// A regression tree
+//
+// Nodes are stored in a flat vector with the root first; a split sends
+// rows whose feature is at most the threshold to the left.
+#[derive(Debug)]
+pub struct Tree {
+    nodes: Vec<Node>
+}
+
+// The best split of a node found so far: gain, feature and threshold.
+type Candidate = Option<(f64, usize, f64)>;
+
+impl Tree {
+    // Grow a tree on gradients `g` and Hessians `h`
+    //
+    // Splits maximize the second-order gain G_L^2 / H_L + G_R^2 / H_R -
+    // G^2 / H over all thresholds between distinct values of every feature,
+    // and leaves predict G / H. `sorted` holds, for every feature, the row
+    // indices ordered by that feature, and `counts` how often every row is
+    // in the sample (the caller scales `g` and `h` by it). The tree is grown
+    // level by level, one pass over every feature's order per level, up to
+    // `depth` levels and without leaves of fewer than `MIN_LEAF` rows. With
+    // `features` every node only considers that many features, drawn at
+    // random.
+    pub fn grow(
+        x: &Array2<f64>,
+        sorted: &[Vec<usize>],
+        g: &[f64],
+        h: &[f64],
+        counts: &[usize],
+        depth: usize,
+        mut features: Option<(usize, &mut StdRng)>
+    ) -> Tree {
+        let n = g.len();
+        let mut tree = Tree { nodes: vec![Node::Leaf(0.0)] };
+        let mut node_of = vec![0usize; n];
+        let mut active = vec![0usize];
+        for level in 0..=depth {
+            // Totals of every active node: gradient, Hessian and rows
+            let mut totals = vec![(0.0, 0.0, 0usize); tree.nodes.len()];
+            for i in 0..n {
+                let t = &mut totals[node_of[i]];
+                t.0 += g[i];
+                t.1 += h[i];
+                t.2 += counts[i];
+            }
+            // The features every node may split on
+            let mut allowed = vec![vec![true; sorted.len()]; tree.nodes.len()];
+            if let Some((m, rng)) = features.as_mut() {
+                for node in &active {
+                    allowed[*node] = vec![false; sorted.len()];
+                    for feature in sample(*rng, sorted.len(), (*m).min(sorted.len())) {
+                        allowed[*node][feature] = true;
+                    }
+                }
+            }
+            let mut best: Vec<Candidate> = vec![None; tree.nodes.len()];
+            if level < depth {
+                for (feature, order) in sorted.iter().enumerate() {
+                    let mut left = vec![(0.0, 0.0, 0usize, f64::NAN); tree.nodes.len()];
+                    for i in order.iter().filter(|i| counts[**i] > 0) {
+                        let node = node_of[*i];
+                        if !allowed[node][feature] {
+                            continue;
+                        }
+                        let value = x[[*i, feature]];
+                        let (gl, hl, cl, last) = left[node];
+                        let (gt, ht, ct) = totals[node];
+                        if cl >= MIN_LEAF && ct - cl >= MIN_LEAF && value > last && hl > 0.0 && ht - hl > 0.0 {
+                            let (gr, hr) = (gt - gl, ht - hl);
+                            let gain = gl * gl / hl + gr * gr / hr - gt * gt / ht;
+                            if best[node].map_or(gain > 0.0, |(b, _, _)| gain > b) {
+                                best[node] = Some((gain, feature, (last + value) / 2.0));
+                            }
+                        }
+                        left[node] = (gl + g[*i], hl + h[*i], cl + counts[*i], value);
+                    }
+                }
+            }
+            let mut next = Vec::new();
+            for node in active {
+                let (gt, ht, _) = totals[node];
+                match best[node] {
+                    Some((_, feature, threshold)) => {
+                        let (left, right) = (tree.nodes.len(), tree.nodes.len() + 1);
+                        tree.nodes.push(Node::Leaf(0.0));
+                        tree.nodes.push(Node::Leaf(0.0));
+                        tree.nodes[node] = Node::Split { feature, threshold, left, right };
+                        next.extend([left, right]);
+                    },
+                    None => tree.nodes[node] = Node::Leaf(if ht > 0.0 { gt / ht } else { 0.0 })
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            for (i, node) in node_of.iter_mut().enumerate() {
+                if let Node::Split { feature, threshold, left, right } = tree.nodes[*node] {
+                    *node = if x[[i, feature]] <= threshold { left } else { right };
+                }
+            }
+            active = next;
+        }
+        tree
+    }
+
+    // Prediction for one row.
+    pub fn predict(&self, row: ArrayView1<f64>) -> f64 {
+        let mut node = 0;
+        loop {
+            match self.nodes[node] {
+                Node::Split { feature, threshold, left, right } => {
+                    node = if row[feature] <= threshold { left } else { right };
+                },
+                Node::Leaf(value) => return value
+            }
+        }
+    }
+}
+
+// Row indices ordered by every column of `x`.
+pub fn sort_columns(x: &Array2<f64>) -> Vec<Vec<usize>> {
+    x.axis_iter(Axis(1))
+        .map(|column| {
+            let mut order: Vec<usize> = (0..column.len()).collect();
+            order.sort_by(|a, b| column[*a].total_cmp(&column[*b]));
+            order
+        })
+        .collect()
+}
+
+fn sigmoid(eta: f64) -> f64 {
+    1.0 / (1.0 + (-eta).exp())
+}
+
+// Gradient boosted trees for a binary response
+//
+// Boosts regression trees on the logistic loss (Friedman, 2001) with
+// Newton steps, the approach of xgboost and lightgbm: starting from the
+// log odds of the treated share, every round fits a tree to the gradients
+// d - p and Hessians p (1 - p) of the current fit and adds `GBM_RATE`
+// times its predictions to the log odds. Rows aren't subsampled, so a fit
+// is reproducible. Sampling weights scale every row's gradient and
+// Hessian.
+#[derive(Debug)]
+pub struct Gbm {
+    base: f64,
+    trees: Vec<Tree>
+}
+
+impl Gbm {
+    // Fit the model to predictors `x`, binary response `d` and weights `w`.
+    pub fn fit(x: &Array2<f64>, d: &Array1<i64>, w: &Array1<f64>, options: &LogitOptions) -> Gbm {
+        let y: Vec<f64> = d.iter().map(|d| *d as f64).collect();
+        let share = (w.dot(&Array1::from(y.clone())) / w.sum()).clamp(1e-6, 1.0 - 1e-6);
+        let mut model = Gbm { base: (share / (1.0 - share)).ln(), trees: Vec::new() };
+        let sorted = sort_columns(x);
+        let ones = vec![1; y.len()];
+        let mut eta = vec![model.base; y.len()];
+        for _ in 0..options.trees.unwrap_or(GBM_TREES) {
+            let p: Vec<f64> = eta.iter().map(|e| sigmoid(*e)).collect();
+            let g: Vec<f64> = (0..y.len()).map(|i| w[i] * (y[i] - p[i])).collect();
+            let h: Vec<f64> = (0..y.len()).map(|i| w[i] * p[i] * (1.0 - p[i])).collect();
+            let tree = Tree::grow(x, &sorted, &g, &h, &ones, options.depth.unwrap_or(GBM_DEPTH), None);
+            for (e, row) in eta.iter_mut().zip(x.axis_iter(Axis(0))) {
+                *e += GBM_RATE * tree.predict(row);
+            }
+            model.trees.push(tree);
+        }
+        model
+    }
+
+    // Number of boosting rounds.
+    pub fn rounds(&self) -> usize {
+        self.trees.len()
+    }
+
+    // Predicted probabilities for the rows of `x`.
+    pub fn predict_probabilities(&self, x: &Array2<f64>) -> Array1<f64> {
+        x.axis_iter(Axis(0))
+            .map(|row| sigmoid(self.base + GBM_RATE * self.trees.iter().map(|t| t.predict(row)).sum::<f64>()))
+            .collect()
+    }
+}
+
+// A random forest for a binary response
+//
+// Every tree is grown on a bootstrap sample of the rows, considering the
+// square root of the number of features at every split, and its leaves
+// estimate the (weighted) treated share. The forest's probability is the
+// average of its trees'. A unit's out-of-bag probability averages only the
+// trees whose bootstrap sample left it out, which keeps the scores of the
+// units the forest was fit on from overfitting; a unit that was in every
+// sample falls back to all trees.
+#[derive(Debug)]
+pub struct Forest {
+    trees: Vec<Tree>,
+    out_of_bag: Vec<f64>
+}
+
+impl Forest {
+    // Fit the forest to predictors `x`, binary response `d` and weights `w`,
+    // seeded with `options.seed`.
+    pub fn fit(x: &Array2<f64>, d: &Array1<i64>, w: &Array1<f64>, options: &LogitOptions) -> Forest {
+        let n = d.len();
+        let y: Vec<f64> = d.iter().map(|d| *d as f64).collect();
+        let sorted = sort_columns(x);
+        let m = ((x.ncols() as f64).sqrt().round() as usize).max(1);
+        let mut rng = rng(options.seed);
+        let mut model = Forest { trees: Vec::new(), out_of_bag: vec![f64::NAN; n] };
+        let (mut total, mut uses) = (vec![0.0; n], vec![0usize; n]);
+        for _ in 0..options.trees.unwrap_or(FOREST_TREES) {
+            let mut counts = vec![0usize; n];
+            for _ in 0..n {
+                counts[rng.gen_range(0..n)] += 1;
+            }
+            let h: Vec<f64> = counts.iter().zip(w).map(|(c, w)| *c as f64 * w).collect();
+            let g: Vec<f64> = y.iter().zip(&h).map(|(y, h)| y * h).collect();
+            let depth = options.depth.unwrap_or(FOREST_DEPTH);
+            let tree = Tree::grow(x, &sorted, &g, &h, &counts, depth, Some((m, &mut rng)));
+            for (i, row) in x.axis_iter(Axis(0)).enumerate().filter(|(i, _)| counts[*i] == 0) {
+                total[i] += tree.predict(row);
+                uses[i] += 1;
+            }
+            model.trees.push(tree);
+        }
+        let all = model.predict_probabilities(x);
+        for i in 0..n {
+            model.out_of_bag[i] = if uses[i] > 0 { total[i] / uses[i] as f64 } else { all[i] };
+        }
+        model
+    }
+
+    // Number of trees.
+    pub fn size(&self) -> usize {
+        self.trees.len()
+    }
+
+    // Out-of-bag probabilities of the rows the forest was fit on.
+    pub fn out_of_bag(&self) -> Array1<f64> {
+        Array1::from(self.out_of_bag.clone())
+    }
+
+    // Predicted probabilities for the rows of `x`, averaging every tree.
+    pub fn predict_probabilities(&self, x: &Array2<f64>) -> Array1<f64> {
+        x.axis_iter(Axis(0))
+            .map(|row| self.trees.iter().map(|t| t.predict(row)).sum::<f64>() / self.trees.len() as f64)
+            .collect()
+    }
+}
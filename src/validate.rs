@@ -0,0 +1,145 @@
+use polars::datatypes::DataType::{Boolean, Float64};
+use polars::prelude::DataFrame;
+use std::error::Error;
+
+use crate::data::column_values;
+use crate::propensity::covariate_names;
+
+// Check that the data can be analysed
+//
+// Runs before any modeling so that bad input fails with a message that says
+// what to fix, rather than with an error from deep inside polars or a panic
+// during matching. The treatment column must exist and be binary (0/1 after
+// casting to numbers) with no missing values, the outcome must exist and be
+// numeric, no covariate may be entirely missing, and there must be at least
+// two treated and two control units, since the variance estimator matches
+// every unit to another unit in its own group.
+pub fn validate(data: &DataFrame, treatment: &str, outcome: &str) -> Result<(), Box<dyn Error>> {
+    let names = data.get_column_names();
+    for (role, column) in [("treatment", treatment), ("outcome", outcome)] {
+        if !names.contains(&column) {
+            return Err(format!(
+                "The {} column `{}` is not in the data; available columns are: {}",
+                role,
+                column,
+                names.join(", ")
+            ).into());
+        }
+    }
+    let treat = data.column(treatment)?;
+    let coerced = treat.cast(&Float64).map_err(|_| {
+        format!("The treatment column `{}` must be numeric 0/1 but has type {}", treatment, treat.dtype())
+    })?;
+    let coerced = coerced.f64()?;
+    if coerced.null_count() > treat.null_count() {
+        return Err(format!(
+            "The treatment column `{}` has values that can't be read as 0/1",
+            treatment
+        ).into());
+    }
+    if treat.null_count() > 0 {
+        return Err(format!(
+            "The treatment column `{}` has {} missing values; drop or impute them first",
+            treatment,
+            treat.null_count()
+        ).into());
+    }
+    let other: Vec<f64> = coerced.into_no_null_iter().filter(|v| *v != 0.0 && *v != 1.0).collect();
+    if let Some(value) = other.first() {
+        return Err(format!(
+            "The treatment column `{}` must be binary (0/1) but has {} other values, e.g. {}",
+            treatment,
+            other.len(),
+            value
+        ).into());
+    }
+    let n_treat = coerced.into_no_null_iter().filter(|v| *v == 1.0).count();
+    let n_control = coerced.len() - n_treat;
+    if n_treat < 2 || n_control < 2 {
+        return Err(format!(
+            "The treatment column `{}` needs at least 2 treated (1) and 2 control (0) units but has {} treated and {} control",
+            treatment,
+            n_treat,
+            n_control
+        ).into());
+    }
+    let y = data.column(outcome)?;
+    if !y.dtype().is_numeric() && *y.dtype() != Boolean {
+        return Err(format!(
+            "The outcome column `{}` must be numeric but has type {}",
+            outcome,
+            y.dtype()
+        ).into());
+    }
+    for covariate in covariate_names(data, &[treatment, outcome]) {
+        let column = data.column(&covariate)?;
+        if column.null_count() == column.len() {
+            return Err(format!(
+                "The covariate `{}` is missing for every observation; drop it from the data",
+                covariate
+            ).into());
+        }
+    }
+    Ok(())
+}
+
+// Check a column of propensity scores supplied with `--pscore-col`
+//
+// Scores estimated elsewhere must be numeric, present for every
+// observation and strictly between 0 and 1, since matching and the
+// overlap diagnostics work on their logits.
+pub fn validate_pscore(data: &DataFrame, column: &str) -> Result<(), Box<dyn Error>> {
+    let names = data.get_column_names();
+    if !names.contains(&column) {
+        return Err(format!(
+            "The propensity score column `{}` is not in the data; available columns are: {}",
+            column,
+            names.join(", ")
+        ).into());
+    }
+    let scores = data.column(column)?;
+    if !scores.dtype().is_numeric() {
+        return Err(format!(
+            "The propensity score column `{}` must be numeric but has type {}",
+            column,
+            scores.dtype()
+        ).into());
+    }
+    let values = column_values(data, column)?;
+    let missing = values.iter().filter(|p| p.is_nan()).count();
+    if missing > 0 {
+        return Err(format!(
+            "The propensity score column `{}` has {} missing values; every observation needs a score",
+            column,
+            missing
+        ).into());
+    }
+    let outside: Vec<f64> = values.into_iter().filter(|p| *p <= 0.0 || *p >= 1.0).collect();
+    if let Some(value) = outside.first() {
+        return Err(format!(
+            "The propensity score column `{}` must be strictly between 0 and 1 but has {} other values, e.g. {}",
+            column,
+            outside.len(),
+            value
+        ).into());
+    }
+    Ok(())
+}
+
+// Check that every propensity score is a probability
+//
+// A logistic fit that breaks down (e.g. with very few treated units or
+// covariates that perfectly predict treatment) yields missing or non-finite
+// scores, which would otherwise make matching fail.
+pub fn validate_propensities(data: &DataFrame) -> Result<(), Box<dyn Error>> {
+    let scores = column_values(data, "propensities")?;
+    let bad = scores.iter().filter(|p| !p.is_finite()).count();
+    if bad > 0 {
+        return Err(format!(
+            "The propensity model failed to score {} of {} observations; check for too few treated or control units or covariates that perfectly predict treatment",
+            bad,
+            scores.len()
+        ).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,268 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::diagnostics::control_reuse;
+use crate::propensity::{LogitFit, Separation};
+
+// Treated sample sizes below which estimates are flagged.
+const SMALL_SAMPLE: (usize, usize) = (100, 30);
+
+// Overlapping coefficients below which overlap is flagged.
+const POOR_OVERLAP: (f64, f64) = (0.5, 0.2);
+
+// Ratios of the largest to the mean survey weight above which weights are
+// flagged.
+const EXTREME_WEIGHTS: (f64, f64) = (10.0, 25.0);
+
+// Shares of the treated units matched to a single control above which
+// control reuse is flagged.
+const CONTROL_REUSE: (f64, f64) = (0.05, 0.1);
+
+// How serious a warning is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Caution,
+    Serious
+}
+
+// Implement `Display` for `Severity`.
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Caution => write!(f, "caution"),
+            Severity::Serious => write!(f, "serious")
+        }
+    }
+}
+
+// The problems Strike warns about
+//
+// Each kind has a short code that `--fail-on-warning` accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WarningKind {
+    SmallSample,
+    PoorOverlap,
+    ExtremeWeights,
+    NonConvergence,
+    ControlReuse,
+    Separation
+}
+
+// Implement `FromStr` for `WarningKind`.
+impl FromStr for WarningKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "small-sample" => Ok(WarningKind::SmallSample),
+            "poor-overlap" => Ok(WarningKind::PoorOverlap),
+            "extreme-weights" => Ok(WarningKind::ExtremeWeights),
+            "non-convergence" => Ok(WarningKind::NonConvergence),
+            "control-reuse" => Ok(WarningKind::ControlReuse),
+            "separation" => Ok(WarningKind::Separation),
+            _ => Err(format!("Unknown warning `{}`", s))
+        }
+    }
+}
+
+// Implement `Display` for `WarningKind`.
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WarningKind::SmallSample => write!(f, "small-sample"),
+            WarningKind::PoorOverlap => write!(f, "poor-overlap"),
+            WarningKind::ExtremeWeights => write!(f, "extreme-weights"),
+            WarningKind::NonConvergence => write!(f, "non-convergence"),
+            WarningKind::ControlReuse => write!(f, "control-reuse"),
+            WarningKind::Separation => write!(f, "separation")
+        }
+    }
+}
+
+// A single warning about the analysis.
+#[derive(Clone, Debug)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub severity: Severity,
+    pub message: String
+}
+
+// Implement `Display` for `Warning`.
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.severity, self.kind, self.message)
+    }
+}
+
+// Which warnings `--fail-on-warning` turns into errors
+//
+// `all` fails on any warning, `serious` on serious warnings only, and a
+// comma-separated list of codes on warnings of those kinds.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FailOn {
+    All,
+    Serious,
+    Kinds(Vec<WarningKind>)
+}
+
+impl FailOn {
+    pub fn fails(&self, warning: &Warning) -> bool {
+        match self {
+            FailOn::All => true,
+            FailOn::Serious => warning.severity == Severity::Serious,
+            FailOn::Kinds(kinds) => kinds.contains(&warning.kind)
+        }
+    }
+}
+
+// Implement `FromStr` for `FailOn`.
+impl FromStr for FailOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(FailOn::All),
+            "serious" => Ok(FailOn::Serious),
+            _ => Ok(FailOn::Kinds(
+                s.split(',').map(|k| k.trim().parse()).collect::<Result<_, _>>()?
+            ))
+        }
+    }
+}
+
+// Pick the severity of a value that is bad when above the thresholds.
+fn above(value: f64, (caution, serious): (f64, f64)) -> Option<Severity> {
+    match value {
+        v if v > serious => Some(Severity::Serious),
+        v if v > caution => Some(Severity::Caution),
+        _ => None
+    }
+}
+
+// Pick the severity of a value that is bad when below the thresholds.
+fn below(value: f64, (caution, serious): (f64, f64)) -> Option<Severity> {
+    match value {
+        v if v < serious => Some(Severity::Serious),
+        v if v < caution => Some(Severity::Caution),
+        _ => None
+    }
+}
+
+// Collect every warning that applies to an analysis
+//
+// `control_ids` are the ids of the matched controls (one per treated unit),
+// `weights` the survey weights of the treated units if any, `fit` the
+// convergence diagnostics of the propensity model and `separation` the
+// predictors that separate treated from control units.
+pub fn collect_warnings(
+    n_treat: usize,
+    overlap: f64,
+    control_ids: &[f64],
+    weights: Option<&[f64]>,
+    fit: Option<LogitFit>,
+    separation: &[Separation]
+) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let small = (SMALL_SAMPLE.0 as f64, SMALL_SAMPLE.1 as f64);
+    if let Some(severity) = below(n_treat as f64, small) {
+        warnings.push(Warning {
+            kind: WarningKind::SmallSample,
+            severity,
+            message: format!("only {} treated units; large-sample variances may be unreliable", n_treat)
+        });
+    }
+    if let Some(severity) = below(overlap, POOR_OVERLAP) {
+        warnings.push(Warning {
+            kind: WarningKind::PoorOverlap,
+            severity,
+            message: format!(
+                "the treated and control propensity distributions share only {:.1}% of their area",
+                overlap * 100.0
+            )
+        });
+    }
+    if let Some(weights) = weights {
+        let mean = weights.iter().sum::<f64>() / weights.len() as f64;
+        let ratio = weights.iter().fold(0.0_f64, |m, w| m.max(*w)) / mean;
+        if let Some(severity) = above(ratio, EXTREME_WEIGHTS) {
+            warnings.push(Warning {
+                kind: WarningKind::ExtremeWeights,
+                severity,
+                message: format!("the largest survey weight is {:.1} times the mean weight", ratio)
+            });
+        }
+    }
+    warnings.extend(model_warnings(fit, separation));
+    let reuse = control_reuse(control_ids);
+    if let Some(severity) = above(reuse.max_share, CONTROL_REUSE) {
+        warnings.push(Warning {
+            kind: WarningKind::ControlReuse,
+            severity,
+            message: format!(
+                "a single control is matched to {} treated units ({:.1}%)",
+                reuse.max_uses,
+                reuse.max_share * 100.0
+            )
+        });
+    }
+    warnings
+}
+
+// Warnings about the propensity model: `fit` didn't converge or predictors
+// in `separation` separate treated from control units.
+pub fn model_warnings(fit: Option<LogitFit>, separation: &[Separation]) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    if let Some(fit) = fit {
+        if !fit.converged() {
+            let iterations = match fit.iterations {
+                Some(i) => format!(" after {} iterations", i),
+                None => String::new()
+            };
+            warnings.push(Warning {
+                kind: WarningKind::NonConvergence,
+                severity: Severity::Serious,
+                message: format!(
+                    "the propensity model did not converge{} (largest scaled score {:.2e}, \
+                     log-likelihood {:.3}); try a larger --ps-max-iter, a smaller --ps-tolerance or \
+                     starting values with --ps-init",
+                    iterations,
+                    fit.max_gradient,
+                    fit.log_likelihood
+                )
+            });
+        }
+    }
+    let describe = |s: &Separation| match s.complete {
+        true => format!("{} (complete)", s.covariate),
+        false => format!("{} (quasi-complete)", s.covariate)
+    };
+    let verb = |n: usize| if n == 1 { "separates" } else { "separate" };
+    let kept: Vec<String> = separation.iter().filter(|s| !s.dropped).map(describe).collect();
+    if !kept.is_empty() {
+        warnings.push(Warning {
+            kind: WarningKind::Separation,
+            severity: Severity::Serious,
+            message: format!(
+                "{} perfectly {} treated from control units, so propensities are pushed to 0 or 1; \
+                 rerun with --drop-separating to leave them out of the propensity model or with \
+                 --ps-model firth for finite estimates",
+                kept.join(", "),
+                verb(kept.len())
+            )
+        });
+    }
+    let dropped: Vec<String> = separation.iter().filter(|s| s.dropped).map(describe).collect();
+    if !dropped.is_empty() {
+        warnings.push(Warning {
+            kind: WarningKind::Separation,
+            severity: Severity::Caution,
+            message: format!(
+                "{} {} treated from control units and {} left out of the propensity model",
+                dropped.join(", "),
+                verb(dropped.len()),
+                if dropped.len() == 1 { "was" } else { "were" }
+            )
+        });
+    }
+    warnings
+}
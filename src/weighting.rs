@@ -0,0 +1,261 @@
+use ndarray::{Array1, Array2};
+use polars::prelude::{DataFrame, PolarsResult};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::data::column_values;
+use crate::format::num;
+use crate::formula::predictor_values;
+use crate::propensity::Coefficients;
+use crate::regression::generalized_inverse;
+
+// Propensity score weighting estimators reported next to the matched ATT
+//
+// `Overlap` weights treated units by 1 - p and controls by p, which
+// targets the average effect in the overlap population (ATO), where units
+// could plausibly have been in either group. Units with extreme scores get
+// little weight instead of being trimmed. `Matching` is the matching
+// weights of Li and Greene (2013), min(p, 1 - p) divided by the
+// probability of the unit's own group, the smooth analogue of 1:1 pair
+// matching; its estimand (ATM) is that of the matched sample.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Weighting {
+    Overlap,
+    Matching
+}
+
+// Implement `FromStr` for `Weighting`.
+impl FromStr for Weighting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overlap" => Ok(Weighting::Overlap),
+            "matching" => Ok(Weighting::Matching),
+            _ => Err(format!("Unknown weighting `{}`; use overlap or matching", s))
+        }
+    }
+}
+
+// Implement `Display` for `Weighting`.
+impl fmt::Display for Weighting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Weighting::Overlap => write!(f, "overlap"),
+            Weighting::Matching => write!(f, "matching")
+        }
+    }
+}
+
+impl Weighting {
+    // Name of the estimand the weights target.
+    pub fn estimand(&self) -> &'static str {
+        match self {
+            Weighting::Overlap => "ATO",
+            Weighting::Matching => "ATM"
+        }
+    }
+
+    // Weight of a unit with propensity score `p` in group `d` (1 treated,
+    // 0 control) and its derivative with respect to `p`. The matching
+    // weights have a kink at p = 0.5, where the derivative from below is
+    // used.
+    fn weight(&self, p: f64, d: f64) -> (f64, f64) {
+        match (self, d == 1.0) {
+            (Weighting::Overlap, true) => (1.0 - p, -1.0),
+            (Weighting::Overlap, false) => (p, 1.0),
+            (Weighting::Matching, true) if p <= 0.5 => (1.0, 0.0),
+            (Weighting::Matching, true) => ((1.0 - p) / p, -1.0 / (p * p)),
+            (Weighting::Matching, false) if p <= 0.5 => (p / (1.0 - p), 1.0 / (1.0 - p).powi(2)),
+            (Weighting::Matching, false) => (1.0, 0.0)
+        }
+    }
+}
+
+// A propensity score weighted estimate
+//
+// The difference of the weighted mean outcomes of the treated units and
+// the controls, with its sandwich variance and the effective sample size
+// of each group's weights, (sum w)^2 / sum w^2.
+#[derive(Debug)]
+pub struct WeightedEstimate {
+    pub method: Weighting,
+    pub estimate: f64,
+    pub variance: f64,
+    pub effective_n: (f64, f64)
+}
+
+// Calculate a propensity score weighted estimate
+//
+// `samples` hold every unit of the analysis once, with its propensity
+// score, and `model` is the propensity model that produced the scores. The
+// variance is the M-estimation sandwich of the weighted means stacked with
+// the score equations of the logistic propensity model, so it accounts for
+// the scores being estimated: each weighted mean's influence is corrected
+// by the projection of its derivative in the coefficients on the model's
+// scores. With a Firth model the score equations are those of plain
+// maximum likelihood, which it approaches in large samples.
+pub fn weighted_estimate(
+    samples: &[&DataFrame],
+    treatment: &str,
+    outcome: &str,
+    model: &Coefficients,
+    method: Weighting
+) -> PolarsResult<WeightedEstimate> {
+    let (mut d, mut y, mut p) = (Vec::new(), Vec::new(), Vec::new());
+    let mut columns: Vec<Vec<f64>> = vec![Vec::new(); model.slopes.len()];
+    for data in samples {
+        d.extend(column_values(data, treatment)?);
+        y.extend(column_values(data, outcome)?);
+        p.extend(column_values(data, "propensities")?);
+        for ((name, _), column) in model.slopes.iter().zip(columns.iter_mut()) {
+            column.extend(predictor_values(data, name)?);
+        }
+    }
+    let (n, q) = (d.len(), model.slopes.len() + 1);
+    let mut x = Array2::<f64>::ones((n, q));
+    for (k, column) in columns.into_iter().enumerate() {
+        x.column_mut(k + 1).assign(&Array1::from(column));
+    }
+    let weights: Vec<(f64, f64)> = p.iter().zip(&d).map(|(p, d)| method.weight(*p, *d)).collect();
+    let group_mean = |group: f64| {
+        let (total, sum) = (0..n)
+            .filter(|i| d[*i] == group)
+            .fold((0.0, 0.0), |(t, s), i| (t + weights[i].0, s + weights[i].0 * y[i]));
+        (total, sum / total)
+    };
+    let ((a1, mu1), (a0, mu0)) = (group_mean(1.0), group_mean(0.0));
+    // Information of the propensity model and the derivatives of the
+    // weighted means' estimating equations in its coefficients
+    let mut information = Array2::<f64>::zeros((q, q));
+    let (mut h1, mut h0) = (Array1::<f64>::zeros(q), Array1::<f64>::zeros(q));
+    for i in 0..n {
+        let xi = x.row(i);
+        let v = p[i] * (1.0 - p[i]);
+        for j in 0..q {
+            for k in 0..q {
+                information[[j, k]] += v * xi[j] * xi[k];
+            }
+        }
+        let derivative = weights[i].1 * v;
+        match d[i] == 1.0 {
+            true => h1.scaled_add(-derivative * (y[i] - mu1), &xi),
+            false => h0.scaled_add(-derivative * (y[i] - mu0), &xi)
+        }
+    }
+    let (inverse, _) = generalized_inverse(&information);
+    let (g1, g0) = (inverse.dot(&h1), inverse.dot(&h0));
+    let variance: f64 = (0..n)
+        .map(|i| {
+            let score = &x.row(i) * (d[i] - p[i]);
+            let (psi1, psi0) = match d[i] == 1.0 {
+                true => (weights[i].0 * (y[i] - mu1), 0.0),
+                false => (0.0, weights[i].0 * (y[i] - mu0))
+            };
+            ((psi1 - g1.dot(&score)) / a1 - (psi0 - g0.dot(&score)) / a0).powi(2)
+        })
+        .sum();
+    let effective_n = |group: f64| {
+        let w: Vec<f64> = (0..n).filter(|i| d[*i] == group).map(|i| weights[i].0).collect();
+        w.iter().sum::<f64>().powi(2) / w.iter().map(|w| w * w).sum::<f64>()
+    };
+    Ok(WeightedEstimate {
+        method,
+        estimate: mu1 - mu0,
+        variance,
+        effective_n: (effective_n(1.0), effective_n(0.0))
+    })
+}
+
+// Implement `Display` for `WeightedEstimate`.
+impl fmt::Display for WeightedEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let se = self.variance.sqrt();
+        write!(
+            f,
+            "Propensity score weighting ({})\n\
+            {:<24}: {}\n\
+            Variance (sandwich)     : {}\n\
+            95% Confidence Interval : ({}, {})\n\
+            Effective sample size   : {} treated | {} control\n",
+            self.method,
+            self.method.estimand(),
+            num(self.estimate),
+            num(self.variance),
+            num(self.estimate - 1.96 * se),
+            num(self.estimate + 1.96 * se),
+            num(self.effective_n.0),
+            num(self.effective_n.1)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::df;
+    use polars::prelude::NamedFrom;
+
+    // Two treated units (y = 4, 8) and two controls (y = 1, 3) with the
+    // given propensity scores, and an intercept-only propensity model.
+    fn estimate(p: [f64; 4], method: Weighting) -> WeightedEstimate {
+        let data = df!(
+            "d" => [1.0, 1.0, 0.0, 0.0],
+            "y" => [4.0, 8.0, 1.0, 3.0],
+            "propensities" => p
+        ).unwrap();
+        let model = Coefficients { intercept: 0.0, slopes: Vec::new() };
+        weighted_estimate(&[&data], "d", "y", &model, method).unwrap()
+    }
+
+    // Check a weight's derivative against a central difference.
+    fn check_derivative(method: Weighting, p: f64, d: f64) {
+        let h = 1e-6;
+        let numeric = (method.weight(p + h, d).0 - method.weight(p - h, d).0) / (2.0 * h);
+        assert!((method.weight(p, d).1 - numeric).abs() < 1e-6);
+    }
+
+    #[test]
+    fn overlap_weights() {
+        // Treated: (0.5 * 4 + 0.75 * 8) / 1.25 = 6.4; controls:
+        // (0.5 * 1 + 0.75 * 3) / 1.25 = 2.2
+        let estimate = estimate([0.5, 0.25, 0.5, 0.75], Weighting::Overlap);
+        assert!((estimate.estimate - 4.2).abs() < 1e-12);
+        assert!((estimate.effective_n.0 - 1.5625 / 0.8125).abs() < 1e-12);
+        for (p, d) in [(0.3, 1.0), (0.3, 0.0), (0.8, 1.0), (0.8, 0.0)] {
+            check_derivative(Weighting::Overlap, p, d);
+        }
+    }
+
+    #[test]
+    fn overlap_variance_with_constant_scores() {
+        // Equal weights make the estimate a difference in means, and the
+        // model correction vanishes as the residuals sum to zero in each
+        // group: 8 / 2^2 + 2 / 2^2.
+        let estimate = estimate([0.5; 4], Weighting::Overlap);
+        assert!((estimate.estimate - 4.0).abs() < 1e-12);
+        assert!((estimate.variance - 2.5).abs() < 1e-12);
+        assert_eq!(estimate.effective_n, (2.0, 2.0));
+    }
+
+    #[test]
+    fn matching_weights() {
+        // Treated: (1 * 4 + 1/3 * 8) / (4/3) = 5; controls:
+        // (1/3 * 1 + 1 * 3) / (4/3) = 2.5
+        let estimate = estimate([0.25, 0.75, 0.25, 0.75], Weighting::Matching);
+        assert!((estimate.estimate - 2.5).abs() < 1e-12);
+        for (p, d) in [(0.3, 1.0), (0.3, 0.0), (0.8, 1.0), (0.8, 0.0)] {
+            check_derivative(Weighting::Matching, p, d);
+        }
+    }
+
+    #[test]
+    fn matching_weights_at_one_half_are_one() {
+        // Every unit is weighted 1, as in 1:1 pair matching of every unit
+        assert_eq!(Weighting::Matching.weight(0.5, 1.0).0, 1.0);
+        assert_eq!(Weighting::Matching.weight(0.5, 0.0).0, 1.0);
+        let estimate = estimate([0.5; 4], Weighting::Matching);
+        assert!((estimate.estimate - 4.0).abs() < 1e-12);
+        assert!((estimate.variance - 2.5).abs() < 1e-12);
+    }
+}